@@ -0,0 +1,252 @@
+//! Key bindings, read from `bindings.toml` in the data directory and
+//! changed in the Controls menu of the main menu.
+//!
+//! Every action lists the keys that trigger it, replacing its default keys.
+//! Actions that aren't listed keep their defaults, and an empty list leaves
+//! an action without a key.
+//!
+//! ```toml
+//! a = ["Z", "J"]
+//! select = ["Space", "Backspace"]
+//! speed2 = ["2", "Tab"]
+//! fullscreen = ["F11"]
+//! ```
+//!
+//! Keys are named after what is printed on them, like `A`, `1`, `F1`, `Up`,
+//! `Space`, `Return`, `Backspace`, `Tab` and `Escape`.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    keypad::{KeyboardKey, KeypadKey},
+    saves,
+};
+
+/// How many speed presets there are, from the Game Boy's own speed to the
+/// fastest
+pub const SPEED_PRESETS: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Keypad(KeypadKey),
+    /// One of the speed presets, counting from 0
+    Speed(usize),
+    Fullscreen,
+    Screenshot,
+    ToggleRecording,
+    /// Prints the instruction trace
+    Trace,
+    /// Loads the snapshot in this slot, or saves to it when shift is held
+    Snapshot(u8),
+}
+
+/// Every action with its name in the bindings file and its default keys
+#[rustfmt::skip]
+const ACTIONS: [(Action, &str, &[KeyboardKey]); 22] = [
+    (Action::Keypad(KeypadKey::Up), "up", &[KeyboardKey::Up, KeyboardKey::W]),
+    (Action::Keypad(KeypadKey::Down), "down", &[KeyboardKey::Down, KeyboardKey::S]),
+    (Action::Keypad(KeypadKey::Left), "left", &[KeyboardKey::Left, KeyboardKey::A]),
+    (Action::Keypad(KeypadKey::Right), "right", &[KeyboardKey::Right, KeyboardKey::D]),
+    (Action::Keypad(KeypadKey::A), "a", &[KeyboardKey::Z, KeyboardKey::N]),
+    (Action::Keypad(KeypadKey::B), "b", &[KeyboardKey::X, KeyboardKey::M]),
+    (Action::Keypad(KeypadKey::Start), "start", &[KeyboardKey::Return]),
+    (Action::Keypad(KeypadKey::Select), "select", &[KeyboardKey::Space, KeyboardKey::Backspace]),
+    (Action::Speed(0), "speed1", &[KeyboardKey::Key1]),
+    (Action::Speed(1), "speed2", &[KeyboardKey::Key2]),
+    (Action::Speed(2), "speed3", &[KeyboardKey::Key3]),
+    (Action::Speed(3), "speed4", &[KeyboardKey::Key4]),
+    (Action::Speed(4), "speed5", &[KeyboardKey::Key5]),
+    (Action::Speed(5), "speed6", &[KeyboardKey::Key6]),
+    (Action::Fullscreen, "fullscreen", &[KeyboardKey::F11]),
+    (Action::Screenshot, "screenshot", &[KeyboardKey::F6]),
+    (Action::ToggleRecording, "record", &[KeyboardKey::F7]),
+    (Action::Trace, "trace", &[KeyboardKey::F5]),
+    (Action::Snapshot(1), "snapshot1", &[KeyboardKey::F1]),
+    (Action::Snapshot(2), "snapshot2", &[KeyboardKey::F2]),
+    (Action::Snapshot(3), "snapshot3", &[KeyboardKey::F3]),
+    (Action::Snapshot(4), "snapshot4", &[KeyboardKey::F4]),
+];
+
+impl Action {
+    /// Every action, in the order of the Controls menu
+    pub fn all() -> impl Iterator<Item = Action> {
+        ACTIONS.iter().map(|&(action, _, _)| action)
+    }
+
+    /// Name in the bindings file
+    pub fn name(self) -> &'static str {
+        ACTIONS[self.index()].1
+    }
+
+    fn index(self) -> usize {
+        ACTIONS.iter().position(|&(a, _, _)| a == self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bindings {
+    /// Keys of each action, in the same order as `ACTIONS`
+    keys: Vec<Vec<KeyboardKey>>,
+    /// Where changes from the Controls menu are saved
+    path: Option<PathBuf>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Bindings {
+            keys: ACTIONS.iter().map(|(_, _, keys)| keys.to_vec()).collect(),
+            path: None,
+        }
+    }
+}
+
+impl Bindings {
+    pub fn default_path() -> PathBuf {
+        saves::get_bindings_path()
+    }
+
+    /// Reads the bindings file, which is optional. Changes made in the
+    /// Controls menu are saved to `path`.
+    pub fn load(path: &Path) -> io::Result<Bindings> {
+        let mut bindings = match fs::read_to_string(path) {
+            Ok(text) => Bindings::parse(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Bindings::default(),
+            Err(e) => return Err(e),
+        };
+
+        bindings.path = Some(path.to_owned());
+
+        Ok(bindings)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Bindings> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+
+        let raw: BTreeMap<String, Vec<String>> =
+            toml::from_str(text).map_err(|e: toml::de::Error| invalid(e.to_string()))?;
+
+        let mut bindings = Bindings::default();
+
+        for (name, keys) in raw {
+            let Some(index) = ACTIONS.iter().position(|(_, n, _)| *n == name) else {
+                return Err(invalid(format!("Unknown action {}", name)));
+            };
+
+            bindings.keys[index] = keys
+                .iter()
+                .map(|key| key.parse())
+                .collect::<Result<_, _>>()
+                .map_err(invalid)?;
+        }
+
+        Ok(bindings)
+    }
+
+    /// Writes every action, so that the file shows all of them
+    pub fn to_toml(&self) -> String {
+        let mut text = String::new();
+
+        for ((_, name, _), keys) in ACTIONS.iter().zip(&self.keys) {
+            let keys = keys
+                .iter()
+                .map(|key| format!("\"{}\"", key))
+                .collect::<Vec<_>>();
+
+            text.push_str(&format!("{} = [{}]\n", name, keys.join(", ")));
+        }
+
+        text
+    }
+
+    /// Writes the bindings back to the file they were loaded from, if any
+    pub fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => fs::write(path, self.to_toml()),
+            None => Ok(()),
+        }
+    }
+
+    /// What `key` does, if anything. A key bound to several actions only
+    /// triggers the first of them.
+    pub fn action(&self, key: KeyboardKey) -> Option<Action> {
+        ACTIONS
+            .iter()
+            .zip(&self.keys)
+            .find(|(_, keys)| keys.contains(&key))
+            .map(|(&(action, _, _), _)| action)
+    }
+
+    pub fn keys(&self, action: Action) -> &[KeyboardKey] {
+        &self.keys[action.index()]
+    }
+
+    /// Binds `key` to `action` alone, or unbinds it if it already was bound
+    /// to it
+    pub fn toggle(&mut self, action: Action, key: KeyboardKey) {
+        let index = action.index();
+
+        if self.keys[index].contains(&key) {
+            self.keys[index].retain(|&k| k != key);
+            return;
+        }
+
+        for keys in &mut self.keys {
+            keys.retain(|&k| k != key);
+        }
+
+        self.keys[index].push(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let bindings = Bindings::parse("a = [\"j\", \"Space\"]\nfullscreen = []\n").unwrap();
+
+        assert_eq!(
+            bindings.action(KeyboardKey::J),
+            Some(Action::Keypad(KeypadKey::A))
+        );
+        assert_eq!(bindings.action(KeyboardKey::Z), None);
+        assert_eq!(bindings.action(KeyboardKey::F11), None);
+        assert_eq!(bindings.action(KeyboardKey::Key3), Some(Action::Speed(2)));
+
+        // The first action wins over the default binding of select
+        assert_eq!(
+            bindings.action(KeyboardKey::Space),
+            Some(Action::Keypad(KeypadKey::A))
+        );
+
+        assert_eq!(Bindings::parse(&bindings.to_toml()).unwrap(), bindings);
+        assert_eq!(Bindings::parse("").unwrap(), Bindings::default());
+
+        assert!(Bindings::parse("jump = [\"Space\"]").is_err());
+        assert!(Bindings::parse("a = [\"Shift\"]").is_err());
+        assert!(Bindings::parse("a = \"Z\"").is_err());
+    }
+
+    #[test]
+    fn toggle() {
+        let mut bindings = Bindings::default();
+
+        bindings.toggle(Action::Keypad(KeypadKey::B), KeyboardKey::Z);
+        assert_eq!(
+            bindings.keys(Action::Keypad(KeypadKey::A)),
+            [KeyboardKey::N]
+        );
+        assert_eq!(
+            bindings.keys(Action::Keypad(KeypadKey::B)),
+            [KeyboardKey::X, KeyboardKey::M, KeyboardKey::Z]
+        );
+
+        bindings.toggle(Action::Keypad(KeypadKey::B), KeyboardKey::X);
+        assert_eq!(bindings.action(KeyboardKey::X), None);
+    }
+}
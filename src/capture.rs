@@ -0,0 +1,624 @@
+//! Screenshots and animated recordings of the composited screen, including
+//! the layers drawn by Rust code.
+
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::{saves, sound::CLOCKS_PER_SECOND};
+
+#[derive(Debug, Clone, Copy)]
+pub enum CaptureRequest {
+    Screenshot,
+    ToggleRecording,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecordingFormat {
+    /// Plays everywhere, but at no more than 50 frames per second
+    #[default]
+    Gif,
+    /// Keeps every frame and its exact timing
+    Apng,
+}
+
+impl RecordingFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Gif => "gif",
+            RecordingFormat::Apng => "png",
+        }
+    }
+
+    /// Frame durations are in centiseconds in a GIF and in milliseconds in
+    /// an APNG
+    fn time_units_per_second(self) -> u64 {
+        match self {
+            RecordingFormat::Gif => 100,
+            RecordingFormat::Apng => 1000,
+        }
+    }
+
+    /// Most viewers play GIF frames shorter than 2 cs much slower
+    fn min_frame_duration(self) -> u64 {
+        match self {
+            RecordingFormat::Gif => 2,
+            RecordingFormat::Apng => 1,
+        }
+    }
+}
+
+impl FromStr for RecordingFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gif" => Ok(RecordingFormat::Gif),
+            "apng" => Ok(RecordingFormat::Apng),
+            _ => Err(format!("Unknown format {}, expected gif or apng", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureOptions {
+    pub dir: PathBuf,
+    pub format: RecordingFormat,
+    /// Capture at 160x144 instead of the upscaled size
+    pub native: bool,
+}
+
+impl Default for CaptureOptions {
+    fn default() -> Self {
+        CaptureOptions {
+            dir: saves::get_capture_dir(),
+            format: RecordingFormat::default(),
+            native: false,
+        }
+    }
+}
+
+pub struct Capture {
+    options: CaptureOptions,
+    screenshot: bool,
+    recording: Option<Recording>,
+    /// Emulated time, for the frame durations of recordings
+    ticks: u64,
+}
+
+impl Capture {
+    pub fn new(options: CaptureOptions) -> Self {
+        Capture {
+            options,
+            screenshot: false,
+            recording: None,
+            ticks: 0,
+        }
+    }
+
+    /// Screenshots are taken of the next frame
+    pub fn request(&mut self, request: CaptureRequest) {
+        match request {
+            CaptureRequest::Screenshot => self.screenshot = true,
+            CaptureRequest::ToggleRecording => match self.recording.take() {
+                Some(recording) => recording.finish(self.ticks),
+                None => self.start_recording(),
+            },
+        }
+    }
+
+    pub fn do_cycle(&mut self, ticks: u32) {
+        if self.recording.is_some() {
+            self.ticks += ticks as u64;
+        }
+    }
+
+    fn start_recording(&mut self) {
+        let path = match next_path(
+            &self.options.dir,
+            "recording",
+            self.options.format.extension(),
+        ) {
+            Ok(path) => path,
+            Err(e) => {
+                log::error!("Failed to start recording: {}", e);
+                return;
+            }
+        };
+
+        log::info!("Recording to {}", path.display());
+
+        self.ticks = 0;
+        self.recording = Some(Recording {
+            path,
+            format: self.options.format,
+            encoder: None,
+            pending: None,
+        });
+    }
+
+    /// Called with every RGB frame that is presented, which is `scale` times
+    /// the native resolution
+    /// `width` and `height` are at the native resolution
+    pub fn frame(&mut self, frame: &[u8], width: usize, height: usize, scale: usize) {
+        if !self.screenshot && self.recording.is_none() {
+            return;
+        }
+
+        let (frame, width, height) = if self.options.native && scale > 1 {
+            (downscale(frame, width, height, scale), width, height)
+        } else {
+            (Cow::Borrowed(frame), width * scale, height * scale)
+        };
+
+        if std::mem::take(&mut self.screenshot) {
+            match write_screenshot(&self.options.dir, &frame, width, height) {
+                Ok(path) => log::info!("Saved screenshot to {}", path.display()),
+                Err(e) => log::error!("Failed to save screenshot: {}", e),
+            }
+        }
+
+        let time = self.ticks;
+
+        if let Some(recording) = self.recording.as_mut() {
+            if let Err(e) = recording.frame(&frame, width, height, time) {
+                log::error!("Failed to record to {}: {}", recording.path.display(), e);
+                self.recording = None;
+            }
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            recording.finish(self.ticks);
+        }
+    }
+}
+
+/// Picks the top left pixel of every block
+fn downscale(frame: &[u8], width: usize, height: usize, scale: usize) -> Cow<'static, [u8]> {
+    let mut native = Vec::with_capacity(width * height * 3);
+
+    for y in 0..height {
+        for x in 0..width {
+            let src = ((y * scale * width * scale) + (x * scale)) * 3;
+            native.extend_from_slice(&frame[src..src + 3]);
+        }
+    }
+
+    Cow::Owned(native)
+}
+
+/// The first `<prefix>-NNNN` file name that is free
+fn next_path(dir: &Path, prefix: &str, extension: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    Ok((1..)
+        .map(|n| dir.join(format!("{}-{:04}.{}", prefix, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap())
+}
+
+fn write_screenshot(dir: &Path, frame: &[u8], width: usize, height: usize) -> io::Result<PathBuf> {
+    let path = next_path(dir, "screenshot", "png")?;
+
+    image::save_buffer_with_format(
+        &path,
+        frame,
+        width as u32,
+        height as u32,
+        image::ColorType::Rgb8,
+        image::ImageFormat::Png,
+    )
+    .map_err(io::Error::other)?;
+
+    Ok(path)
+}
+
+enum Encoder {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Apng(ApngWriter),
+}
+
+impl Encoder {
+    fn write(
+        &mut self,
+        frame: &[u8],
+        width: usize,
+        height: usize,
+        duration: u16,
+    ) -> io::Result<()> {
+        match self {
+            Encoder::Gif(encoder) => {
+                let mut frame = gif_frame(frame, width as u16, height as u16);
+                frame.delay = duration;
+                encoder.write_frame(&frame).map_err(io::Error::other)
+            }
+            Encoder::Apng(writer) => writer.write_frame(frame, duration),
+        }
+    }
+
+    /// The GIF trailer is written when the encoder is dropped
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::Gif(_) => Ok(()),
+            Encoder::Apng(writer) => writer.finish(),
+        }
+    }
+}
+
+/// How many frames can wait to be encoded before the game waits for the
+/// encoder to catch up
+const ENCODER_QUEUE: usize = 8;
+
+/// Encodes frames on its own thread, since finding the palette of a GIF
+/// frame or compressing an APNG frame takes longer than emulating one
+struct EncoderThread {
+    frames: SyncSender<(Vec<u8>, u16)>,
+    thread: JoinHandle<io::Result<()>>,
+}
+
+impl EncoderThread {
+    fn spawn(mut encoder: Encoder, width: usize, height: usize) -> io::Result<Self> {
+        let (frames, receiver) = mpsc::sync_channel::<(Vec<u8>, u16)>(ENCODER_QUEUE);
+
+        let thread = thread::Builder::new()
+            .name("recording".into())
+            .spawn(move || {
+                for (frame, duration) in receiver {
+                    encoder.write(&frame, width, height, duration)?;
+                }
+
+                encoder.finish()
+            })?;
+
+        Ok(EncoderThread { frames, thread })
+    }
+
+    /// Waits for the queued frames to be written
+    fn finish(self) -> io::Result<()> {
+        drop(self.frames);
+
+        self.thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("the encoder panicked")))
+    }
+}
+
+struct Recording {
+    path: PathBuf,
+    format: RecordingFormat,
+    /// Started with the size of the first frame
+    encoder: Option<EncoderThread>,
+    /// The latest frame, and when it was first shown in time units of the
+    /// format. It is written once it changes, so that frames that stay the
+    /// same are stored only once.
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl Recording {
+    fn frame(&mut self, frame: &[u8], width: usize, height: usize, ticks: u64) -> io::Result<()> {
+        if self.encoder.is_none() {
+            let encoder = self.create_encoder(width, height)?;
+            self.encoder = Some(EncoderThread::spawn(encoder, width, height)?);
+        }
+
+        let time = self.time(ticks);
+
+        let Some((pending, start)) = self.pending.as_mut() else {
+            self.pending = Some((frame.to_vec(), time));
+            return Ok(());
+        };
+
+        if pending.as_slice() == frame {
+            return Ok(());
+        }
+
+        if time - *start < self.format.min_frame_duration() {
+            // Too short to be shown on its own, so the new frame takes over
+            // its time
+            pending.clear();
+            pending.extend_from_slice(frame);
+            return Ok(());
+        }
+
+        let duration = time - *start;
+        let previous = std::mem::replace(pending, frame.to_vec());
+        *start = time;
+
+        self.write(previous, duration)
+    }
+
+    fn time(&self, ticks: u64) -> u64 {
+        ticks * self.format.time_units_per_second() / CLOCKS_PER_SECOND as u64
+    }
+
+    fn create_encoder(&self, width: usize, height: usize) -> io::Result<Encoder> {
+        let file = BufWriter::new(File::create(&self.path)?);
+
+        Ok(match self.format {
+            RecordingFormat::Gif => {
+                let mut encoder = gif::Encoder::new(file, width as u16, height as u16, &[])
+                    .map_err(io::Error::other)?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(io::Error::other)?;
+                Encoder::Gif(encoder)
+            }
+            RecordingFormat::Apng => {
+                Encoder::Apng(ApngWriter::new(file, width as u32, height as u32)?)
+            }
+        })
+    }
+
+    fn write(&mut self, frame: Vec<u8>, duration: u64) -> io::Result<()> {
+        let duration = duration.min(u16::MAX as u64) as u16;
+
+        let Some(encoder) = self.encoder.as_ref() else {
+            return Ok(());
+        };
+
+        if encoder.frames.send((frame, duration)).is_ok() {
+            return Ok(());
+        }
+
+        // The thread only stops early when writing fails
+        let encoder = self.encoder.take().unwrap();
+        Err(encoder
+            .finish()
+            .err()
+            .unwrap_or_else(|| io::Error::other("the encoder stopped")))
+    }
+
+    /// Writes the last frame, which is shown until `ticks`
+    fn finish(mut self, ticks: u64) {
+        let time = self.time(ticks);
+
+        let result = match self.pending.take() {
+            Some((frame, start)) => {
+                let duration = (time - start).max(self.format.min_frame_duration());
+                self.write(frame, duration)
+            }
+            None => Ok(()),
+        };
+
+        let result = result.and_then(|()| match self.encoder.take() {
+            Some(encoder) => encoder.finish(),
+            None => Ok(()),
+        });
+
+        match result {
+            Ok(()) => log::info!("Saved recording to {}", self.path.display()),
+            Err(e) => log::error!("Failed to record to {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Colors are indexed exactly when there are few enough of them, which is
+/// almost always the case for the Game Boy screen
+fn gif_frame(frame: &[u8], width: u16, height: u16) -> gif::Frame<'static> {
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut indices = Vec::with_capacity(frame.len() / 3);
+
+    for pixel in frame.chunks_exact(3) {
+        let pixel = [pixel[0], pixel[1], pixel[2]];
+
+        let index = match palette.iter().position(|color| *color == pixel) {
+            Some(index) => index,
+            None if palette.len() < 256 => {
+                palette.push(pixel);
+                palette.len() - 1
+            }
+            None => return gif::Frame::from_rgb_speed(width, height, frame, 10),
+        };
+
+        indices.push(index as u8);
+    }
+
+    gif::Frame {
+        width,
+        height,
+        buffer: Cow::Owned(indices),
+        palette: Some(palette.concat()),
+        ..gif::Frame::default()
+    }
+}
+
+/// Writes an animated PNG as frames arrive. The number of frames is filled
+/// in when it is finished.
+struct ApngWriter {
+    file: BufWriter<File>,
+    width: u32,
+    height: u32,
+    frames: u32,
+    sequence: u32,
+}
+
+/// Where the frame count of the `acTL` chunk is, after the signature and the
+/// `IHDR` chunk
+const ACTL_OFFSET: u64 = 8 + 25;
+
+impl ApngWriter {
+    fn new(file: BufWriter<File>, width: u32, height: u32) -> io::Result<Self> {
+        let mut writer = ApngWriter {
+            file,
+            width,
+            height,
+            frames: 0,
+            sequence: 0,
+        };
+
+        writer.file.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        // 8 bit RGB, no interlacing
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        writer.chunk(b"IHDR", &ihdr)?;
+        writer.actl()?;
+
+        Ok(writer)
+    }
+
+    fn actl(&mut self) -> io::Result<()> {
+        let mut actl = Vec::with_capacity(8);
+        actl.extend_from_slice(&self.frames.to_be_bytes());
+        // Loop forever
+        actl.extend_from_slice(&0u32.to_be_bytes());
+        self.chunk(b"acTL", &actl)
+    }
+
+    fn chunk(&mut self, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(data);
+
+        self.file.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.file.write_all(kind)?;
+        self.file.write_all(data)?;
+        self.file.write_all(&crc.finalize().to_be_bytes())
+    }
+
+    /// `frame` is RGB, and is shown for `duration` milliseconds
+    fn write_frame(&mut self, frame: &[u8], duration: u16) -> io::Result<()> {
+        let mut fctl = Vec::with_capacity(26);
+        fctl.extend_from_slice(&self.sequence.to_be_bytes());
+        fctl.extend_from_slice(&self.width.to_be_bytes());
+        fctl.extend_from_slice(&self.height.to_be_bytes());
+        fctl.extend_from_slice(&0u32.to_be_bytes());
+        fctl.extend_from_slice(&0u32.to_be_bytes());
+        fctl.extend_from_slice(&duration.to_be_bytes());
+        fctl.extend_from_slice(&1000u16.to_be_bytes());
+        // Every frame covers the whole image, so there is nothing to dispose
+        // of or blend with
+        fctl.extend_from_slice(&[0, 0]);
+        self.chunk(b"fcTL", &fctl)?;
+        self.sequence += 1;
+
+        let row_len = self.width as usize * 3;
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+
+        for row in frame.chunks_exact(row_len) {
+            // No filtering
+            encoder.write_all(&[0])?;
+            encoder.write_all(row)?;
+        }
+
+        let data = encoder.finish()?;
+
+        if self.frames == 0 {
+            self.chunk(b"IDAT", &data)?;
+        } else {
+            let mut fdat = Vec::with_capacity(4 + data.len());
+            fdat.extend_from_slice(&self.sequence.to_be_bytes());
+            fdat.extend_from_slice(&data);
+            self.chunk(b"fdAT", &fdat)?;
+            self.sequence += 1;
+        }
+
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.chunk(b"IEND", &[])?;
+        self.file.seek(SeekFrom::Start(ACTL_OFFSET))?;
+        self.actl()?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::{GB_SCREEN_H, GB_SCREEN_W};
+
+    #[test]
+    fn records_changed_frames_to_apng() {
+        let dir = std::env::temp_dir().join("rustic-yellow-capture-test");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut capture = Capture::new(CaptureOptions {
+            dir: dir.clone(),
+            format: RecordingFormat::Apng,
+            native: false,
+        });
+
+        let black = vec![0; GB_SCREEN_W * GB_SCREEN_H * 3];
+        let white = vec![0xff; GB_SCREEN_W * GB_SCREEN_H * 3];
+
+        capture.request(CaptureRequest::ToggleRecording);
+
+        for frame in [&black, &black, &white, &white, &black] {
+            capture.frame(frame, GB_SCREEN_W, GB_SCREEN_H, 1);
+            capture.do_cycle(70224);
+        }
+
+        capture.request(CaptureRequest::ToggleRecording);
+
+        let path = dir.join("recording-0001.png");
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The frame count in acTL
+        assert_eq!(&bytes[ACTL_OFFSET as usize + 4..][..4], b"acTL");
+        assert_eq!(&bytes[ACTL_OFFSET as usize + 8..][..4], &3u32.to_be_bytes());
+
+        // Decoders without APNG support show the first frame
+        let image = image::load_from_memory(&bytes).unwrap().into_rgb8();
+        assert_eq!(image.dimensions(), (GB_SCREEN_W as u32, GB_SCREEN_H as u32));
+        assert_eq!(image.as_raw(), &black);
+    }
+
+    #[test]
+    fn records_to_gif_on_the_encoder_thread() {
+        let dir = std::env::temp_dir().join("rustic-yellow-capture-gif-test");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut capture = Capture::new(CaptureOptions {
+            dir: dir.clone(),
+            format: RecordingFormat::Gif,
+            native: false,
+        });
+
+        let frames: Vec<Vec<u8>> = (0..20u8)
+            .map(|n| vec![n * 10; GB_SCREEN_W * GB_SCREEN_H * 3])
+            .collect();
+
+        capture.request(CaptureRequest::ToggleRecording);
+
+        // More frames than fit in the queue, each shown for 25 cs
+        for frame in &frames {
+            capture.frame(frame, GB_SCREEN_W, GB_SCREEN_H, 1);
+            capture.do_cycle(CLOCKS_PER_SECOND / 4);
+        }
+
+        capture.request(CaptureRequest::ToggleRecording);
+
+        let file = File::open(dir.join("recording-0001.gif")).unwrap();
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = options.read_info(file).unwrap();
+
+        let mut colors = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.delay, 25);
+            colors.push(frame.buffer[0]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        let expected: Vec<u8> = frames.iter().map(|frame| frame[0]).collect();
+        assert_eq!(colors, expected);
+    }
+}
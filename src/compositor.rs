@@ -0,0 +1,518 @@
+//! Draws the layers of Rust UI on top of the upscaled Game Boy screen.
+//!
+//! The result is kept between frames, and only the 8x8 cells where the Game
+//! Boy screen or one of the layers changed are drawn again.
+
+use std::collections::HashMap;
+
+use image::RgbaImage;
+
+use crate::{
+    gpu::{GpuAtlas, GpuLayer, GpuSprite, GpuTile, GB_SCREEN_H, GB_SCREEN_W},
+    upscale::Upscaler,
+};
+
+pub const CELLS_X: usize = GB_SCREEN_W / 8;
+pub const CELLS_Y: usize = GB_SCREEN_H / 8;
+pub const CELLS: usize = CELLS_X * CELLS_Y;
+
+/// The built in atlases are drawn at 4 times the native resolution
+const BUILT_IN_TILE_SIZE: usize = 32;
+
+fn load_png(bytes: &[u8]) -> RgbaImage {
+    image::load_from_memory_with_format(bytes, image::ImageFormat::Png)
+        .unwrap()
+        .into_rgba8()
+}
+
+struct Atlas {
+    image: RgbaImage,
+    /// Pixels per tile in each direction
+    tile_size: usize,
+}
+
+/// A tile from an atlas at the current scale
+struct ScaledTile {
+    /// RGBA, row by row
+    pixels: Vec<u8>,
+}
+
+pub struct Compositor {
+    upscaler: Upscaler,
+    atlas_box_border: Atlas,
+    atlas_font: Atlas,
+    atlas_pokemon_icons: Atlas,
+    registered: Vec<Atlas>,
+    tiles: HashMap<GpuTile, ScaledTile>,
+    /// The Game Boy screen that `base` was upscaled from
+    frame: Vec<u8>,
+    /// The upscaled Game Boy screen
+    base: Vec<u8>,
+    /// `base` with the layers on top
+    screen: Vec<u8>,
+    /// Cells of `screen` that are out of date
+    dirty: [bool; CELLS],
+    /// One cell of a layer, in RGBA, before its opacity and tint are applied
+    cell: Vec<u8>,
+    /// Sprites in the cell being drawn, by z and id
+    sprites: Vec<(i32, usize)>,
+}
+
+impl Compositor {
+    pub fn new(upscaler: Upscaler) -> Self {
+        Compositor {
+            upscaler,
+            atlas_box_border: Atlas {
+                image: load_png(include_bytes!("../gfx/box_border.png")),
+                tile_size: BUILT_IN_TILE_SIZE,
+            },
+            atlas_font: Atlas {
+                image: load_png(include_bytes!("../gfx/font.png")),
+                tile_size: BUILT_IN_TILE_SIZE,
+            },
+            atlas_pokemon_icons: Atlas {
+                image: load_png(include_bytes!("../gfx/pokemon_icons.png")),
+                tile_size: BUILT_IN_TILE_SIZE,
+            },
+            registered: Vec::new(),
+            tiles: HashMap::new(),
+            frame: Vec::new(),
+            base: Vec::new(),
+            screen: Vec::new(),
+            dirty: [true; CELLS],
+            cell: Vec::new(),
+            sprites: Vec::new(),
+        }
+    }
+
+    pub fn upscaler(&self) -> Upscaler {
+        self.upscaler
+    }
+
+    pub fn set_upscaler(&mut self, upscaler: Upscaler) {
+        if self.upscaler != upscaler {
+            self.upscaler = upscaler;
+            self.tiles.clear();
+            self.invalidate();
+        }
+    }
+
+    pub fn register_atlas(&mut self, image: RgbaImage, tile_size: usize) -> GpuAtlas {
+        assert!(tile_size > 0, "Atlas tiles can't be empty");
+
+        self.registered.push(Atlas { image, tile_size });
+        GpuAtlas::Registered(self.registered.len() - 1)
+    }
+
+    /// Draws everything again on the next frame
+    pub fn invalidate(&mut self) {
+        self.frame.clear();
+    }
+
+    /// The cells that a removed layer covered have to be drawn again
+    pub fn remove_layer(&mut self, layer: &GpuLayer) {
+        for (cell, dirty) in self.dirty.iter_mut().enumerate() {
+            *dirty |= layer.covers(cell);
+        }
+    }
+
+    /// Returns the RGB screen at the size of the upscaler
+    pub fn compose(&mut self, frame: &[u8], layers: &mut [GpuLayer]) -> &[u8] {
+        if self.frame.len() != frame.len() {
+            self.frame.clear();
+            self.frame.extend_from_slice(frame);
+            self.upscaler.upscale_into(frame, &mut self.base);
+            self.screen.clone_from(&self.base);
+            self.dirty = [true; CELLS];
+        } else if self.frame != frame {
+            self.mark_changed_cells(frame);
+            self.frame.copy_from_slice(frame);
+            self.upscaler.upscale_into(frame, &mut self.base);
+        }
+
+        for layer in layers.iter_mut() {
+            for (dirty, changed) in self.dirty.iter_mut().zip(layer.dirty.iter_mut()) {
+                *dirty |= std::mem::take(changed);
+            }
+        }
+
+        for cell in 0..CELLS {
+            if std::mem::take(&mut self.dirty[cell]) {
+                self.draw_cell(cell, layers);
+            }
+        }
+
+        &self.screen
+    }
+
+    /// Filters look at the pixels around the one they scale, so the cells
+    /// next to a change are drawn again as well
+    fn mark_changed_cells(&mut self, frame: &[u8]) {
+        let row_len = GB_SCREEN_W * 3;
+        let cell_len = 8 * 3;
+
+        for cy in 0..CELLS_Y {
+            for cx in 0..CELLS_X {
+                let changed = (0..8).any(|y| {
+                    let start = (cy * 8 + y) * row_len + cx * cell_len;
+                    frame[start..start + cell_len] != self.frame[start..start + cell_len]
+                });
+
+                if !changed {
+                    continue;
+                }
+
+                for y in cy.saturating_sub(1)..(cy + 2).min(CELLS_Y) {
+                    for x in cx.saturating_sub(1)..(cx + 2).min(CELLS_X) {
+                        self.dirty[y * CELLS_X + x] = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn draw_cell(&mut self, cell: usize, layers: &[GpuLayer]) {
+        let scale = self.upscaler.scale();
+        let cell_size = 8 * scale;
+        let row_len = GB_SCREEN_W * scale * 3;
+        let x = (cell % CELLS_X) * cell_size * 3;
+        let y = (cell / CELLS_X) * cell_size;
+
+        for dy in 0..cell_size {
+            let start = (y + dy) * row_len + x;
+            let end = start + cell_size * 3;
+            self.screen[start..end].copy_from_slice(&self.base[start..end]);
+        }
+
+        for layer in layers {
+            if layer.opacity == 0 || !layer.covers(cell) {
+                continue;
+            }
+
+            self.draw_layer_cell(cell, layer);
+
+            let tint = layer.tint;
+            let opacity = layer.opacity as u32;
+            let plain = opacity == 255 && tint == [255; 3];
+
+            for dy in 0..cell_size {
+                let start = (y + dy) * row_len + x;
+                let dst = &mut self.screen[start..start + cell_size * 3];
+                let src = &self.cell[dy * cell_size * 4..(dy + 1) * cell_size * 4];
+                let pixels = dst.chunks_exact_mut(3).zip(src.chunks_exact(4));
+
+                // Rows of text are mostly transparent
+                if plain {
+                    if src.chunks_exact(4).any(|pixel| pixel[3] != 0) {
+                        pixels.for_each(|(dst, src)| blend(dst, src));
+                    }
+                    continue;
+                }
+
+                for (dst, src) in pixels {
+                    let alpha = (src[3] as u32 * opacity + 127) / 255;
+                    let r = (src[0] as u32 * tint[0] as u32 + 127) / 255;
+                    let g = (src[1] as u32 * tint[1] as u32 + 127) / 255;
+                    let b = (src[2] as u32 * tint[2] as u32 + 127) / 255;
+                    blend(dst, &[r as u8, g as u8, b as u8, alpha as u8]);
+                }
+            }
+        }
+    }
+
+    /// Draws the background tile and the sprites of a layer in one cell into
+    /// `self.cell`
+    fn draw_layer_cell(&mut self, cell: usize, layer: &GpuLayer) {
+        let cell_size = 8 * self.upscaler.scale();
+
+        self.sprites.clear();
+        self.sprites.extend(
+            layer
+                .sprites()
+                .filter(|(_, sprite)| sprite.covers(cell))
+                .map(|(id, sprite)| (sprite.z, id)),
+        );
+        self.sprites.sort_unstable();
+
+        let below = self.sprites.partition_point(|&(z, _)| z < 0);
+
+        self.cell.clear();
+
+        // Most cells only have a background tile, which is copied as it is
+        match layer.background[cell] {
+            Some(tile) if below == 0 => {
+                self.scale_tile_once(tile);
+                self.cell.extend_from_slice(&self.tiles[&tile].pixels);
+            }
+            background => {
+                self.cell.resize(cell_size * cell_size * 4, 0);
+
+                for i in 0..below {
+                    let sprite = layer.sprites[self.sprites[i].1].unwrap();
+                    self.draw_sprite(cell, &sprite);
+                }
+
+                if let Some(tile) = background {
+                    self.draw_tile(tile, (0, 0), (false, false));
+                }
+            }
+        }
+
+        for i in below..self.sprites.len() {
+            let sprite = layer.sprites[self.sprites[i].1].unwrap();
+            self.draw_sprite(cell, &sprite);
+        }
+    }
+
+    fn draw_sprite(&mut self, cell: usize, sprite: &GpuSprite) {
+        let scale = self.upscaler.scale() as i32;
+        let cell_x = (cell % CELLS_X) as i32 * 8;
+        let cell_y = (cell / CELLS_X) as i32 * 8;
+
+        for ty in 0..sprite.height {
+            for tx in 0..sprite.width {
+                let x = sprite.x + tx as i32 * 8 - cell_x;
+                let y = sprite.y + ty as i32 * 8 - cell_y;
+
+                if (-7..8).contains(&x) && (-7..8).contains(&y) {
+                    let tile = sprite.tile_at(tx, ty);
+                    let offset = ((x * scale) as isize, (y * scale) as isize);
+                    self.draw_tile(tile, offset, (sprite.flip_x, sprite.flip_y));
+                }
+            }
+        }
+    }
+
+    /// Draws a tile into `self.cell`, with its top left corner at `offset`
+    /// pixels from the corner of the cell
+    fn draw_tile(&mut self, tile: GpuTile, offset: (isize, isize), flip: (bool, bool)) {
+        self.scale_tile_once(tile);
+
+        let scaled = &self.tiles[&tile];
+        let size = (8 * self.upscaler.scale()) as isize;
+
+        for dy in offset.1.max(0)..(offset.1 + size).min(size) {
+            let sy = if flip.1 {
+                size - 1 - (dy - offset.1)
+            } else {
+                dy - offset.1
+            };
+
+            for dx in offset.0.max(0)..(offset.0 + size).min(size) {
+                let sx = if flip.0 {
+                    size - 1 - (dx - offset.0)
+                } else {
+                    dx - offset.0
+                };
+                let dst = ((dy * size + dx) * 4) as usize;
+                let src = ((sy * size + sx) * 4) as usize;
+
+                over(&mut self.cell[dst..dst + 4], &scaled.pixels[src..src + 4]);
+            }
+        }
+    }
+
+    fn scale_tile_once(&mut self, tile: GpuTile) {
+        if !self.tiles.contains_key(&tile) {
+            let scaled = self.scale_tile(tile);
+            self.tiles.insert(tile, scaled);
+        }
+    }
+
+    fn atlas(&self, atlas: GpuAtlas) -> &Atlas {
+        match atlas {
+            GpuAtlas::BoxBorder => &self.atlas_box_border,
+            GpuAtlas::Font => &self.atlas_font,
+            GpuAtlas::PokemonIcons => &self.atlas_pokemon_icons,
+            GpuAtlas::Registered(id) => &self.registered[id],
+        }
+    }
+
+    fn scale_tile(&self, tile: GpuTile) -> ScaledTile {
+        let atlas = self.atlas(tile.atlas);
+        let size = 8 * self.upscaler.scale();
+        let mut pixels = Vec::with_capacity(size * size * 4);
+
+        for dy in 0..size {
+            for dx in 0..size {
+                let sx = tile.src_x * atlas.tile_size + dx * atlas.tile_size / size;
+                let sy = tile.src_y * atlas.tile_size + dy * atlas.tile_size / size;
+                pixels.extend_from_slice(&atlas.image.get_pixel(sx as u32, sy as u32).0);
+            }
+        }
+
+        ScaledTile { pixels }
+    }
+}
+
+/// Blends an RGBA pixel onto an RGB one
+fn blend(dst: &mut [u8], src: &[u8]) {
+    let alpha = src[3] as u32;
+
+    match alpha {
+        0 => {}
+        255 => dst.copy_from_slice(&src[..3]),
+        _ => {
+            for (dst, src) in dst.iter_mut().zip(src) {
+                *dst = ((*src as u32 * alpha + *dst as u32 * (255 - alpha) + 127) / 255) as u8;
+            }
+        }
+    }
+}
+
+/// Blends an RGBA pixel onto another RGBA pixel
+fn over(dst: &mut [u8], src: &[u8]) {
+    let alpha = src[3] as u32;
+
+    match alpha {
+        0 => {}
+        255 => dst.copy_from_slice(src),
+        _ => {
+            let below = dst[3] as u32 * (255 - alpha) / 255;
+            let total = alpha + below;
+
+            for i in 0..3 {
+                let color = src[i] as u32 * alpha + dst[i] as u32 * below;
+                dst[i] = ((color + total / 2) / total) as u8;
+            }
+
+            dst[3] = total as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::upscale::{Filter, DEFAULT_SCALE};
+
+    fn menu_layer() -> GpuLayer {
+        let mut layer = GpuLayer::new();
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                layer.set_background(x, y, GpuTile::new(GpuAtlas::Font, x % 16, y % 4));
+            }
+        }
+
+        layer
+    }
+
+    #[test]
+    fn redraws_only_what_changed() {
+        let frame = vec![0x60; GB_SCREEN_W * GB_SCREEN_H * 3];
+        let mut layers = vec![menu_layer()];
+
+        let mut compositor = Compositor::new(Upscaler::new(Filter::Nearest, 2));
+        let first = compositor.compose(&frame, &mut layers).to_vec();
+
+        layers[0].set_background(0, 0, GpuTile::new(GpuAtlas::BoxBorder, 0, 0));
+        assert_ne!(compositor.compose(&frame, &mut layers), first);
+
+        layers[0].set_background(0, 0, GpuTile::new(GpuAtlas::Font, 0, 0));
+        assert_eq!(compositor.compose(&frame, &mut layers), first);
+
+        let removed = layers.pop().unwrap();
+        compositor.remove_layer(&removed);
+
+        let mut plain = Vec::new();
+        Upscaler::new(Filter::Nearest, 2).upscale_into(&frame, &mut plain);
+        assert_eq!(compositor.compose(&frame, &mut layers), plain);
+    }
+
+    #[test]
+    fn draws_sprites_with_flips_and_opacity() {
+        // A red tile and a blue tile, two by two pixels each
+        let mut atlas = RgbaImage::new(4, 2);
+        for (x, _, pixel) in atlas.enumerate_pixels_mut() {
+            pixel.0 = if x < 2 {
+                [255, 0, 0, 255]
+            } else {
+                [0, 0, 255, 255]
+            };
+        }
+
+        let frame = vec![0; GB_SCREEN_W * GB_SCREEN_H * 3];
+        let mut compositor = Compositor::new(Upscaler::new(Filter::Nearest, 1));
+        let atlas = compositor.register_atlas(atlas, 2);
+
+        let pixel = |screen: &[u8], x: usize, y: usize| {
+            let i = (y * GB_SCREEN_W + x) * 3;
+            [screen[i], screen[i + 1], screen[i + 2]]
+        };
+
+        let mut layers = vec![GpuLayer::new()];
+        let sprite = GpuSprite::new(GpuTile::new(atlas, 0, 0), 13, 5).size(2, 1);
+        let id = layers[0].add_sprite(sprite);
+
+        let screen = compositor.compose(&frame, &mut layers);
+        assert_eq!(pixel(screen, 13, 5), [255, 0, 0]);
+        assert_eq!(pixel(screen, 21, 12), [0, 0, 255]);
+        assert_eq!(pixel(screen, 29, 5), [0, 0, 0]);
+
+        layers[0].set_sprite(id, sprite.flip(true, false));
+        layers[0].add_sprite(GpuSprite::new(GpuTile::new(atlas, 0, 0), 20, 5).z(-1));
+        layers[0].set_opacity(128);
+
+        let screen = compositor.compose(&frame, &mut layers);
+        assert_eq!(pixel(screen, 13, 5), [0, 0, 128]);
+        assert_eq!(pixel(screen, 21, 12), [128, 0, 0]);
+        assert_eq!(pixel(screen, 20, 5), [0, 0, 128]);
+
+        layers[0].remove_sprite(id);
+        layers[0].set_opacity(255);
+        layers[0].set_tint([255, 128, 255]);
+
+        let screen = compositor.compose(&frame, &mut layers);
+        assert_eq!(pixel(screen, 13, 5), [0, 0, 0]);
+        assert_eq!(pixel(screen, 20, 5), [255, 0, 0]);
+    }
+
+    /// Only meaningful with optimizations, run with
+    /// `cargo test --release composites_400_fps`
+    #[test]
+    #[cfg_attr(debug_assertions, ignore = "needs optimizations, run with --release")]
+    fn composites_400_fps() {
+        // Consecutive frames differ in every byte
+        let pattern: Vec<u8> = (0..GB_SCREEN_W * GB_SCREEN_H * 3)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let frames: Vec<Vec<u8>> = [0x00, 0xff, 0x55, 0xaa]
+            .into_iter()
+            .map(|mask| pattern.iter().map(|byte| byte ^ mask).collect())
+            .collect();
+
+        let mut layers = vec![menu_layer()];
+        let mut compositor = Compositor::new(Upscaler::new(Filter::Nearest, DEFAULT_SCALE));
+
+        // The first frames draw everything and scale the tiles of the menu
+        for frame in &frames {
+            compositor.compose(frame, &mut layers);
+        }
+
+        const FRAMES: usize = 400;
+        let start = Instant::now();
+
+        for i in 0..FRAMES {
+            // The whole Game Boy screen changes on every frame, and the menu
+            // on some of them
+            if i % 8 == 0 {
+                let tile = GpuTile::new(GpuAtlas::Font, i % 16, 0);
+                layers[0].set_background(i % CELLS_X, 0, tile);
+            }
+
+            let frame = &frames[i % frames.len()];
+            std::hint::black_box(compositor.compose(frame, &mut layers));
+        }
+
+        // 400 fps leaves 2.5 ms for each frame, which also has to be emulated
+        let per_frame = start.elapsed() / FRAMES as u32;
+        assert!(
+            per_frame <= Duration::from_millis(2),
+            "Compositing took {:?} per frame",
+            per_frame
+        );
+    }
+}
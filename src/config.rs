@@ -0,0 +1,120 @@
+//! User settings, read from `config.toml` in the data directory.
+//!
+//! ```toml
+//! rom = "/path/to/pokeyellow.gbc"
+//! crystal_rom = "/path/to/pokecrystal.gbc"
+//!
+//! [music]
+//! chiptune = false
+//! chiptune_tracks = ["PalletTown", "Routes1"]
+//! manifest = "/path/to/soundtrack/manifest.toml"
+//! ```
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use serde::Deserialize;
+
+use crate::saves;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Pokemon Yellow ROM, relative paths are relative to the config file
+    pub rom: Option<PathBuf>,
+    /// Pokemon Crystal ROM, relative paths are relative to the config file
+    pub crystal_rom: Option<PathBuf>,
+    pub music: MusicConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MusicConfig {
+    /// Play the original chiptune from the ROM instead of the FLAC soundtrack
+    pub chiptune: bool,
+    /// Play only these tracks as chiptune, named like the `Music` or
+    /// `MusicSfx` variants
+    pub chiptune_tracks: Vec<String>,
+    /// Soundtrack manifest to use instead of `music/manifest.toml`, relative
+    /// paths are relative to the config file
+    pub manifest: Option<PathBuf>,
+}
+
+impl MusicConfig {
+    pub fn is_chiptune(&self, track: &str) -> bool {
+        self.chiptune || self.chiptune_tracks.iter().any(|t| t == track)
+    }
+}
+
+impl Config {
+    /// The config file as it was when first needed, for settings that are
+    /// read while the game is running
+    pub fn current() -> &'static Config {
+        static CURRENT: OnceLock<Config> = OnceLock::new();
+
+        CURRENT.get_or_init(|| {
+            Config::load().unwrap_or_else(|e| {
+                log::warn!("Failed to read {}: {}", Config::path().display(), e);
+                Config::default()
+            })
+        })
+    }
+
+    pub fn path() -> PathBuf {
+        saves::get_config_path()
+    }
+
+    /// Reads the config file, which is optional
+    pub fn load() -> io::Result<Config> {
+        let path = Config::path();
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e),
+        };
+
+        let mut config = Config::parse(&text)?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+
+        for path in [
+            &mut config.rom,
+            &mut config.crystal_rom,
+            &mut config.music.manifest,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+
+        Ok(config)
+    }
+
+    pub fn parse(text: &str) -> io::Result<Config> {
+        toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let config = Config::parse("rom = \"roms/yellow.gbc\"\n").unwrap();
+        assert_eq!(config.rom, Some(PathBuf::from("roms/yellow.gbc")));
+        assert_eq!(config.crystal_rom, None);
+
+        assert!(Config::parse("").unwrap().rom.is_none());
+        assert!(!Config::parse("").unwrap().music.is_chiptune("PalletTown"));
+
+        let config = Config::parse("[music]\nchiptune_tracks = [\"Routes1\"]\n").unwrap();
+        assert!(config.music.is_chiptune("Routes1"));
+        assert!(!config.music.is_chiptune("PalletTown"));
+        assert!(Config::parse("rom = 1").is_err());
+    }
+}
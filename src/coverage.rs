@@ -0,0 +1,245 @@
+//! Records which ROM routines still run in the interpreter, to find the ones
+//! that are most worth porting to Rust next.
+//!
+//! Every CALL, RST and JP executed by the interpreter (as well as every
+//! `Cpu::call` from Rust) counts as entering a routine. Cycles are attributed
+//! to the routine that is currently running, not including the routines it
+//! calls. A report ranking the unported routines by how often they were
+//! entered is written to disk by `Game::write_coverage_report`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::{
+    hooks::{HookKind, HookTable},
+    symbols::{self, Symbols},
+};
+
+/// Give up on matching returns when something keeps growing the stack without
+/// ever returning, e.g. code that discards return addresses
+const MAX_DEPTH: usize = 1024;
+
+#[derive(Default)]
+struct RoutineStats {
+    calls: u64,
+    jumps: u64,
+    cycles: u64,
+}
+
+pub struct Coverage {
+    report_path: PathBuf,
+    symbols: Option<Arc<Symbols>>,
+    ported: HashSet<(usize, u16)>,
+    routines: HashMap<(usize, u16), RoutineStats>,
+    /// `sp` right after the return address was pushed, and the caller
+    stack: Vec<(u16, Option<(usize, u16)>)>,
+    current: Option<(usize, u16)>,
+}
+
+impl Coverage {
+    pub fn new(report_path: PathBuf, symbols: Option<Arc<Symbols>>, hooks: &HookTable) -> Coverage {
+        let ported = hooks
+            .iter()
+            .filter(|(_, _, hook)| !matches!(hook.kind, HookKind::Observer(_)))
+            .map(|(bank, addr, _)| symbols::normalize(bank, addr))
+            .collect();
+
+        Coverage {
+            report_path,
+            symbols,
+            ported,
+            routines: HashMap::new(),
+            stack: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Pops every routine whose return address is no longer on the stack.
+    /// This catches both RET and hooks that return by popping `pc` directly.
+    pub fn sync(&mut self, sp: u16) {
+        while let Some(&(frame_sp, caller)) = self.stack.last() {
+            if sp <= frame_sp {
+                break;
+            }
+
+            self.stack.pop();
+            self.current = caller;
+        }
+    }
+
+    pub fn call(&mut self, bank: usize, addr: u16, sp: u16) {
+        let key = symbols::normalize(bank, addr);
+
+        if self.stack.len() == MAX_DEPTH {
+            self.stack.remove(0);
+        }
+
+        self.stack.push((sp, self.current));
+        self.current = Some(key);
+        self.routines.entry(key).or_default().calls += 1;
+    }
+
+    pub fn jump(&mut self, bank: usize, addr: u16) {
+        let key = symbols::normalize(bank, addr);
+
+        self.current = Some(key);
+        self.routines.entry(key).or_default().jumps += 1;
+    }
+
+    pub fn spend(&mut self, cycles: u32) {
+        if let Some(key) = self.current {
+            self.routines.entry(key).or_default().cycles += cycles as u64;
+        }
+    }
+
+    /// Routine that `(bank, addr)` is part of. With symbols, local labels
+    /// (`Routine.loop`) and addresses in the middle of a routine are folded
+    /// into the routine itself.
+    fn routine_name(&self, bank: usize, addr: u16) -> Option<&str> {
+        let (name, _) = self.symbols.as_ref()?.lookup(bank, addr)?;
+        Some(name.split('.').next().unwrap_or(name))
+    }
+
+    /// Writes the report to the path it was created with
+    pub fn write_report(&self) -> io::Result<()> {
+        let mut out = io::BufWriter::new(fs::File::create(&self.report_path)?);
+        self.report(&mut out)?;
+        out.flush()
+    }
+
+    fn report(&self, out: &mut impl Write) -> io::Result<()> {
+        struct Entry<'a> {
+            bank: usize,
+            addr: u16,
+            name: Option<&'a str>,
+            ported: bool,
+            stats: RoutineStats,
+        }
+
+        let mut entries: HashMap<_, Entry> = HashMap::new();
+
+        for (&(bank, addr), stats) in &self.routines {
+            let name = self.routine_name(bank, addr);
+            let group = match name {
+                Some(name) => (bank, name.to_owned()),
+                None => (bank, format!("{:04x}", addr)),
+            };
+
+            let entry = entries.entry(group).or_insert(Entry {
+                bank,
+                addr,
+                name,
+                ported: false,
+                stats: RoutineStats::default(),
+            });
+
+            entry.addr = entry.addr.min(addr);
+            entry.ported |= self.ported.contains(&(bank, addr));
+            entry.stats.calls += stats.calls;
+            entry.stats.jumps += stats.jumps;
+            entry.stats.cycles += stats.cycles;
+        }
+
+        let mut unported = entries.values().filter(|e| !e.ported).collect::<Vec<_>>();
+        unported.sort_by_key(|e| {
+            (
+                std::cmp::Reverse(e.stats.calls + e.stats.jumps),
+                std::cmp::Reverse(e.stats.cycles),
+                e.bank,
+                e.addr,
+            )
+        });
+
+        let total_cycles = self.routines.values().map(|s| s.cycles).sum::<u64>();
+        let ported_hit = entries.values().filter(|e| e.ported).count();
+
+        writeln!(out, "# Routines reached: {}", entries.len())?;
+        writeln!(out, "# Already ported: {}", ported_hit)?;
+        writeln!(out, "# Cycles in the interpreter: {}", total_cycles)?;
+        writeln!(out, "#")?;
+        writeln!(
+            out,
+            "# {:>4} {:<9} {:>10} {:>10} {:>12} {:>6}  name",
+            "rank", "location", "calls", "jumps", "cycles", "cyc%"
+        )?;
+
+        for (rank, entry) in unported.iter().enumerate() {
+            let share = if total_cycles == 0 {
+                0.0
+            } else {
+                entry.stats.cycles as f64 * 100.0 / total_cycles as f64
+            };
+
+            writeln!(
+                out,
+                "  {:>4} {:02x}:{:04x}   {:>10} {:>10} {:>12} {:>5.1}%  {}",
+                rank + 1,
+                entry.bank,
+                entry.addr,
+                entry.stats.calls,
+                entry.stats.jumps,
+                entry.stats.cycles,
+                share,
+                entry.name.unwrap_or("?"),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::Hook;
+
+    fn report(coverage: &Coverage) -> String {
+        let mut out = Vec::new();
+        coverage.report(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn ranks_unported_routines() {
+        let symbols =
+            Symbols::parse("00:0061 Ported\n00:0100 Often\n00:0110 Often.loop\n01:4000 Rarely\n");
+
+        let mut hooks = HookTable::new();
+        hooks.insert(0x00, 0x0061, Hook::guard("Ported"));
+
+        let mut coverage = Coverage::new(PathBuf::new(), Some(Arc::new(symbols)), &hooks);
+
+        coverage.call(0x00, 0x0061, 0xdffe);
+        coverage.spend(100);
+        coverage.sync(0xe000);
+
+        coverage.call(0x01, 0x4000, 0xdffe);
+        coverage.spend(40);
+        coverage.call(0x00, 0x0100, 0xdffc);
+        coverage.spend(20);
+        coverage.jump(0x00, 0x0110);
+        coverage.spend(20);
+        coverage.sync(0xdffe);
+        coverage.spend(20);
+        coverage.sync(0xe000);
+
+        let report = report(&coverage);
+        let lines = report.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines[0], "# Routines reached: 3");
+        assert_eq!(lines[1], "# Already ported: 1");
+        assert_eq!(lines[2], "# Cycles in the interpreter: 200");
+        assert_eq!(
+            lines[5..],
+            [
+                "     1 00:0100            1          1           40  20.0%  Often",
+                "     2 01:4000            1          0           60  30.0%  Rarely",
+            ]
+        );
+    }
+}
@@ -1,15 +1,26 @@
 use std::{
-    path,
-    sync::mpsc::{Receiver, SyncSender},
+    fs,
+    io::{self, Write},
+    panic, path,
 };
 
+use image::RgbaImage;
+
 use crate::{
+    coverage::Coverage,
+    disasm,
+    frontend::{AudioSink, InputSource, VideoSink},
     game_state::GameState,
-    gpu::GpuLayer,
-    keypad::{KeyboardEvent, KeypadKey, TextEvent},
+    gdb::GdbStub,
+    gpu::{GpuAtlas, GpuLayer},
+    hooks::{HookKind, HookTable},
+    keypad::{KeyboardKey, KeypadKey, TextEvent},
     mmu::Mmu,
     save_state::SaveState,
+    saves,
+    snapshot::{SnapshotReader, SnapshotRequest, SnapshotRestored, SnapshotWriter},
     sound2::{Music, Sfx},
+    trace::{Event, Step, Trace},
     PokemonSpecies,
 };
 use CpuFlag::{C, H, N, Z};
@@ -39,15 +50,31 @@ pub struct Cpu {
     pub(crate) setdi: u32,
     pub(crate) setei: u32,
 
+    /// Number of nested `call`s currently running, see [`Cpu::safe_point`]
+    call_depth: usize,
+
+    hooks: HookTable,
+
+    pub(crate) coverage: Option<Coverage>,
+
+    pub(crate) gdb: Option<GdbStub>,
+
+    pub(crate) trace: Option<Trace>,
+
     pub(crate) mmu: Mmu,
 
     pub(crate) starter: PokemonSpecies,
+
+    /// Where snapshots are saved to and loaded from
+    pub(crate) snapshot_dir: path::PathBuf,
 }
 
 impl Cpu {
     pub fn new(
-        update_screen: SyncSender<Vec<u8>>,
-        keyboard_events: Receiver<KeyboardEvent>,
+        video: Box<dyn VideoSink>,
+        input: Box<dyn InputSource>,
+        audio: Box<dyn AudioSink>,
+        hooks: HookTable,
         starter: PokemonSpecies,
     ) -> Cpu {
         Cpu {
@@ -67,9 +94,21 @@ impl Cpu {
             setdi: 0,
             setei: 0,
 
-            mmu: Mmu::new(update_screen, keyboard_events),
+            call_depth: 0,
+
+            hooks,
+
+            coverage: None,
+
+            gdb: None,
+
+            trace: None,
+
+            mmu: Mmu::new(video, input, audio),
 
             starter,
+
+            snapshot_dir: saves::get_snapshot_dir(),
         }
     }
 
@@ -77,141 +116,136 @@ impl Cpu {
         self.mmu.sound.sync();
     }
 
-    #[rustfmt::skip]
     pub fn call(&mut self, pc: u16) {
         assert_ne!(pc, 0x0000);
 
         self.stack_push(0x0000);
         self.pc = pc;
 
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.call(self.mmu.mbc.rombank, pc, self.sp);
+        }
+
+        self.call_depth += 1;
+        self.run();
+        self.call_depth -= 1;
+    }
+
+    /// Continues the outermost `call` after a snapshot has been loaded, with
+    /// the return marker already on the restored stack
+    pub fn resume(&mut self) {
+        self.call_depth = 1;
+        self.run();
+        self.call_depth = 0;
+    }
+
+    fn run(&mut self) {
         loop {
-            match (self.bank(), self.pc) {
-                (_, 0x0000) => break,
-                (_, 0x0001) => panic!("Invalid call to 0x0001"),
-                (_, 0x01d7) => crate::game::home::overworld::enter_map(self),
-                (_, 0x0242) => crate::game::home::overworld::overworld_loop(self),
-                (_, 0x0245) => crate::game::home::overworld::overworld_loop_less_delay(self),
-                (_, 0x0457) => panic!("step_count_check should only be called from Rust"),
-                (_, 0x0475) => panic!("all_pokemon_fainted should only be called from Rust"),
-                (_, 0x0480) => panic!("new_battle should only be called from Rust"),
-                (_, 0x049d) => panic!("do_bike_speedup should only be called from Rust"),
-                (_, 0x04bd) => crate::game::home::overworld::check_warps_no_collision(self),
-                (_, 0x051a) => crate::game::home::overworld::check_warps_collision(self),
-                (_, 0x0543) => panic!("warp_found1 should only be called from Rust"),
-                (_, 0x054a) => crate::game::home::overworld::warp_found2(self),
-                (_, 0x05db) => crate::game::home::overworld::check_map_connections(self),
-                (_, 0x06ef) => panic!("play_map_change_sound should only be called from Rust"),
-                (_, 0x0712) => crate::game::home::overworld::check_if_in_outside_map(self),
-                (_, 0x071a) => panic!("extra_warp_check should only be called from Rust"),
-                (_, 0x0750) => panic!("map_entry_after_battle should only be called from Rust"),
-                (_, 0x0762) => panic!("handle_black_out should only be called from Rust"),
-                (_, 0x0785) => crate::game::home::overworld::stop_music(self),
-                (_, 0x0794) => panic!("handle_fly_warp_or_dungeon_warp should only be called from Rust"),
-                (_, 0x07c4) => panic!("stop_bike_surf should only be called from Rust"),
-                (_, 0x07d7) => crate::game::home::overworld::load_player_sprite_graphics(self),
-                (_, 0x0805) => crate::game::home::overworld::is_bike_riding_allowed(self),
-                (_, 0x0828) => crate::game::home::overworld::load_tileset_tile_pattern_data(self),
-                (_, 0x083c) => crate::game::home::overworld::load_tile_block_map(self),
-                (_, 0x0919) => panic!("load_north_south_connections_tile_map should only be called from Rust"),
-                (_, 0x093d) => panic!("load_east_west_connections_tile_map should only be called from Rust"),
-                (_, 0x09f2) => panic!("sign_loop should only be called from Rust"),
-                (_, 0x095e) => panic!("is_sprite_or_sign_in_front_of_player should only be called from Rust"),
-                (_, 0x0983) => crate::game::home::overworld::is_sprite_in_front_of_player(self),
-                (_, 0x0985) => crate::game::home::overworld::is_sprite_in_front_of_player2(self),
-                (_, 0x0a1c) => panic!("collision_check_on_land should only be called from Rust"),
-                (_, 0x0a79) => panic!("check_tile_passable should only be called from Rust"),
-                (_, 0x0a86) => panic!("check_for_jumping_and_tile_pair_collisions should only be called from Rust"),
-                (_, 0x0aa0) => crate::game::home::overworld::check_for_tile_pair_collisions2(self),
-                (_, 0x0aa6) => crate::game::home::overworld::check_for_tile_pair_collisions(self),
-                (_, 0x0b06) => crate::game::home::overworld::load_current_map_view(self),
-                (_, 0x0b7f) => crate::game::home::overworld::advance_player_sprite(self),
-                (_, 0x0b95) => crate::game::home::overworld::schedule_north_row_redraw(self),
-                (_, 0x0baa) => crate::game::home::overworld::copy_to_redraw_row_or_column_src_tiles(self),
-                (_, 0x0bb6) => crate::game::home::overworld::schedule_south_row_redraw(self),
-                (_, 0x0bd7) => crate::game::home::overworld::schedule_east_column_redraw(self),
-                (_, 0x0bf6) => panic!("schedule_column_redraw_helper should only be called from Rust"),
-                (_, 0x0c0c) => crate::game::home::overworld::schedule_west_column_redraw(self),
-                (_, 0x0c21) => panic!("draw_tile_block should only be called from Rust"),
-                (_, 0x0c51) => panic!("joypad_overworld should only be called from Rust"),
-                (_, 0x0c65) => panic!("force_bike_down should only be called from Rust"),
-                (_, 0x0c7b) => panic!("are_inputs_simulated should only be called from Rust"),
-                (_, 0x0cb3) => panic!("get_simulated_input should only be called from Rust"),
-                (_, 0x0cca) => panic!("collision_check_on_water should only be called from Rust"),
-                (_, 0x0d2c) => panic!("run_map_script should only be called from Rust"),
-                (_, 0x0d5e) => crate::game::home::overworld::load_walking_player_sprite_graphics(self),
-                (_, 0x0d69) => panic!("load_surfing_player_sprite_graphics2 should only be called from Rust"),
-                (_, 0x0d83) => panic!("load_surfing_player_sprite_graphics should only be called from Rust"),
-                (_, 0x0d8a) => panic!("load_bike_player_sprite_graphics should only be called from Rust"),
-                (_, 0x0d8f) => panic!("load_player_sprite_graphics_common should only be called from Rust"),
-                (_, 0x0dab) => panic!("load_map_header should only be called from Rust"),
-                (_, 0x0eaa) => crate::game::home::overworld::copy_map_connection_header(self),
-                (_, 0x0eb3) => panic!("copy_sign_data should only be called from Rust"),
-                (_, 0x0ecb) => panic!("load_map_data should only be called from Rust"),
-                (_, 0x0f0c) => panic!("load_screen_related_data should only be called from Rust"),
-                (_, 0x0f16) => crate::game::home::overworld::reload_map_after_surfing_minigame(self),
-                (_, 0x0f3d) => crate::game::home::overworld::reload_map_after_printer(self),
-                (_, 0x0f56) => panic!("reset_map_variables should only be called from Rust"),
-                (_, 0x0f70) => panic!("copy_map_view_to_vram should only be called from Rust"),
-                (_, 0x0f73) => panic!("copy_map_view_to_vram2 should only be called from Rust"),
-                (_, 0x0f8b) => crate::game::home::overworld::switch_to_map_rom_bank(self),
-                (_, 0x0fa7) => panic!("get_map_header_pointer should only be called from Rust"),
-                (_, 0x0fc3) => panic!("ignore_input_for_half_second should only be called from Rust"),
-                (_, 0x0fd0) => crate::game::home::overworld::reset_using_strength_out_of_battle_bit(self),
-                (_, 0x0fd6) => crate::game::home::overworld::force_bike_or_surf(self),
-                (_, 0x0fe1) => panic!("handle_mid_jump should only be called from Rust"),
-                (_, 0x0ff0) => panic!("is_spinning should only be called from Rust"),
-                (_, 0x1006) => panic!("init_sprites should only be called from Rust"),
-                (_, 0x1050) => panic!("zero_sprite_state_data should only be called from Rust"),
-                (_, 0x1060) => panic!("disable_regular_sprites should only be called from Rust"),
-                (_, 0x106f) => panic!("load_sprite should only be called from Rust"),
-                (_, 0x10ba) => crate::game::home::overworld::check_for_user_interruption(self),
-                (_, 0x10d5) => panic!("load_destination_warp_position should only be called from Rust"),
-                (_, 0x143e) => crate::game::home::pics::load_mon_front_sprite(self),
-                (_, 0x2238) => crate::game::home::audio::play_sound(self),
-                (_, 0x309d) => panic!("is_player_character_being_controlled_by_game should only be called from Rust"),
-                (_, 0x30ae) => panic!("run_npc_movement_script should only be called from Rust"),
-                (_, 0x3422) => crate::game::home::map_objects::is_item_in_bag(self),
-                (_, 0x342a) => panic!("is_surfing_pikachu_in_party should only be called from Rust"),
-                (_, 0x3ed7) => crate::game::home::predef::get_predef_registers(self),
-                (_, 0x3ef9) => panic!("check_for_hidden_object_or_bookshelf_or_card_key_door should only be called from Rust"),
-                (0x01, 0x42bf) => crate::game::engine::movie::title::display_title_screen_go_to_main_menu(self),
-                (0x01, 0x581e) => crate::game::engine::link::cable_club::return_to_cable_club_room(self),
-                (0x01, 0x5ba6) => panic!("main_menu should only be called from Rust"),
-                (0x01, 0x5dfb) => panic!("check_for_player_name_in_sram should only be called from Rust"),
-                (0x02, 0x5064) => crate::game::audio::play_battle_music::play_battle_music(self),
-                (0x03, 0x407c) => panic!("clear_variables_on_enter_map should only be called from Rust"),
-                (0x03, 0x44f4) => crate::game::engine::overworld::tilesets::load_tileset_header(self),
-                (0x03, 0x4b62) => crate::game::engine::overworld::wild_mons::load_wild_data(self),
-                (0x03, 0x6807) => crate::game::engine::items::item_effects::hook_send_new_mon_to_box_end(self),
-                (0x03, 0x752b) => crate::game::engine::events::heal_party::heal_party(self),
-                (0x03, 0x7735) => crate::game::engine::items::get_bag_item_quantity::get_quantity_of_item_in_bag(self),
-                (0x04, 0x783a) => crate::game::engine::battle::wild_encounters::try_do_wild_encounter(self),
-                (0x06, 0x4f0a) => crate::game::scripts::pallete_town::pallet_town_script4(self),
-                (0x07, 0x4b40) => crate::game::scripts::oaks_lab::oaks_lab_player_received_mon_text(self),
-                (0x08, 0x5495) => crate::game::engine::pokemon::bills_pc::bills_pc_menu(self),
-                (0x0f, 0x59ac) => crate::game::engine::battle::core::is_ghost_battle(self),
-                (0x1c, 0x61f8) => crate::game::engine::gfx::palettes::load_sgb(self),
-                (0x1c, 0x7b91) => crate::game::engine::menus::save::save_sav_to_sram(self),
-                (0x3c, 0x4000) => crate::game::engine::pikachu::pikachu_pcm::play_pikachu_sound_clip(self),
-                (0x3c, 0x4274) => crate::game::engine::events::black_out::reset_status_and_halve_money_on_blackout(self),
-                (0x3d, 0x42db) => crate::game::engine::battle::decrement_pp::decrement_pp(self),
-                (0x3d, 0x5ff2) => crate::game::engine::battle::init_battle::init_battle(self),
-                (0x3d, 0x5ff8) => crate::game::engine::battle::init_battle::init_opponent(self),
-                (0x3d, 0x615a) => crate::game::engine::battle::init_battle::_load_trainer_pic(self),
-                (0x3d, 0x6178) => crate::game::engine::battle::init_battle::load_mon_back_pic(self),
-                (0x3d, 0x61a6) => crate::game::engine::battle::init_battle::animate_sending_out_mon(self),
-                (0x3d, 0x61f9) => crate::game::engine::battle::init_battle::copy_uncompressed_pic_to_tilemap(self),
-                (0x3d, 0x6203) => crate::game::engine::battle::init_battle::copy_uncompressed_pic_to_hl(self),
-                (0x3d, 0x674d) => crate::game::engine::events::give_pokemon::hook_give_pokemon_next_end(self),
-
-                _ => {
-                    let ticks = if self.halted { 4 } else { self.step() * 4 };
-                    self.cycle(ticks);
+            match self.pc {
+                0x0000 => break,
+                0x0001 => panic!("Invalid call to 0x0001"),
+                _ => {}
+            }
+
+            if let Some(mut gdb) = self.gdb.take() {
+                gdb.check(self);
+                self.gdb = Some(gdb);
+            }
+
+            let Some(hook) = self.hooks.get(self.bank(), self.pc).copied() else {
+                self.interpret();
+                continue;
+            };
+
+            let bank = self.bank();
+            if let Some(trace) = self.trace.as_mut() {
+                trace.record(Event::Hook {
+                    bank,
+                    pc: self.pc,
+                    name: hook.name,
+                });
+            }
+
+            match hook.kind {
+                HookKind::Replacement(handler) => handler(self),
+                HookKind::Guard => panic!("{} should only be called from Rust", hook.name),
+                HookKind::Observer(handler) => {
+                    handler(self);
+                    self.interpret();
                 }
             }
         }
     }
 
+    fn interpret(&mut self) {
+        if self.trace.is_some() && !self.halted {
+            self.record_step();
+        }
+
+        let ticks = match (self.halted, self.coverage.is_some()) {
+            (true, _) => 4,
+            (false, false) => self.step() * 4,
+            (false, true) => self.step_traced() * 4,
+        };
+
+        self.cycle(ticks);
+
+        if self.call_depth == 1 && self.mmu.keypad.has_snapshot_request() {
+            self.safe_point(self.pc);
+        }
+    }
+
+    fn record_step(&mut self) {
+        // Peeking keeps the trace from triggering watchpoints or polling input
+        let mut bytes = [self.mmu.peek(self.pc), 0, 0];
+
+        for i in 1..disasm::length(bytes[0]) {
+            bytes[i as usize] = self.mmu.peek(self.pc.wrapping_add(i));
+        }
+
+        let step = Step {
+            bank: self.bank(),
+            pc: self.pc,
+            bytes,
+            af: self.af(),
+            bc: self.bc(),
+            de: self.de(),
+            hl: self.hl(),
+            sp: self.sp,
+        };
+
+        if let Some(trace) = self.trace.as_mut() {
+            trace.record(Event::Step(step));
+        }
+    }
+
+    /// Prints the most recently interpreted instructions to stderr, if tracing
+    /// is enabled
+    pub fn dump_trace(&self) {
+        let Some(trace) = self.trace.as_ref() else {
+            return;
+        };
+
+        let mut out = io::stderr().lock();
+        let _ = writeln!(out, "Last instructions, oldest first:");
+
+        if let Err(e) = trace.dump(&mut out) {
+            log::error!("Error writing trace: {}", e);
+        }
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn hooks(&self) -> &HookTable {
+        &self.hooks
+    }
+
     pub fn jump(&mut self, pc: u16) {
         self.call(pc);
         self.pc = self.stack_pop();
@@ -233,6 +267,14 @@ impl Cpu {
         self.mmu.do_cycle(ticks);
         self.updateime();
         self.handleinterrupt();
+
+        if self.mmu.keypad.take_trace_request() {
+            self.dump_trace();
+        }
+
+        if let Some(request) = self.mmu.keypad.take_capture_request() {
+            self.mmu.gpu.capture(request);
+        }
     }
 
     pub fn borrow_sram(&self) -> &SaveState {
@@ -263,6 +305,148 @@ impl Cpu {
         self.mmu.mbc.save_to_disk();
     }
 
+    /// Handles a pending snapshot request, if any.
+    ///
+    /// This must only be called where the whole state of the game lives in the
+    /// emulated machine (see [`crate::snapshot`]). `resume_pc` is where
+    /// execution continues when the snapshot is loaded. Loading unwinds back
+    /// to `Game::boot` and doesn't return.
+    pub fn safe_point(&mut self, resume_pc: u16) {
+        if self.call_depth != 1 {
+            return;
+        }
+
+        let Some(request) = self.mmu.keypad.take_snapshot_request() else {
+            return;
+        };
+
+        match request {
+            SnapshotRequest::Save(slot) => {
+                let path = self.snapshot_path(slot);
+
+                let pc = self.pc;
+                self.pc = resume_pc;
+                let mut writer = SnapshotWriter::new();
+                self.save_snapshot(&mut writer);
+                self.pc = pc;
+
+                match writer.write_to_file(&path) {
+                    Ok(()) => log::info!("Saved snapshot to {}", path.display()),
+                    Err(e) => log::error!("Error saving snapshot to {}: {}", path.display(), e),
+                }
+            }
+
+            SnapshotRequest::Load(slot) => {
+                let path = self.snapshot_path(slot);
+
+                let result = fs::read(&path).and_then(|data| self.restore_snapshot(&data));
+
+                if let Err(e) = result {
+                    log::error!("Error loading snapshot from {}: {}", path.display(), e);
+                    return;
+                }
+
+                log::info!("Loaded snapshot from {}", path.display());
+                panic::resume_unwind(Box::new(SnapshotRestored));
+            }
+        }
+    }
+
+    /// Snapshots are stored per save file
+    fn snapshot_path(&self, slot: u8) -> path::PathBuf {
+        let name = self
+            .mmu
+            .mbc
+            .save_path()
+            .and_then(|path| path.file_stem())
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("untitled");
+
+        self.snapshot_dir
+            .join(format!("{}-{}", name, slot))
+            .with_extension("snap")
+    }
+
+    fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.a);
+        w.write_u8(self.b);
+        w.write_u8(self.c);
+        w.write_u8(self.d);
+        w.write_u8(self.e);
+        w.write_u8(self.f);
+        w.write_u8(self.h);
+        w.write_u8(self.l);
+        w.write_u16(self.pc);
+        w.write_u16(self.sp);
+        w.write_bool(self.halted);
+        w.write_bool(self.ime);
+        w.write_u32(self.setdi);
+        w.write_u32(self.setei);
+        self.mmu.save_snapshot(w);
+
+        match self.mmu.sound2.music_id() {
+            Some(id) => {
+                w.write_bool(true);
+                w.write_u32(id);
+            }
+            None => w.write_bool(false),
+        }
+    }
+
+    fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<Option<u32>> {
+        self.a = r.read_u8()?;
+        self.b = r.read_u8()?;
+        self.c = r.read_u8()?;
+        self.d = r.read_u8()?;
+        self.e = r.read_u8()?;
+        self.f = r.read_u8()?;
+        self.h = r.read_u8()?;
+        self.l = r.read_u8()?;
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u16()?;
+        self.halted = r.read_bool()?;
+        self.ime = r.read_bool()?;
+        self.setdi = r.read_u32()?;
+        self.setei = r.read_u32()?;
+        self.mmu.load_snapshot(r)?;
+
+        let music = if r.read_bool()? {
+            Some(r.read_u32()?)
+        } else {
+            None
+        };
+
+        Ok(music)
+    }
+
+    /// Leaves the current state untouched if the snapshot can't be read
+    fn restore_snapshot(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut backup = SnapshotWriter::new();
+        self.save_snapshot(&mut backup);
+
+        let result = SnapshotReader::new(data).and_then(|mut r| {
+            let music = self.load_snapshot(&mut r)?;
+            r.finish()?;
+            Ok(music)
+        });
+
+        match result {
+            Ok(Some(id)) => match crate::game::audio::music::Music::from_id(id) {
+                Some(music) => self.start_music(music.track(self.borrow_wram())),
+                None => self.mmu.sound2.stop_music(),
+            },
+            Ok(None) => self.mmu.sound2.stop_music(),
+            Err(e) => {
+                let backup = backup.into_bytes();
+                let mut r = SnapshotReader::new(&backup).unwrap();
+                self.load_snapshot(&mut r).unwrap();
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn gpu_push_layer(&mut self) -> usize {
         self.mmu.gpu.layer_push()
     }
@@ -275,21 +459,53 @@ impl Cpu {
         self.mmu.gpu.layer_mut(layer)
     }
 
+    pub fn gpu_register_atlas(&mut self, image: RgbaImage, tile_size: usize) -> GpuAtlas {
+        self.mmu.gpu.register_atlas(image, tile_size)
+    }
+
     pub fn gpu_update_screen(&mut self) {
-        self.mmu.gpu.update_screen();
+        self.mmu.gpu.update_menu_screen(false);
+    }
+
+    /// Presents the screen while waiting for input that isn't queued yet
+    fn gpu_wait_for_input(&mut self) {
+        self.mmu.gpu.update_menu_screen(true);
     }
 
     pub fn keypad_wait(&mut self) -> KeypadKey {
-        self.mmu.keypad.wait()
+        loop {
+            if let Some(key) = self.mmu.keypad.wait(self.mmu.gpu.frames()) {
+                return key;
+            }
+
+            // Input sources that can't block only deliver new events between frames
+            self.gpu_wait_for_input();
+        }
+    }
+
+    pub fn keyboard_key(&mut self) -> KeyboardKey {
+        loop {
+            if let Some(key) = self.mmu.keypad.key() {
+                return key;
+            }
+
+            self.gpu_wait_for_input();
+        }
     }
 
     pub fn keyboard_text(&mut self) -> TextEvent {
-        self.mmu.keypad.text()
+        loop {
+            if let Some(event) = self.mmu.keypad.text() {
+                return event;
+            }
+
+            self.gpu_wait_for_input();
+        }
     }
 
     pub fn start_music<T, TSource>(&mut self, music: T)
     where
-        T: Music<TSource>,
+        T: Music<TSource> + Clone,
         TSource: rodio::Source + Send + 'static,
         f32: cpal::FromSample<TSource::Item>,
         TSource::Item: rodio::Sample + Send,
@@ -299,7 +515,7 @@ impl Cpu {
 
     pub fn play_sfx<T, TSource>(&mut self, sfx: T)
     where
-        T: Sfx<TSource>,
+        T: Sfx<TSource> + Clone,
         TSource: rodio::Source + Send + 'static,
         f32: cpal::FromSample<TSource::Item>,
         TSource::Item: rodio::Sample + Send,
@@ -374,6 +590,36 @@ impl Cpu {
         res
     }
 
+    /// Same as `step`, but also keeps track of which routine is running
+    #[rustfmt::skip]
+    fn step_traced(&mut self) -> u32 {
+        let (pc, sp) = (self.pc, self.sp);
+        let opcode = self.mmu.peek(pc);
+
+        let cycles = self.step();
+
+        let bank = self.bank();
+        let Some(coverage) = self.coverage.as_mut() else {
+            return cycles;
+        };
+
+        coverage.spend(cycles * 4);
+
+        match opcode {
+            // CALL, CALL cc and RST
+            0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC if self.sp == sp.wrapping_sub(2) => coverage.call(bank, self.pc, self.sp),
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => coverage.call(bank, self.pc, self.sp),
+
+            // JP, JP cc and JP HL
+            0xC3 | 0xC2 | 0xCA | 0xD2 | 0xDA if self.pc != pc.wrapping_add(3) => coverage.jump(bank, self.pc),
+            0xE9 => coverage.jump(bank, self.pc),
+
+            _ => coverage.sync(self.sp),
+        }
+
+        cycles
+    }
+
     #[rustfmt::skip]
     fn step(&mut self) -> u32 {
         let opcode = self.fetch_byte();
@@ -0,0 +1,309 @@
+//! Disassembler for the SM83, the CPU of the Game Boy.
+//!
+//! Output uses the same syntax as rgbds and pret/pokeyellow, so that traces
+//! can be compared with the disassembly directly. When symbols are available,
+//! jump targets and memory addresses are printed by name.
+
+use crate::symbols::Symbols;
+
+const REGISTERS: [&str; 8] = ["b", "c", "d", "e", "h", "l", "[hl]", "a"];
+
+const ALU: [&str; 8] = [
+    "add a, ", "adc a, ", "sub ", "sbc a, ", "and ", "xor ", "or ", "cp ",
+];
+
+const ROTATIONS: [&str; 8] = ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    None,
+    /// Immediate byte
+    D8,
+    /// Immediate word, printed by name only if a symbol is at exactly that
+    /// address since it often isn't an address at all
+    D16,
+    /// High RAM address, `$ff00 + n`
+    A8,
+    /// Absolute address
+    A16,
+    /// Jump target relative to the next instruction
+    R8,
+    /// Signed offset added to `sp`
+    S8,
+}
+
+impl Operand {
+    fn len(self) -> u16 {
+        match self {
+            Operand::None => 0,
+            Operand::D8 | Operand::A8 | Operand::R8 | Operand::S8 => 1,
+            Operand::D16 | Operand::A16 => 2,
+        }
+    }
+}
+
+/// Instructions that don't fit the regular `ld r, r'` and ALU blocks. The
+/// operand is substituted for `{}`.
+#[rustfmt::skip]
+fn irregular(opcode: u8) -> (&'static str, Operand) {
+    use Operand::*;
+
+    match opcode {
+        0x00 => ("nop", None),
+        0x01 => ("ld bc, {}", D16),
+        0x02 => ("ld [bc], a", None),
+        0x03 => ("inc bc", None),
+        0x07 => ("rlca", None),
+        0x08 => ("ld [{}], sp", A16),
+        0x09 => ("add hl, bc", None),
+        0x0A => ("ld a, [bc]", None),
+        0x0B => ("dec bc", None),
+        0x0F => ("rrca", None),
+        // Takes a padding byte on hardware, but `Cpu::step` doesn't skip it
+        0x10 => ("stop", None),
+        0x11 => ("ld de, {}", D16),
+        0x12 => ("ld [de], a", None),
+        0x13 => ("inc de", None),
+        0x17 => ("rla", None),
+        0x18 => ("jr {}", R8),
+        0x19 => ("add hl, de", None),
+        0x1A => ("ld a, [de]", None),
+        0x1B => ("dec de", None),
+        0x1F => ("rra", None),
+        0x20 => ("jr nz, {}", R8),
+        0x21 => ("ld hl, {}", D16),
+        0x22 => ("ld [hli], a", None),
+        0x23 => ("inc hl", None),
+        0x27 => ("daa", None),
+        0x28 => ("jr z, {}", R8),
+        0x29 => ("add hl, hl", None),
+        0x2A => ("ld a, [hli]", None),
+        0x2B => ("dec hl", None),
+        0x2F => ("cpl", None),
+        0x30 => ("jr nc, {}", R8),
+        0x31 => ("ld sp, {}", D16),
+        0x32 => ("ld [hld], a", None),
+        0x33 => ("inc sp", None),
+        0x37 => ("scf", None),
+        0x38 => ("jr c, {}", R8),
+        0x39 => ("add hl, sp", None),
+        0x3A => ("ld a, [hld]", None),
+        0x3B => ("dec sp", None),
+        0x3F => ("ccf", None),
+        0x76 => ("halt", None),
+        0xC0 => ("ret nz", None),
+        0xC1 => ("pop bc", None),
+        0xC2 => ("jp nz, {}", A16),
+        0xC3 => ("jp {}", A16),
+        0xC4 => ("call nz, {}", A16),
+        0xC5 => ("push bc", None),
+        0xC6 => ("add a, {}", D8),
+        0xC7 => ("rst $00", None),
+        0xC8 => ("ret z", None),
+        0xC9 => ("ret", None),
+        0xCA => ("jp z, {}", A16),
+        0xCC => ("call z, {}", A16),
+        0xCD => ("call {}", A16),
+        0xCE => ("adc a, {}", D8),
+        0xCF => ("rst $08", None),
+        0xD0 => ("ret nc", None),
+        0xD1 => ("pop de", None),
+        0xD2 => ("jp nc, {}", A16),
+        0xD4 => ("call nc, {}", A16),
+        0xD5 => ("push de", None),
+        0xD6 => ("sub {}", D8),
+        0xD7 => ("rst $10", None),
+        0xD8 => ("ret c", None),
+        0xD9 => ("reti", None),
+        0xDA => ("jp c, {}", A16),
+        0xDC => ("call c, {}", A16),
+        0xDE => ("sbc a, {}", D8),
+        0xDF => ("rst $18", None),
+        0xE0 => ("ldh [{}], a", A8),
+        0xE1 => ("pop hl", None),
+        0xE2 => ("ldh [c], a", None),
+        0xE5 => ("push hl", None),
+        0xE6 => ("and {}", D8),
+        0xE7 => ("rst $20", None),
+        0xE8 => ("add sp, {}", S8),
+        0xE9 => ("jp hl", None),
+        0xEA => ("ld [{}], a", A16),
+        0xEE => ("xor {}", D8),
+        0xEF => ("rst $28", None),
+        0xF0 => ("ldh a, [{}]", A8),
+        0xF1 => ("pop af", None),
+        0xF2 => ("ldh a, [c]", None),
+        0xF3 => ("di", None),
+        0xF5 => ("push af", None),
+        0xF6 => ("or {}", D8),
+        0xF7 => ("rst $30", None),
+        0xF8 => ("ld hl, sp{}", S8),
+        0xF9 => ("ld sp, hl", None),
+        0xFA => ("ld a, [{}]", A16),
+        0xFB => ("ei", None),
+        0xFE => ("cp {}", D8),
+        0xFF => ("rst $38", None),
+        _ => ("", None),
+    }
+}
+
+/// Number of bytes taken by the instruction starting with `opcode`
+pub fn length(opcode: u8) -> u16 {
+    match opcode {
+        0xCB => 2,
+        0x40..=0xBF => 1,
+        // inc r, dec r and ld r, d8
+        op if op < 0x40 && matches!(op & 0x07, 0x04 | 0x05) => 1,
+        op if op < 0x40 && op & 0x07 == 0x06 => 2,
+        _ => 1 + irregular(opcode).1.len(),
+    }
+}
+
+/// Disassembles the instruction in `bytes`, which is located at `pc` in ROM
+/// bank `bank`. Missing operand bytes are read as zero.
+pub fn disassemble(bytes: &[u8], bank: usize, pc: u16, symbols: Option<&Symbols>) -> String {
+    let byte = |i: usize| bytes.get(i).copied().unwrap_or(0);
+    let opcode = byte(0);
+
+    match opcode {
+        0x40..=0x7F if opcode != 0x76 => {
+            let dst = REGISTERS[(opcode as usize >> 3) & 7];
+            let src = REGISTERS[opcode as usize & 7];
+            return format!("ld {}, {}", dst, src);
+        }
+        0x80..=0xBF => {
+            let op = ALU[(opcode as usize >> 3) & 7];
+            return format!("{}{}", op, REGISTERS[opcode as usize & 7]);
+        }
+        0xCB => return disassemble_cb(byte(1)),
+        0x00..=0x3F if matches!(opcode & 0x07, 0x04..=0x06) => {
+            let reg = REGISTERS[(opcode as usize >> 3) & 7];
+            return match opcode & 0x07 {
+                0x04 => format!("inc {}", reg),
+                0x05 => format!("dec {}", reg),
+                _ => format!("ld {}, ${:02x}", reg, byte(1)),
+            };
+        }
+        _ => {}
+    }
+
+    let (template, operand) = irregular(opcode);
+
+    if template.is_empty() {
+        return format!("db ${:02x}", opcode);
+    }
+
+    let word = u16::from_le_bytes([byte(1), byte(2)]);
+    let value = match operand {
+        Operand::None => return template.to_owned(),
+        Operand::D8 => format!("${:02x}", byte(1)),
+        Operand::D16 => match symbols.and_then(|s| s.exact(bank, word)) {
+            Some(name) => name.to_owned(),
+            None => format!("${:04x}", word),
+        },
+        Operand::A8 => address(0xFF00 | byte(1) as u16, bank, symbols),
+        Operand::A16 => address(word, bank, symbols),
+        Operand::R8 => {
+            let target = pc.wrapping_add(2).wrapping_add(byte(1) as i8 as u16);
+            address(target, bank, symbols)
+        }
+        Operand::S8 => format!("{:+}", byte(1) as i8),
+    };
+
+    template.replacen("{}", &value, 1)
+}
+
+fn disassemble_cb(opcode: u8) -> String {
+    let reg = REGISTERS[opcode as usize & 7];
+    let bit = (opcode >> 3) & 7;
+
+    match opcode >> 6 {
+        0 => format!("{} {}", ROTATIONS[bit as usize], reg),
+        1 => format!("bit {}, {}", bit, reg),
+        2 => format!("res {}, {}", bit, reg),
+        _ => format!("set {}, {}", bit, reg),
+    }
+}
+
+/// Names an address by its symbol. Addresses in RAM may also be named
+/// relative to the closest preceding symbol, e.g. `wPartyMon1HP+1`.
+fn address(addr: u16, bank: usize, symbols: Option<&Symbols>) -> String {
+    let Some(symbols) = symbols else {
+        return format!("${:04x}", addr);
+    };
+
+    if let Some(name) = symbols.exact(bank, addr) {
+        return name.to_owned();
+    }
+
+    match symbols.lookup(bank, addr) {
+        Some((name, offset)) if addr >= 0x8000 && offset < 0x100 => {
+            format!("{}+{}", name, offset)
+        }
+        _ => format!("${:04x}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths() {
+        assert_eq!(length(0x00), 1);
+        assert_eq!(length(0x01), 3);
+        assert_eq!(length(0x06), 2);
+        assert_eq!(length(0x18), 2);
+        assert_eq!(length(0x3E), 2);
+        assert_eq!(length(0x41), 1);
+        assert_eq!(length(0xCB), 2);
+        assert_eq!(length(0xCD), 3);
+        assert_eq!(length(0xE0), 2);
+        assert_eq!(length(0xF8), 2);
+        assert_eq!(length(0xFA), 3);
+        assert_eq!(length(0xD3), 1);
+    }
+
+    #[test]
+    fn without_symbols() {
+        let d = |bytes: &[u8], pc| disassemble(bytes, 0x01, pc, None);
+
+        assert_eq!(d(&[0x00], 0x0150), "nop");
+        assert_eq!(d(&[0x21, 0x58, 0xd1], 0x0150), "ld hl, $d158");
+        assert_eq!(d(&[0x0e, 0x0a], 0x0150), "ld c, $0a");
+        assert_eq!(d(&[0x35], 0x0150), "dec [hl]");
+        assert_eq!(d(&[0x7e], 0x0150), "ld a, [hl]");
+        assert_eq!(d(&[0x76], 0x0150), "halt");
+        assert_eq!(d(&[0xae], 0x0150), "xor [hl]");
+        assert_eq!(d(&[0x98], 0x0150), "sbc a, b");
+        assert_eq!(d(&[0x20, 0xfc], 0x0150), "jr nz, $014e");
+        assert_eq!(d(&[0xf0, 0x44], 0x0150), "ldh a, [$ff44]");
+        assert_eq!(d(&[0xf8, 0xfe], 0x0150), "ld hl, sp-2");
+        assert_eq!(d(&[0xe8, 0x04], 0x0150), "add sp, +4");
+        assert_eq!(d(&[0xcb, 0x37], 0x0150), "swap a");
+        assert_eq!(d(&[0xcb, 0x7e], 0x0150), "bit 7, [hl]");
+        assert_eq!(d(&[0xcb, 0xc1], 0x0150), "set 0, c");
+        assert_eq!(d(&[0xd3], 0x0150), "db $d3");
+    }
+
+    #[test]
+    fn with_symbols() {
+        let symbols = Symbols::parse(
+            "00:0242 OverworldLoop\n\
+             01:5ba6 MainMenu\n\
+             00:d158 wPlayerName\n\
+             00:ffb3 hJoyPressed\n",
+        );
+        let d = |bytes: &[u8], bank, pc| disassemble(bytes, bank, pc, Some(&symbols));
+
+        assert_eq!(d(&[0xc3, 0x42, 0x02], 0x01, 0x5000), "jp OverworldLoop");
+        assert_eq!(d(&[0xcd, 0xa6, 0x5b], 0x01, 0x0150), "call MainMenu");
+        assert_eq!(d(&[0xcd, 0xa6, 0x5b], 0x02, 0x0150), "call $5ba6");
+        assert_eq!(
+            d(&[0xfa, 0x5a, 0xd1], 0x01, 0x0150),
+            "ld a, [wPlayerName+2]"
+        );
+        assert_eq!(d(&[0xf0, 0xb3], 0x01, 0x0150), "ldh a, [hJoyPressed]");
+        assert_eq!(d(&[0x21, 0x43, 0x02], 0x01, 0x0150), "ld hl, $0243");
+    }
+}
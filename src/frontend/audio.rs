@@ -0,0 +1,79 @@
+use rodio::{OutputStream, OutputStreamHandle, Sink, StreamError};
+
+pub type AudioSource = Box<dyn rodio::Source<Item = f32> + Send>;
+
+pub trait AudioSink {
+    /// Start playing `source` right away, mixed with everything else that is playing
+    fn play(&mut self, source: AudioSource) -> Box<dyn AudioVoice>;
+}
+
+/// A source that has been handed to an [`AudioSink`]
+pub trait AudioVoice {
+    fn stop(&self);
+
+    /// Scale the volume of the source, where 1.0 is unchanged
+    fn set_volume(&self, volume: f32);
+
+    /// Whether the source has been stopped or played to the end
+    fn is_done(&self) -> bool;
+}
+
+impl AudioVoice for Sink {
+    fn stop(&self) {
+        Sink::stop(self)
+    }
+
+    fn set_volume(&self, volume: f32) {
+        Sink::set_volume(self, volume)
+    }
+
+    fn is_done(&self) -> bool {
+        self.empty()
+    }
+}
+
+/// Plays audio on the default output device
+pub struct RodioAudioSink {
+    handle: OutputStreamHandle,
+    _stream: OutputStream,
+}
+
+impl RodioAudioSink {
+    pub fn try_default() -> Result<RodioAudioSink, StreamError> {
+        let (stream, handle) = OutputStream::try_default()?;
+
+        Ok(RodioAudioSink {
+            handle,
+            _stream: stream,
+        })
+    }
+}
+
+impl AudioSink for RodioAudioSink {
+    fn play(&mut self, source: AudioSource) -> Box<dyn AudioVoice> {
+        let sink = Sink::try_new(&self.handle).unwrap();
+        sink.append(source);
+        Box::new(sink)
+    }
+}
+
+/// Drops all sounds
+pub struct NullAudioSink;
+
+struct NullAudioVoice;
+
+impl AudioSink for NullAudioSink {
+    fn play(&mut self, _source: AudioSource) -> Box<dyn AudioVoice> {
+        Box::new(NullAudioVoice)
+    }
+}
+
+impl AudioVoice for NullAudioVoice {
+    fn stop(&self) {}
+
+    fn set_volume(&self, _volume: f32) {}
+
+    fn is_done(&self) -> bool {
+        true
+    }
+}
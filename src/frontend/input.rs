@@ -0,0 +1,38 @@
+use std::sync::mpsc::Receiver;
+
+use crate::keypad::KeyboardEvent;
+
+pub trait InputSource {
+    /// Returns the next queued event without blocking
+    fn poll(&mut self) -> Option<KeyboardEvent>;
+
+    /// Blocks until the next event arrives.
+    ///
+    /// Sources that cannot block return `None` once no event is queued, in
+    /// which case a frame is presented before asking again.
+    fn wait(&mut self) -> Option<KeyboardEvent> {
+        self.poll()
+    }
+}
+
+impl InputSource for Receiver<KeyboardEvent> {
+    fn poll(&mut self) -> Option<KeyboardEvent> {
+        self.try_recv().ok()
+    }
+
+    fn wait(&mut self) -> Option<KeyboardEvent> {
+        match self.recv() {
+            Ok(event) => Some(event),
+            Err(_) => panic!("Keypad event channel closed"),
+        }
+    }
+}
+
+/// Never produces any events
+pub struct NullInputSource;
+
+impl InputSource for NullInputSource {
+    fn poll(&mut self) -> Option<KeyboardEvent> {
+        None
+    }
+}
@@ -0,0 +1,13 @@
+//! Extension points for everything the game needs from the outside world.
+//!
+//! A frontend provides a [`VideoSink`] that finished frames are presented to,
+//! an [`InputSource`] that keyboard events are read from, and an [`AudioSink`]
+//! that music and sound effects are played on.
+
+mod audio;
+mod input;
+mod video;
+
+pub use audio::{AudioSink, AudioSource, AudioVoice, NullAudioSink, RodioAudioSink};
+pub use input::{InputSource, NullInputSource};
+pub use video::{frame_channel, FrameReceiver, FrameSender, NullVideoSink, VideoSink};
@@ -0,0 +1,79 @@
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError};
+
+pub trait VideoSink {
+    /// Called for every finished frame, with RGB pixels at the size from
+    /// `GameBuilder::screen_size`
+    fn present(&mut self, frame: &[u8]);
+
+    /// Called instead of `present` when a menu implemented in Rust redraws
+    /// the screen outside of a vblank, with `waiting` set while it waits for
+    /// input. Defaults to presenting the frame like any other.
+    fn present_menu(&mut self, frame: &[u8], waiting: bool) {
+        let _ = waiting;
+        self.present(frame);
+    }
+}
+
+impl VideoSink for SyncSender<Vec<u8>> {
+    fn present(&mut self, frame: &[u8]) {
+        if self.send(frame.to_vec()).is_err() {
+            panic!("Screen disconnected")
+        }
+    }
+}
+
+/// Discards every frame
+pub struct NullVideoSink;
+
+impl VideoSink for NullVideoSink {
+    fn present(&mut self, _frame: &[u8]) {}
+}
+
+/// Creates a channel for frames to another thread, which reuses the buffers
+/// that are handed back instead of allocating one for every frame
+pub fn frame_channel() -> (FrameSender, FrameReceiver) {
+    let (frames, frames_receiver) = mpsc::sync_channel(1);
+    let (spare_sender, spare) = mpsc::channel();
+
+    (
+        FrameSender { frames, spare },
+        FrameReceiver {
+            frames: frames_receiver,
+            spare: spare_sender,
+        },
+    )
+}
+
+pub struct FrameSender {
+    frames: SyncSender<Vec<u8>>,
+    spare: Receiver<Vec<u8>>,
+}
+
+impl VideoSink for FrameSender {
+    fn present(&mut self, frame: &[u8]) {
+        let mut buffer = self.spare.try_recv().unwrap_or_default();
+        buffer.clear();
+        buffer.extend_from_slice(frame);
+
+        if self.frames.send(buffer).is_err() {
+            panic!("Screen disconnected")
+        }
+    }
+}
+
+pub struct FrameReceiver {
+    frames: Receiver<Vec<u8>>,
+    spare: Sender<Vec<u8>>,
+}
+
+impl FrameReceiver {
+    pub fn try_recv(&self) -> Result<Vec<u8>, TryRecvError> {
+        self.frames.try_recv()
+    }
+
+    /// Hands a frame back once it has been drawn, to be reused
+    pub fn recycle(&self, frame: Vec<u8>) {
+        // The game has exited if nobody is left to reuse it
+        let _ = self.spare.send(frame);
+    }
+}
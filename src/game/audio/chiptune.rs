@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use rodio::Source;
+
+use crate::{
+    game::audio::sfx::{Sfx, SynthesizerSource},
+    sound2::Sfx as SfxTrait,
+};
+
+/// A music track rendered from the ROM's own music data, which starts over
+/// whenever the synthesizer reaches the end of it
+pub struct Chiptune {
+    track: Sfx,
+    source: SynthesizerSource<'static>,
+}
+
+impl Chiptune {
+    /// Renders the track whose audio header is at `addr` in ROM bank `bank`
+    pub fn new(bank: u8, addr: u16) -> Self {
+        let track = Sfx::new(bank, addr);
+
+        Self {
+            track,
+            source: track.open(),
+        }
+    }
+}
+
+impl Iterator for Chiptune {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(sample) = self.source.next() {
+            return Some(sample);
+        }
+
+        self.source = self.track.open();
+        self.source.next()
+    }
+}
+
+impl Source for Chiptune {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_over_at_the_end() {
+        if !crate::rom::load_for_tests() {
+            return;
+        }
+
+        // SFX_Press_AB, which is short
+        let (bank, addr) = (0x02, 0x41b0);
+
+        let once: Vec<f32> = Sfx::new(bank, addr).open().collect();
+        assert!(!once.is_empty());
+
+        let looped: Vec<f32> = Chiptune::new(bank, addr).take(once.len() * 2).collect();
+
+        assert_eq!(looped[..once.len()], once[..]);
+        assert_eq!(looped[once.len()..], once[..]);
+    }
+}
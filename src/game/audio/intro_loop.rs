@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use rodio::Source;
+use serde::Deserialize;
+
+/// Where a track loops, in samples per channel from the start of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct LoopPoints {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Plays a track from the beginning up to the loop end, and then repeats the
+/// part between the loop start and end forever.
+///
+/// The looping part is kept in memory the first time it plays, so that the
+/// loop is seamless without having to seek in the file.
+pub struct IntroLoop<S: Source<Item = i16>> {
+    source: Option<S>,
+    channels: u16,
+    sample_rate: u32,
+    /// Interleaved samples read from the source so far
+    position: u64,
+    start: u64,
+    end: u64,
+    body: Vec<i16>,
+    replay: usize,
+}
+
+impl<S: Source<Item = i16>> IntroLoop<S> {
+    pub fn new(source: S, points: LoopPoints) -> Self {
+        let channels = source.channels();
+
+        Self {
+            channels,
+            sample_rate: source.sample_rate(),
+            source: Some(source),
+            position: 0,
+            start: points.start * channels as u64,
+            end: points.end * channels as u64,
+            body: Vec::new(),
+            replay: 0,
+        }
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for IntroLoop<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if let Some(source) = self.source.as_mut() {
+            if self.position < self.end {
+                if let Some(sample) = source.next() {
+                    if self.position >= self.start {
+                        self.body.push(sample);
+                    }
+                    self.position += 1;
+                    return Some(sample);
+                }
+            }
+
+            // Loop whatever was read if the file ends before the loop end
+            self.source = None;
+        }
+
+        let sample = *self.body.get(self.replay)?;
+        self.replay = (self.replay + 1) % self.body.len();
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = i16>> Source for IntroLoop<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    #[test]
+    fn plays_intro_once_then_loops() {
+        let source = SamplesBuffer::new(2, 44100, vec![0i16, 0, 1, 1, 2, 2, 3, 3, 4, 4]);
+        let music = IntroLoop::new(source, LoopPoints { start: 1, end: 3 });
+
+        let samples: Vec<i16> = music.take(12).collect();
+        assert_eq!(samples, [0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1]);
+    }
+}
@@ -0,0 +1,160 @@
+//! Measures where a soundtrack file loops, for the `loop_start` and
+//! `loop_end` entries in the manifest.
+//!
+//! The album tracks play the intro, the looping part twice and then fade
+//! out. The loop is found as the delay at which the loudness of the track
+//! repeats itself for the longest time, and then lined up to the sample by
+//! comparing the waveforms around the start of the loop.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use rodio::{Decoder, Source};
+
+use crate::game::audio::intro_loop::LoopPoints;
+
+/// The loudness is compared in blocks of 10 ms
+const BLOCKS_PER_SECOND: u32 = 100;
+
+/// Shorter repeats are usually bars or phrases within the loop
+const MIN_LOOP_SECONDS: u32 = 4;
+
+/// How much the loudness of two blocks can differ, relative to the louder
+/// one, and still count as the same
+const TOLERANCE: f32 = 0.05;
+
+/// Blocks quieter than this fraction of the loudest block are silence
+const SILENCE: f32 = 0.01;
+
+/// Decodes the whole file and measures its loop points
+pub fn measure(path: &Path) -> io::Result<Option<LoopPoints>> {
+    let decoder = Decoder::new(BufReader::new(File::open(path)?))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let channels = decoder.channels();
+    let sample_rate = decoder.sample_rate();
+    let samples: Vec<i16> = decoder.collect();
+
+    Ok(find_loop(&samples, channels, sample_rate))
+}
+
+/// The loop points of interleaved samples, if the looping part plays at
+/// least one and a half times
+pub fn find_loop(samples: &[i16], channels: u16, sample_rate: u32) -> Option<LoopPoints> {
+    let channels = channels.max(1) as usize;
+    let mono: Vec<f32> = samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().map(|&s| s as f32).sum::<f32>() / channels as f32)
+        .collect();
+
+    let block = (sample_rate / BLOCKS_PER_SECOND).max(1) as usize;
+    let envelope: Vec<f32> = mono
+        .chunks_exact(block)
+        .map(|b| b.iter().map(|s| s.abs()).sum::<f32>() / block as f32)
+        .collect();
+
+    let silence = envelope.iter().fold(0.0f32, |max, &e| max.max(e)) * SILENCE;
+    let same = |a: f32, b: f32| (a - b).abs() <= a.max(b) * TOLERANCE || a.max(b) < silence;
+
+    // The longest run of matching blocks, as (length, start, delay)
+    let mut best = (0, 0, 0);
+    for delay in (MIN_LOOP_SECONDS * BLOCKS_PER_SECOND) as usize..envelope.len() {
+        let mut run = 0;
+        for i in 0..envelope.len() - delay {
+            if !same(envelope[i], envelope[i + delay]) {
+                run = 0;
+                continue;
+            }
+
+            run += 1;
+            if run > best.0 {
+                best = (run, i + 1 - run, delay);
+            }
+        }
+    }
+
+    let (run, start, delay) = best;
+    if run == 0 || run * 2 < delay {
+        return None;
+    }
+
+    // Line up the waveforms over a second, one block into the loop
+    let start = (start + 1) * block;
+    let window = sample_rate as usize;
+    let difference = |delay: usize| -> Option<f32> {
+        let a = mono.get(start..start + window)?;
+        let b = mono.get(start + delay..start + delay + window)?;
+        Some(a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum())
+    };
+
+    let delay = (delay * block - block..=delay * block + block)
+        .filter_map(|delay| Some((difference(delay)?, delay)))
+        .min_by(|a, b| a.0.total_cmp(&b.0))?
+        .1;
+
+    Some(LoopPoints {
+        start: start as u64,
+        end: (start + delay) as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use super::*;
+
+    const RATE: u32 = 8000;
+
+    /// Notes of 100 ms with random pitches and volumes
+    fn notes(seed: u32, seconds: f32) -> Vec<f32> {
+        let mut state = seed;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        let note = RATE as usize / 10;
+        let mut samples = Vec::new();
+        for _ in 0..(seconds * 10.0) as usize {
+            let pitch = 200.0 + (random() % 600) as f32;
+            let volume = 2000.0 + (random() % 8000) as f32;
+            samples
+                .extend((0..note).map(|i| (i as f32 * pitch * TAU / RATE as f32).sin() * volume));
+        }
+        samples
+    }
+
+    #[test]
+    fn finds_the_loop_between_intro_and_fade_out() {
+        let intro = notes(1, 1.5);
+        let body = notes(2, 5.0);
+        let fade = body[..RATE as usize * 2]
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s * (1.0 - i as f32 / (RATE * 2) as f32));
+
+        let track: Vec<i16> = intro
+            .iter()
+            .chain(&body)
+            .chain(&body)
+            .copied()
+            .chain(fade)
+            .flat_map(|s| [s as i16, s as i16])
+            .collect();
+
+        let points = find_loop(&track, 2, RATE).unwrap();
+        assert_eq!(points.end - points.start, body.len() as u64);
+        assert!(points.start >= intro.len() as u64 - RATE as u64 / 10);
+        assert!(points.start <= intro.len() as u64 + RATE as u64 / 10);
+    }
+
+    #[test]
+    fn needs_the_loop_to_repeat() {
+        let track: Vec<i16> = notes(3, 12.0).iter().map(|&s| s as i16).collect();
+        assert_eq!(find_loop(&track, 1, RATE), None);
+    }
+}
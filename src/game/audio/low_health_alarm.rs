@@ -0,0 +1,128 @@
+//! The beeping that plays in battle while the player's Pokemon is low on
+//! health, synthesized the same way as `Music_DoLowHealthAlarm` programs the
+//! first square channel.
+
+use std::time::Duration;
+
+const SAMPLE_RATE: u32 = 44100;
+const FRAMES_PER_SECOND: f64 = 59.7275;
+
+/// Frequency register values of the two tones
+const HIGH_TONE: u16 = 0x750;
+const LOW_TONE: u16 = 0x6ee;
+
+/// The alarm timer is reset to 30 when the high tone starts, the low tone
+/// starts when it has counted down to 20, and it wraps around after 0
+const HIGH_TONE_FRAMES: u32 = 11;
+const LOW_TONE_FRAMES: u32 = 20;
+
+/// Both tones start at volume 14 (`$E2`) and fade by one step every 2/64 s
+const INITIAL_VOLUME: f64 = 14.0;
+const ENVELOPE_STEPS_PER_SECOND: f64 = 32.0;
+
+/// Loops until it is stopped
+pub struct LowHealthAlarm {
+    sample: u64,
+}
+
+impl LowHealthAlarm {
+    pub fn new() -> Self {
+        Self { sample: 0 }
+    }
+}
+
+fn frequency(tone: u16) -> f64 {
+    131072.0 / (2048 - tone) as f64
+}
+
+impl Iterator for LowHealthAlarm {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame_duration = 1.0 / FRAMES_PER_SECOND;
+        let high_duration = HIGH_TONE_FRAMES as f64 * frame_duration;
+        let cycle_duration = (HIGH_TONE_FRAMES + LOW_TONE_FRAMES) as f64 * frame_duration;
+
+        let time = (self.sample as f64 / SAMPLE_RATE as f64) % cycle_duration;
+        self.sample += 1;
+
+        let (tone, elapsed) = if time < high_duration {
+            (HIGH_TONE, time)
+        } else {
+            (LOW_TONE, time - high_duration)
+        };
+
+        let volume = (INITIAL_VOLUME - (elapsed * ENVELOPE_STEPS_PER_SECOND).floor()).max(0.0);
+
+        // 50% duty cycle
+        let level = if (elapsed * frequency(tone)).fract() < 0.5 {
+            1.0
+        } else {
+            -1.0
+        };
+
+        Some((level * volume / 15.0 * 0.25) as f32)
+    }
+}
+
+impl rodio::Source for LowHealthAlarm {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The frequency of a square wave, from how often it changes sign
+    fn measure_frequency(samples: &[f32]) -> f64 {
+        let changes = samples
+            .windows(2)
+            .filter(|pair| pair[0].signum() != pair[1].signum())
+            .count();
+        changes as f64 / 2.0 / (samples.len() as f64 / SAMPLE_RATE as f64)
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn alternates_between_two_fading_tones() {
+        let samples_per_frame = SAMPLE_RATE as f64 / FRAMES_PER_SECOND;
+        let high_end = (HIGH_TONE_FRAMES as f64 * samples_per_frame) as usize;
+        let cycle = ((HIGH_TONE_FRAMES + LOW_TONE_FRAMES) as f64 * samples_per_frame) as usize;
+
+        let samples: Vec<f32> = LowHealthAlarm::new().take(cycle * 2).collect();
+
+        // About 745 Hz for 11 frames, then about 478 Hz for 20 frames
+        let high = measure_frequency(&samples[..high_end - 1]);
+        let low = measure_frequency(&samples[high_end + 1..cycle - 1]);
+        assert!((high - frequency(HIGH_TONE)).abs() < 5.0, "{high} Hz");
+        assert!((low - frequency(LOW_TONE)).abs() < 5.0, "{low} Hz");
+
+        // Each tone starts at volume 14 and fades out
+        let full = (INITIAL_VOLUME / 15.0 * 0.25) as f32;
+        assert_eq!(peak(&samples[..100]), full);
+        assert!(peak(&samples[high_end - 100..high_end - 1]) < full);
+        assert_eq!(peak(&samples[high_end + 1..high_end + 100]), full);
+
+        // Then the high tone starts over
+        let again = measure_frequency(&samples[cycle + 1..cycle + high_end - 1]);
+        assert!((again - frequency(HIGH_TONE)).abs() < 5.0, "{again} Hz");
+        assert_eq!(peak(&samples[cycle + 1..cycle + 100]), full);
+    }
+}
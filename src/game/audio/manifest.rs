@@ -0,0 +1,435 @@
+//! The soundtrack manifest, `music/manifest.toml`, which says which audio
+//! file plays for each `Music` track, keyed by the name of the variant.
+//!
+//! ```toml
+//! [tracks.PalletTown]
+//! file = "03 - Pallet Town.flac"
+//! volume = 0.8
+//! loop_start = 220500
+//! loop_end = 2646000
+//!
+//! # Route 22 plays the rival's theme instead of the route music
+//! [tracks.Routes3.maps.33]
+//! file = "07 - Rival.flac"
+//!
+//! # Battles against a Pikachu
+//! [tracks.WildBattle.opponents.84]
+//! file = "Pikachu Battle.ogg"
+//! ```
+//!
+//! Overrides under `maps` are keyed by the map id (`wCurMap`), and the ones
+//! under `opponents` by the wild Pokemon's species id or the trainer class
+//! plus 200 (`wCurOpponent`). Ids can be written in hex as `"0x21"`. An
+//! opponent override wins over a map override.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::game::audio::intro_loop::LoopPoints;
+use crate::game::audio::loop_finder;
+use crate::game::audio::music::Music;
+use crate::game::audio::music_sfx::check_music_sfx;
+use crate::game::resources_root;
+
+pub fn music_dir() -> PathBuf {
+    resources_root()
+        .unwrap_or(std::env::current_dir().unwrap())
+        .join("music")
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct RawManifest {
+    tracks: HashMap<String, RawTrack>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RawTrack {
+    file: Option<PathBuf>,
+    volume: Option<f32>,
+    loop_start: Option<u64>,
+    loop_end: Option<u64>,
+    maps: HashMap<String, RawTrack>,
+    opponents: HashMap<String, RawTrack>,
+}
+
+/// An audio file in any format that rodio can decode
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackFile {
+    pub path: PathBuf,
+    pub volume: f32,
+    /// The whole file loops when there are no loop points
+    pub loop_points: Option<LoopPoints>,
+}
+
+/// Loop points of the files that have none in the manifest, as measured in
+/// the background by `measure_in_background`
+static MEASURED: Mutex<Vec<(PathBuf, LoopPoints)>> = Mutex::new(Vec::new());
+
+impl TrackFile {
+    /// The loop points from the manifest, or the measured ones once the file
+    /// has been measured
+    pub fn loop_points_to_play(&self) -> Option<LoopPoints> {
+        self.loop_points.or_else(|| {
+            let measured = MEASURED.lock().unwrap();
+            measured
+                .iter()
+                .find(|(path, _)| *path == self.path)
+                .map(|&(_, points)| points)
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Track {
+    default: Option<TrackFile>,
+    maps: HashMap<u8, TrackFile>,
+    opponents: HashMap<u8, TrackFile>,
+}
+
+#[derive(Debug, Default)]
+pub struct Manifest {
+    tracks: HashMap<Music, Track>,
+}
+
+impl Manifest {
+    /// The manifest as it was when first needed. Tracks fall back to the
+    /// chiptune if it can't be read.
+    pub fn current() -> &'static Manifest {
+        static CURRENT: OnceLock<Manifest> = OnceLock::new();
+
+        CURRENT.get_or_init(|| {
+            let path = Manifest::path();
+
+            let manifest = Manifest::load(&path).unwrap_or_else(|e| {
+                log::warn!("Failed to read {}: {}", path.display(), e);
+                Manifest::default()
+            });
+
+            manifest.measure_in_background();
+            manifest
+        })
+    }
+
+    /// Measures the files without loop points, so that they stop looping
+    /// their intro and fade-out. That takes a while, since every track is
+    /// decoded as a whole, and the whole file loops until then.
+    fn measure_in_background(&self) {
+        let files: Vec<(String, PathBuf)> = self
+            .files()
+            .into_iter()
+            .filter(|(_, file)| file.loop_points.is_none() && file.path.is_file())
+            .map(|(name, file)| (name, file.path.clone()))
+            .collect();
+
+        if files.is_empty() {
+            return;
+        }
+
+        let spawned = thread::Builder::new()
+            .name("loop finder".into())
+            .spawn(move || {
+                for (name, path) in files {
+                    match loop_finder::measure(&path) {
+                        Ok(Some(points)) => {
+                            log::info!(
+                                "Measured the loop points of {}, add \
+                                 `loop_start = {}` and `loop_end = {}` to its entry in the manifest",
+                                name,
+                                points.start,
+                                points.end
+                            );
+                            MEASURED.lock().unwrap().push((path, points));
+                        }
+                        Ok(None) => log::warn!("No loop found in {}", path.display()),
+                        Err(e) => log::warn!("Failed to measure {}: {}", path.display(), e),
+                    }
+                }
+            });
+
+        if let Err(e) = spawned {
+            log::warn!("Failed to start measuring loop points: {}", e);
+        }
+    }
+
+    /// `music/manifest.toml`, unless another manifest is set in the config
+    pub fn path() -> PathBuf {
+        match Config::current().music.manifest.as_ref() {
+            Some(path) => path.clone(),
+            None => music_dir().join("manifest.toml"),
+        }
+    }
+
+    /// File names in the manifest are relative to its directory
+    pub fn load(path: &Path) -> io::Result<Manifest> {
+        let text = fs::read_to_string(path)?;
+        Manifest::parse(&text, path.parent().unwrap_or(Path::new(".")))
+    }
+
+    pub fn parse(text: &str, dir: &Path) -> io::Result<Manifest> {
+        let raw: RawManifest = toml::from_str(text).map_err(invalid_data)?;
+        let mut tracks = HashMap::new();
+
+        for (name, raw) in raw.tracks {
+            let Some(music) = Music::all().find(|music| format!("{:?}", music) == name) else {
+                return Err(invalid_data(format!("Unknown track: {}", name)));
+            };
+
+            let track = Track {
+                default: match raw.file {
+                    Some(_) => Some(track_file(&name, &raw, dir)?),
+                    None => None,
+                },
+                maps: overrides(&name, &raw.maps, dir)?,
+                opponents: overrides(&name, &raw.opponents, dir)?,
+            };
+
+            tracks.insert(music, track);
+        }
+
+        Ok(Manifest { tracks })
+    }
+
+    /// The file to play for `music` on the current map and against the
+    /// current opponent, if any, together with a number that tells apart
+    /// the entries of the same track
+    pub fn file(&self, music: Music, map: u8, opponent: u8) -> Option<(u32, &TrackFile)> {
+        let track = self.tracks.get(&music)?;
+
+        if opponent != 0 {
+            if let Some(file) = track.opponents.get(&opponent) {
+                return Some((0x200 | opponent as u32, file));
+            }
+        }
+
+        if let Some(file) = track.maps.get(&map) {
+            return Some((0x100 | map as u32, file));
+        }
+
+        track.default.as_ref().map(|file| (0, file))
+    }
+
+    /// Every file in the manifest, named the same way as its table in the
+    /// manifest
+    fn files(&self) -> Vec<(String, &TrackFile)> {
+        let mut files = Vec::new();
+
+        for music in Music::all() {
+            let Some(track) = self.tracks.get(&music) else {
+                continue;
+            };
+
+            if let Some(file) = track.default.as_ref() {
+                files.push((format!("{:?}", music), file));
+            }
+
+            for (id, file) in &track.maps {
+                files.push((format!("{:?}.maps.{}", music, id), file));
+            }
+
+            for (id, file) in &track.opponents {
+                files.push((format!("{:?}.opponents.{}", music, id), file));
+            }
+        }
+
+        files
+    }
+
+    /// Tracks without a file, and files that don't exist, which would play
+    /// as chiptune instead, or that would loop their intro and fade out
+    /// because they have no loop points
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for music in Music::all() {
+            match self.tracks.get(&music) {
+                None => problems.push(format!("{:?}: no entry", music)),
+                Some(track) if track.default.is_none() => {
+                    problems.push(format!("{:?}: no file", music))
+                }
+                Some(_) => {}
+            }
+        }
+
+        for (name, file) in self.files() {
+            if !file.path.is_file() {
+                problems.push(format!("{}: missing {}", name, file.path.display()));
+            } else if file.loop_points.is_none() {
+                problems.push(format!("{}: no loop points", name));
+            }
+        }
+
+        problems
+    }
+}
+
+fn track_file(name: &str, raw: &RawTrack, dir: &Path) -> io::Result<TrackFile> {
+    let Some(file) = raw.file.as_ref() else {
+        return Err(invalid_data(format!("{}: an override needs a file", name)));
+    };
+
+    let loop_points = match (raw.loop_start, raw.loop_end) {
+        (None, None) => None,
+        (Some(start), Some(end)) if start < end => Some(LoopPoints { start, end }),
+        _ => {
+            return Err(invalid_data(format!(
+                "{}: loop_start must come before loop_end",
+                name
+            )))
+        }
+    };
+
+    Ok(TrackFile {
+        path: dir.join(file),
+        volume: raw.volume.unwrap_or(1.0),
+        loop_points,
+    })
+}
+
+fn overrides(
+    name: &str,
+    raw: &HashMap<String, RawTrack>,
+    dir: &Path,
+) -> io::Result<HashMap<u8, TrackFile>> {
+    let mut files = HashMap::new();
+
+    for (key, raw) in raw {
+        if !raw.maps.is_empty() || !raw.opponents.is_empty() {
+            return Err(invalid_data(format!("{}: overrides can't be nested", name)));
+        }
+
+        let id = match key.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => key.parse(),
+        };
+
+        let Ok(id) = id else {
+            return Err(invalid_data(format!("{}: invalid id {}", name, key)));
+        };
+
+        files.insert(id, track_file(name, raw, dir)?);
+    }
+
+    Ok(files)
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Reports everything in the manifest that can't be played, and the sound
+/// effects whose files are missing
+pub fn check_music() -> Vec<String> {
+    let path = Manifest::path();
+
+    let mut problems = match Manifest::load(&path) {
+        Ok(manifest) => manifest.problems(),
+        Err(e) => vec![format!("Failed to read {}: {}", path.display(), e)],
+    };
+
+    problems.extend(check_music_sfx());
+    problems
+}
+
+/// Measures the loop points of every file in the manifest that doesn't have
+/// them, as entries to copy into the manifest
+pub fn measure_loops() -> Vec<String> {
+    let path = Manifest::path();
+    let manifest = match Manifest::load(&path) {
+        Ok(manifest) => manifest,
+        Err(e) => return vec![format!("Failed to read {}: {}", path.display(), e)],
+    };
+
+    let mut lines = Vec::new();
+
+    for (name, file) in manifest.files() {
+        if file.loop_points.is_some() {
+            continue;
+        }
+
+        let line = match loop_finder::measure(&file.path) {
+            Ok(Some(points)) => format!(
+                "[tracks.{}]\nloop_start = {}\nloop_end = {}",
+                name, points.start, points.end
+            ),
+            Ok(None) => format!("{}: no loop found in {}", name, file.path.display()),
+            Err(e) => format!("{}: failed to read {}: {}", name, file.path.display(), e),
+        };
+
+        lines.push(line);
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_most_specific_file() {
+        let manifest = Manifest::parse(
+            r#"
+            [tracks.WildBattle]
+            file = "wild.flac"
+            loop_start = 10
+            loop_end = 20
+
+            [tracks.WildBattle.maps.0x21]
+            file = "route22.ogg"
+            volume = 0.5
+
+            [tracks.WildBattle.opponents.84]
+            file = "pikachu.wav"
+            "#,
+            Path::new("music"),
+        )
+        .unwrap();
+
+        let (variant, file) = manifest.file(Music::WildBattle, 0x01, 0x10).unwrap();
+        assert_eq!(variant, 0);
+        assert_eq!(file.path, Path::new("music/wild.flac"));
+        assert_eq!(file.loop_points, Some(LoopPoints { start: 10, end: 20 }));
+
+        let (variant, file) = manifest.file(Music::WildBattle, 0x21, 0x10).unwrap();
+        assert_eq!(variant, 0x121);
+        assert_eq!(file.volume, 0.5);
+
+        let (variant, _) = manifest.file(Music::WildBattle, 0x21, 84).unwrap();
+        assert_eq!(variant, 0x254);
+
+        assert!(manifest.file(Music::PalletTown, 0, 0).is_none());
+
+        assert!(Manifest::parse("[tracks.Nope]\nfile = \"a.flac\"", Path::new("")).is_err());
+        assert!(Manifest::parse(
+            "[tracks.Routes1]\nfile = \"a.flac\"\nloop_start = 2\nloop_end = 1",
+            Path::new("")
+        )
+        .is_err());
+    }
+
+    /// The album files aren't part of the repository, so this checks the
+    /// ones that have been put into `music`
+    #[test]
+    fn shipped_tracks_have_loop_points() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("music/manifest.toml");
+        let manifest = Manifest::load(&path).unwrap();
+
+        let missing: Vec<String> = manifest
+            .files()
+            .into_iter()
+            .filter(|(_, file)| file.path.is_file() && file.loop_points.is_none())
+            .map(|(name, _)| name)
+            .collect();
+
+        assert!(missing.is_empty(), "No loop points for {:?}", missing);
+    }
+}
@@ -1,3 +1,8 @@
+pub mod chiptune;
+pub mod intro_loop;
+pub mod loop_finder;
+pub mod low_health_alarm;
+pub mod manifest;
 pub mod music;
 pub mod music_sfx;
 pub mod pikachu_cries;
@@ -1,22 +1,19 @@
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use rodio::decoder::LoopedDecoder;
-use rodio::Decoder;
+use rodio::{Decoder, Source};
 
-use crate::game::resources_root;
+use crate::config::Config;
+use crate::game::audio::chiptune::Chiptune;
+use crate::game::audio::intro_loop::IntroLoop;
+use crate::game::audio::manifest::{Manifest, TrackFile};
+use crate::game_state::GameState;
 use crate::sound2::{Music as MusicTrait, Sfx as SfxTrait};
 
-type MusicDecoder = LoopedDecoder<BufReader<File>>;
+type MusicDecoder = Box<dyn Source<Item = i16> + Send>;
 
-fn open_music(name: &str) -> MusicDecoder {
-    let root = resources_root().unwrap_or(std::env::current_dir().unwrap());
-    let file = File::open(root.join("music").join(name)).unwrap();
-
-    Decoder::new_looped(BufReader::new(file)).unwrap()
-}
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[repr(u32)]
 pub enum Music {
     PalletTown,
@@ -129,73 +126,112 @@ impl Music {
             _ => None,
         }
     }
+
+    /// Every track, in the order they are in the ROM
+    pub fn all() -> impl Iterator<Item = Music> {
+        [0x02, 0x08, 0x1f, 0x20]
+            .into_iter()
+            .flat_map(|bank| (0..=255).filter_map(move |id| Music::from_bank_and_id(bank, id)))
+    }
+
+    /// Reverse of `MusicTrack::id`, only used when restoring snapshots
+    pub fn from_id(id: u32) -> Option<Music> {
+        Music::all().find(|music| *music as u32 == id & 0xff)
+    }
+
+    /// Bank and address of the track's audio header in the ROM
+    fn header(self) -> (u8, u16) {
+        [0x02, 0x08, 0x1f, 0x20]
+            .into_iter()
+            .flat_map(|bank| (0..=255).map(move |id| (bank, id)))
+            .find(|&(bank, id)| Music::from_bank_and_id(bank, id) == Some(self))
+            .map(|(bank, id)| (bank, 0x4000 + (id as u16) * 3))
+            .unwrap()
+    }
+
+    /// The track with the file from the manifest for the current map and
+    /// opponent
+    pub fn track(self, wram: &GameState) -> MusicTrack {
+        let file = Manifest::current().file(self, wram.cur_map(), wram.cur_opponent());
+
+        MusicTrack {
+            music: self,
+            variant: file.map_or(0, |(variant, _)| variant),
+            file: file.map(|(_, file)| file),
+        }
+    }
+}
+
+/// A `Music` together with the file that the manifest picked for it
+#[derive(Debug, Clone, Copy)]
+pub struct MusicTrack {
+    music: Music,
+    variant: u32,
+    file: Option<&'static TrackFile>,
+}
+
+impl MusicTrack {
+    fn open_file(&self) -> io::Result<MusicDecoder> {
+        let Some(file) = self.file else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the track isn't in the manifest",
+            ));
+        };
+
+        let reader = BufReader::new(File::open(&file.path)?);
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+
+        let source: MusicDecoder = match file.loop_points_to_play() {
+            Some(points) => Box::new(IntroLoop::new(
+                Decoder::new(reader).map_err(invalid)?,
+                points,
+            )),
+            None => Box::new(Decoder::new_looped(reader).map_err(invalid)?),
+        };
+
+        Ok(Box::new(source.amplify(file.volume)))
+    }
 }
 
-impl SfxTrait<MusicDecoder> for Music {
-    #[rustfmt::skip]
+impl SfxTrait<MusicDecoder> for MusicTrack {
+    /// Plays the file from the manifest, or the original chiptune when that
+    /// is configured or the file can't be played
     fn open(self) -> MusicDecoder {
-        match self {
-            // Bank 02
-            Music::PalletTown => open_music("03 - Pallet Town.flac"),
-            Music::Pokecenter => open_music("17 - Pokémon Center.flac"),
-            Music::Gym => open_music("23 - Pokémon Gym.flac"),
-            Music::Cities1 => open_music("16 - Pewter City.flac"),
-            Music::Cities2 => open_music("30 - Cerulean City.flac"),
-            Music::Celadon => open_music("41 - Celadon City.flac"),
-            Music::Cinnabar => open_music("47 - Cinnabar Island.flac"),
-            Music::Vermilion => open_music("35 - Vermilion City.flac"),
-            Music::Lavender => open_music("39 - Lavender Town.flac"),
-            Music::SSAnne => open_music("36 - S.S. Anne.flac"),
-            Music::MeetProfOak => open_music("04 - Professor Oak.flac"),
-            Music::MeetRival => open_music("07 - Rival.flac"),
-            Music::MuseumGuy => open_music("21 - Hurry Along.flac"),
-            Music::SafariZone => open_music("32 - Evolution.flac"),
-            Music::PkmnHealed => open_music("18 - Pokémon Healed.flac"),
-            Music::Routes1 => open_music("11 - Route 1.flac"),
-            Music::Routes2 => open_music("31 - Route 24.flac"),
-            Music::Routes3 => open_music("27 - Route 3.flac"),
-            Music::Routes4 => open_music("38 - Route 11.flac"),
-            Music::IndigoPlateau => open_music("49 - Victory Road.flac"),
-            // Bank 08
-            Music::GymLeaderBattle => open_music("25 - Battle! (Gym Leader).flac"),
-            Music::TrainerBattle => open_music("08 - Battle! (Trainer).flac"),
-            Music::WildBattle => open_music("13 - Battle! (Wild Pokémon).flac"),
-            Music::FinalBattle => open_music("50 - Final Battle! (Rival).flac"),
-            Music::DefeatedTrainer => open_music("09 - Victory! (Trainer).flac"),
-            Music::DefeatedWildMon => open_music("14 - Victory! (Wild Pokémon).flac"),
-            Music::DefeatedGymLeader => open_music("26 - Victory! (Gym Leader).flac"),
-            // Bank 1f
-            Music::TitleScreen => open_music("02 - Title Screen (Yellow).flac"),
-            Music::Credits => open_music("52 - Ending.flac"),
-            Music::HallOfFame => open_music("51 - Hall of Fame.flac"),
-            Music::OaksLab => open_music("05 - Oak Pokémon Lab.flac"),
-            Music::JigglypuffSong => open_music("22 - Jigglypuff's Song.flac"),
-            Music::BikeRiding => open_music("37 - Cycling.flac"),
-            Music::Surfing => open_music("46 - Surf.flac"),
-            Music::GameCorner => open_music("42 - Game Corner.flac"),
-            Music::YellowIntro => open_music("01 - Opening Movie (Yellow).flac"),
-            Music::Dungeon1 => open_music("43 - Rocket Hideout.flac"),
-            Music::Dungeon2 => open_music("19 - Viridian Forest.flac"),
-            Music::Dungeon3 => open_music("29 - Mt. Moon.flac"),
-            Music::CinnabarMansion => open_music("48 - Pokémon Mansion.flac"),
-            Music::PokemonTower => open_music("40 - Pokémon Tower.flac"),
-            Music::SilphCo => open_music("45 - Silph Co..flac"),
-            Music::MeetEvilTrainer => open_music("44 - Trainers' Eyes Meet (Team Rocket).flac"),
-            Music::MeetFemaleTrainer => open_music("28 - Trainers' Eyes Meet (Girl).flac"),
-            Music::MeetMaleTrainer => open_music("20 - Trainers' Eyes Meet (Boy).flac"),
-            // Bank 20
-            Music::SurfingPikachu => open_music("04 - Pikachu's Beach.flac"),
-            Music::MeetJessieJames => open_music("03 - Jessie & James.flac"),
-            Music::YellowUnusedSong => open_music("05 - Giovanni [Hidden Track].flac"),
-
-            // The Printer Menu track isn't part of the Soundtrack CD from the Internet Archive, so I'm using the hidden track from the CD instead
-            Music::GBPrinter => open_music("05 - Giovanni [Hidden Track].flac"),
+        if !Config::current()
+            .music
+            .is_chiptune(&format!("{:?}", self.music))
+        {
+            match self.open_file() {
+                Ok(source) => return source,
+                Err(e) => {
+                    // Usually the whole music directory is missing, which
+                    // doesn't need to be repeated for every track
+                    static WARNED: AtomicBool = AtomicBool::new(false);
+
+                    let message = format!(
+                        "Failed to play {:?}, playing the chiptune instead: {}",
+                        self.music, e
+                    );
+
+                    if WARNED.swap(true, Ordering::Relaxed) {
+                        log::debug!("{}", message);
+                    } else {
+                        log::warn!("{}", message);
+                    }
+                }
+            }
         }
+
+        let (bank, addr) = self.music.header();
+        Box::new(Chiptune::new(bank, addr).convert_samples())
     }
 }
 
-impl MusicTrait<MusicDecoder> for Music {
+impl MusicTrait<MusicDecoder> for MusicTrack {
+    /// The `Music` in the low byte, and which manifest entry is playing above
+    /// it, so that e.g. entering a map with its own file restarts the music
     fn id(&self) -> u32 {
-        *self as u32
+        self.music as u32 | self.variant << 8
     }
 }
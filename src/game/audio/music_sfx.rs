@@ -1,13 +1,17 @@
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use rodio::Decoder;
+use rodio::{Decoder, Source};
 
-use crate::game::resources_root;
-use crate::sound2::Sfx as SfxTrait;
+use crate::config::Config;
+use crate::game::audio::manifest::music_dir;
+use crate::game::audio::sfx::Sfx;
+use crate::sound2::{Sfx as SfxTrait, SfxPriority};
 
 /// A sound effect that is implemented as a music track.
-/// #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MusicSfx {
     Battle35_00_80,
     Battle35_11_18,
@@ -47,31 +51,126 @@ impl MusicSfx {
             _ => None,
         }
     }
+
+    pub fn all() -> impl Iterator<Item = MusicSfx> {
+        [
+            MusicSfx::Battle35_00_80,
+            MusicSfx::Battle35_11_18,
+            MusicSfx::Battle35_42_01,
+            MusicSfx::CaughtPokemon,
+            MusicSfx::LevelUp,
+            MusicSfx::ObtainedItem,
+            MusicSfx::ObtainedKeyItem,
+            MusicSfx::PokedexRating,
+            MusicSfx::Pokeflute,
+            MusicSfx::PokemonEvolved,
+        ]
+        .into_iter()
+    }
+
+    #[rustfmt::skip]
+    fn file_name(self) -> &'static str {
+        match self {
+            MusicSfx::Battle35_00_80 => "SFX_Battle_35_00_80.flac",
+            MusicSfx::Battle35_11_18 => "SFX_Battle_35_11_18.flac",
+            MusicSfx::Battle35_42_01 => "SFX_Battle_35_42_01.flac",
+            MusicSfx::CaughtPokemon => "15 - Caught a Pokémon!.flac",
+            MusicSfx::LevelUp => "10 - Level Up!.flac",
+            MusicSfx::ObtainedItem => "12 - Obtained an Item!.flac",
+            MusicSfx::ObtainedKeyItem => "06 - Obtained a Key Item!.flac",
+            MusicSfx::PokedexRating => "34 - Pokédex Evaluation- You're on Your Way!.flac",
+            MusicSfx::Pokeflute => "SFX_Pokeflute.flac",
+            MusicSfx::PokemonEvolved => "33 - Congratulations! Your Pokémon Evolved!.flac",
+        }
+    }
+
+    pub fn path(self) -> PathBuf {
+        music_dir().join(self.file_name())
+    }
+
+    /// The sound together with the header it was played from, which is
+    /// synthesized when the file can't be played
+    pub fn track(self, bank: u8, id: u8, pitch: u8, length: i8) -> MusicSfxTrack {
+        let mut header = Sfx::new(bank, 0x4000 + (id as u16) * 3);
+
+        // Like `play_sound`, only battle sounds are tweaked
+        if header.is_battle_sfx() {
+            header.tweak(pitch, length);
+        }
+
+        MusicSfxTrack { sfx: self, header }
+    }
 }
 
-type MusicSfxDecoder = Decoder<BufReader<File>>;
+/// Reports the sounds whose files can't be found, which would be
+/// synthesized instead
+pub fn check_music_sfx() -> Vec<String> {
+    MusicSfx::all()
+        .filter(|sfx| !sfx.path().is_file())
+        .map(|sfx| format!("{:?}: missing {}", sfx, sfx.path().display()))
+        .collect()
+}
 
-fn open_music_sfx(name: &str) -> MusicSfxDecoder {
-    let root = resources_root().unwrap_or(std::env::current_dir().unwrap());
-    let file = File::open(root.join("music").join(name)).unwrap();
+type MusicSfxDecoder = Box<dyn Source<Item = i16> + Send>;
 
-    Decoder::new(BufReader::new(file)).unwrap()
+/// A `MusicSfx` together with the ROM's audio header for it
+#[derive(Debug, Clone, Copy)]
+pub struct MusicSfxTrack {
+    sfx: MusicSfx,
+    header: Sfx,
 }
 
-impl SfxTrait<MusicSfxDecoder> for MusicSfx {
-    #[rustfmt::skip]
+impl MusicSfxTrack {
+    fn open_file(&self) -> io::Result<MusicSfxDecoder> {
+        let reader = BufReader::new(File::open(self.sfx.path())?);
+        let decoder =
+            Decoder::new(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Box::new(decoder))
+    }
+}
+
+impl SfxTrait<MusicSfxDecoder> for MusicSfxTrack {
+    /// Plays the file, or synthesizes the sound from the ROM when the
+    /// chiptune is configured or the file can't be played
     fn open(self) -> MusicSfxDecoder {
-        match self {
-            MusicSfx::Battle35_00_80 => open_music_sfx("SFX_Battle_35_00_80.flac"),
-            MusicSfx::Battle35_11_18 => open_music_sfx("SFX_Battle_35_11_18.flac"),
-            MusicSfx::Battle35_42_01 => open_music_sfx("SFX_Battle_35_42_01.flac"),
-            MusicSfx::CaughtPokemon => open_music_sfx("15 - Caught a Pokémon!.flac"),
-            MusicSfx::LevelUp => open_music_sfx("10 - Level Up!.flac"),
-            MusicSfx::ObtainedItem => open_music_sfx("12 - Obtained an Item!.flac"),
-            MusicSfx::ObtainedKeyItem => open_music_sfx("06 - Obtained a Key Item!.flac"),
-            MusicSfx::PokedexRating => open_music_sfx("34 - Pokédex Evaluation- You're on Your Way!.flac"),
-            MusicSfx::Pokeflute => open_music_sfx("SFX_Pokeflute.flac"),
-            MusicSfx::PokemonEvolved => open_music_sfx("33 - Congratulations! Your Pokémon Evolved!.flac"),
+        if !Config::current()
+            .music
+            .is_chiptune(&format!("{:?}", self.sfx))
+        {
+            match self.open_file() {
+                Ok(source) => return source,
+                Err(e) => {
+                    static WARNED: AtomicBool = AtomicBool::new(false);
+
+                    let message = format!(
+                        "Failed to play {:?}, synthesizing it instead: {}",
+                        self.sfx, e
+                    );
+
+                    if WARNED.swap(true, Ordering::Relaxed) {
+                        log::debug!("{}", message);
+                    } else {
+                        log::warn!("{}", message);
+                    }
+                }
+            }
         }
+
+        Box::new(self.header.open().convert_samples())
+    }
+
+    fn priority(&self) -> SfxPriority {
+        match self.sfx {
+            MusicSfx::Battle35_00_80 | MusicSfx::Battle35_11_18 | MusicSfx::Battle35_42_01 => {
+                SfxPriority::Normal
+            }
+            _ => SfxPriority::High,
+        }
+    }
+
+    /// These are played on the music channels, which pauses the music
+    fn music_volume(&self) -> f32 {
+        0.0
     }
 }
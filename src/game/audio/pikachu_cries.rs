@@ -1,5 +1,9 @@
-use crate::{rom::ROM, sound2::Sfx};
+use crate::{
+    rom::ROM,
+    sound2::{Sfx, SfxPriority},
+};
 
+#[derive(Clone)]
 pub struct PikachuCry {
     data: &'static [u8],
     pos: usize,
@@ -74,4 +78,14 @@ impl Sfx<PikachuCry> for PikachuCry {
     fn open(self) -> PikachuCry {
         self
     }
+
+    fn priority(&self) -> SfxPriority {
+        SfxPriority::High
+    }
+
+    /// The original game plays these samples with the CPU, which halts
+    /// everything else, music included
+    fn music_volume(&self) -> f32 {
+        0.0
+    }
 }
@@ -1,10 +1,14 @@
 use pokemon_synthesizer::SoundIterator;
 use rodio::Source;
 
-use crate::{rom::ROM, sound2::Sfx as SfxTrait};
+use crate::{
+    rom::ROM,
+    sound2::{Sfx as SfxTrait, SfxPriority},
+};
 
 pub const DENIED: Sfx = Sfx::new(0x02, 0x41ef);
 pub const PRESS_AB: Sfx = Sfx::new(0x02, 0x41b0);
+pub const START_MENU: Sfx = Sfx::new(0x02, 0x41ad);
 
 pub const CRY_00: Sfx = Sfx::new(0x02, 0x403c);
 pub const CRY_01: Sfx = Sfx::new(0x02, 0x4045);
@@ -54,7 +58,7 @@ pub struct Sfx {
 }
 
 impl Sfx {
-    const fn new(bank: u8, addr: u16) -> Self {
+    pub const fn new(bank: u8, addr: u16) -> Self {
         Self {
             bank,
             addr,
@@ -339,6 +343,19 @@ impl Sfx {
         (BATTLE_SFX_START..BATTLE_SFX_END).contains(&self.addr)
     }
 
+    /// The short blips of menus, which are at the same address in every
+    /// audio bank
+    pub fn is_menu_beep(&self) -> bool {
+        const TINK: u16 = 0x41a4;
+
+        !self.is_instrument() && [TINK, START_MENU.addr, PRESS_AB.addr].contains(&self.addr)
+    }
+
+    /// The noise instruments that music uses for drums
+    pub fn is_instrument(&self) -> bool {
+        self.bank == 0x1f && self.addr < CRY_00.addr
+    }
+
     pub fn tweak(&mut self, pitch: u8, length: i8) {
         self.pitch = pitch;
         self.length = length;
@@ -391,8 +408,68 @@ impl Source for SynthesizerSource<'_> {
 impl SfxTrait<SynthesizerSource<'static>> for Sfx {
     fn open(self) -> SynthesizerSource<'static> {
         SynthesizerSource::new(
-            pokemon_synthesizer::synthesis(ROM, self.bank, self.addr, self.pitch, self.length)
+            pokemon_synthesizer::synthesis(&ROM, self.bank, self.addr, self.pitch, self.length)
                 .iter(),
         )
     }
+
+    fn priority(&self) -> SfxPriority {
+        if self.is_cry() {
+            SfxPriority::High
+        } else if self.is_instrument() || self.is_menu_beep() {
+            SfxPriority::Low
+        } else {
+            SfxPriority::Normal
+        }
+    }
+
+    /// Pitch and length tweaks don't matter, a cry replaces the same cry
+    fn sfx_id(&self) -> Option<u32> {
+        Some((self.bank as u32) << 16 | self.addr as u32)
+    }
+
+    /// Cries take over every music channel except the wave channel
+    fn music_volume(&self) -> f32 {
+        if self.is_cry() {
+            0.3
+        } else {
+            1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priorities() {
+        for bank in [0x02, 0x08, 0x1f, 0x20] {
+            for id in [140, 143, 144] {
+                let sfx = Sfx::from_bank_and_id(bank, id).unwrap();
+                assert_eq!(sfx.priority(), SfxPriority::Low, "{:?}", sfx);
+            }
+        }
+
+        assert_eq!(PRESS_AB.priority(), SfxPriority::Low);
+        assert_eq!(START_MENU.priority(), SfxPriority::Low);
+        assert_eq!(
+            Sfx::from_bank_and_id(0x1f, 5).unwrap().priority(),
+            SfxPriority::Low
+        );
+
+        assert_eq!(DENIED.priority(), SfxPriority::Normal);
+        assert_eq!(
+            Sfx::from_bank_and_id(0x02, 180).unwrap().priority(),
+            SfxPriority::Normal
+        );
+        assert_eq!(
+            Sfx::from_bank_and_id(0x08, 176).unwrap().priority(),
+            SfxPriority::Normal
+        );
+
+        assert_eq!(CRY_00.priority(), SfxPriority::High);
+        assert_eq!(CRY_25.tweaked(0x80, 0x10).priority(), SfxPriority::High);
+        assert_eq!(CRY_25.tweaked(0x80, 0x10).sfx_id(), CRY_25.sfx_id());
+    }
 }
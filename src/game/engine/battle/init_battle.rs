@@ -57,6 +57,9 @@ fn determine_wild_opponent(cpu: &mut Cpu) {
 }
 
 fn init_battle_common(cpu: &mut Cpu) {
+    // The battle screen replaces the map view in the BG map
+    cpu.mmu.gpu.set_wide_map_view(false);
+
     // Save Map Pal Offset
     let map_pal_offset = cpu.borrow_wram().map_pal_offset();
     cpu.stack_push((map_pal_offset as u16) << 8);
@@ -1,10 +1,35 @@
 use crate::{cpu::Cpu, game::ram::wram};
 
+const LOAD_SGB: u16 = 0x61f8;
+
 pub fn load_sgb(cpu: &mut Cpu) {
     // This function should only be called once
     assert_eq!(cpu.read_byte(wram::W_ON_SGB), 0x00);
     cpu.write_byte(wram::W_ON_SGB, 0x01);
 
+    if cpu.mmu.gpu.sgb_border_enabled() {
+        send_sgb_border(cpu);
+    }
+
     // ret
     cpu.pc = cpu.stack_pop();
 }
+
+/// Runs the part of LoadSGB that is skipped on a Game Boy Color, which sends
+/// the border tiles, the border palettes and the system palettes to the Super
+/// Game Boy with VRAM transfers
+fn send_sgb_border(cpu: &mut Cpu) {
+    // xor a
+    // ld [wOnSGB], a
+    // call CheckSGB
+    // jr c, .onSGB
+    if cpu.read_byte(LOAD_SGB) != 0xaf || cpu.read_byte(LOAD_SGB + 7) != 0x38 {
+        log::warn!("Unexpected code in LoadSGB, playing without the SGB border");
+        return;
+    }
+
+    let offset = cpu.read_byte(LOAD_SGB + 8) as i8;
+    let on_sgb = (LOAD_SGB + 9).wrapping_add_signed(offset as i16);
+
+    cpu.call(on_sgb);
+}
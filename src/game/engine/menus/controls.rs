@@ -0,0 +1,111 @@
+use std::sync::RwLock;
+
+use crate::{
+    bindings::{Action, Bindings},
+    cpu::Cpu,
+    game::{audio, home::text},
+    keypad::{KeyboardKey, KeypadKey},
+};
+
+/// The menus can't be used without these, so they always keep a key
+const MENU_BUTTONS: [KeypadKey; 4] = [KeypadKey::Up, KeypadKey::Down, KeypadKey::A, KeypadKey::B];
+
+/// Lists every action with its keys, and saves the bindings if any of them
+/// were changed
+pub fn controls_menu(cpu: &mut Cpu) {
+    let bindings = cpu.mmu.keypad.bindings();
+    let before = bindings.read().unwrap().clone();
+
+    let layer = cpu.gpu_push_layer();
+    let mut selected = 0;
+
+    loop {
+        let choices = {
+            let bindings = bindings.read().unwrap();
+            Action::all()
+                .map(|action| format!("{:<11}{}", label(action), keys(&bindings, action, ",")))
+                .collect::<Vec<_>>()
+        };
+        let choices = choices.iter().map(String::as_str).collect::<Vec<_>>();
+
+        let Some(index) = super::menu_single_choice(cpu, layer, &mut selected, (0, 0), &choices)
+        else {
+            break;
+        };
+
+        rebind(cpu, &bindings, Action::all().nth(index).unwrap());
+    }
+
+    cpu.gpu_pop_layer(layer);
+
+    let bindings = bindings.read().unwrap();
+
+    if *bindings != before {
+        // Buttons that were held might not have a key to let go of them anymore
+        cpu.mmu.keypad.release_all();
+
+        if let Err(e) = bindings.save() {
+            log::error!("Failed to save key bindings: {}", e);
+        }
+    }
+}
+
+/// Pressing a key binds it to `action`, or unbinds it if it already was,
+/// until Escape is pressed
+fn rebind(cpu: &mut Cpu, bindings: &RwLock<Bindings>, action: Action) {
+    let layer = cpu.gpu_push_layer();
+
+    text::text_box_border(cpu.gpu_mut_layer(layer), 0, 12, 18, 4);
+    text::place_string(
+        cpu.gpu_mut_layer(layer),
+        1,
+        14,
+        &format!("{} keys:", label(action)),
+    );
+
+    loop {
+        let keys = keys(&bindings.read().unwrap(), action, " ");
+        text::place_string(
+            cpu.gpu_mut_layer(layer),
+            1,
+            16,
+            &super::truncate_and_pad(&keys, 18),
+        );
+
+        cpu.gpu_update_screen();
+
+        let key = match cpu.keyboard_key() {
+            KeyboardKey::Escape => break,
+            key => key,
+        };
+
+        let mut changed = bindings.read().unwrap().clone();
+        changed.toggle(action, key);
+
+        let usable = MENU_BUTTONS
+            .iter()
+            .all(|&button| !changed.keys(Action::Keypad(button)).is_empty());
+
+        if usable {
+            *bindings.write().unwrap() = changed;
+            cpu.play_sfx(audio::sfx::PRESS_AB);
+        } else {
+            cpu.play_sfx(audio::sfx::DENIED);
+        }
+    }
+
+    cpu.gpu_pop_layer(layer);
+}
+
+fn label(action: Action) -> String {
+    action.name().to_uppercase()
+}
+
+fn keys(bindings: &Bindings, action: Action, separator: &str) -> String {
+    bindings
+        .keys(action)
+        .iter()
+        .map(|key| key.to_string())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
@@ -11,7 +11,10 @@ use crate::{
     saves,
 };
 
-pub fn main_menu(cpu: &mut Cpu) {
+/// Returns `true` once a game has been started, with `cpu.pc` pointing to where
+/// the game continues. Jumping there directly instead of through `cpu.jump`
+/// keeps the rest of the game from running nested inside this menu.
+pub fn main_menu(cpu: &mut Cpu) -> bool {
     init_options(cpu);
 
     cpu.write_byte(wram::W_OPTIONS_INITIALIZED, 0);
@@ -41,6 +44,17 @@ pub fn main_menu(cpu: &mut Cpu) {
     // Toggle link feature bit off
     cpu.borrow_wram_mut().set_using_link_feature(false);
 
+    // Input movies continue straight from the save they were recorded with.
+    // Without a save path, saving in the game doesn't touch any save file.
+    if let Some(save) = cpu.mmu.keypad.take_movie_save() {
+        cpu.write_byte(wram::W_SAVE_FILE_STATUS, 2);
+        cpu.replace_ram(save);
+        super::save::load_sav(cpu);
+        prepare_for_game(cpu);
+        cpu.pc = 0x5c83; // MainMenu.pressedA
+        return true;
+    }
+
     let mut selected = 0;
     let layer = cpu.gpu_push_layer();
 
@@ -50,20 +64,21 @@ pub fn main_menu(cpu: &mut Cpu) {
             layer,
             &mut selected,
             (0, 0),
-            &["CONTINUE", "NEW GAME"][if has_saves { 0.. } else { 1.. }],
+            &["CONTINUE", "NEW GAME", "CONTROLS"][if has_saves { 0.. } else { 1.. }],
         );
 
         match (selected, has_saves) {
             (None, _) => {
                 cpu.gpu_pop_layer(layer);
-                return;
+                return false;
             }
 
             (Some(0), true) => {
                 if main_menu_select_save(cpu) {
                     cpu.gpu_pop_layer(layer);
                     prepare_for_game(cpu);
-                    return cpu.jump(0x5c83); // MainMenu.pressedA
+                    cpu.pc = 0x5c83; // MainMenu.pressedA
+                    return true;
                 }
             }
 
@@ -71,10 +86,13 @@ pub fn main_menu(cpu: &mut Cpu) {
                 if main_menu_new_game(cpu) {
                     cpu.gpu_pop_layer(layer);
                     prepare_for_game(cpu);
-                    return cpu.jump(0x5cd2); // StartNewGame
+                    cpu.pc = 0x5cd2; // StartNewGame
+                    return true;
                 }
             }
 
+            (Some(1), false) | (Some(2), true) => super::controls::controls_menu(cpu),
+
             _ => unreachable!(),
         }
     }
@@ -10,9 +10,9 @@ pub fn display_title_screen_go_to_main_menu(cpu: &mut Cpu) {
     // Make sure the Pikachu sound is done playing
     home::delay::delay_frames(cpu, 20);
 
-    menus::main_menu::main_menu(cpu);
-
-    cpu.pc = 0x42a4; // DisplayTitleScreen.titleScreenLoop
+    if !menus::main_menu::main_menu(cpu) {
+        cpu.pc = 0x42a4; // DisplayTitleScreen.titleScreenLoop
+    }
 }
 
 pub fn title_screen_copy_tile_map_to_vram(cpu: &mut Cpu, dst: u16) {
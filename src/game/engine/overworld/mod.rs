@@ -1,3 +1,4 @@
 pub mod clear_variables;
 pub mod tilesets;
+pub mod widescreen;
 pub mod wild_mons;
@@ -0,0 +1,369 @@
+//! The map and NPCs on the sides of the screen in widescreen mode.
+
+use crate::{
+    cpu::Cpu,
+    game::{
+        constants::{gfx_constants, map_data_constants::MAP_BORDER},
+        ram::{hram, wram},
+    },
+    widescreen::{WideSprite, SIDE_W},
+};
+
+/// Tile columns on each side of the map view
+const SIDE_COLUMNS: i32 = (SIDE_W / 8) as i32;
+
+/// Where the player's sprite is on the screen
+const PLAYER_SCREEN_X: i32 = 0x40;
+const PLAYER_SCREEN_Y: i32 = 0x3c;
+
+/// Sprite 15 is Pikachu, who is never far enough away to be on the sides
+const NPC_SPRITES: std::ops::RangeInclusive<u16> = 1..=14;
+
+/// Tile offsets of the standing frame for each facing direction, and
+/// whether it's flipped
+const STANDING: [([u8; 4], bool); 4] = [
+    ([0x00, 0x01, 0x02, 0x03], false),
+    ([0x04, 0x05, 0x06, 0x07], false),
+    ([0x08, 0x09, 0x0a, 0x0b], false),
+    ([0x08, 0x09, 0x0a, 0x0b], true),
+];
+
+/// Writes the columns to the left and right of the map view into the BG
+/// map, from the tile blocks around the view. Expects the tileset bank to
+/// be loaded, like LoadCurrentMapView.
+pub fn draw_map_view_sides(cpu: &mut Cpu) {
+    let wram = cpu.borrow_wram();
+
+    let view = wram.current_tile_block_map_view_pointer();
+    let stride = wram.cur_map_width() as i32 + (MAP_BORDER * 2) as i32;
+    let view_row = (view - wram::W_OVERWORLD_MAP) as i32 / stride;
+    let view_col = (view - wram::W_OVERWORLD_MAP) as i32 % stride;
+
+    // LoadCurrentMapView starts 2 tiles into the blocks when the player is
+    // in the second half of one
+    let x_offset = wram.x_block_coord().min(1) as i32 * 2;
+    let y_offset = wram.y_block_coord().min(1) as i32 * 2;
+
+    let background = wram.map_background_tile();
+    let blocks = wram.tileset_blocks_pointer();
+    let vram_ptr = wram.map_view_vram_pointer();
+
+    let columns = (-SIDE_COLUMNS..0).chain(
+        gfx_constants::SCREEN_WIDTH as i32..gfx_constants::SCREEN_WIDTH as i32 + SIDE_COLUMNS,
+    );
+
+    for x in columns {
+        for y in 0..gfx_constants::SCREEN_HEIGHT as i32 {
+            let (tile_x, tile_y) = (x + x_offset, y + y_offset);
+            let col = view_col + tile_x.div_euclid(4);
+
+            // Columns past the map border in the tile block map
+            let block = match (0..stride).contains(&col) {
+                true => cpu.read_byte(
+                    wram::W_OVERWORLD_MAP + ((view_row + tile_y / 4) * stride + col) as u16,
+                ),
+                false => background,
+            };
+
+            let tile = cpu.read_byte(
+                blocks + block as u16 * 0x10 + (tile_y % 4 * 4 + tile_x.rem_euclid(4)) as u16,
+            );
+
+            let row = ((vram_ptr - 0x9800) >> 5) as i32 + y;
+            let col = (vram_ptr & 0x1f) as i32 + x;
+            cpu.write_byte(0x9800 + ((row & 0x1f) * 32 + (col & 0x1f)) as u16, tile);
+        }
+    }
+
+    cpu.mmu.gpu.set_wide_map_view(true);
+}
+
+/// Hands the NPCs around the map view to the Gpu. The game hides them,
+/// since they're off its screen, and doesn't move them until they're back.
+pub fn update_side_sprites(cpu: &mut Cpu) {
+    let wram = cpu.borrow_wram();
+
+    let (x_coord, y_coord) = (wram.x_coord() as i32, wram.y_coord() as i32);
+
+    // How far the BG has scrolled since the player started the current step
+    let (step_y, step_x) = wram.sprite_player_state_data1_step_vector();
+    let progress = match wram.walk_counter() {
+        0 => 0,
+        counter => (8 - counter as i32) * 2,
+    };
+
+    let scx = cpu.read_byte(hram::H_SCX) as i32;
+    let scy = cpu.read_byte(hram::H_SCY) as i32;
+
+    let mut sprites = vec![];
+
+    for i in NPC_SPRITES {
+        let data1 = wram::W_SPRITE_DATA_START + i * 0x10;
+        let data2 = data1 + 0x100;
+
+        // No sprite, or one the game draws itself
+        if cpu.read_byte(data1) == 0 || cpu.read_byte(data1 + 2) != 0xff {
+            continue;
+        }
+
+        // Map coordinates are offset by 4
+        let dx = cpu.read_byte(data2 + 5) as i32 - 4 - x_coord;
+        let dy = cpu.read_byte(data2 + 4) as i32 - 4 - y_coord;
+
+        // Same as the game's range of visible sprites, but wider
+        let on_screen = (-4..=5).contains(&dx);
+        if on_screen || !(-7..=8).contains(&dx) || !(-4..=4).contains(&dy) {
+            continue;
+        }
+
+        let image_base_offset = cpu.read_byte(data2 + 0x0e);
+        if image_base_offset == 0 || is_object_hidden(cpu, i as u8) {
+            continue;
+        }
+
+        let x = scx + PLAYER_SCREEN_X + dx * 16 - progress * step_x as i32;
+        let y = scy + PLAYER_SCREEN_Y + dy * 16 - progress * step_y as i32;
+
+        // Sprites 10 and up are item balls, boulders and the like, which only
+        // have one frame. Sprite 11 starts 4 tiles later than it would.
+        let slot = image_base_offset - 1;
+        let (base, (tiles, flip)) = match slot {
+            0x0b => (0x0a * 12 + 4, STANDING[0]),
+            0x0a.. => (slot * 12, STANDING[0]),
+            _ => {
+                let facing = cpu.read_byte(data1 + 9) as usize / 4;
+                (slot * 12, STANDING[facing & 3])
+            }
+        };
+
+        // The lower half goes behind the grass
+        let in_grass = cpu.read_byte(data2 + 7) & 0x80;
+
+        for (n, tile) in tiles.into_iter().enumerate() {
+            let column = (n as i32 % 2) ^ flip as i32;
+            let row = n as i32 / 2;
+
+            sprites.push(WideSprite {
+                x: (x + column * 8) as u8,
+                y: (y + row * 8) as u8,
+                tile: base + tile,
+                flags: (if flip { 1 << 5 } else { 0 }) | (if row == 1 { in_grass } else { 0 }),
+            });
+        }
+    }
+
+    cpu.mmu.gpu.set_wide_sprites(sprites);
+}
+
+/// Whether a missable object, such as an item ball that has been picked up,
+/// is hidden, like IsObjectHidden
+fn is_object_hidden(cpu: &mut Cpu, sprite: u8) -> bool {
+    // Up to 16 entries and the terminator
+    for entry in 0..17 {
+        let entry = wram::W_MISSABLE_OBJECT_LIST + entry * 2;
+
+        match cpu.read_byte(entry) {
+            0xff => return false,
+            index if index == sprite => {
+                let object = cpu.read_byte(entry + 1) as u16;
+                let flags = cpu.read_byte(wram::W_MISSABLE_OBJECT_FLAGS + object / 8);
+
+                return flags & (1 << (object % 8)) != 0;
+            }
+            _ => {}
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        gpu::GB_SCREEN_H,
+        hooks::Hook,
+        keypad::{KeyboardEvent, KeyboardKey},
+        rom::ROM,
+        widescreen::WIDE_SCREEN_W,
+        Game,
+    };
+
+    static CHECKED: AtomicUsize = AtomicUsize::new(0);
+    static MISMATCHES: AtomicUsize = AtomicUsize::new(0);
+    /// One bit for each x coordinate the player was checked at
+    static X_COORDS: AtomicU64 = AtomicU64::new(0);
+
+    /// Tiles the tile block map is widened by on each side, which is more
+    /// than the sides can reach past the map border
+    const PADDING: usize = 8;
+
+    /// Every tile of the tile block map, including the border, as rows of
+    /// `width` tiles, independently of how the game picks the view
+    fn expanded_map(cpu: &mut Cpu) -> (Vec<u8>, usize) {
+        let wram = cpu.borrow_wram();
+        let stride = wram.cur_map_width() as usize + MAP_BORDER as usize * 2;
+        let rows = wram.cur_map_height() as usize + MAP_BORDER as usize * 2;
+        let background = wram.map_background_tile();
+        let blocks = wram.tileset_blocks_pointer() as usize;
+        let bank = wram.tileset_bank() as usize;
+
+        let width = stride * 4 + PADDING * 2;
+        let mut tiles = vec![0; width * rows * 4];
+
+        for y in 0..rows * 4 {
+            for x in 0..width {
+                let col = (x / 4) as isize - (PADDING / 4) as isize;
+
+                let block = match (0..stride as isize).contains(&col) {
+                    true => {
+                        cpu.read_byte(wram::W_OVERWORLD_MAP + (y / 4 * stride) as u16 + col as u16)
+                    }
+                    false => background,
+                };
+
+                let addr = blocks + block as usize * 0x10 + y % 4 * 4 + x % 4;
+                tiles[y * width + x] = match addr {
+                    0x0000..=0x3fff => ROM[addr],
+                    _ => ROM[bank * 0x4000 + addr - 0x4000],
+                };
+            }
+        }
+
+        (tiles, width)
+    }
+
+    /// Finds where the view that the game drew is in the tile block map, and
+    /// compares the sides to the tiles around it there
+    fn check_sides(cpu: &mut Cpu) {
+        // Only between steps, once the last row or column has been drawn
+        if !cpu.mmu.gpu.wide_map_view()
+            || cpu.borrow_wram().walk_counter() != 0
+            || cpu.read_byte(0xffd0) != 0
+        {
+            return;
+        }
+
+        let vram_ptr = cpu.borrow_wram().map_view_vram_pointer();
+        let (view_row, view_col) = (((vram_ptr - 0x9800) >> 5) as i32, (vram_ptr & 0x1f) as i32);
+        let bg = |cpu: &mut Cpu, x: i32, y: i32| {
+            let (row, col) = ((view_row + y) & 0x1f, (view_col + x) & 0x1f);
+            cpu.read_byte(0x9800 + (row * 32 + col) as u16)
+        };
+
+        let (width, height) = (
+            gfx_constants::SCREEN_WIDTH as i32,
+            gfx_constants::SCREEN_HEIGHT as i32,
+        );
+        let in_view = || (0..height).flat_map(move |y| (0..width).map(move |x| (x, y)));
+        let in_sides = || {
+            (0..height).flat_map(move |y| {
+                (-SIDE_COLUMNS..0)
+                    .chain(width..width + SIDE_COLUMNS)
+                    .map(move |x| (x, y))
+            })
+        };
+
+        let view: Vec<u8> = in_view().map(|(x, y)| bg(cpu, x, y)).collect();
+        let sides: Vec<u8> = in_sides().map(|(x, y)| bg(cpu, x, y)).collect();
+
+        let (map, map_width) = expanded_map(cpu);
+        let map_height = map.len() / map_width;
+        let tile = |x: i32, y: i32| map[y as usize * map_width + x as usize];
+
+        let candidates: Vec<(i32, i32)> = (0..=map_height as i32 - height)
+            .flat_map(|oy| {
+                (SIDE_COLUMNS..=map_width as i32 - width - SIDE_COLUMNS).map(move |ox| (ox, oy))
+            })
+            .filter(|&(ox, oy)| {
+                in_view()
+                    .zip(&view)
+                    .all(|((x, y), &t)| tile(ox + x, oy + y) == t)
+            })
+            .collect();
+
+        // The view can't be found while something else is in the BG map
+        if candidates.is_empty() {
+            return;
+        }
+
+        let matches = candidates.iter().any(|&(ox, oy)| {
+            in_sides()
+                .zip(&sides)
+                .all(|((x, y), &t)| tile(ox + x, oy + y) == t)
+        });
+
+        CHECKED.fetch_add(1, Ordering::Relaxed);
+        if !matches {
+            MISMATCHES.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let x = cpu.borrow_wram().x_coord().min(63);
+        X_COORDS.fetch_or(1 << x, Ordering::Relaxed);
+    }
+
+    fn tap(key: KeyboardKey, frame: usize) -> Vec<KeyboardEvent> {
+        match frame {
+            0 => vec![KeyboardEvent::Down { key, shift: false }],
+            1 => vec![KeyboardEvent::Up { key }],
+            _ => vec![],
+        }
+    }
+
+    #[test]
+    fn sides_follow_the_tile_block_map() {
+        if !crate::rom::load_for_tests() {
+            return;
+        }
+
+        // LoadGBPal runs once per frame in the overworld loop
+        let mut headless = Game::builder()
+            .without_audio()
+            .widescreen(true)
+            .hook(0x00, 0x1e6f, Hook::observer("check_sides", check_sides))
+            .build_headless()
+            .unwrap();
+
+        // Through the intro and Oak's speech. Naming the player and the rival
+        // types As until Start ends the name.
+        let mut frames = 0;
+        while CHECKED.load(Ordering::Relaxed) == 0 {
+            assert!(frames < 20000, "Never reached the overworld");
+
+            let key = match frames / 10 % 2 {
+                0 => KeyboardKey::Z,
+                _ => KeyboardKey::Return,
+            };
+
+            headless.step_frame(&tap(key, frames % 10));
+            frames += 1;
+        }
+
+        // Close the start menu, in case the last Start opened it
+        for frame in 0..60 {
+            headless.step_frame(&tap(KeyboardKey::X, frame % 20));
+        }
+
+        // Around the player's room, ending up two steps right of the start
+        use KeyboardKey::{Down, Left, Right, Up};
+        for key in [Left, Left, Up, Right, Right, Right, Right, Down] {
+            headless.step_frame(&[KeyboardEvent::Down { key, shift: false }]);
+            for _ in 0..16 {
+                headless.step_frame(&[]);
+            }
+            headless.step_frame(&[KeyboardEvent::Up { key }]);
+            for _ in 0..16 {
+                headless.step_frame(&[]);
+            }
+        }
+
+        assert_eq!(headless.frame().len(), WIDE_SCREEN_W * GB_SCREEN_H * 3);
+        assert!(
+            X_COORDS.load(Ordering::Relaxed).count_ones() > 1,
+            "Never moved"
+        );
+        assert_eq!(MISMATCHES.load(Ordering::Relaxed), 0);
+    }
+}
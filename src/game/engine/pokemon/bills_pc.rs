@@ -9,7 +9,7 @@ use crate::{
         engine::{events, items::item_effects::add_pokemon_to_box, menus, pikachu},
         home,
     },
-    gpu::{GpuAtlas, GpuTile},
+    gpu::{GpuAtlas, GpuSprite, GpuTile},
     keypad::KeypadKey,
     save_state::{BoxId, BoxedPokemon},
 };
@@ -116,6 +116,11 @@ fn pick_pokemon(cpu: &mut Cpu, pos: (usize, usize), choices: &[BoxedPokemon]) ->
     home::text::text_box_border(cpu.gpu_mut_layer(layer), pos.0, pos.1, width, height);
 
     let window_height = height / 3;
+    let cursor = cpu.gpu_mut_layer(layer).add_sprite(GpuSprite::new(
+        GpuTile::new(GpuAtlas::Font, 13, 6),
+        0,
+        0,
+    ));
 
     let mut selected = 0;
     let mut scroll_pos = 0;
@@ -144,17 +149,12 @@ fn pick_pokemon(cpu: &mut Cpu, pos: (usize, usize), choices: &[BoxedPokemon]) ->
                 .set_background(pos.0 + 3, pos.1 + 2 + i * 3, icon.3);
 
             if scroll_pos + i == selected {
-                cpu.gpu_mut_layer(layer).set_background(
-                    pos.0 + 1,
-                    pos.1 + 2 + i * 3,
-                    GpuTile::new(GpuAtlas::Font, 13, 6),
-                );
-            } else {
-                cpu.gpu_mut_layer(layer).set_background(
-                    pos.0 + 1,
-                    pos.1 + 2 + i * 3,
-                    GpuTile::new(GpuAtlas::BoxBorder, 1, 1),
-                );
+                let x = (pos.0 + 1) * 8;
+                let y = (pos.1 + 2 + i * 3) * 8;
+                let arrow = GpuTile::new(GpuAtlas::Font, 13, 6);
+
+                cpu.gpu_mut_layer(layer)
+                    .set_sprite(cursor, GpuSprite::new(arrow, x as i32, y as i32));
             }
 
             home::text::place_string(
@@ -18,9 +18,17 @@ pub fn play_sound(cpu: &mut Cpu) {
     if cpu.a == 0xff {
         // Stop all sounds?
     } else if let Some(music) = Music::from_bank_and_id(bank, cpu.a) {
-        cpu.start_music(music);
+        // The ROM fades out the current music first, and then plays this
+        // sound again once the fade is done
+        let fading_out = cpu.borrow_wram().audio_fade_out_control() != 0
+            && cpu.read_byte(wram::W_LAST_MUSIC_SOUND_ID) != 0xff;
+
+        if !fading_out {
+            let track = music.track(cpu.borrow_wram());
+            cpu.start_music(track);
+        }
     } else if let Some(music_sfx) = MusicSfx::from_bank_and_id(bank, cpu.a, pitch, length) {
-        cpu.play_sfx(music_sfx);
+        cpu.play_sfx(music_sfx.track(bank, cpu.a, pitch, length));
     } else if let Some(mut sfx) = Sfx::from_bank_and_id(bank, cpu.a) {
         if sfx.is_cry() || sfx.is_battle_sfx() {
             sfx.tweak(pitch, length);
@@ -23,7 +23,7 @@ use crate::{
             tileset_constants::{CEMETERY, FACILITY, OVERWORLD, PLATEAU, SHIP, SHIP_PORT},
         },
         data::tilesets::bike_riding_tilesets::BIKE_RIDING_TILESETS,
-        engine::overworld::clear_variables::clear_variables_on_enter_map,
+        engine::overworld::{clear_variables::clear_variables_on_enter_map, widescreen},
         home::{
             self,
             hidden_objects::check_for_hidden_object_or_bookshelf_or_card_key_door,
@@ -123,8 +123,16 @@ pub fn overworld_loop_less_delay(cpu: &mut Cpu) {
     loop {
         log::trace!("overworld_loop_less_delay()");
 
+        // Every iteration starts from scratch, making this a good place to take snapshots
+        cpu.safe_point(0x0245);
+
         home::vblank::delay_frame(cpu);
 
+        // Not while the sides are blacked out, e.g. after loading a snapshot
+        if cpu.mmu.gpu.wide_map_view() {
+            widescreen::update_side_sprites(cpu);
+        }
+
         map_objects::is_surfing_pikachu_in_party(cpu);
         cpu.call(0x1e6f); // LoadGBPal
 
@@ -1700,6 +1708,10 @@ pub fn load_current_map_view(cpu: &mut Cpu) {
         dst += gfx_constants::SCREEN_WIDTH as u16;
     }
 
+    if cpu.mmu.gpu.widescreen_enabled() {
+        widescreen::draw_map_view_sides(cpu);
+    }
+
     // restore previous ROM bank
     cpu.a = saved_bank;
     cpu.call(0x3e7e); // BankswitchCommon
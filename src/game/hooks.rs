@@ -0,0 +1,141 @@
+use crate::hooks::{Hook, HookTable};
+
+use super::{audio, engine, home, scripts};
+
+/// All routines of the ROM that have been ported to Rust
+#[rustfmt::skip]
+const BUILTIN: &[(usize, u16, Hook)] = &[
+    (0x00, 0x01d7, Hook::replacement("enter_map", home::overworld::enter_map)),
+    (0x00, 0x0242, Hook::replacement("overworld_loop", home::overworld::overworld_loop)),
+    (0x00, 0x0245, Hook::replacement("overworld_loop_less_delay", home::overworld::overworld_loop_less_delay)),
+    (0x00, 0x0457, Hook::guard("step_count_check")),
+    (0x00, 0x0475, Hook::guard("all_pokemon_fainted")),
+    (0x00, 0x0480, Hook::guard("new_battle")),
+    (0x00, 0x049d, Hook::guard("do_bike_speedup")),
+    (0x00, 0x04bd, Hook::replacement("check_warps_no_collision", home::overworld::check_warps_no_collision)),
+    (0x00, 0x051a, Hook::replacement("check_warps_collision", home::overworld::check_warps_collision)),
+    (0x00, 0x0543, Hook::guard("warp_found1")),
+    (0x00, 0x054a, Hook::replacement("warp_found2", home::overworld::warp_found2)),
+    (0x00, 0x05db, Hook::replacement("check_map_connections", home::overworld::check_map_connections)),
+    (0x00, 0x06ef, Hook::guard("play_map_change_sound")),
+    (0x00, 0x0712, Hook::replacement("check_if_in_outside_map", home::overworld::check_if_in_outside_map)),
+    (0x00, 0x071a, Hook::guard("extra_warp_check")),
+    (0x00, 0x0750, Hook::guard("map_entry_after_battle")),
+    (0x00, 0x0762, Hook::guard("handle_black_out")),
+    (0x00, 0x0785, Hook::replacement("stop_music", home::overworld::stop_music)),
+    (0x00, 0x0794, Hook::guard("handle_fly_warp_or_dungeon_warp")),
+    (0x00, 0x07c4, Hook::guard("stop_bike_surf")),
+    (0x00, 0x07d7, Hook::replacement("load_player_sprite_graphics", home::overworld::load_player_sprite_graphics)),
+    (0x00, 0x0805, Hook::replacement("is_bike_riding_allowed", home::overworld::is_bike_riding_allowed)),
+    (0x00, 0x0828, Hook::replacement("load_tileset_tile_pattern_data", home::overworld::load_tileset_tile_pattern_data)),
+    (0x00, 0x083c, Hook::replacement("load_tile_block_map", home::overworld::load_tile_block_map)),
+    (0x00, 0x0919, Hook::guard("load_north_south_connections_tile_map")),
+    (0x00, 0x093d, Hook::guard("load_east_west_connections_tile_map")),
+    (0x00, 0x09f2, Hook::guard("sign_loop")),
+    (0x00, 0x095e, Hook::guard("is_sprite_or_sign_in_front_of_player")),
+    (0x00, 0x0983, Hook::replacement("is_sprite_in_front_of_player", home::overworld::is_sprite_in_front_of_player)),
+    (0x00, 0x0985, Hook::replacement("is_sprite_in_front_of_player2", home::overworld::is_sprite_in_front_of_player2)),
+    (0x00, 0x0a1c, Hook::guard("collision_check_on_land")),
+    (0x00, 0x0a79, Hook::guard("check_tile_passable")),
+    (0x00, 0x0a86, Hook::guard("check_for_jumping_and_tile_pair_collisions")),
+    (0x00, 0x0aa0, Hook::replacement("check_for_tile_pair_collisions2", home::overworld::check_for_tile_pair_collisions2)),
+    (0x00, 0x0aa6, Hook::replacement("check_for_tile_pair_collisions", home::overworld::check_for_tile_pair_collisions)),
+    (0x00, 0x0b06, Hook::replacement("load_current_map_view", home::overworld::load_current_map_view)),
+    (0x00, 0x0b7f, Hook::replacement("advance_player_sprite", home::overworld::advance_player_sprite)),
+    (0x00, 0x0b95, Hook::replacement("schedule_north_row_redraw", home::overworld::schedule_north_row_redraw)),
+    (0x00, 0x0baa, Hook::replacement("copy_to_redraw_row_or_column_src_tiles", home::overworld::copy_to_redraw_row_or_column_src_tiles)),
+    (0x00, 0x0bb6, Hook::replacement("schedule_south_row_redraw", home::overworld::schedule_south_row_redraw)),
+    (0x00, 0x0bd7, Hook::replacement("schedule_east_column_redraw", home::overworld::schedule_east_column_redraw)),
+    (0x00, 0x0bf6, Hook::guard("schedule_column_redraw_helper")),
+    (0x00, 0x0c0c, Hook::replacement("schedule_west_column_redraw", home::overworld::schedule_west_column_redraw)),
+    (0x00, 0x0c21, Hook::guard("draw_tile_block")),
+    (0x00, 0x0c51, Hook::guard("joypad_overworld")),
+    (0x00, 0x0c65, Hook::guard("force_bike_down")),
+    (0x00, 0x0c7b, Hook::guard("are_inputs_simulated")),
+    (0x00, 0x0cb3, Hook::guard("get_simulated_input")),
+    (0x00, 0x0cca, Hook::guard("collision_check_on_water")),
+    (0x00, 0x0d2c, Hook::guard("run_map_script")),
+    (0x00, 0x0d5e, Hook::replacement("load_walking_player_sprite_graphics", home::overworld::load_walking_player_sprite_graphics)),
+    (0x00, 0x0d69, Hook::guard("load_surfing_player_sprite_graphics2")),
+    (0x00, 0x0d83, Hook::guard("load_surfing_player_sprite_graphics")),
+    (0x00, 0x0d8a, Hook::guard("load_bike_player_sprite_graphics")),
+    (0x00, 0x0d8f, Hook::guard("load_player_sprite_graphics_common")),
+    (0x00, 0x0dab, Hook::guard("load_map_header")),
+    (0x00, 0x0eaa, Hook::replacement("copy_map_connection_header", home::overworld::copy_map_connection_header)),
+    (0x00, 0x0eb3, Hook::guard("copy_sign_data")),
+    (0x00, 0x0ecb, Hook::guard("load_map_data")),
+    (0x00, 0x0f0c, Hook::guard("load_screen_related_data")),
+    (0x00, 0x0f16, Hook::replacement("reload_map_after_surfing_minigame", home::overworld::reload_map_after_surfing_minigame)),
+    (0x00, 0x0f3d, Hook::replacement("reload_map_after_printer", home::overworld::reload_map_after_printer)),
+    (0x00, 0x0f56, Hook::guard("reset_map_variables")),
+    (0x00, 0x0f70, Hook::guard("copy_map_view_to_vram")),
+    (0x00, 0x0f73, Hook::guard("copy_map_view_to_vram2")),
+    (0x00, 0x0f8b, Hook::replacement("switch_to_map_rom_bank", home::overworld::switch_to_map_rom_bank)),
+    (0x00, 0x0fa7, Hook::guard("get_map_header_pointer")),
+    (0x00, 0x0fc3, Hook::guard("ignore_input_for_half_second")),
+    (0x00, 0x0fd0, Hook::replacement("reset_using_strength_out_of_battle_bit", home::overworld::reset_using_strength_out_of_battle_bit)),
+    (0x00, 0x0fd6, Hook::replacement("force_bike_or_surf", home::overworld::force_bike_or_surf)),
+    (0x00, 0x0fe1, Hook::guard("handle_mid_jump")),
+    (0x00, 0x0ff0, Hook::guard("is_spinning")),
+    (0x00, 0x1006, Hook::guard("init_sprites")),
+    (0x00, 0x1050, Hook::guard("zero_sprite_state_data")),
+    (0x00, 0x1060, Hook::guard("disable_regular_sprites")),
+    (0x00, 0x106f, Hook::guard("load_sprite")),
+    (0x00, 0x10ba, Hook::replacement("check_for_user_interruption", home::overworld::check_for_user_interruption)),
+    (0x00, 0x10d5, Hook::guard("load_destination_warp_position")),
+    (0x00, 0x143e, Hook::replacement("load_mon_front_sprite", home::pics::load_mon_front_sprite)),
+    (0x00, 0x2238, Hook::replacement("play_sound", home::audio::play_sound)),
+    (0x00, 0x309d, Hook::guard("is_player_character_being_controlled_by_game")),
+    (0x00, 0x30ae, Hook::guard("run_npc_movement_script")),
+    (0x00, 0x3422, Hook::replacement("is_item_in_bag", home::map_objects::is_item_in_bag)),
+    (0x00, 0x342a, Hook::guard("is_surfing_pikachu_in_party")),
+    (0x00, 0x3ed7, Hook::replacement("get_predef_registers", home::predef::get_predef_registers)),
+    (0x00, 0x3ef9, Hook::guard("check_for_hidden_object_or_bookshelf_or_card_key_door")),
+    (0x01, 0x42bf, Hook::replacement("display_title_screen_go_to_main_menu", engine::movie::title::display_title_screen_go_to_main_menu)),
+    (0x01, 0x581e, Hook::replacement("return_to_cable_club_room", engine::link::cable_club::return_to_cable_club_room)),
+    (0x01, 0x5ba6, Hook::guard("main_menu")),
+    (0x01, 0x5dfb, Hook::guard("check_for_player_name_in_sram")),
+    (0x02, 0x5064, Hook::replacement("play_battle_music", audio::play_battle_music::play_battle_music)),
+    (0x03, 0x407c, Hook::guard("clear_variables_on_enter_map")),
+    (0x03, 0x44f4, Hook::replacement("load_tileset_header", engine::overworld::tilesets::load_tileset_header)),
+    (0x03, 0x4b62, Hook::replacement("load_wild_data", engine::overworld::wild_mons::load_wild_data)),
+    (0x03, 0x6807, Hook::replacement("hook_send_new_mon_to_box_end", engine::items::item_effects::hook_send_new_mon_to_box_end)),
+    (0x03, 0x752b, Hook::replacement("heal_party", engine::events::heal_party::heal_party)),
+    (0x03, 0x7735, Hook::replacement("get_quantity_of_item_in_bag", engine::items::get_bag_item_quantity::get_quantity_of_item_in_bag)),
+    (0x04, 0x783a, Hook::replacement("try_do_wild_encounter", engine::battle::wild_encounters::try_do_wild_encounter)),
+    (0x06, 0x4f0a, Hook::replacement("pallet_town_script4", scripts::pallete_town::pallet_town_script4)),
+    (0x07, 0x4b40, Hook::replacement("oaks_lab_player_received_mon_text", scripts::oaks_lab::oaks_lab_player_received_mon_text)),
+    (0x08, 0x5495, Hook::replacement("bills_pc_menu", engine::pokemon::bills_pc::bills_pc_menu)),
+    (0x0f, 0x59ac, Hook::replacement("is_ghost_battle", engine::battle::core::is_ghost_battle)),
+    (0x1c, 0x61f8, Hook::replacement("load_sgb", engine::gfx::palettes::load_sgb)),
+    (0x1c, 0x7b91, Hook::replacement("save_sav_to_sram", engine::menus::save::save_sav_to_sram)),
+    (0x3c, 0x4000, Hook::replacement("play_pikachu_sound_clip", engine::pikachu::pikachu_pcm::play_pikachu_sound_clip)),
+    (0x3c, 0x4274, Hook::replacement("reset_status_and_halve_money_on_blackout", engine::events::black_out::reset_status_and_halve_money_on_blackout)),
+    (0x3d, 0x42db, Hook::replacement("decrement_pp", engine::battle::decrement_pp::decrement_pp)),
+    (0x3d, 0x5ff2, Hook::replacement("init_battle", engine::battle::init_battle::init_battle)),
+    (0x3d, 0x5ff8, Hook::replacement("init_opponent", engine::battle::init_battle::init_opponent)),
+    (0x3d, 0x615a, Hook::replacement("_load_trainer_pic", engine::battle::init_battle::_load_trainer_pic)),
+    (0x3d, 0x6178, Hook::replacement("load_mon_back_pic", engine::battle::init_battle::load_mon_back_pic)),
+    (0x3d, 0x61a6, Hook::replacement("animate_sending_out_mon", engine::battle::init_battle::animate_sending_out_mon)),
+    (0x3d, 0x61f9, Hook::replacement("copy_uncompressed_pic_to_tilemap", engine::battle::init_battle::copy_uncompressed_pic_to_tilemap)),
+    (0x3d, 0x6203, Hook::replacement("copy_uncompressed_pic_to_hl", engine::battle::init_battle::copy_uncompressed_pic_to_hl)),
+    (0x3d, 0x674d, Hook::replacement("hook_give_pokemon_next_end", engine::events::give_pokemon::hook_give_pokemon_next_end)),
+];
+
+impl HookTable {
+    pub fn builtin() -> HookTable {
+        let mut table = HookTable::new();
+
+        for &(bank, addr, hook) in BUILTIN {
+            let previous = table.insert(bank, addr, hook);
+            assert!(
+                previous.is_none(),
+                "Duplicate hook at {:02x}:{:04x}",
+                bank,
+                addr
+            );
+        }
+
+        table
+    }
+}
@@ -1,15 +1,52 @@
 use std::{
+    io,
+    panic::{self, AssertUnwindSafe},
     path::PathBuf,
-    sync::mpsc::{Receiver, SyncSender},
+    sync::{
+        mpsc::{Receiver, SyncSender},
+        Arc, RwLock,
+    },
 };
 
-use crate::{cpu::Cpu, keypad::KeyboardEvent, rom::ROM, PokemonSpecies};
+use crate::{
+    bindings::Bindings,
+    capture::{CaptureOptions, RecordingFormat},
+    coverage::Coverage,
+    cpu::Cpu,
+    frontend::{
+        AudioSink, InputSource, NullAudioSink, NullInputSource, NullVideoSink, RodioAudioSink,
+        VideoSink,
+    },
+    gdb::GdbStub,
+    gpu::GB_SCREEN_H,
+    headless::{Disconnected, Headless},
+    hooks::{Hook, HookTable},
+    input_movie::{Movie, MovieRecorder},
+    keypad::KeyboardEvent,
+    link::Link,
+    palette::PaletteTheme,
+    printer::Printer,
+    recorder::AudioRecorder,
+    rom::{self, RomError, ROM},
+    save_state::SaveState,
+    saves,
+    serial::SerialDevice,
+    sgb::{SGB_SCREEN_H, SGB_SCREEN_W},
+    snapshot::SnapshotRestored,
+    sound2::DEFAULT_SFX_VOICES,
+    symbols::Symbols,
+    trace::Trace,
+    upscale::{Filter, Upscaler, DEFAULT_SCALE},
+    widescreen::WIDE_SCREEN_W,
+    PokemonSpecies,
+};
 
 pub mod audio;
 pub mod constants;
 pub mod data;
 pub mod engine;
 pub mod home;
+mod hooks;
 pub mod macros;
 pub mod ram;
 pub mod scripts;
@@ -35,26 +72,529 @@ pub struct Game {
     cpu: Cpu,
 }
 
+/// How music and sound effects are played, on top of the [`AudioSink`]
+struct AudioOptions {
+    recording: Option<PathBuf>,
+    sfx_voices: usize,
+}
+
+/// Recording or playing back an input movie
+enum MovieOptions {
+    Record { path: PathBuf, save: String },
+    Play(PathBuf),
+}
+
+/// Optional tools for debugging the game, which all start out disabled
+#[derive(Default)]
+struct Debugging {
+    coverage: Option<Coverage>,
+    gdb: Option<GdbStub>,
+    trace: Option<Trace>,
+}
+
 impl Game {
     pub fn new(
         update_screen: SyncSender<Vec<u8>>,
         keyboard_events: Receiver<KeyboardEvent>,
         starter: PokemonSpecies,
+    ) -> Result<Self, RomError> {
+        Game::builder()
+            .starter(starter)
+            .video(update_screen)
+            .input(keyboard_events)
+            .build()
+    }
+
+    pub fn builder() -> GameBuilder {
+        GameBuilder {
+            starter: PokemonSpecies::Pikachu,
+            video: None,
+            input: None,
+            bindings: Arc::default(),
+            audio: None,
+            hooks: HookTable::builtin(),
+            symbols: None,
+            coverage_report: None,
+            gdb_port: None,
+            trace_capacity: None,
+            link: None,
+            printout_dir: None,
+            snapshot_dir: None,
+            audio_options: AudioOptions {
+                recording: None,
+                sfx_voices: DEFAULT_SFX_VOICES,
+            },
+            filter: Filter::Nearest,
+            scale: DEFAULT_SCALE,
+            palette_theme: PaletteTheme::default(),
+            sgb_border: false,
+            widescreen: false,
+            capture_options: CaptureOptions::default(),
+            movie: None,
+        }
+    }
+
+    fn from_parts(
+        video: Box<dyn VideoSink>,
+        input: Box<dyn InputSource>,
+        audio: Box<dyn AudioSink>,
+        hooks: HookTable,
+        debugging: Debugging,
+        serial: SerialDevice,
+        starter: PokemonSpecies,
     ) -> Self {
         assert_eq!(ROM[0x143], 0x80);
         assert_eq!(ROM[0x147], 0x1b);
         assert_eq!(ROM[0x149], 0x03);
 
-        Self {
-            cpu: Cpu::new(update_screen, keyboard_events, starter),
+        let mut cpu = Cpu::new(video, input, audio, hooks, starter);
+        cpu.coverage = debugging.coverage;
+        cpu.gdb = debugging.gdb;
+        cpu.trace = debugging.trace;
+
+        cpu.mmu.serial.plug(serial);
+
+        Self { cpu }
+    }
+
+    fn configure_movie(&mut self, movie: MovieOptions) {
+        let keypad = &mut self.cpu.mmu.keypad;
+
+        match movie {
+            MovieOptions::Record { path, save } => {
+                let save_path = saves::get_save_path(&save);
+
+                let save = match SaveState::from_file(&save_path) {
+                    Ok(save) => save,
+                    Err(e) => {
+                        log::error!("Failed to read {}: {}", save_path.display(), e);
+                        return;
+                    }
+                };
+
+                match MovieRecorder::create(&path, &save) {
+                    Ok(recorder) => keypad.record_movie(recorder, save),
+                    Err(e) => log::error!("Failed to record movie to {}: {}", path.display(), e),
+                }
+            }
+
+            MovieOptions::Play(path) => match Movie::load(&path) {
+                Ok(movie) => keypad.play_movie(movie),
+                Err(e) => log::error!("Failed to play movie {}: {}", path.display(), e),
+            },
+        }
+    }
+
+    fn configure_audio(&mut self, options: AudioOptions) {
+        self.cpu.mmu.sound2.set_sfx_voices(options.sfx_voices);
+
+        if let Some(path) = options.recording {
+            match AudioRecorder::create(&path) {
+                Ok(recorder) => self.cpu.mmu.record_audio(recorder),
+                Err(e) => log::error!("Failed to record audio to {}: {}", path.display(), e),
+            }
         }
     }
 
     pub fn boot(&mut self) {
-        self.cpu.call(0x0100)
+        let mut result = panic::catch_unwind(AssertUnwindSafe(|| self.cpu.call(0x0100)));
+
+        // Loading a snapshot unwinds out of whatever was running, so that we
+        // can pick up where the snapshot left off
+        while let Err(payload) = result {
+            if !payload.is::<SnapshotRestored>() {
+                // Dropping a `Headless` isn't a crash worth a trace
+                if !payload.is::<Disconnected>() {
+                    self.cpu.dump_trace();
+                }
+                panic::resume_unwind(payload);
+            }
+
+            result = panic::catch_unwind(AssertUnwindSafe(|| self.cpu.resume()));
+        }
     }
 
     pub fn sync_audio(&mut self) {
         self.cpu.sync_audio()
     }
+
+    /// Every routine of the ROM that is currently handled by Rust code
+    pub fn hooks(&self) -> &HookTable {
+        self.cpu.hooks()
+    }
+
+    /// Writes the coverage report enabled with
+    /// [`GameBuilder::coverage_report`]. Does nothing without one.
+    pub fn write_coverage_report(&self) -> io::Result<()> {
+        match &self.cpu.coverage {
+            Some(coverage) => coverage.write_report(),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct GameBuilder {
+    starter: PokemonSpecies,
+    video: Option<Box<dyn VideoSink + Send>>,
+    input: Option<Box<dyn InputSource + Send>>,
+    bindings: Arc<RwLock<Bindings>>,
+    audio: Option<Box<dyn AudioSink + Send>>,
+    hooks: HookTable,
+    symbols: Option<Arc<Symbols>>,
+    coverage_report: Option<PathBuf>,
+    gdb_port: Option<u16>,
+    trace_capacity: Option<usize>,
+    link: Option<Link>,
+    printout_dir: Option<PathBuf>,
+    snapshot_dir: Option<PathBuf>,
+    audio_options: AudioOptions,
+    filter: Filter,
+    scale: usize,
+    palette_theme: PaletteTheme,
+    sgb_border: bool,
+    widescreen: bool,
+    capture_options: CaptureOptions,
+    movie: Option<MovieOptions>,
+}
+
+impl GameBuilder {
+    /// Which Pokemon to start with, defaults to Pikachu
+    pub fn starter(mut self, starter: PokemonSpecies) -> Self {
+        self.starter = starter;
+        self
+    }
+
+    /// Where to present finished frames, defaults to discarding them
+    pub fn video(mut self, video: impl VideoSink + Send + 'static) -> Self {
+        self.video = Some(Box::new(video));
+        self
+    }
+
+    /// Where to read keyboard events from, defaults to no input at all
+    pub fn input(mut self, input: impl InputSource + Send + 'static) -> Self {
+        self.input = Some(Box::new(input));
+        self
+    }
+
+    /// Which keys press the buttons and trigger the hotkeys, defaults to
+    /// [`Bindings::default`]. The Controls menu changes them in place, so a
+    /// frontend that handles the speed and fullscreen actions itself should
+    /// keep looking them up through this.
+    pub fn bindings(mut self, bindings: Arc<RwLock<Bindings>>) -> Self {
+        self.bindings = bindings;
+        self
+    }
+
+    /// Where to play music and sound effects, defaults to the default output
+    /// device, or to dropping all sounds if there is none
+    pub fn audio(mut self, audio: impl AudioSink + Send + 'static) -> Self {
+        self.audio = Some(Box::new(audio));
+        self
+    }
+
+    /// Drop all sounds instead of opening an audio device
+    pub fn without_audio(self) -> Self {
+        self.audio(NullAudioSink)
+    }
+
+    /// Record the APU, music and sound effects to a WAV file at `path`, in
+    /// emulated time, which is finished when the game is dropped
+    pub fn record_audio(mut self, path: impl Into<PathBuf>) -> Self {
+        self.audio_options.recording = Some(path.into());
+        self
+    }
+
+    /// How many sound effects can play over each other, defaults to 4. When
+    /// all are busy, lower priority sounds such as menu beeps give way first.
+    pub fn sfx_voices(mut self, count: usize) -> Self {
+        self.audio_options.sfx_voices = count;
+        self
+    }
+
+    /// How the screen is scaled up, defaults to nearest neighbour
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// How many times the native resolution the frames passed to the video
+    /// sink are, defaults to 4
+    pub fn scale(mut self, scale: usize) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Draw the Super Game Boy border around the screen, which makes the
+    /// frames 256x224 times the scale
+    pub fn sgb_border(mut self, enabled: bool) -> Self {
+        self.sgb_border = enabled;
+        self
+    }
+
+    /// Show more of the map on each side of the screen in the overworld,
+    /// which makes the frames 240x144 times the scale. Battles and menus stay
+    /// in the middle. Has no effect together with the SGB border.
+    pub fn widescreen(mut self, enabled: bool) -> Self {
+        self.widescreen = enabled;
+        self
+    }
+
+    /// Width and height of the frames passed to the video sink
+    pub fn screen_size(&self) -> (usize, usize) {
+        match (self.sgb_border, self.widescreen) {
+            (true, _) => (SGB_SCREEN_W * self.scale, SGB_SCREEN_H * self.scale),
+            (false, true) => (WIDE_SCREEN_W * self.scale, GB_SCREEN_H * self.scale),
+            (false, false) => self.upscaler().screen_size(),
+        }
+    }
+
+    fn upscaler(&self) -> Upscaler {
+        Upscaler::new(self.filter, self.scale)
+    }
+
+    /// Which colors the screen is drawn with, defaults to the colors that a
+    /// Game Boy Color shows
+    pub fn palette_theme(mut self, theme: PaletteTheme) -> Self {
+        self.palette_theme = theme;
+        self
+    }
+
+    /// Where screenshots (F6) and recordings (F7) are saved, defaults to the
+    /// `captures` directory next to the saves
+    pub fn capture_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.capture_options.dir = dir.into();
+        self
+    }
+
+    /// Record to animated GIFs or PNGs, defaults to GIF
+    pub fn recording_format(mut self, format: RecordingFormat) -> Self {
+        self.capture_options.format = format;
+        self
+    }
+
+    /// Save screenshots and recordings at 160x144 instead of the scaled size
+    pub fn native_captures(mut self, native: bool) -> Self {
+        self.capture_options.native = native;
+        self
+    }
+
+    /// Record every keypad event to an input movie at `path`. The game
+    /// continues from the save named `save` instead of showing the main menu,
+    /// and saving in the game doesn't write to the save file.
+    pub fn record_movie(mut self, path: impl Into<PathBuf>, save: impl Into<String>) -> Self {
+        self.movie = Some(MovieOptions::Record {
+            path: path.into(),
+            save: save.into(),
+        });
+        self
+    }
+
+    /// Play back the input movie at `path`, ignoring the keypad part of the
+    /// input until it runs out. The game continues from the save that the
+    /// movie was recorded with instead of showing the main menu.
+    pub fn play_movie(mut self, path: impl Into<PathBuf>) -> Self {
+        self.movie = Some(MovieOptions::Play(path.into()));
+        self
+    }
+
+    /// Run `hook` whenever execution reaches `addr` in ROM bank `bank`,
+    /// replacing any built-in hook at the same address
+    pub fn hook(mut self, bank: usize, addr: u16, hook: Hook) -> Self {
+        if let Some(previous) = self.hooks.insert(bank, addr, hook) {
+            log::debug!(
+                "Hook {} at {:02x}:{:04x} replaces {}",
+                hook.name,
+                bank,
+                addr,
+                previous.name
+            );
+        }
+
+        self
+    }
+
+    /// Plug a link cable to another instance of the game into the serial port,
+    /// for trading and battling in the Cable Club. Replaces the printer.
+    pub fn link(mut self, link: Link) -> Self {
+        self.link = Some(link);
+        self
+    }
+
+    /// Where the Game Boy Printer writes its printouts, defaults to the
+    /// `printouts` directory next to the saves
+    pub fn printout_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.printout_dir = Some(dir.into());
+        self
+    }
+
+    /// Where snapshots are saved to and loaded from, defaults to the
+    /// `snapshots` directory next to the saves
+    pub fn snapshot_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.snapshot_dir = Some(dir.into());
+        self
+    }
+
+    /// The link cable if there is one, and the printer otherwise
+    fn serial(&mut self) -> SerialDevice {
+        match self.link.take() {
+            Some(link) => SerialDevice::Link(link),
+            None => SerialDevice::Printer(Printer::new(
+                self.printout_dir
+                    .take()
+                    .unwrap_or_else(saves::get_printout_dir),
+            )),
+        }
+    }
+
+    /// Names for ROM addresses, used in diagnostics such as the coverage
+    /// report and the instruction trace
+    pub fn symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = Some(Arc::new(symbols));
+        self
+    }
+
+    /// Trace which ROM routines run in the interpreter, and write a report
+    /// ranking the unported ones to `path` with [`Game::write_coverage_report`]
+    pub fn coverage_report(mut self, path: impl Into<PathBuf>) -> Self {
+        self.coverage_report = Some(path.into());
+        self
+    }
+
+    /// Listen for a GDB remote serial protocol debugger on this local port
+    pub fn gdb_port(mut self, port: u16) -> Self {
+        self.gdb_port = Some(port);
+        self
+    }
+
+    /// Keep the last `capacity` interpreted instructions, which are printed
+    /// when the game panics or F5 is pressed
+    pub fn trace(mut self, capacity: usize) -> Self {
+        self.trace_capacity = Some(capacity);
+        self
+    }
+
+    fn gdb(&self) -> Option<GdbStub> {
+        let port = self.gdb_port?;
+
+        match GdbStub::bind(("127.0.0.1", port)) {
+            Ok(gdb) => Some(gdb),
+            Err(e) => {
+                log::error!("Failed to listen for debugger on port {}: {}", port, e);
+                None
+            }
+        }
+    }
+
+    fn debugging(&mut self) -> Debugging {
+        Debugging {
+            coverage: self
+                .coverage_report
+                .take()
+                .map(|path| Coverage::new(path, self.symbols.clone(), &self.hooks)),
+            gdb: self.gdb(),
+            trace: self
+                .trace_capacity
+                .map(|capacity| Trace::new(capacity, self.symbols.clone())),
+        }
+    }
+
+    /// Creates the game, first loading the ROMs from the configured or
+    /// default locations unless [`load_roms`](crate::load_roms) was called
+    pub fn build(mut self) -> Result<Game, RomError> {
+        rom::ensure_loaded()?;
+
+        let debugging = self.debugging();
+        let serial = self.serial();
+        let upscaler = self.upscaler();
+
+        let audio: Box<dyn AudioSink> = match self.audio {
+            Some(audio) => audio,
+            None => match RodioAudioSink::try_default() {
+                Ok(audio) => Box::new(audio),
+                Err(e) => {
+                    log::warn!("Failed to open audio device, playing without sound: {}", e);
+                    Box::new(NullAudioSink)
+                }
+            },
+        };
+
+        let mut game = Game::from_parts(
+            self.video.unwrap_or_else(|| Box::new(NullVideoSink)),
+            self.input.unwrap_or_else(|| Box::new(NullInputSource)),
+            audio,
+            self.hooks,
+            debugging,
+            serial,
+            self.starter,
+        );
+
+        game.cpu.mmu.keypad.set_bindings(self.bindings);
+        if let Some(dir) = self.snapshot_dir {
+            game.cpu.snapshot_dir = dir;
+        }
+        game.cpu.mmu.gpu.set_upscaler(upscaler);
+        game.cpu.mmu.gpu.set_palette_theme(self.palette_theme);
+        game.cpu.mmu.gpu.set_sgb_border(self.sgb_border);
+        game.cpu
+            .mmu
+            .gpu
+            .set_widescreen(self.widescreen && !self.sgb_border);
+        game.cpu.mmu.gpu.set_capture_options(self.capture_options);
+        game.configure_audio(self.audio_options);
+
+        if let Some(movie) = self.movie {
+            game.configure_movie(movie);
+        }
+
+        Ok(game)
+    }
+
+    /// Run the game on a background thread, advancing one frame at a time
+    /// with [`Headless::step_frame`].
+    ///
+    /// Video and input are provided by the returned [`Headless`], and audio
+    /// defaults to dropping all sounds. Frames are 160x144, or 240x144 in
+    /// widescreen mode, regardless of the filter, scale and SGB border. The
+    /// ROMs are loaded like in [`GameBuilder::build`].
+    pub fn build_headless(mut self) -> Result<Headless, RomError> {
+        rom::ensure_loaded()?;
+
+        let debugging = self.debugging();
+        let serial = self.serial();
+        let starter = self.starter;
+        let audio = self.audio.unwrap_or_else(|| Box::new(NullAudioSink));
+        let hooks = self.hooks;
+        let bindings = self.bindings;
+        let audio_options = self.audio_options;
+        let palette_theme = self.palette_theme;
+        let capture_options = self.capture_options;
+        let widescreen = self.widescreen;
+        let movie = self.movie;
+        let snapshot_dir = self.snapshot_dir;
+
+        Ok(Headless::spawn(move |video, input| {
+            let mut game = Game::from_parts(video, input, audio, hooks, debugging, serial, starter);
+            game.cpu.mmu.keypad.set_bindings(bindings);
+            if let Some(dir) = snapshot_dir {
+                game.cpu.snapshot_dir = dir;
+            }
+
+            // Frames are handed over at native resolution
+            game.cpu
+                .mmu
+                .gpu
+                .set_upscaler(Upscaler::new(Filter::Nearest, 1));
+            game.cpu.mmu.gpu.set_palette_theme(palette_theme);
+            game.cpu.mmu.gpu.set_widescreen(widescreen);
+            game.cpu.mmu.gpu.set_capture_options(capture_options);
+
+            game.configure_audio(audio_options);
+
+            if let Some(movie) = movie {
+                game.configure_movie(movie);
+            }
+
+            game
+        }))
+    }
 }
@@ -387,6 +387,13 @@ pub const W_GRASS_TILE: u16 = 0xd534;
 /// bit 7: whether the player has changed boxes before
 pub const W_CURRENT_BOX_NUM: u16 = 0xd59f;
 
+/// one bit for each missable object, set when it's hidden
+pub const W_MISSABLE_OBJECT_FLAGS: u16 = 0xd5a5;
+
+/// sprite index and missable object index for each missable object on the current map \
+/// terminated with $ff
+pub const W_MISSABLE_OBJECT_LIST: u16 = 0xd5cd;
+
 pub const W_PALLET_TOWN_CUR_SCRIPT: u16 = 0xd5f0;
 
 pub const W_RIVAL_STARTER: u16 = 0xd714;
@@ -1,6 +1,9 @@
+use std::io;
+
 use crate::{
     game::constants::sprite_data_constants::PlayerDirection,
     save_state::{BoxView, BoxViewMut, PartyView, PartyViewMut},
+    snapshot::{SnapshotReader, SnapshotWriter},
     PokemonSpecies,
 };
 
@@ -172,6 +175,16 @@ impl GameState {
         self.data[addr] = value;
     }
 
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_bytes(&self.data);
+        w.write_bytes(&self.high_ram);
+    }
+
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        r.read_bytes(&mut self.data)?;
+        r.read_bytes(&mut self.high_ram)
+    }
+
     pub fn high_ram_byte(&self, addr: usize) -> u8 {
         self.high_ram[addr]
     }
@@ -200,6 +213,10 @@ impl GameState {
         PartyViewMut::new(&mut self.data[PARTY_DATA_START..])
     }
 
+    pub fn sprite_player_state_data1_step_vector(&self) -> (i8, i8) {
+        (self.data[0x0103] as i8, self.data[0x0105] as i8)
+    }
+
     pub fn set_sprite_player_state_data1_step_vector(&mut self, y: i8, x: i8) {
         self.data[0x0103] = y as u8;
         self.data[0x0105] = x as u8;
@@ -632,6 +649,10 @@ impl GameState {
 
     /// Low health alarm counter/enable. \
     /// high bit = enable, others = timer to cycle frequencies
+    pub fn low_health_alarm(&self) -> u8 {
+        self.data[0x1082]
+    }
+
     pub fn set_low_health_alarm(&mut self, value: u8) {
         self.data[0x1082] = value;
     }
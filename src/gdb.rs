@@ -0,0 +1,667 @@
+//! A GDB remote serial protocol stub, for debugging the interpreter and the
+//! hooks around it with gdb or any other RSP capable debugger.
+//!
+//! The stub listens on a local TCP port and is polled from the `Cpu` loop
+//! before every instruction and hook, so execution stops before a hook runs
+//! when there is a breakpoint on its address. A debugger that connects stops
+//! the game right away.
+//!
+//! Registers are exposed as `AF BC DE HL SP PC`, 16 bits each (the first six
+//! registers of gdb's z80 target). Addresses above `0xFFFF` select a ROM bank
+//! for the switchable region, e.g. `0x3d5ff2` is `3d:5ff2`; this works for
+//! both breakpoints and memory reads. Plain addresses match any bank.
+//!
+//! Watchpoints trigger on accesses through the memory bus, i.e. interpreted
+//! code and `Cpu::read_byte`/`write_byte`, but not on the `GameState`
+//! accessors used by Rust code.
+
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+};
+
+use crate::{cpu::Cpu, rom::ROM};
+
+/// How many instructions to run between checking for new connections or an
+/// interrupt from the debugger
+const POLL_INTERVAL: u32 = 4096;
+
+/// Largest packet we accept and send, as advertised in `qSupported`
+const PACKET_SIZE: usize = 0x1000;
+
+/// Memory reads are cut short to fit the hex encoded reply in a packet
+const MAX_READ: u32 = (PACKET_SIZE as u32 - 4) / 2;
+
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?>"#,
+    r#"<!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    r#"<target version="1.0">"#,
+    r#"<architecture>z80</architecture>"#,
+    r#"<feature name="org.gnu.gdb.z80.cpu">"#,
+    r#"<reg name="af" bitsize="16" type="int"/>"#,
+    r#"<reg name="bc" bitsize="16" type="int"/>"#,
+    r#"<reg name="de" bitsize="16" type="int"/>"#,
+    r#"<reg name="hl" bitsize="16" type="int"/>"#,
+    r#"<reg name="sp" bitsize="16" type="data_ptr"/>"#,
+    r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#,
+    r#"</feature>"#,
+    r#"</target>"#,
+);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub len: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, addr: u16, write: bool) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Write => write,
+            WatchKind::Read => !write,
+            WatchKind::Access => true,
+        };
+
+        kind_matches && addr >= self.addr && addr - self.addr < self.len
+    }
+}
+
+/// `(bank, addr)` for an address from the debugger, with `None` matching any bank
+fn split_address(addr: u32) -> (Option<usize>, u16) {
+    let bank = (addr >> 16) as usize;
+    let addr = addr as u16;
+
+    if bank != 0 && (0x4000..0x8000).contains(&addr) {
+        (Some(bank), addr)
+    } else {
+        (None, addr)
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct Connection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            stream,
+        })
+    }
+
+    /// Returns the contents of the next packet, or `"\x03"` for an interrupt
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            let mut byte = [0];
+            self.reader.read_exact(&mut byte)?;
+
+            match byte[0] {
+                0x03 => return Ok(String::from("\x03")),
+                b'$' => {}
+                // Acks, and anything between packets
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            self.reader.read_until(b'#', &mut data)?;
+            data.pop();
+
+            let mut checksum = [0; 2];
+            self.reader.read_exact(&mut checksum)?;
+
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+            let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+
+            if expected != Some(actual) {
+                self.stream.write_all(b"-")?;
+                continue;
+            }
+
+            self.stream.write_all(b"+")?;
+            return Ok(String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum)
+    }
+
+    /// Checks, without blocking, whether the debugger asked us to stop
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let result = self.reader.fill_buf().map(|buf| buf.to_vec());
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(buf) if buf.is_empty() => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(buf) => {
+                let interrupted = buf.contains(&0x03);
+
+                // Drop acks and the interrupt itself, but keep any packets
+                if buf.iter().all(|b| matches!(b, b'+' | b'-' | 0x03)) {
+                    self.reader.consume(buf.len());
+                }
+
+                Ok(interrupted)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    connection: Option<Connection>,
+    breakpoints: HashSet<(Option<usize>, u16)>,
+    stepping: bool,
+    countdown: u32,
+}
+
+impl GdbStub {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        log::info!("Waiting for debugger on {}", listener.local_addr()?);
+
+        Ok(GdbStub {
+            listener,
+            connection: None,
+            breakpoints: HashSet::new(),
+            stepping: false,
+            countdown: 0,
+        })
+    }
+
+    /// Called before every instruction and hook, stops and talks to the
+    /// debugger when needed
+    pub fn check(&mut self, cpu: &mut Cpu) {
+        let bank = cpu.bank();
+        let pc = cpu.pc;
+
+        let breakpoint =
+            self.breakpoints.contains(&(None, pc)) || self.breakpoints.contains(&(Some(bank), pc));
+
+        let reason = if self.stepping || breakpoint {
+            Some(String::from("S05"))
+        } else {
+            cpu.mmu.watch_hit.take().map(|(watchpoint, addr)| {
+                let kind = match watchpoint.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T05{}:{:04x};", kind, addr)
+            })
+        };
+
+        if let Some(reason) = reason {
+            return self.serve(cpu, &reason, true);
+        }
+
+        if self.countdown > 0 {
+            self.countdown -= 1;
+            return;
+        }
+
+        self.countdown = POLL_INTERVAL;
+
+        if let Some((reason, announce)) = self.poll() {
+            self.serve(cpu, reason, announce);
+        }
+    }
+
+    /// Accepts new connections and checks for interrupts, returning the stop
+    /// reason and whether to report it right away
+    fn poll(&mut self) -> Option<(&'static str, bool)> {
+        match self.connection.as_mut().map(Connection::interrupted) {
+            Some(Ok(true)) => return Some(("S02", true)),
+            Some(Ok(false)) => return None,
+            Some(Err(e)) => {
+                log::info!("Debugger disconnected: {}", e);
+                self.detach();
+            }
+            None => {}
+        }
+
+        match self.listener.accept() {
+            Ok((stream, addr)) => match Connection::new(stream) {
+                Ok(connection) => {
+                    log::info!("Debugger connected from {}", addr);
+                    self.connection = Some(connection);
+                    Some(("S05", false))
+                }
+                Err(e) => {
+                    log::error!("Failed to set up debugger connection: {}", e);
+                    None
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+            Err(e) => {
+                log::error!("Failed to accept debugger connection: {}", e);
+                None
+            }
+        }
+    }
+
+    fn detach(&mut self) {
+        self.connection = None;
+        self.breakpoints.clear();
+        self.stepping = false;
+    }
+
+    fn serve(&mut self, cpu: &mut Cpu, reason: &str, announce: bool) {
+        self.stepping = false;
+
+        // Memory accesses from the debugger shouldn't trigger watchpoints
+        let watchpoints = mem::take(&mut cpu.mmu.watchpoints);
+
+        let result = self.serve_connection(cpu, reason, announce, watchpoints);
+
+        match result {
+            Ok((Some(Resume::Step), watchpoints)) => {
+                self.stepping = true;
+                cpu.mmu.watchpoints = watchpoints;
+            }
+            Ok((Some(Resume::Continue), watchpoints)) => {
+                cpu.mmu.watchpoints = watchpoints;
+            }
+            Ok((None, _)) => {
+                log::info!("Debugger detached");
+                self.detach();
+            }
+            Err(e) => {
+                log::info!("Debugger disconnected: {}", e);
+                self.detach();
+            }
+        }
+
+        cpu.mmu.watch_hit = None;
+    }
+
+    /// Handles packets until the debugger resumes execution, `None` means that
+    /// it detached
+    fn serve_connection(
+        &mut self,
+        cpu: &mut Cpu,
+        reason: &str,
+        announce: bool,
+        mut watchpoints: Vec<Watchpoint>,
+    ) -> io::Result<(Option<Resume>, Vec<Watchpoint>)> {
+        let Some(connection) = self.connection.as_mut() else {
+            return Ok((None, watchpoints));
+        };
+
+        // The stop when a debugger connects is only reported when it asks with `?`
+        if announce {
+            connection.send_packet(reason)?;
+        }
+
+        loop {
+            let packet = connection.read_packet()?;
+
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => String::from(reason),
+                Some(b'g') => format!(
+                    "{}{}{}{}{}{}",
+                    hex16(cpu.af()),
+                    hex16(cpu.bc()),
+                    hex16(cpu.de()),
+                    hex16(cpu.hl()),
+                    hex16(cpu.sp),
+                    hex16(cpu.pc),
+                ),
+                Some(b'G') => match decode_hex(&packet[1..]) {
+                    Some(bytes) if bytes.len() >= 12 => {
+                        for (n, value) in bytes.chunks(2).take(6).enumerate() {
+                            write_register(cpu, n, u16::from_le_bytes([value[0], value[1]]));
+                        }
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                },
+                Some(b'p') => match parse_hex(&packet[1..]) {
+                    Some(n) if n < 6 => hex16(read_register(cpu, n as usize)),
+                    _ => String::from("E01"),
+                },
+                Some(b'P') => {
+                    let parsed = packet[1..]
+                        .split_once('=')
+                        .and_then(|(n, value)| Some((parse_hex(n)?, decode_hex(value)?)));
+
+                    match parsed {
+                        Some((n, value)) if n < 6 && value.len() == 2 => {
+                            write_register(
+                                cpu,
+                                n as usize,
+                                u16::from_le_bytes([value[0], value[1]]),
+                            );
+                            String::from("OK")
+                        }
+                        _ => String::from("E01"),
+                    }
+                }
+                Some(b'm') => {
+                    let parsed = packet[1..]
+                        .split_once(',')
+                        .and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+
+                    match parsed {
+                        Some((addr, len)) if addr.checked_add(len.min(MAX_READ)).is_some() => (0
+                            ..len.min(MAX_READ))
+                            .map(|i| format!("{:02x}", read_memory(cpu, addr + i)))
+                            .collect(),
+                        _ => String::from("E01"),
+                    }
+                }
+                Some(b'M') => {
+                    let parsed = packet[1..].split_once(':').and_then(|(header, data)| {
+                        let (addr, _) = header.split_once(',')?;
+                        Some((parse_hex(addr)?, decode_hex(data)?))
+                    });
+
+                    match parsed {
+                        Some((addr, data))
+                            if addr
+                                .checked_add(data.len() as u32)
+                                .is_some_and(|end| end <= 0x10000) =>
+                        {
+                            for (i, value) in data.into_iter().enumerate() {
+                                cpu.mmu.wb((addr as usize + i) as u16, value);
+                            }
+                            String::from("OK")
+                        }
+                        _ => String::from("E01"),
+                    }
+                }
+                Some(b'Z' | b'z') => {
+                    let insert = packet.starts_with('Z');
+                    let mut parts = packet[1..].split(',');
+                    let kind = parts.next();
+                    let addr = parts.next().and_then(parse_hex);
+                    let len = parts.next().and_then(parse_hex);
+
+                    match (kind, addr, len) {
+                        (Some("0" | "1"), Some(addr), _) => {
+                            let key = split_address(addr);
+                            if insert {
+                                self.breakpoints.insert(key);
+                            } else {
+                                self.breakpoints.remove(&key);
+                            }
+                            String::from("OK")
+                        }
+                        (Some(kind @ ("2" | "3" | "4")), Some(addr), Some(len))
+                            if addr <= 0xFFFF && len <= 0xFFFF =>
+                        {
+                            let watchpoint = Watchpoint {
+                                addr: addr as u16,
+                                len: len.max(1) as u16,
+                                kind: match kind {
+                                    "2" => WatchKind::Write,
+                                    "3" => WatchKind::Read,
+                                    _ => WatchKind::Access,
+                                },
+                            };
+
+                            if insert {
+                                watchpoints.push(watchpoint);
+                            } else {
+                                watchpoints.retain(|w| {
+                                    (w.addr, w.len, w.kind)
+                                        != (watchpoint.addr, watchpoint.len, watchpoint.kind)
+                                });
+                            }
+                            String::from("OK")
+                        }
+                        // Ranges that don't fit in the address space
+                        (Some("2" | "3" | "4"), _, _) => String::from("E01"),
+                        _ => String::new(),
+                    }
+                }
+                Some(b'c') => return Ok((Some(Resume::Continue), watchpoints)),
+                Some(b's') => return Ok((Some(Resume::Step), watchpoints)),
+                Some(b'D') => {
+                    connection.send_packet("OK")?;
+                    return Ok((None, watchpoints));
+                }
+                Some(b'k') => return Ok((None, watchpoints)),
+                Some(b'H') | Some(b'T') => String::from("OK"),
+                Some(0x03) => String::from("S02"),
+                Some(b'q') if packet.starts_with("qSupported") => {
+                    format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+                }
+                Some(b'q') if packet.starts_with("qXfer:features:read:target.xml:") => {
+                    let range = &packet["qXfer:features:read:target.xml:".len()..];
+                    let parsed = range
+                        .split_once(',')
+                        .and_then(|(offset, len)| Some((parse_hex(offset)?, parse_hex(len)?)));
+
+                    match parsed {
+                        Some((offset, len)) => {
+                            let start = (offset as usize).min(TARGET_XML.len());
+                            let end = (start + len as usize).min(TARGET_XML.len());
+                            let prefix = if end == TARGET_XML.len() { "l" } else { "m" };
+                            format!("{}{}", prefix, &TARGET_XML[start..end])
+                        }
+                        None => String::from("E01"),
+                    }
+                }
+                Some(b'q') if packet == "qAttached" => String::from("1"),
+                Some(b'q') if packet == "qC" => String::from("QC1"),
+                Some(b'q') if packet == "qfThreadInfo" => String::from("m1"),
+                Some(b'q') if packet == "qsThreadInfo" => String::from("l"),
+                _ => String::new(),
+            };
+
+            connection.send_packet(&reply)?;
+        }
+    }
+}
+
+fn hex16(value: u16) -> String {
+    let [lo, hi] = value.to_le_bytes();
+    format!("{:02x}{:02x}", lo, hi)
+}
+
+fn read_register(cpu: &Cpu, n: usize) -> u16 {
+    match n {
+        0 => cpu.af(),
+        1 => cpu.bc(),
+        2 => cpu.de(),
+        3 => cpu.hl(),
+        4 => cpu.sp,
+        _ => cpu.pc,
+    }
+}
+
+fn write_register(cpu: &mut Cpu, n: usize, value: u16) {
+    match n {
+        0 => cpu.set_af(value),
+        1 => cpu.set_bc(value),
+        2 => cpu.set_de(value),
+        3 => cpu.set_hl(value),
+        4 => cpu.sp = value,
+        _ => cpu.pc = value,
+    }
+}
+
+/// Reads without side effects, so that looking at the keypad register or
+/// the APU doesn't change what the game sees next
+fn read_memory(cpu: &Cpu, addr: u32) -> u8 {
+    match split_address(addr) {
+        (Some(bank), addr) => *ROM
+            .get(bank * 0x4000 + (addr as usize - 0x4000))
+            .unwrap_or(&0xff),
+        (None, addr) => cpu.mmu.peek(addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        frontend::{NullAudioSink, NullInputSource, NullVideoSink},
+        hooks::HookTable,
+        save_state::PokemonSpecies,
+    };
+
+    /// The debugger side of a connection, which checks every ack
+    struct Client(TcpStream);
+
+    impl Client {
+        fn send(&mut self, data: &str) {
+            let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            write!(self.0, "${}#{:02x}", data, checksum).unwrap();
+            assert_eq!(self.read_byte(), b'+');
+        }
+
+        fn receive(&mut self) -> String {
+            assert_eq!(self.read_byte(), b'$');
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+
+            let checksum = [self.read_byte(), self.read_byte()];
+            self.0.write_all(b"+").unwrap();
+
+            let data = String::from_utf8(data).unwrap();
+            let expected = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            assert_eq!(checksum, format!("{:02x}", expected).as_bytes());
+            data
+        }
+
+        fn request(&mut self, data: &str) -> String {
+            self.send(data);
+            self.receive()
+        }
+
+        fn read_byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.0.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+    }
+
+    #[test]
+    fn debugs_over_tcp() {
+        let mut cpu = Cpu::new(
+            Box::new(NullVideoSink),
+            Box::new(NullInputSource),
+            Box::new(NullAudioSink),
+            HookTable::new(),
+            PokemonSpecies::Pikachu,
+        );
+
+        // INC A, INC A, RET
+        for (i, byte) in [0x3c, 0x3c, 0xc9].into_iter().enumerate() {
+            cpu.write_byte(0xc000 + i as u16, byte);
+        }
+
+        let stub = GdbStub::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(stub.listener.local_addr().unwrap()).unwrap();
+        cpu.gdb = Some(stub);
+
+        let debugger = thread::spawn(move || {
+            let mut client = Client(stream);
+
+            // Stopped at the first instruction, with the return marker pushed
+            assert_eq!(client.request("?"), "S05");
+            assert_eq!(&client.request("g")[16..], "fcff00c0");
+            assert_eq!(client.request("p5"), "00c0");
+
+            assert_eq!(client.request("Mc100,2:abcd"), "OK");
+            assert_eq!(client.request("mc100,2"), "abcd");
+            assert_eq!(
+                client.request("mc000,ffffffff").len(),
+                MAX_READ as usize * 2
+            );
+            assert_eq!(client.request("mffffffff,2"), "E01");
+            assert_eq!(client.request("Mffff,2:0000"), "E01");
+            assert_eq!(client.request("Z2,c000,10000"), "E01");
+
+            assert_eq!(client.request("Z0,c002,1"), "OK");
+
+            client.send("s");
+            assert_eq!(client.receive(), "S05");
+            assert_eq!(client.request("p5"), "01c0");
+
+            client.send("c");
+            assert_eq!(client.receive(), "S05");
+            let registers = client.request("g");
+            assert_eq!(&registers[2..4], "13");
+            assert_eq!(&registers[20..], "02c0");
+
+            assert_eq!(client.request("D"), "OK");
+        });
+
+        cpu.call(0xc000);
+        debugger.join().unwrap();
+
+        assert_eq!(cpu.a, 0x13);
+        assert_eq!(cpu.mmu.peek(0xc101), 0xcd);
+    }
+
+    #[test]
+    fn banked_addresses() {
+        assert_eq!(split_address(0x3d5ff2), (Some(0x3d), 0x5ff2));
+        assert_eq!(split_address(0x5ff2), (None, 0x5ff2));
+        assert_eq!(split_address(0x3d0242), (None, 0x0242));
+    }
+
+    #[test]
+    fn watchpoint_ranges() {
+        let watchpoint = Watchpoint {
+            addr: 0xd158,
+            len: 2,
+            kind: WatchKind::Write,
+        };
+
+        assert!(watchpoint.matches(0xd158, true));
+        assert!(watchpoint.matches(0xd159, true));
+        assert!(!watchpoint.matches(0xd15a, true));
+        assert!(!watchpoint.matches(0xd158, false));
+    }
+}
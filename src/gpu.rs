@@ -1,17 +1,23 @@
-use std::{
-    cmp::Ordering,
-    sync::mpsc::{SendError, SyncSender},
+use std::{cmp::Ordering, io};
+
+use image::RgbaImage;
+
+use crate::{
+    capture::{Capture, CaptureOptions, CaptureRequest},
+    compositor::{Compositor, CELLS, CELLS_X},
+    frontend::VideoSink,
+    palette::{Color, PaletteTheme, Shades},
+    sgb::{Border, Command, SGB_SCREEN_H, SGB_SCREEN_W, TRANSFER_SIZE},
+    snapshot::{SnapshotReader, SnapshotWriter},
+    upscale::Upscaler,
+    widescreen::{side_index, side_x, WideSprite, Widescreen, SIDE_W, WIDE_SCREEN_W},
 };
 
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
 
-const GB_SCALE: usize = 4;
-const GB_SCREEN_W: usize = 160;
-const GB_SCREEN_H: usize = 144;
-
-pub const SCREEN_W: usize = GB_SCREEN_W * GB_SCALE;
-pub const SCREEN_H: usize = GB_SCREEN_H * GB_SCALE;
+pub const GB_SCREEN_W: usize = 160;
+pub const GB_SCREEN_H: usize = 144;
 
 #[derive(PartialEq, Copy, Clone)]
 enum PrioType {
@@ -20,14 +26,16 @@ enum PrioType {
     Normal,
 }
 
-#[derive(PartialEq, Eq, Copy, Clone)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub enum GpuAtlas {
     BoxBorder,
     Font,
     PokemonIcons,
+    /// An atlas added with `Gpu::register_atlas`
+    Registered(usize),
 }
 
-#[derive(PartialEq, Eq, Copy, Clone)]
+#[derive(PartialEq, Eq, Hash, Copy, Clone, Debug)]
 pub struct GpuTile {
     pub atlas: GpuAtlas,
     pub src_x: usize,
@@ -44,31 +52,177 @@ impl GpuTile {
     }
 }
 
+/// A block of tiles from an atlas that can be placed at any pixel
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct GpuSprite {
+    /// The top left tile of the block
+    pub tile: GpuTile,
+    /// Size of the block in tiles
+    pub width: usize,
+    pub height: usize,
+    /// Position in Game Boy pixels, can be partly off screen
+    pub x: i32,
+    pub y: i32,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    /// Sprites with a higher z are drawn on top. Sprites with a negative z
+    /// are drawn below the background tiles of their layer.
+    pub z: i32,
+}
+
+impl GpuSprite {
+    pub const fn new(tile: GpuTile, x: i32, y: i32) -> GpuSprite {
+        GpuSprite {
+            tile,
+            width: 1,
+            height: 1,
+            x,
+            y,
+            flip_x: false,
+            flip_y: false,
+            z: 0,
+        }
+    }
+
+    pub const fn size(self, width: usize, height: usize) -> GpuSprite {
+        GpuSprite {
+            width,
+            height,
+            ..self
+        }
+    }
+
+    pub const fn flip(self, flip_x: bool, flip_y: bool) -> GpuSprite {
+        GpuSprite {
+            flip_x,
+            flip_y,
+            ..self
+        }
+    }
+
+    pub const fn z(self, z: i32) -> GpuSprite {
+        GpuSprite { z, ..self }
+    }
+
+    /// The atlas tile drawn at column `x` and row `y` of the sprite
+    pub(crate) fn tile_at(&self, x: usize, y: usize) -> GpuTile {
+        let x = if self.flip_x { self.width - 1 - x } else { x };
+        let y = if self.flip_y { self.height - 1 - y } else { y };
+
+        GpuTile::new(self.tile.atlas, self.tile.src_x + x, self.tile.src_y + y)
+    }
+
+    pub(crate) fn covers(&self, cell: usize) -> bool {
+        let x = (cell % CELLS_X) as i32 * 8;
+        let y = (cell / CELLS_X) as i32 * 8;
+
+        self.x < x + 8
+            && x < self.x + self.width as i32 * 8
+            && self.y < y + 8
+            && y < self.y + self.height as i32 * 8
+    }
+}
+
 pub struct GpuLayer {
-    pub background: [Option<GpuTile>; 20 * 18],
+    pub(crate) background: [Option<GpuTile>; CELLS],
+    /// Removed sprites leave an empty slot, so that ids stay the same
+    pub(crate) sprites: Vec<Option<GpuSprite>>,
+    pub(crate) opacity: u8,
+    /// Multiplied with the colors of the layer
+    pub(crate) tint: [u8; 3],
+    /// Cells that changed since the layer was last composited
+    pub(crate) dirty: [bool; CELLS],
 }
 
 impl GpuLayer {
     pub fn new() -> GpuLayer {
         GpuLayer {
-            background: [None; 20 * 18],
+            background: [None; CELLS],
+            sprites: Vec::new(),
+            opacity: 255,
+            tint: [255; 3],
+            dirty: [false; CELLS],
         }
     }
 
     pub fn set_background(&mut self, x: usize, y: usize, tile: GpuTile) {
-        self.background[y * 20 + x] = Some(tile);
+        self.set_cell(y * 20 + x, Some(tile));
     }
 
     pub fn clear_background(&mut self, x: usize, y: usize) {
-        self.background[y * 20 + x] = None;
+        self.set_cell(y * 20 + x, None);
+    }
+
+    fn set_cell(&mut self, idx: usize, tile: Option<GpuTile>) {
+        if self.background[idx] != tile {
+            self.background[idx] = tile;
+            self.dirty[idx] = true;
+        }
+    }
+
+    /// Returns an id for moving or removing the sprite later
+    pub fn add_sprite(&mut self, sprite: GpuSprite) -> usize {
+        let id = match self.sprites.iter().position(Option::is_none) {
+            Some(id) => id,
+            None => {
+                self.sprites.push(None);
+                self.sprites.len() - 1
+            }
+        };
+
+        self.set_sprite(id, sprite);
+        id
+    }
+
+    pub fn set_sprite(&mut self, id: usize, sprite: GpuSprite) {
+        self.replace_sprite(id, Some(sprite));
+    }
+
+    pub fn remove_sprite(&mut self, id: usize) {
+        self.replace_sprite(id, None);
+    }
+
+    fn replace_sprite(&mut self, id: usize, sprite: Option<GpuSprite>) {
+        let old = std::mem::replace(&mut self.sprites[id], sprite);
+
+        if old == sprite {
+            return;
+        }
+
+        for (cell, dirty) in self.dirty.iter_mut().enumerate() {
+            *dirty |= old.is_some_and(|old| old.covers(cell))
+                || sprite.is_some_and(|sprite| sprite.covers(cell));
+        }
+    }
+
+    pub fn sprites(&self) -> impl Iterator<Item = (usize, &GpuSprite)> {
+        self.sprites
+            .iter()
+            .enumerate()
+            .filter_map(|(id, sprite)| Some((id, sprite.as_ref()?)))
+    }
+
+    /// 0 is invisible and 255 is opaque
+    pub fn set_opacity(&mut self, opacity: u8) {
+        if self.opacity != opacity {
+            self.opacity = opacity;
+            self.dirty = [true; CELLS];
+        }
+    }
+
+    /// Every color of the layer is multiplied with the tint, white leaves
+    /// them as they are
+    pub fn set_tint(&mut self, tint: [u8; 3]) {
+        if self.tint != tint {
+            self.tint = tint;
+            self.dirty = [true; CELLS];
+        }
     }
-}
 
-fn load_png(bytes: &[u8]) -> Vec<u8> {
-    image::load_from_memory_with_format(bytes, image::ImageFormat::Png)
-        .unwrap()
-        .into_rgba8()
-        .into_raw()
+    /// Whether anything on the layer is drawn in the cell
+    pub(crate) fn covers(&self, cell: usize) -> bool {
+        self.background[cell].is_some() || self.sprites().any(|(_, sprite)| sprite.covers(cell))
+    }
 }
 
 pub struct Gpu {
@@ -113,16 +267,22 @@ pub struct Gpu {
     bgprio: [PrioType; GB_SCREEN_W],
     pub interrupt: u8,
     hblanking: bool,
-    update_screen: SyncSender<Vec<u8>>,
+    video: Box<dyn VideoSink>,
+    /// Frames presented since boot, which input movies are timed by
+    frames: u32,
+    theme: PaletteTheme,
+    /// The Super Game Boy border around the screen, when it's enabled
+    border: Option<Border>,
+    /// The sides of the screen in widescreen mode
+    wide: Option<Widescreen>,
+    compositor: Compositor,
+    capture: Capture,
 
     layers: Vec<GpuLayer>,
-    atlas_box_border: Vec<u8>,
-    atlas_font: Vec<u8>,
-    atlas_pokemon_icons: Vec<u8>,
 }
 
 impl Gpu {
-    pub fn new(update_screen: SyncSender<Vec<u8>>) -> Gpu {
+    pub fn new(video: Box<dyn VideoSink>) -> Gpu {
         Gpu {
             mode: 0,
             modeclock: 0,
@@ -165,21 +325,106 @@ impl Gpu {
             csprit: [[[0u8; 3]; 4]; 8],
             vrambank: 0,
             hblanking: false,
-            update_screen,
+            video,
+            frames: 0,
+            theme: PaletteTheme::default(),
+            border: None,
+            wide: None,
+            compositor: Compositor::new(Upscaler::default()),
+            capture: Capture::new(CaptureOptions::default()),
             layers: vec![],
-            atlas_box_border: load_png(include_bytes!("../gfx/box_border.png")),
-            atlas_font: load_png(include_bytes!("../gfx/font.png")),
-            atlas_pokemon_icons: load_png(include_bytes!("../gfx/pokemon_icons.png")),
         }
     }
 
+    pub fn set_upscaler(&mut self, upscaler: Upscaler) {
+        self.compositor.set_upscaler(upscaler);
+    }
+
+    pub fn set_palette_theme(&mut self, theme: PaletteTheme) {
+        self.theme = theme;
+    }
+
+    pub fn set_sgb_border(&mut self, enabled: bool) {
+        self.border = enabled.then(Border::new);
+    }
+
+    pub fn sgb_border_enabled(&self) -> bool {
+        self.border.is_some()
+    }
+
+    pub fn set_widescreen(&mut self, enabled: bool) {
+        self.wide = enabled.then(Widescreen::new);
+    }
+
+    pub fn widescreen_enabled(&self) -> bool {
+        self.wide.is_some()
+    }
+
+    /// Shows the extra columns of the map view in the BG map on the sides,
+    /// or blacks them out when the BG map holds something else
+    pub fn set_wide_map_view(&mut self, map_view: bool) {
+        if let Some(wide) = self.wide.as_mut() {
+            wide.set_map_view(map_view);
+        }
+    }
+
+    /// Whether the sides show the map view
+    pub fn wide_map_view(&self) -> bool {
+        self.wide.as_ref().is_some_and(Widescreen::map_view)
+    }
+
+    /// Sets the sprites that are drawn on the sides
+    pub fn set_wide_sprites(&mut self, sprites: Vec<WideSprite>) {
+        if let Some(wide) = self.wide.as_mut() {
+            wide.set_sprites(sprites);
+        }
+    }
+
+    /// Copies what a Super Game Boy would read from the screen for a VRAM
+    /// transfer: the data of the first 256 BG tiles, row by row
+    pub fn sgb_transfer(&mut self, command: Command) {
+        if self.border.is_none() || matches!(command, Command::Other(_)) {
+            return;
+        }
+
+        let mut data = Vec::with_capacity(TRANSFER_SIZE);
+
+        for i in 0..256 {
+            let tilenr = self.rbvram0(self.bg_tilemap + (i / 20) * 32 + i % 20);
+            let tileaddress = self.tilebase
+                + (if self.tilebase == 0x8000 {
+                    tilenr as u16
+                } else {
+                    (tilenr as i8 as i16 + 128) as u16
+                }) * 16;
+
+            for a in tileaddress..tileaddress + 16 {
+                data.push(self.vram[a as usize & 0x1FFF]);
+            }
+        }
+
+        if let Some(border) = self.border.as_mut() {
+            border.transfer(command, &data);
+        }
+    }
+
+    pub fn set_capture_options(&mut self, options: CaptureOptions) {
+        self.capture = Capture::new(options);
+    }
+
+    pub fn capture(&mut self, request: CaptureRequest) {
+        self.capture.request(request);
+    }
+
     pub fn layer_push(&mut self) -> usize {
         self.layers.push(GpuLayer::new());
         self.layers.len() - 1
     }
 
     pub fn layer_pop(&mut self, layer: usize) {
-        self.layers.pop();
+        if let Some(removed) = self.layers.pop() {
+            self.compositor.remove_layer(&removed);
+        }
         assert_eq!(layer, self.layers.len());
     }
 
@@ -187,7 +432,14 @@ impl Gpu {
         &mut self.layers[layer]
     }
 
+    /// Adds an atlas where each tile is `tile_size` pixels wide and high
+    pub fn register_atlas(&mut self, image: RgbaImage, tile_size: usize) -> GpuAtlas {
+        self.compositor.register_atlas(image, tile_size)
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) {
+        self.capture.do_cycle(ticks);
+
         if !self.lcd_on {
             return;
         }
@@ -357,6 +609,7 @@ impl Gpu {
                 self.sprite_on = v & 0x02 == 0x02;
                 self.lcdc0 = v & 0x01 == 0x01;
                 if orig_lcd_on && !self.lcd_on {
+                    self.set_wide_map_view(false);
                     self.modeclock = 0;
                     self.line = 0;
                     self.mode = 0;
@@ -445,73 +698,144 @@ impl Gpu {
         self.update_screen();
     }
 
-    pub fn update_screen(&mut self) {
-        debug_assert_eq!(self.data.len(), GB_SCREEN_W * GB_SCREEN_H * 3);
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.mode);
+        w.write_u32(self.modeclock);
+        w.write_u8(self.line);
+        w.write_u8(self.lyc);
+        w.write_bool(self.lcd_on);
+        w.write_u16(self.win_tilemap);
+        w.write_bool(self.win_on);
+        w.write_u16(self.tilebase);
+        w.write_u16(self.bg_tilemap);
+        w.write_u32(self.sprite_size);
+        w.write_bool(self.sprite_on);
+        w.write_bool(self.lcdc0);
+        w.write_bool(self.lyc_inte);
+        w.write_bool(self.m0_inte);
+        w.write_bool(self.m1_inte);
+        w.write_bool(self.m2_inte);
+        w.write_u8(self.scy);
+        w.write_u8(self.scx);
+        w.write_u8(self.winy);
+        w.write_u8(self.winx);
+        w.write_bool(self.wy_trigger);
+        w.write_i32(self.wy_pos);
+        w.write_u8(self.palbr);
+        w.write_u8(self.pal0r);
+        w.write_u8(self.pal1r);
+        w.write_bytes(&self.palb);
+        w.write_bytes(&self.pal0);
+        w.write_bytes(&self.pal1);
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.voam);
+        w.write_bool(self.cbgpal_inc);
+        w.write_u8(self.cbgpal_ind);
+        w.write_bytes(self.cbgpal.as_flattened().as_flattened());
+        w.write_bool(self.csprit_inc);
+        w.write_u8(self.csprit_ind);
+        w.write_bytes(self.csprit.as_flattened().as_flattened());
+        w.write_u8(self.vrambank as u8);
+        w.write_bytes(&self.data);
+        w.write_u8(self.interrupt);
+        w.write_bool(self.hblanking);
+    }
 
-        let mut screen = Vec::with_capacity(SCREEN_W * SCREEN_H * 3);
-        let mut src = 0;
+    /// Layers belong to menus implemented in Rust, which are never active at
+    /// a safe point, so they are dropped rather than restored
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.mode = r.read_u8()?;
+        self.modeclock = r.read_u32()?;
+        self.line = r.read_u8()?;
+        self.lyc = r.read_u8()?;
+        self.lcd_on = r.read_bool()?;
+        self.win_tilemap = r.read_u16()?;
+        self.win_on = r.read_bool()?;
+        self.tilebase = r.read_u16()?;
+        self.bg_tilemap = r.read_u16()?;
+        self.sprite_size = r.read_u32()?;
+        self.sprite_on = r.read_bool()?;
+        self.lcdc0 = r.read_bool()?;
+        self.lyc_inte = r.read_bool()?;
+        self.m0_inte = r.read_bool()?;
+        self.m1_inte = r.read_bool()?;
+        self.m2_inte = r.read_bool()?;
+        self.scy = r.read_u8()?;
+        self.scx = r.read_u8()?;
+        self.winy = r.read_u8()?;
+        self.winx = r.read_u8()?;
+        self.wy_trigger = r.read_bool()?;
+        self.wy_pos = r.read_i32()?;
+        self.palbr = r.read_u8()?;
+        self.pal0r = r.read_u8()?;
+        self.pal1r = r.read_u8()?;
+        r.read_bytes(&mut self.palb)?;
+        r.read_bytes(&mut self.pal0)?;
+        r.read_bytes(&mut self.pal1)?;
+        r.read_bytes(&mut self.vram)?;
+        r.read_bytes(&mut self.voam)?;
+        self.cbgpal_inc = r.read_bool()?;
+        self.cbgpal_ind = r.read_u8()?;
+        r.read_bytes(self.cbgpal.as_flattened_mut().as_flattened_mut())?;
+        self.csprit_inc = r.read_bool()?;
+        self.csprit_ind = r.read_u8()?;
+        r.read_bytes(self.csprit.as_flattened_mut().as_flattened_mut())?;
+        self.vrambank = r.read_u8()? as usize;
+        r.read_bytes(&mut self.data)?;
+        self.interrupt = r.read_u8()?;
+        self.hblanking = r.read_bool()?;
+
+        self.layers.clear();
+        self.compositor.invalidate();
+
+        // The sides come back once the game draws the map view again
+        self.set_wide_map_view(false);
+
+        Ok(())
+    }
 
-        for _ in 0..GB_SCREEN_H {
-            let row_start = screen.len();
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
 
-            for _ in 0..GB_SCREEN_W {
-                for _ in 0..GB_SCALE {
-                    screen.push(self.data[src]);
-                    screen.push(self.data[src + 1]);
-                    screen.push(self.data[src + 2]);
-                }
+    /// Presents the screen at the end of a vblank
+    pub fn update_screen(&mut self) {
+        self.present(None);
+    }
 
-                src += 3;
-            }
+    /// Presents the screen for a menu implemented in Rust, which redraws it
+    /// while the emulation is paused
+    pub fn update_menu_screen(&mut self, waiting: bool) {
+        self.present(Some(waiting));
+    }
 
-            for _ in 0..(GB_SCALE / 2) {
-                screen.extend_from_within(row_start..);
-            }
-        }
+    /// `menu` is whether a menu is waiting for input, or `None` for vblanks
+    fn present(&mut self, menu: Option<bool>) {
+        self.frames = self.frames.wrapping_add(1);
 
-        debug_assert_eq!(screen.len(), SCREEN_W * SCREEN_H * 3);
-
-        for layer in &self.layers {
-            for (idx, tile) in layer.background.iter().enumerate() {
-                if let Some(tile) = tile {
-                    let dst_x = (idx % 20) * 32;
-                    let dst_y = (idx / 20) * 32;
-
-                    let (src, src_w) = match tile.atlas {
-                        GpuAtlas::BoxBorder => (&self.atlas_box_border[..], 96),
-                        GpuAtlas::Font => (&self.atlas_font[..], 512),
-                        GpuAtlas::PokemonIcons => (&self.atlas_pokemon_icons[..], 1024),
-                    };
-
-                    for dy in 0..32 {
-                        for dx in 0..32 {
-                            let src_idx =
-                                ((((tile.src_y * 32) + dy) * src_w) + (tile.src_x * 32) + dx) * 4;
-                            let dst_idx = (((dst_y + dy) * SCREEN_W) + dst_x + dx) * 3;
-
-                            let alpha = src[src_idx + 3] as f32 / 255.0;
-                            let inv_alpha = 1.0 - alpha;
-
-                            screen[dst_idx + 0] = (screen[dst_idx + 0] as f32 * inv_alpha
-                                + src[src_idx + 0] as f32 * alpha)
-                                as u8;
-                            screen[dst_idx + 1] = (screen[dst_idx + 1] as f32 * inv_alpha
-                                + src[src_idx + 1] as f32 * alpha)
-                                as u8;
-                            screen[dst_idx + 2] = (screen[dst_idx + 2] as f32 * inv_alpha
-                                + src[src_idx + 2] as f32 * alpha)
-                                as u8;
-                        }
-                    }
-                }
-            }
-        }
+        debug_assert_eq!(self.data.len(), GB_SCREEN_W * GB_SCREEN_H * 3);
 
-        match self.update_screen.send(screen) {
-            Ok(_) => {}
-            Err(SendError(_)) => {
-                panic!("Screen disconnected")
+        let upscaler = self.compositor.upscaler();
+        let scale = upscaler.scale();
+        let screen = self.compositor.compose(&self.data, &mut self.layers);
+
+        let (screen, width, height) = match (self.border.as_mut(), self.wide.as_mut()) {
+            (Some(border), _) => {
+                let backdrop = self
+                    .theme
+                    .color(Shades::Background, self.palbr, &self.cbgpal[0], 0);
+                border.set_backdrop(backdrop);
+                (border.frame(screen, scale), SGB_SCREEN_W, SGB_SCREEN_H)
             }
+            (None, Some(wide)) => (wide.frame(screen, upscaler), WIDE_SCREEN_W, GB_SCREEN_H),
+            (None, None) => (screen, GB_SCREEN_W, GB_SCREEN_H),
+        };
+
+        self.capture.frame(screen, width, height, scale);
+
+        match menu {
+            Some(waiting) => self.video.present_menu(screen, waiting),
+            None => self.video.present(screen),
         }
     }
 
@@ -537,8 +861,16 @@ impl Gpu {
             self.setcolor(x, 255);
             self.bgprio[x] = PrioType::Normal;
         }
+        // Text boxes and menus cover the map view with the window, so the
+        // sides keep the map from before them
+        let window_covers_line = self.win_on && self.wy_trigger && self.winx <= 7;
+
         self.draw_bg();
         self.draw_sprites();
+
+        if self.wide.as_ref().is_some_and(Widescreen::map_view) && !window_covers_line {
+            self.draw_sides();
+        }
     }
 
     fn setcolor(&mut self, x: usize, color: u8) {
@@ -547,19 +879,9 @@ impl Gpu {
         self.data[self.line as usize * GB_SCREEN_W * 3 + x * 3 + 2] = color;
     }
 
-    fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
-        // Gameboy Color RGB correction
-        // Taken from the Gambatte emulator
-        // assume r, g and b are between 0 and 1F
+    fn setrgb(&mut self, x: usize, color: Color) {
         let baseidx = self.line as usize * GB_SCREEN_W * 3 + x * 3;
-
-        let r = r as u32;
-        let g = g as u32;
-        let b = b as u32;
-
-        self.data[baseidx + 0] = ((r * 13 + g * 2 + b) >> 1) as u8;
-        self.data[baseidx + 1] = ((g * 3 + b) << 1) as u8;
-        self.data[baseidx + 2] = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
+        self.data[baseidx..baseidx + 3].copy_from_slice(&color);
     }
 
     fn draw_bg(&mut self) {
@@ -598,42 +920,7 @@ impl Gpu {
                 )
             };
 
-            let tilenr: u8 = self.rbvram0(tilemapbase + tiley * 32 + tilex);
-
-            let (palnr, vram1, xflip, yflip, prio) = {
-                let flags = self.rbvram1(tilemapbase + tiley * 32 + tilex) as usize;
-                (
-                    flags & 0x07,
-                    flags & (1 << 3) != 0,
-                    flags & (1 << 5) != 0,
-                    flags & (1 << 6) != 0,
-                    flags & (1 << 7) != 0,
-                )
-            };
-
-            let tileaddress = self.tilebase
-                + (if self.tilebase == 0x8000 {
-                    tilenr as u16
-                } else {
-                    (tilenr as i8 as i16 + 128) as u16
-                }) * 16;
-
-            let a0 = match yflip {
-                false => tileaddress + (pixely * 2),
-                true => tileaddress + (14 - (pixely * 2)),
-            };
-
-            let (b1, b2) = match vram1 {
-                false => (self.rbvram0(a0), self.rbvram0(a0 + 1)),
-                true => (self.rbvram1(a0), self.rbvram1(a0 + 1)),
-            };
-
-            let xbit = match xflip {
-                true => pixelx,
-                false => 7 - pixelx,
-            } as u32;
-            let colnr = if b1 & (1 << xbit) != 0 { 1 } else { 0 }
-                | if b2 & (1 << xbit) != 0 { 2 } else { 0 };
+            let (colnr, palnr, prio) = self.tile_pixel(tilemapbase, tilex, tiley, pixelx, pixely);
 
             self.bgprio[x] = if colnr == 0 {
                 PrioType::Color0
@@ -643,13 +930,63 @@ impl Gpu {
                 PrioType::Normal
             };
 
-            let r = self.cbgpal[palnr][colnr][0];
-            let g = self.cbgpal[palnr][colnr][1];
-            let b = self.cbgpal[palnr][colnr][2];
-            self.setrgb(x, r, g, b);
+            let color =
+                self.theme
+                    .color(Shades::Background, self.palbr, &self.cbgpal[palnr], colnr);
+            self.setrgb(x, color);
         }
     }
 
+    /// The color number, CGB palette and priority flag of a pixel of a tile
+    /// in a tile map
+    fn tile_pixel(
+        &self,
+        tilemapbase: u16,
+        tilex: u16,
+        tiley: u16,
+        pixelx: u8,
+        pixely: u16,
+    ) -> (usize, usize, bool) {
+        let tilenr: u8 = self.rbvram0(tilemapbase + tiley * 32 + tilex);
+
+        let (palnr, vram1, xflip, yflip, prio) = {
+            let flags = self.rbvram1(tilemapbase + tiley * 32 + tilex) as usize;
+            (
+                flags & 0x07,
+                flags & (1 << 3) != 0,
+                flags & (1 << 5) != 0,
+                flags & (1 << 6) != 0,
+                flags & (1 << 7) != 0,
+            )
+        };
+
+        let tileaddress = self.tilebase
+            + (if self.tilebase == 0x8000 {
+                tilenr as u16
+            } else {
+                (tilenr as i8 as i16 + 128) as u16
+            }) * 16;
+
+        let a0 = match yflip {
+            false => tileaddress + (pixely * 2),
+            true => tileaddress + (14 - (pixely * 2)),
+        };
+
+        let (b1, b2) = match vram1 {
+            false => (self.rbvram0(a0), self.rbvram0(a0 + 1)),
+            true => (self.rbvram1(a0), self.rbvram1(a0 + 1)),
+        };
+
+        let xbit = match xflip {
+            true => pixelx,
+            false => 7 - pixelx,
+        } as u32;
+        let colnr =
+            if b1 & (1 << xbit) != 0 { 1 } else { 0 } | if b2 & (1 << xbit) != 0 { 2 } else { 0 };
+
+        (colnr, palnr, prio)
+    }
+
     fn draw_sprites(&mut self) {
         if !self.sprite_on {
             return;
@@ -690,6 +1027,10 @@ impl Gpu {
             let belowbg: bool = flags & (1 << 7) != 0;
             let c_palnr = flags & 0x07;
             let c_vram1: bool = flags & (1 << 3) != 0;
+            let (shades, dmg) = match flags & (1 << 4) != 0 {
+                false => (Shades::Sprites0, self.pal0r),
+                true => (Shades::Sprites1, self.pal1r),
+            };
 
             let tiley: u16 = if yflip {
                 (sprite_size - 1 - (line - spritey)) as u16
@@ -722,12 +1063,100 @@ impl Gpu {
                 {
                     continue 'xloop;
                 }
-                let r = self.csprit[c_palnr][colnr][0];
-                let g = self.csprit[c_palnr][colnr][1];
-                let b = self.csprit[c_palnr][colnr][2];
-                self.setrgb((spritex + x) as usize, r, g, b);
+                let color = self.theme.color(shades, dmg, &self.csprit[c_palnr], colnr);
+                self.setrgb((spritex + x) as usize, color);
+            }
+        }
+    }
+
+    /// Draws the current line of the sides in widescreen mode, from the BG
+    /// map and the sprites that the game placed there
+    fn draw_sides(&mut self) {
+        let Some(wide) = self.wide.as_ref() else {
+            return;
+        };
+
+        let line = self.line as i32;
+        let bgy = self.scy.wrapping_add(self.line);
+
+        let mut colors = [[0; 3]; 2 * SIDE_W];
+        let mut bgprio = [PrioType::Normal; 2 * SIDE_W];
+
+        for (i, color) in colors.iter_mut().enumerate() {
+            let bgx = (self.scx as i32 + side_x(i)) as u8;
+
+            let (colnr, palnr, prio) = self.tile_pixel(
+                self.bg_tilemap,
+                bgx as u16 >> 3,
+                bgy as u16 >> 3,
+                bgx & 0x07,
+                bgy as u16 & 0x07,
+            );
+
+            bgprio[i] = if colnr == 0 {
+                PrioType::Color0
+            } else if prio {
+                PrioType::PrioFlag
+            } else {
+                PrioType::Normal
+            };
+
+            *color = self
+                .theme
+                .color(Shades::Background, self.palbr, &self.cbgpal[palnr], colnr);
+        }
+
+        // Like OAM, earlier sprites are drawn on top
+        for sprite in wide.sprites().iter().rev().filter(|_| self.sprite_on) {
+            let (spritex, spritey) = sprite.screen_position(self.scx, self.scy);
+            if line < spritey || line >= spritey + 8 {
+                continue;
+            }
+
+            let xflip = sprite.flags & (1 << 5) != 0;
+            let yflip = sprite.flags & (1 << 6) != 0;
+            let belowbg = sprite.flags & (1 << 7) != 0;
+            let c_palnr = (sprite.flags & 0x07) as usize;
+            let (shades, dmg) = match sprite.flags & (1 << 4) != 0 {
+                false => (Shades::Sprites0, self.pal0r),
+                true => (Shades::Sprites1, self.pal1r),
+            };
+
+            let tiley = match yflip {
+                true => 7 - (line - spritey),
+                false => line - spritey,
+            } as u16;
+
+            let tileaddress = 0x8000u16 + sprite.tile as u16 * 16 + tiley * 2;
+            let (b1, b2) = (self.rbvram0(tileaddress), self.rbvram0(tileaddress + 1));
+
+            for x in 0..8 {
+                let Some(i) = side_index(spritex + x) else {
+                    continue;
+                };
+
+                let xbit = 1 << (if xflip { x } else { 7 - x } as u32);
+                let colnr =
+                    (if b1 & xbit != 0 { 1 } else { 0 }) | (if b2 & xbit != 0 { 2 } else { 0 });
+                if colnr == 0 {
+                    continue;
+                }
+
+                if self.lcdc0
+                    && (bgprio[i] == PrioType::PrioFlag
+                        || (belowbg && bgprio[i] != PrioType::Color0))
+                {
+                    continue;
+                }
+
+                colors[i] = self.theme.color(shades, dmg, &self.csprit[c_palnr], colnr);
             }
         }
+
+        let line = self.line as usize;
+        if let Some(wide) = self.wide.as_mut() {
+            wide.set_line(line, &colors);
+        }
     }
 
     pub fn may_hdma(&self) -> bool {
@@ -0,0 +1,211 @@
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, Receiver, Sender, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    frontend::{InputSource, VideoSink},
+    keypad::KeyboardEvent,
+    Game,
+};
+
+/// Used to quietly unwind the game thread when the `Headless` is dropped
+pub(crate) struct Disconnected;
+
+/// A frame handed over by the game thread, and whether a menu implemented in
+/// Rust is waiting for input on it
+type Step = (Vec<u8>, bool);
+
+/// Hands every vblank frame over at native resolution, and then pauses the
+/// emulation until the next step is requested.
+struct HeadlessVideo {
+    frames: SyncSender<Step>,
+    step: Receiver<()>,
+}
+
+impl HeadlessVideo {
+    fn hand_over(&mut self, frame: &[u8], waiting: bool) {
+        if self.frames.send((frame.to_vec(), waiting)).is_err() || self.step.recv().is_err() {
+            panic::resume_unwind(Box::new(Disconnected));
+        }
+    }
+}
+
+impl VideoSink for HeadlessVideo {
+    fn present(&mut self, frame: &[u8]) {
+        self.hand_over(frame, false);
+    }
+
+    /// Redraws are skipped, since the emulation doesn't advance during them,
+    /// but a menu waiting for input can only continue after another step
+    fn present_menu(&mut self, frame: &[u8], waiting: bool) {
+        if waiting {
+            self.hand_over(frame, true);
+        }
+    }
+}
+
+/// Only delivers the events that were queued before the current step
+struct HeadlessInput(Receiver<KeyboardEvent>);
+
+impl InputSource for HeadlessInput {
+    fn poll(&mut self) -> Option<KeyboardEvent> {
+        self.0.try_recv().ok()
+    }
+}
+
+/// A game running without a window, advanced one frame at a time.
+///
+/// The emulation is paused between calls to [`Headless::step_frame`], so the
+/// same sequence of inputs always produces the same sequence of frames.
+pub struct Headless {
+    keyboard_events: Sender<KeyboardEvent>,
+    frames: Receiver<Step>,
+    step: SyncSender<()>,
+    frame: Vec<u8>,
+    waiting: bool,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Headless {
+    pub(crate) fn spawn<F>(create: F) -> Headless
+    where
+        F: FnOnce(Box<dyn VideoSink>, Box<dyn InputSource>) -> Game + Send + 'static,
+    {
+        let (keyboard_sender, keyboard_receiver) = mpsc::channel();
+        let (frame_sender, frame_receiver) = mpsc::sync_channel(1);
+        let (step_sender, step_receiver) = mpsc::sync_channel(1);
+
+        let thread = thread::spawn(move || {
+            let video = HeadlessVideo {
+                frames: frame_sender,
+                step: step_receiver,
+            };
+            let input = HeadlessInput(keyboard_receiver);
+
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                create(Box::new(video), Box::new(input)).boot();
+            }));
+
+            if let Err(payload) = result {
+                if !payload.is::<Disconnected>() {
+                    panic::resume_unwind(payload);
+                }
+            }
+        });
+
+        let mut headless = Headless {
+            keyboard_events: keyboard_sender,
+            frames: frame_receiver,
+            step: step_sender,
+            frame: Vec::new(),
+            waiting: false,
+            thread: Some(thread),
+        };
+
+        headless.receive_frame();
+        headless
+    }
+
+    /// Feed `input` to the game, run it until the next vblank, and return the
+    /// new frame as 160x144 RGB pixels.
+    ///
+    /// Menus implemented in Rust pause the emulation while they wait for
+    /// input, so a step also ends when one of them starts or keeps waiting,
+    /// which [`Headless::waiting_for_input`] tells apart from a vblank.
+    pub fn step_frame(&mut self, input: &[KeyboardEvent]) -> &[u8] {
+        for event in input {
+            // A closed channel means the game has exited, which is reported below
+            let _ = self.keyboard_events.send(*event);
+        }
+
+        if self.step.send(()).is_err() {
+            self.propagate_exit();
+        }
+
+        self.receive_frame();
+        &self.frame
+    }
+
+    /// The most recent frame, as 160x144 RGB pixels
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Whether the last step ended at a menu implemented in Rust waiting for
+    /// input, instead of at a vblank
+    pub fn waiting_for_input(&self) -> bool {
+        self.waiting
+    }
+
+    fn receive_frame(&mut self) {
+        match self.frames.recv() {
+            Ok((frame, waiting)) => {
+                self.frame = frame;
+                self.waiting = waiting;
+            }
+            Err(_) => self.propagate_exit(),
+        }
+    }
+
+    fn propagate_exit(&mut self) -> ! {
+        let payload: Box<dyn Any + Send> = match self.thread.take().map(JoinHandle::join) {
+            Some(Err(payload)) => payload,
+            _ => Box::new("Game thread exited"),
+        };
+
+        panic::resume_unwind(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gpu::{GB_SCREEN_H, GB_SCREEN_W},
+        keypad::{KeyboardEvent, KeyboardKey},
+        Game,
+    };
+
+    /// Taps Start every half second until the intro and the title screen are
+    /// over and the main menu waits for input, returning every frame on the
+    /// way
+    fn boot_to_main_menu() -> Vec<Vec<u8>> {
+        let mut headless = Game::builder().without_audio().build_headless().unwrap();
+        let mut frames = vec![headless.frame().to_vec()];
+
+        while !headless.waiting_for_input() {
+            assert!(frames.len() < 3000, "Never reached the main menu");
+
+            let input = match frames.len() % 30 {
+                0 => vec![KeyboardEvent::Down {
+                    key: KeyboardKey::Return,
+                    shift: false,
+                }],
+                1 => vec![KeyboardEvent::Up {
+                    key: KeyboardKey::Return,
+                }],
+                _ => vec![],
+            };
+
+            frames.push(headless.step_frame(&input).to_vec());
+        }
+
+        frames
+    }
+
+    #[test]
+    fn steps_are_deterministic() {
+        if !crate::rom::load_for_tests() {
+            return;
+        }
+
+        let frames = boot_to_main_menu();
+        assert!(frames
+            .iter()
+            .all(|frame| frame.len() == GB_SCREEN_W * GB_SCREEN_H * 3));
+
+        assert_eq!(boot_to_main_menu(), frames);
+    }
+}
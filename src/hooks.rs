@@ -0,0 +1,174 @@
+//! Rust code that takes over from the ROM at specific addresses.
+//!
+//! Whenever execution reaches a `(bank, addr)` that is in the [`HookTable`],
+//! the hook runs instead of (or before) the instruction at that address. Hooks
+//! in the home bank (`0x0000..=0x3FFF`) match regardless of the selected ROM
+//! bank, and are registered with bank `0x00`.
+
+use std::{collections::HashMap, fmt};
+
+use crate::cpu::Cpu;
+
+pub type HookHandler = fn(&mut Cpu);
+
+#[derive(Clone, Copy)]
+pub enum HookKind {
+    /// Runs instead of the ROM routine, and must leave `pc` where execution
+    /// should continue (usually by popping the return address)
+    Replacement(HookHandler),
+    /// The ROM routine has been ported, but is only reachable from other
+    /// ported code, so executing it is a bug
+    Guard,
+    /// Runs before the instruction at the address, which is then executed as
+    /// usual. Must not change `pc`.
+    Observer(HookHandler),
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookKind::Replacement(_) => f.pad("replacement"),
+            HookKind::Guard => f.pad("guard"),
+            HookKind::Observer(_) => f.pad("observer"),
+        }
+    }
+}
+
+impl fmt::Debug for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hook {
+    pub name: &'static str,
+    pub kind: HookKind,
+}
+
+impl Hook {
+    pub const fn replacement(name: &'static str, handler: HookHandler) -> Hook {
+        Hook {
+            name,
+            kind: HookKind::Replacement(handler),
+        }
+    }
+
+    pub const fn guard(name: &'static str) -> Hook {
+        Hook {
+            name,
+            kind: HookKind::Guard,
+        }
+    }
+
+    pub const fn observer(name: &'static str, handler: HookHandler) -> Hook {
+        Hook {
+            name,
+            kind: HookKind::Observer(handler),
+        }
+    }
+}
+
+const fn normalize(bank: usize, addr: u16) -> (usize, u16) {
+    if addr < 0x4000 {
+        (0x00, addr)
+    } else {
+        (bank, addr)
+    }
+}
+
+pub struct HookTable {
+    /// One bit per address that has a hook in any bank, so that the common
+    /// case of there being no hook doesn't have to hash anything
+    present: Box<[u64; 0x10000 / 64]>,
+    hooks: HashMap<(usize, u16), Hook>,
+}
+
+impl HookTable {
+    pub fn new() -> HookTable {
+        HookTable {
+            present: Box::new([0; 0x10000 / 64]),
+            hooks: HashMap::new(),
+        }
+    }
+
+    /// Adds a hook, returning the one previously registered at the same
+    /// address, if any
+    pub fn insert(&mut self, bank: usize, addr: u16, hook: Hook) -> Option<Hook> {
+        self.present[addr as usize / 64] |= 1 << (addr % 64);
+        self.hooks.insert(normalize(bank, addr), hook)
+    }
+
+    pub fn remove(&mut self, bank: usize, addr: u16) -> Option<Hook> {
+        // The presence bit is left set since other banks may still have a hook
+        // at the same address, costing only a failed lookup
+        self.hooks.remove(&normalize(bank, addr))
+    }
+
+    #[inline]
+    pub fn get(&self, bank: usize, addr: u16) -> Option<&Hook> {
+        if self.present[addr as usize / 64] & (1 << (addr % 64)) == 0 {
+            return None;
+        }
+
+        self.hooks.get(&normalize(bank, addr))
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// All hooks as `(bank, addr, hook)`, ordered by bank and address
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u16, &Hook)> {
+        let mut hooks = self
+            .hooks
+            .iter()
+            .map(|(&(bank, addr), hook)| (bank, addr, hook))
+            .collect::<Vec<_>>();
+
+        hooks.sort_by_key(|&(bank, addr, _)| (bank, addr));
+        hooks.into_iter()
+    }
+}
+
+impl Default for HookTable {
+    fn default() -> Self {
+        HookTable::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn home_bank_hooks_match_any_bank() {
+        let mut table = HookTable::new();
+        table.insert(0x00, 0x0242, Hook::guard("home"));
+        table.insert(0x3d, 0x5ff2, Hook::guard("banked"));
+
+        assert_eq!(table.get(0x01, 0x0242).unwrap().name, "home");
+        assert_eq!(table.get(0x3d, 0x0242).unwrap().name, "home");
+        assert_eq!(table.get(0x3d, 0x5ff2).unwrap().name, "banked");
+        assert!(table.get(0x3c, 0x5ff2).is_none());
+        assert!(table.get(0x3d, 0x5ff3).is_none());
+    }
+
+    #[test]
+    fn lists_hooks_in_order() {
+        let mut table = HookTable::new();
+        table.insert(0x3d, 0x5ff2, Hook::guard("c"));
+        table.insert(0x01, 0x42bf, Hook::guard("b"));
+        table.insert(0x00, 0x0242, Hook::guard("a"));
+
+        let names = table
+            .iter()
+            .map(|(_, _, hook)| hook.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c"]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Input movies, which record the keypad from a save file and play it back
+//! exactly.
+//!
+//! A movie starts at boot with the cartridge RAM of a save file, and the main
+//! menu continues straight from it. Every keypad event is stored with the
+//! number of the frame that had been presented when the keypad picked it up.
+//! For that to be enough to reproduce a game:
+//!
+//! - the keypad only takes new input at the first read of each frame, so it
+//!   doesn't matter when during the frame an event arrived or in which order
+//!   it was received relative to the emulation,
+//! - events that a menu implemented in Rust waited for are marked, and are
+//!   handed to the same wait after the same number of frames,
+//! - snapshots are disabled, the link cable can't be used, and saving in the
+//!   game doesn't write to the save file.
+//!
+//! Pacing, audio and the hotkeys don't affect the emulation, so they are free
+//! to follow the wall clock. Once a played movie runs out, the live input
+//! takes over.
+//!
+//! The file starts with `RYMV`, a version and the 32 KiB of cartridge RAM,
+//! followed by 5 bytes per event: the frame as a little endian `u32`, and
+//! the button in the low 3 bits of a byte that has bit 6 set for releases
+//! and bit 7 set for events that were waited for.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use crate::{
+    keypad::{KeypadEvent, KeypadKey},
+    save_state::SaveState,
+};
+
+const MAGIC: &[u8; 4] = b"RYMV";
+
+/// Bump whenever the layout changes
+const VERSION: u16 = 1;
+
+const SAVE_LEN: usize = 0x8000;
+const HEADER_LEN: usize = MAGIC.len() + 2 + SAVE_LEN;
+const EVENT_LEN: usize = 5;
+
+/// In the order of the joypad register bits
+const KEYS: [KeypadKey; 8] = [
+    KeypadKey::Right,
+    KeypadKey::Left,
+    KeypadKey::Up,
+    KeypadKey::Down,
+    KeypadKey::A,
+    KeypadKey::B,
+    KeypadKey::Select,
+    KeypadKey::Start,
+];
+
+const RELEASE: u8 = 1 << 6;
+const WAITED: u8 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MovieEvent {
+    /// How many frames had been presented when the keypad picked it up
+    pub frame: u32,
+    pub event: KeypadEvent,
+    /// Whether a menu implemented in Rust was waiting for it, instead of the
+    /// game reading the joypad register
+    pub waited: bool,
+}
+
+impl MovieEvent {
+    fn encode(&self) -> [u8; EVENT_LEN] {
+        let (key, release) = match self.event {
+            KeypadEvent::Down(key) => (key, 0),
+            KeypadEvent::Up(key) => (key, RELEASE),
+        };
+
+        let index = KEYS.iter().position(|&k| k == key).unwrap() as u8;
+        let flags = index | release | if self.waited { WAITED } else { 0 };
+
+        let mut bytes = [0; EVENT_LEN];
+        bytes[..4].copy_from_slice(&self.frame.to_le_bytes());
+        bytes[4] = flags;
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<MovieEvent> {
+        let flags = bytes[4];
+
+        if flags & !(WAITED | RELEASE | 0x07) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid movie event {:02x}", flags),
+            ));
+        }
+
+        let key = KEYS[(flags & 0x07) as usize];
+
+        Ok(MovieEvent {
+            frame: u32::from_le_bytes(bytes[..4].try_into().unwrap()),
+            event: match flags & RELEASE {
+                0 => KeypadEvent::Down(key),
+                _ => KeypadEvent::Up(key),
+            },
+            waited: flags & WAITED != 0,
+        })
+    }
+}
+
+pub struct Movie {
+    /// Cartridge RAM that the movie starts with
+    pub save: SaveState,
+    pub events: VecDeque<MovieEvent>,
+}
+
+impl Movie {
+    pub fn load(path: &Path) -> io::Result<Movie> {
+        Movie::parse(&std::fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<Movie> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+            return Err(invalid("Not an input movie".to_owned()));
+        }
+
+        let version = u16::from_le_bytes([data[4], data[5]]);
+
+        if version != VERSION {
+            return Err(invalid(format!(
+                "Unsupported movie version {version} (expected {VERSION})"
+            )));
+        }
+
+        let events = &data[HEADER_LEN..];
+
+        if !events.len().is_multiple_of(EVENT_LEN) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Movie is truncated",
+            ));
+        }
+
+        Ok(Movie {
+            save: SaveState::from_bytes(data[6..HEADER_LEN].try_into().unwrap()),
+            events: events
+                .chunks_exact(EVENT_LEN)
+                .map(MovieEvent::decode)
+                .collect::<io::Result<_>>()?,
+        })
+    }
+}
+
+/// Writes each event as soon as it is recorded, so that the movie survives
+/// the game crashing
+pub struct MovieRecorder {
+    file: BufWriter<File>,
+}
+
+impl MovieRecorder {
+    pub fn create(path: &Path, save: &SaveState) -> io::Result<MovieRecorder> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        write_header(&mut file, save)?;
+        file.flush()?;
+
+        Ok(MovieRecorder { file })
+    }
+
+    pub fn record(&mut self, event: MovieEvent) -> io::Result<()> {
+        self.file.write_all(&event.encode())?;
+        self.file.flush()
+    }
+}
+
+fn write_header(out: &mut impl Write, save: &SaveState) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(save.as_bytes())
+}
+
+/// Whether the keypad is recording a movie or playing one back
+pub enum MovieMode {
+    Recording(MovieRecorder),
+    /// The events that haven't been played yet
+    Playing(VecDeque<MovieEvent>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{frontend::NullInputSource, keypad::Keypad};
+
+    fn movie_bytes(events: &[MovieEvent]) -> Vec<u8> {
+        let mut save = [0; SAVE_LEN];
+        save[0x2598] = 0x80;
+
+        let mut data = Vec::new();
+        write_header(&mut data, &SaveState::from_bytes(save)).unwrap();
+
+        for event in events {
+            data.extend_from_slice(&event.encode());
+        }
+
+        data
+    }
+
+    #[test]
+    fn parse() {
+        let events = [
+            MovieEvent {
+                frame: 3,
+                event: KeypadEvent::Down(KeypadKey::Start),
+                waited: false,
+            },
+            MovieEvent {
+                frame: 0x12345,
+                event: KeypadEvent::Up(KeypadKey::Right),
+                waited: true,
+            },
+        ];
+        let data = movie_bytes(&events);
+
+        let movie = Movie::parse(&data).unwrap();
+        assert_eq!(movie.save.as_bytes()[0x2598], 0x80);
+        assert_eq!(movie.events, events);
+
+        assert!(Movie::parse(&data[..data.len() - 1]).is_err());
+        assert!(Movie::parse(&data[1..]).is_err());
+
+        let mut invalid = data.clone();
+        *invalid.last_mut().unwrap() = 0x08;
+        assert!(Movie::parse(&invalid).is_err());
+    }
+
+    #[test]
+    fn plays_events_at_their_frames() {
+        let movie = Movie::parse(&movie_bytes(&[
+            MovieEvent {
+                frame: 2,
+                event: KeypadEvent::Down(KeypadKey::A),
+                waited: false,
+            },
+            MovieEvent {
+                frame: 4,
+                event: KeypadEvent::Up(KeypadKey::A),
+                waited: false,
+            },
+            MovieEvent {
+                frame: 6,
+                event: KeypadEvent::Down(KeypadKey::B),
+                waited: true,
+            },
+        ]))
+        .unwrap();
+
+        let mut keypad = Keypad::new(Box::new(NullInputSource));
+        keypad.play_movie(movie);
+        keypad.wb(0x10); // Select the buttons
+
+        assert_eq!(keypad.rb(1) & 0x0f, 0x0f);
+        assert_eq!(keypad.rb(2) & 0x0f, 0x0e);
+        assert_eq!(keypad.rb(3) & 0x0f, 0x0e);
+        assert_eq!(keypad.rb(4) & 0x0f, 0x0f);
+
+        // Waits for the frames that were presented while recording
+        assert_eq!(keypad.wait(5), None);
+        assert_eq!(keypad.wait(6), Some(KeypadKey::B));
+        assert!(!keypad.has_movie());
+    }
+}
@@ -1,4 +1,17 @@
-use std::sync::mpsc::Receiver;
+use std::{
+    fmt, io,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    bindings::{Action, Bindings},
+    capture::CaptureRequest,
+    frontend::InputSource,
+    input_movie::{Movie, MovieEvent, MovieMode, MovieRecorder},
+    save_state::SaveState,
+    snapshot::{SnapshotReader, SnapshotRequest, SnapshotWriter},
+};
 
 #[derive(Debug, Copy, Clone)]
 pub enum TextEvent {
@@ -8,7 +21,7 @@ pub enum TextEvent {
     Cancel,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum KeyboardKey {
     Escape,
     Left,
@@ -18,6 +31,7 @@ pub enum KeyboardKey {
     Backspace,
     Return,
     Space,
+    Tab,
     A,
     B,
     C,
@@ -44,24 +58,67 @@ pub enum KeyboardKey {
     X,
     Y,
     Z,
+    Key0,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
 }
 
-impl KeyboardKey {
-    fn into_keypad_key(self) -> Option<KeypadKey> {
-        match self {
-            KeyboardKey::Right | KeyboardKey::D => Some(KeypadKey::Right),
-            KeyboardKey::Left | KeyboardKey::A => Some(KeypadKey::Left),
-            KeyboardKey::Up | KeyboardKey::W => Some(KeypadKey::Up),
-            KeyboardKey::Down | KeyboardKey::S => Some(KeypadKey::Down),
+/// Names of the keys in the bindings file
+#[rustfmt::skip]
+const KEY_NAMES: [(KeyboardKey, &str); 57] = [
+    (KeyboardKey::Escape, "Escape"), (KeyboardKey::Left, "Left"), (KeyboardKey::Up, "Up"),
+    (KeyboardKey::Right, "Right"), (KeyboardKey::Down, "Down"), (KeyboardKey::Backspace, "Backspace"),
+    (KeyboardKey::Return, "Return"), (KeyboardKey::Space, "Space"), (KeyboardKey::Tab, "Tab"),
+    (KeyboardKey::A, "A"), (KeyboardKey::B, "B"), (KeyboardKey::C, "C"), (KeyboardKey::D, "D"),
+    (KeyboardKey::E, "E"), (KeyboardKey::F, "F"), (KeyboardKey::G, "G"), (KeyboardKey::H, "H"),
+    (KeyboardKey::I, "I"), (KeyboardKey::J, "J"), (KeyboardKey::K, "K"), (KeyboardKey::L, "L"),
+    (KeyboardKey::M, "M"), (KeyboardKey::N, "N"), (KeyboardKey::O, "O"), (KeyboardKey::P, "P"),
+    (KeyboardKey::Q, "Q"), (KeyboardKey::R, "R"), (KeyboardKey::S, "S"), (KeyboardKey::T, "T"),
+    (KeyboardKey::U, "U"), (KeyboardKey::V, "V"), (KeyboardKey::W, "W"), (KeyboardKey::X, "X"),
+    (KeyboardKey::Y, "Y"), (KeyboardKey::Z, "Z"),
+    (KeyboardKey::Key0, "0"), (KeyboardKey::Key1, "1"), (KeyboardKey::Key2, "2"), (KeyboardKey::Key3, "3"),
+    (KeyboardKey::Key4, "4"), (KeyboardKey::Key5, "5"), (KeyboardKey::Key6, "6"), (KeyboardKey::Key7, "7"),
+    (KeyboardKey::Key8, "8"), (KeyboardKey::Key9, "9"),
+    (KeyboardKey::F1, "F1"), (KeyboardKey::F2, "F2"), (KeyboardKey::F3, "F3"), (KeyboardKey::F4, "F4"),
+    (KeyboardKey::F5, "F5"), (KeyboardKey::F6, "F6"), (KeyboardKey::F7, "F7"), (KeyboardKey::F8, "F8"),
+    (KeyboardKey::F9, "F9"), (KeyboardKey::F10, "F10"), (KeyboardKey::F11, "F11"), (KeyboardKey::F12, "F12"),
+];
 
-            KeyboardKey::Z | KeyboardKey::N => Some(KeypadKey::A),
-            KeyboardKey::X | KeyboardKey::M => Some(KeypadKey::B),
+impl FromStr for KeyboardKey {
+    type Err = String;
 
-            KeyboardKey::Return => Some(KeypadKey::Start),
-            KeyboardKey::Space => Some(KeypadKey::Select),
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KEY_NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+            .map(|&(key, _)| key)
+            .ok_or_else(|| format!("Unknown key {}", s))
+    }
+}
 
-            _ => None,
-        }
+impl fmt::Display for KeyboardKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (_, name) = KEY_NAMES.iter().find(|(key, _)| key == self).unwrap();
+        f.write_str(name)
     }
 }
 
@@ -72,10 +129,15 @@ pub enum KeyboardEvent {
 }
 
 impl KeyboardEvent {
-    fn into_keypad_event(self) -> Option<KeypadEvent> {
+    fn into_keypad_event(self, bindings: &Bindings) -> Option<KeypadEvent> {
+        let into_keypad_key = |key| match bindings.action(key) {
+            Some(Action::Keypad(key)) => Some(key),
+            _ => None,
+        };
+
         match self {
-            KeyboardEvent::Down { key, .. } => key.into_keypad_key().map(KeypadEvent::Down),
-            KeyboardEvent::Up { key } => key.into_keypad_key().map(KeypadEvent::Up),
+            KeyboardEvent::Down { key, .. } => into_keypad_key(key).map(KeypadEvent::Down),
+            KeyboardEvent::Up { key } => into_keypad_key(key).map(KeypadEvent::Up),
         }
     }
 
@@ -90,6 +152,7 @@ impl KeyboardEvent {
             KeyboardEvent::Down { key: KeyboardKey::Backspace, .. } => Some(TextEvent::Delete),
             KeyboardEvent::Down { key: KeyboardKey::Return, .. } => Some(TextEvent::Submit),
             KeyboardEvent::Down { key: KeyboardKey::Space, .. } => Some(TextEvent::Append(' ')),
+            KeyboardEvent::Down { key: KeyboardKey::Tab, .. } => None,
             KeyboardEvent::Down { key: KeyboardKey::A, shift } => Some(TextEvent::Append(if shift { 'A' } else { 'a' })),
             KeyboardEvent::Down { key: KeyboardKey::B, shift } => Some(TextEvent::Append(if shift { 'B' } else { 'b' })),
             KeyboardEvent::Down { key: KeyboardKey::C, shift } => Some(TextEvent::Append(if shift { 'C' } else { 'c' })),
@@ -116,6 +179,11 @@ impl KeyboardEvent {
             KeyboardEvent::Down { key: KeyboardKey::X, shift } => Some(TextEvent::Append(if shift { 'X' } else { 'x' })),
             KeyboardEvent::Down { key: KeyboardKey::Y, shift } => Some(TextEvent::Append(if shift { 'Y' } else { 'y' })),
             KeyboardEvent::Down { key: KeyboardKey::Z, shift } => Some(TextEvent::Append(if shift { 'Z' } else { 'z' })),
+            // Digits are speed presets by default
+            KeyboardEvent::Down { key: KeyboardKey::Key0 | KeyboardKey::Key1 | KeyboardKey::Key2 | KeyboardKey::Key3 | KeyboardKey::Key4, .. } => None,
+            KeyboardEvent::Down { key: KeyboardKey::Key5 | KeyboardKey::Key6 | KeyboardKey::Key7 | KeyboardKey::Key8 | KeyboardKey::Key9, .. } => None,
+            KeyboardEvent::Down { key: KeyboardKey::F1 | KeyboardKey::F2 | KeyboardKey::F3 | KeyboardKey::F4 | KeyboardKey::F5 | KeyboardKey::F6, .. } => None,
+            KeyboardEvent::Down { key: KeyboardKey::F7 | KeyboardKey::F8 | KeyboardKey::F9 | KeyboardKey::F10 | KeyboardKey::F11 | KeyboardKey::F12, .. } => None,
 
             KeyboardEvent::Up { .. } => None,
         }
@@ -126,10 +194,19 @@ pub struct Keypad {
     row0: u8,
     row1: u8,
     data: u8,
-    events: Receiver<KeyboardEvent>,
+    input: Box<dyn InputSource>,
+    bindings: Arc<RwLock<Bindings>>,
+    snapshot_request: Option<SnapshotRequest>,
+    trace_request: bool,
+    capture_request: Option<CaptureRequest>,
+    movie: Option<MovieMode>,
+    /// Cartridge RAM that the movie starts with, until the main menu takes it
+    movie_save: Option<SaveState>,
+    /// The frame that input was last taken in
+    polled_frame: Option<u32>,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum KeypadKey {
     Right,
     Left,
@@ -141,48 +218,256 @@ pub enum KeypadKey {
     Start,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum KeypadEvent {
     Down(KeypadKey),
     Up(KeypadKey),
 }
 
 impl Keypad {
-    pub fn new(events: Receiver<KeyboardEvent>) -> Keypad {
+    pub fn new(input: Box<dyn InputSource>) -> Keypad {
         Keypad {
             row0: 0x0F,
             row1: 0x0F,
             data: 0xFF,
-            events,
+            input,
+            bindings: Arc::default(),
+            snapshot_request: None,
+            trace_request: false,
+            capture_request: None,
+            movie: None,
+            movie_save: None,
+            polled_frame: None,
+        }
+    }
+
+    /// Records every keypad event to `recorder`, starting from `save`
+    pub fn record_movie(&mut self, recorder: MovieRecorder, save: SaveState) {
+        self.movie = Some(MovieMode::Recording(recorder));
+        self.movie_save = Some(save);
+    }
+
+    /// Takes the keypad events from `movie` instead of the input source,
+    /// until it runs out
+    pub fn play_movie(&mut self, movie: Movie) {
+        self.movie = Some(MovieMode::Playing(movie.events));
+        self.movie_save = Some(movie.save);
+    }
+
+    pub fn has_movie(&self) -> bool {
+        self.movie.is_some()
+    }
+
+    fn is_playing_movie(&self) -> bool {
+        matches!(self.movie, Some(MovieMode::Playing(_)))
+    }
+
+    /// The save that the game should continue from instead of showing the
+    /// main menu
+    pub fn take_movie_save(&mut self) -> Option<SaveState> {
+        self.movie_save.take()
+    }
+
+    fn record(&mut self, frame: u32, event: KeypadEvent, waited: bool) {
+        let Some(MovieMode::Recording(recorder)) = self.movie.as_mut() else {
+            return;
+        };
+
+        let event = MovieEvent {
+            frame,
+            event,
+            waited,
+        };
+
+        if let Err(e) = recorder.record(event) {
+            log::error!("Failed to record movie, stopping: {}", e);
+            self.movie = None;
         }
     }
 
-    pub fn wait(&mut self) -> KeypadKey {
+    /// Takes the next event of the movie that is playing, if it's `ready`
+    fn next_movie_event(&mut self, ready: impl Fn(&MovieEvent) -> bool) -> Option<MovieEvent> {
+        let Some(MovieMode::Playing(events)) = self.movie.as_mut() else {
+            return None;
+        };
+
+        if !events.front().is_some_and(ready) {
+            return None;
+        }
+
+        let event = events.pop_front().unwrap();
+
+        if events.is_empty() {
+            log::info!("Movie finished, continuing with the live input");
+            self.movie = None;
+        }
+
+        Some(event)
+    }
+
+    /// Shared with the frontend, which handles the speed and fullscreen
+    /// actions itself
+    pub fn bindings(&self) -> Arc<RwLock<Bindings>> {
+        self.bindings.clone()
+    }
+
+    pub fn set_bindings(&mut self, bindings: Arc<RwLock<Bindings>>) {
+        self.bindings = bindings;
+    }
+
+    pub fn has_snapshot_request(&self) -> bool {
+        self.snapshot_request.is_some()
+    }
+
+    pub fn take_snapshot_request(&mut self) -> Option<SnapshotRequest> {
+        self.snapshot_request.take()
+    }
+
+    /// The trace key prints the instruction trace
+    pub fn take_trace_request(&mut self) -> bool {
+        std::mem::take(&mut self.trace_request)
+    }
+
+    /// The screenshot and recording keys
+    pub fn take_capture_request(&mut self) -> Option<CaptureRequest> {
+        self.capture_request.take()
+    }
+
+    /// Snapshot, trace and capture hotkeys are picked out here so that they work
+    /// regardless of what the game is currently waiting for
+    fn next_event(&mut self, block: bool) -> Option<KeyboardEvent> {
         loop {
-            match self.events.recv().map(|e| e.into_keypad_event()) {
-                Ok(Some(KeypadEvent::Down(key))) => {
+            let event = if block {
+                self.input.wait()
+            } else {
+                self.input.poll()
+            }?;
+
+            let KeyboardEvent::Down { key, shift } = event else {
+                return Some(event);
+            };
+
+            let action = self.bindings.read().unwrap().action(key);
+
+            match action {
+                Some(Action::Trace) => self.trace_request = true,
+                Some(Action::Screenshot) => self.capture_request = Some(CaptureRequest::Screenshot),
+                Some(Action::ToggleRecording) => {
+                    self.capture_request = Some(CaptureRequest::ToggleRecording)
+                }
+                // Restoring the machine state would throw the movie off
+                Some(Action::Snapshot(_)) if self.has_movie() => {
+                    log::warn!("Snapshots are disabled while a movie is recorded or played")
+                }
+                // Holding shift saves to the slot instead of loading it
+                Some(Action::Snapshot(slot)) => {
+                    self.snapshot_request = Some(match shift {
+                        true => SnapshotRequest::Save(slot),
+                        false => SnapshotRequest::Load(slot),
+                    })
+                }
+                // Handled by the frontend before the events get here
+                Some(Action::Speed(_) | Action::Fullscreen) => {}
+                Some(Action::Keypad(_)) | None => return Some(event),
+            }
+        }
+    }
+
+    fn apply(&mut self, event: KeyboardEvent) -> Option<KeypadEvent> {
+        let event = event.into_keypad_event(&self.bindings.read().unwrap());
+
+        if let Some(event) = event {
+            self.press(event);
+        }
+
+        event
+    }
+
+    fn press(&mut self, event: KeypadEvent) {
+        match event {
+            KeypadEvent::Down(key) => self.keydown(key),
+            KeypadEvent::Up(key) => self.keyup(key),
+        }
+    }
+
+    /// Waits for a button to be pressed, `frame` being the number of frames
+    /// presented so far.
+    ///
+    /// Returns `None` if the input source can't block and has no more events
+    /// queued, or a movie is playing whose next event comes after `frame`
+    pub fn wait(&mut self, frame: u32) -> Option<KeypadKey> {
+        while self.is_playing_movie() {
+            match self.next_movie_event(|event| event.frame <= frame) {
+                Some(MovieEvent {
+                    event: KeypadEvent::Down(key),
+                    waited: true,
+                    ..
+                }) => {
                     self.keydown(key);
-                    return key;
+                    return Some(key);
+                }
+                Some(event) => self.press(event.event),
+                None if self.is_playing_movie() => return None,
+                None => {}
+            }
+        }
+
+        while let Some(event) = self.next_event(true) {
+            if let Some(event) = self.apply(event) {
+                self.record(frame, event, true);
+
+                if let KeypadEvent::Down(key) = event {
+                    return Some(key);
                 }
-                Ok(Some(KeypadEvent::Up(key))) => self.keyup(key),
-                Ok(None) => {}
-                Err(_) => panic!("Keypad event channel closed"),
             }
         }
+
+        None
     }
 
-    pub fn text(&mut self) -> TextEvent {
-        loop {
-            match self.events.recv().map(|e| e.into_text_event()) {
-                Ok(Some(event)) => return event,
-                Ok(None) => {}
-                Err(_) => panic!("Keypad event channel closed"),
+    /// The next key that is pressed, including the hotkeys, so that they
+    /// can be rebound too.
+    ///
+    /// Returns `None` if the input source can't block and has no more events queued
+    pub fn key(&mut self) -> Option<KeyboardKey> {
+        while let Some(event) = self.input.wait() {
+            match event {
+                KeyboardEvent::Down { key, .. } => return Some(key),
+                KeyboardEvent::Up { .. } => {
+                    self.apply(event);
+                }
             }
         }
+
+        None
     }
 
-    pub fn rb(&mut self) -> u8 {
-        self.update();
+    /// Lets go of every button, for when the bindings change while some of
+    /// them are held down
+    pub fn release_all(&mut self) {
+        self.row0 = 0x0F;
+        self.row1 = 0x0F;
+    }
+
+    /// Returns `None` if the input source can't block and has no more events queued
+    pub fn text(&mut self) -> Option<TextEvent> {
+        while let Some(event) = self.next_event(true) {
+            if let Some(event) = event.into_text_event() {
+                return Some(event);
+            }
+        }
+
+        None
+    }
+
+    /// `frame` is the number of frames presented so far
+    pub fn rb(&mut self, frame: u32) -> u8 {
+        self.update(frame);
+        self.data
+    }
+
+    /// The register as it was last read, without taking any new input
+    pub fn peek(&self) -> u8 {
         self.data
     }
 
@@ -190,14 +475,12 @@ impl Keypad {
         self.data = (self.data & 0xCF) | (value & 0x30);
     }
 
-    fn update(&mut self) {
-        loop {
-            match self.events.try_recv().map(|e| e.into_keypad_event()) {
-                Ok(Some(KeypadEvent::Down(key))) => self.keydown(key),
-                Ok(Some(KeypadEvent::Up(key))) => self.keyup(key),
-                Ok(None) => {}
-                Err(_) => break,
-            }
+    fn update(&mut self, frame: u32) {
+        // New input is only taken once per frame, so that it doesn't matter
+        // when during the frame it arrived
+        if self.polled_frame != Some(frame) {
+            self.polled_frame = Some(frame);
+            self.poll(frame);
         }
 
         let mut new_values = 0xF;
@@ -212,6 +495,35 @@ impl Keypad {
         self.data = (self.data & 0xF0) | new_values;
     }
 
+    fn poll(&mut self, frame: u32) {
+        // The hotkeys keep working while a movie plays
+        while let Some(event) = self.next_event(false) {
+            if self.is_playing_movie() {
+                continue;
+            }
+
+            if let Some(event) = self.apply(event) {
+                self.record(frame, event, false);
+            }
+        }
+
+        while let Some(event) = self.next_movie_event(|event| event.frame <= frame && !event.waited)
+        {
+            self.press(event.event);
+        }
+    }
+
+    /// Only the row selection is part of the snapshot, the buttons that are
+    /// held down always reflect the live input
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.data);
+    }
+
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.data = r.read_u8()?;
+        Ok(())
+    }
+
     fn keydown(&mut self, key: KeypadKey) {
         match key {
             KeypadKey::Right => self.row0 &= !(1 << 0),
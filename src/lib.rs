@@ -1,21 +1,57 @@
 #![allow(clippy::bool_to_int_with_if, clippy::identity_op)]
 
-pub use crate::game::Game;
-pub use crate::gpu::{SCREEN_H, SCREEN_W};
-pub use crate::keypad::{KeyboardEvent, KeyboardKey};
+pub use crate::bindings::{Action, Bindings, SPEED_PRESETS};
+pub use crate::capture::RecordingFormat;
+pub use crate::cpu::{Cpu, CpuFlag};
+pub use crate::frontend::{
+    frame_channel, AudioSink, AudioSource, AudioVoice, FrameReceiver, FrameSender, InputSource,
+    NullAudioSink, NullInputSource, NullVideoSink, RodioAudioSink, VideoSink,
+};
+pub use crate::game::audio::manifest::{check_music, measure_loops};
+pub use crate::game::{Game, GameBuilder};
+pub use crate::gpu::{GB_SCREEN_H, GB_SCREEN_W};
+pub use crate::headless::Headless;
+pub use crate::hooks::{Hook, HookHandler, HookKind, HookTable};
+pub use crate::keypad::{KeyboardEvent, KeyboardKey, KeypadKey};
+pub use crate::link::Link;
+pub use crate::palette::PaletteTheme;
+pub use crate::rom::{load_roms, RomError, RomKind};
 pub use crate::save_state::PokemonSpecies;
+pub use crate::symbols::Symbols;
+pub use crate::upscale::{Filter, DEFAULT_SCALE};
 
+mod bindings;
+mod capture;
+mod compositor;
+mod config;
+mod coverage;
 pub(crate) mod cpu;
+mod disasm;
+mod frontend;
 pub(crate) mod game;
 mod game_state;
+mod gdb;
 mod gpu;
+mod headless;
+mod hooks;
+mod input_movie;
 mod keypad;
+mod link;
 mod mbc5;
 mod mmu;
+mod palette;
+mod printer;
+mod recorder;
 mod rom;
 mod save_state;
 mod saves;
 mod serial;
+mod sgb;
+mod snapshot;
 mod sound;
 mod sound2;
+mod symbols;
 mod timer;
+mod trace;
+mod upscale;
+mod widescreen;
@@ -0,0 +1,477 @@
+//! Link cable between two running instances of the game, for trading and
+//! battling in the Cable Club.
+//!
+//! One instance listens and the other one connects, either over TCP
+//! (`127.0.0.1:7777`) or a Unix socket (`unix:/tmp/rustic-yellow.sock`).
+//! Which side drives the clock is up to the games, exactly like with a real
+//! cable.
+//!
+//! Every byte transfer is a message from the side using the internal clock,
+//! carrying the byte it shifts out, and a reply from the other side carrying
+//! the byte that is shifted back in. The reply is `0xFF` when the other side
+//! isn't waiting for a transfer, the same as when no cable is connected.
+//!
+//! Nothing ever blocks the emulation: a transfer whose reply hasn't arrived
+//! by the time it would be done on a real cable simply takes longer, while
+//! the game keeps running.
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+const TRANSFER: u8 = 0x01;
+const REPLY: u8 = 0x02;
+
+/// How long to wait for the other side to reply before giving up on the
+/// transfer, e.g. because the other game is paused
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    /// The other side started a transfer with its internal clock
+    Transfer { seq: u8, value: u8 },
+    /// The other side's byte for a transfer that we started
+    Reply { seq: u8, value: u8 },
+}
+
+impl Message {
+    fn encode(self) -> [u8; 3] {
+        match self {
+            Message::Transfer { seq, value } => [TRANSFER, seq, value],
+            Message::Reply { seq, value } => [REPLY, seq, value],
+        }
+    }
+
+    fn decode(bytes: [u8; 3]) -> io::Result<Message> {
+        match bytes {
+            [TRANSFER, seq, value] => Ok(Message::Transfer { seq, value }),
+            [REPLY, seq, value] => Ok(Message::Reply { seq, value }),
+            [kind, ..] => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown link message {:02x}", kind),
+            )),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn accept(&self) -> io::Result<Stream> {
+        let stream = match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => Stream::Unix(listener.accept()?.0),
+        };
+
+        stream.set_nonblocking()?;
+        Ok(stream)
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Reads return right away, with `WouldBlock` if nothing has arrived
+    fn set_nonblocking(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(true),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(true),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+
+    /// Writes return right away as well, with `WouldBlock` if the socket
+    /// can't take any more yet
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+}
+
+pub struct Link {
+    /// Set when listening, so that the other side can reconnect
+    listener: Option<Listener>,
+    stream: Option<Stream>,
+    /// Bytes of a message that has only been partially received
+    buffer: Vec<u8>,
+    /// Bytes of messages that the socket couldn't take yet
+    outgoing: Vec<u8>,
+    next_seq: u8,
+    /// The last reply that arrived, which might be for an earlier transfer
+    received: Option<(u8, u8)>,
+    /// When to give up on the reply to the transfer that we started last
+    deadline: Option<Instant>,
+}
+
+impl Link {
+    /// Waits for the other instance to connect to `addr`, without blocking
+    pub fn listen(addr: &str) -> io::Result<Link> {
+        let listener = match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // Left behind by an earlier run
+                if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+
+                let listener = UnixListener::bind(path)?;
+                listener.set_nonblocking(true)?;
+                Listener::Unix(listener)
+            }
+            #[cfg(not(unix))]
+            Some(_) => return Err(unix_unsupported()),
+            None => {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(listener)
+            }
+        };
+
+        log::info!("Waiting for link cable connection on {}", addr);
+
+        Ok(Link {
+            listener: Some(listener),
+            stream: None,
+            buffer: Vec::new(),
+            outgoing: Vec::new(),
+            next_seq: 0,
+            received: None,
+            deadline: None,
+        })
+    }
+
+    /// Connects to another instance that is listening on `addr`
+    pub fn connect(addr: &str) -> io::Result<Link> {
+        let stream = match addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Stream::Unix(UnixStream::connect(path)?),
+            #[cfg(not(unix))]
+            Some(_) => return Err(unix_unsupported()),
+            None => {
+                let stream = TcpStream::connect(addr)?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+        };
+
+        stream.set_nonblocking()?;
+
+        log::info!("Link cable connected to {}", addr);
+
+        Ok(Link {
+            listener: None,
+            stream: Some(stream),
+            buffer: Vec::new(),
+            outgoing: Vec::new(),
+            next_seq: 0,
+            received: None,
+            deadline: None,
+        })
+    }
+
+    fn disconnect(&mut self, error: io::Error) {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            log::info!("Link cable disconnected");
+        } else {
+            log::error!("Link cable error: {}", error);
+        }
+
+        self.stream = None;
+        self.buffer.clear();
+        self.outgoing.clear();
+    }
+
+    fn send(&mut self, message: Message) {
+        if self.stream.is_none() {
+            return;
+        }
+
+        // Anything queued already means that the socket was full a moment
+        // ago, so this waits for the next poll as well
+        let queued = !self.outgoing.is_empty();
+        self.outgoing.extend_from_slice(&message.encode());
+
+        if !queued {
+            self.flush();
+        }
+    }
+
+    /// Writes as much of the queued messages as the socket takes, leaving the
+    /// rest for later
+    fn flush(&mut self) {
+        while !self.outgoing.is_empty() {
+            let Some(stream) = self.stream.as_mut() else {
+                return;
+            };
+
+            match stream.write(&self.outgoing) {
+                Ok(0) => {
+                    self.disconnect(io::ErrorKind::WriteZero.into());
+                    return;
+                }
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.disconnect(e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Reads one message that has already arrived, without waiting
+    fn receive(&mut self) -> Option<Message> {
+        loop {
+            if self.buffer.len() >= 3 {
+                let bytes = [self.buffer[0], self.buffer[1], self.buffer[2]];
+                self.buffer.drain(..3);
+
+                match Message::decode(bytes) {
+                    Ok(message) => return Some(message),
+                    Err(e) => {
+                        self.disconnect(e);
+                        return None;
+                    }
+                }
+            }
+
+            let stream = self.stream.as_mut()?;
+
+            let mut chunk = [0; 64];
+
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.disconnect(io::ErrorKind::UnexpectedEof.into());
+                    return None;
+                }
+                Ok(n) => self.buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.disconnect(e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Accepts a pending connection, and returns the next transfer started by
+    /// the other side, if any
+    pub fn poll(&mut self) -> Option<(u8, u8)> {
+        if self.stream.is_none() {
+            match self.listener.as_ref()?.accept() {
+                Ok(stream) => {
+                    log::info!("Link cable connected");
+                    self.stream = Some(stream);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) => {
+                    log::error!("Failed to accept link cable connection: {}", e);
+                    return None;
+                }
+            }
+        }
+
+        self.flush();
+
+        loop {
+            match self.receive()? {
+                Message::Transfer { seq, value } => return Some((seq, value)),
+                Message::Reply { seq, value } => self.received = Some((seq, value)),
+            }
+        }
+    }
+
+    /// Starts a transfer with our internal clock, returning the sequence
+    /// number to wait for the reply with
+    pub fn start_transfer(&mut self, value: u8) -> Option<u8> {
+        self.stream.as_ref()?;
+
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.deadline = Some(Instant::now() + REPLY_TIMEOUT);
+        self.send(Message::Transfer { seq, value });
+
+        Some(seq)
+    }
+
+    /// Answers a transfer started by the other side
+    pub fn reply(&mut self, seq: u8, value: u8) {
+        self.send(Message::Reply { seq, value });
+    }
+
+    /// Checks for the other side's byte for a transfer that we started,
+    /// which is `None` once the transfer has timed out or the cable was
+    /// disconnected. Any transfer that the other side starts in the meantime
+    /// gets `0xFF`, since both sides driving the clock means that neither is
+    /// listening.
+    pub fn finish_transfer(&mut self, seq: u8) -> Poll<Option<u8>> {
+        self.flush();
+
+        loop {
+            if let Some((s, value)) = self.received {
+                if s == seq {
+                    self.received = None;
+                    self.deadline = None;
+                    return Poll::Ready(Some(value));
+                }
+            }
+
+            match self.receive() {
+                Some(Message::Reply { seq, value }) => self.received = Some((seq, value)),
+                Some(Message::Transfer { seq, .. }) => self.reply(seq, 0xFF),
+                None => break,
+            }
+        }
+
+        if self.stream.is_none() {
+            return Poll::Ready(None);
+        }
+
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() < deadline)
+        {
+            return Poll::Pending;
+        }
+
+        log::warn!("Link cable transfer timed out");
+        self.deadline = None;
+        Poll::Ready(None)
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            Message::Transfer {
+                seq: 0x12,
+                value: 0x60,
+            },
+            Message::Reply {
+                seq: 0xff,
+                value: 0xfe,
+            },
+        ];
+
+        for message in messages {
+            assert_eq!(Message::decode(message.encode()).unwrap(), message);
+        }
+
+        assert!(Message::decode([0x03, 0x00, 0x00]).is_err());
+    }
+
+    #[test]
+    fn exchanges_bytes_over_tcp() {
+        let mut server = Link::listen("127.0.0.1:0").unwrap();
+        let addr = match server.listener.as_ref().unwrap() {
+            Listener::Tcp(listener) => listener.local_addr().unwrap(),
+            #[cfg(unix)]
+            Listener::Unix(_) => unreachable!(),
+        };
+        let mut client = Link::connect(&addr.to_string()).unwrap();
+
+        let seq = client.start_transfer(0x02).unwrap();
+
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let (s, value) = loop {
+            if let Some(transfer) = server.poll() {
+                break transfer;
+            }
+            assert!(Instant::now() < deadline);
+        };
+        assert_eq!((s, value), (seq, 0x02));
+
+        server.reply(s, 0x01);
+
+        let value = loop {
+            if let Poll::Ready(value) = client.finish_transfer(seq) {
+                break value;
+            }
+            assert!(Instant::now() < deadline);
+        };
+        assert_eq!(value, Some(0x01));
+    }
+
+    #[test]
+    fn queues_what_the_socket_cannot_take() {
+        let mut server = Link::listen("127.0.0.1:0").unwrap();
+        let addr = match server.listener.as_ref().unwrap() {
+            Listener::Tcp(listener) => listener.local_addr().unwrap(),
+            #[cfg(unix)]
+            Listener::Unix(_) => unreachable!(),
+        };
+        let mut client = Link::connect(&addr.to_string()).unwrap();
+
+        // More than the socket buffers hold while nobody reads
+        let count = 1 << 22;
+        for i in 0..count {
+            client.start_transfer(i as u8);
+        }
+        assert!(client.stream.is_some());
+        assert!(!client.outgoing.is_empty());
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut received = 0;
+        while received < count {
+            match server.poll() {
+                Some((seq, value)) => {
+                    assert_eq!((seq, value), (received as u8, received as u8));
+                    received += 1;
+                }
+                None => {
+                    client.poll();
+                    assert!(Instant::now() < deadline);
+                }
+            }
+        }
+        assert!(client.outgoing.is_empty());
+    }
+}
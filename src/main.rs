@@ -1,36 +1,173 @@
 use clap::Parser;
 use glium::glutin::platform::run_return::EventLoopExtRunReturn;
-use rustic_yellow::{Game, KeyboardEvent, PokemonSpecies};
-use std::sync::mpsc::{self, Receiver, SyncSender};
-use std::sync::{atomic::AtomicU64, Arc};
+use rustic_yellow::{
+    Action, Bindings, Filter, FrameSender, Game, GameBuilder, HookTable, KeyboardEvent, Link,
+    PaletteTheme, PokemonSpecies, RecordingFormat, Symbols, SPEED_PRESETS,
+};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{atomic::AtomicU64, Arc, RwLock};
 use std::thread;
 
+/// Microseconds between frames for each speed preset
+const SPEED_DELAYS: [u64; SPEED_PRESETS] = [
+    16_743, // 59.7 fps
+    10_000, // 100 fps
+    8_333,  // 120 fps
+    5_000,  // 200 fps
+    4_166,  // 240 fps
+    2_500,  // 400 fps
+];
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Which Pokemon to start with
     #[arg(long, default_value = "Pikachu")]
     starter: String,
+
+    /// Pokemon Yellow ROM (pokeyellow.gbc), defaults to the path in the
+    /// config file or the data directory
+    #[arg(long, value_name = "FILE")]
+    rom: Option<PathBuf>,
+
+    /// Pokemon Crystal ROM (pokecrystal.gbc), defaults to the path in the
+    /// config file or the data directory
+    #[arg(long, value_name = "FILE")]
+    crystal_rom: Option<PathBuf>,
+
+    /// Print every ROM routine that is handled by Rust code, and exit
+    #[arg(long)]
+    list_hooks: bool,
+
+    /// Trace which ROM routines run in the interpreter, and write a report
+    /// ranking the ones that aren't ported yet to this file on exit
+    #[arg(long, value_name = "FILE")]
+    coverage: Option<PathBuf>,
+
+    /// Symbol file from pret/pokeyellow (pokeyellow.sym), used to name
+    /// routines in reports and traces
+    #[arg(long, value_name = "FILE")]
+    sym: Option<PathBuf>,
+
+    /// Listen for a GDB remote serial protocol debugger on this local port
+    #[arg(long, value_name = "PORT")]
+    gdb: Option<u16>,
+
+    /// Wait for another instance to connect a link cable on this address,
+    /// e.g. `127.0.0.1:7777` or `unix:/tmp/rustic-yellow.sock`
+    #[arg(long, value_name = "ADDR", conflicts_with = "link_connect")]
+    link_listen: Option<String>,
+
+    /// Connect a link cable to another instance listening on this address
+    #[arg(long, value_name = "ADDR")]
+    link_connect: Option<String>,
+
+    /// Directory to write Game Boy Printer printouts to, defaults to the
+    /// `printouts` directory in the data directory
+    #[arg(long, value_name = "DIR")]
+    printouts: Option<PathBuf>,
+
+    /// Record the game audio to this WAV file, in emulated time
+    #[arg(long, value_name = "FILE")]
+    record_audio: Option<PathBuf>,
+
+    /// How many sound effects can play over each other
+    #[arg(long, value_name = "N")]
+    sfx_voices: Option<usize>,
+
+    /// How the screen is scaled up: nearest, scale2x, scale3x, hqx, lcd or
+    /// scanlines
+    #[arg(long, value_name = "NAME", default_value = "nearest")]
+    filter: Filter,
+
+    /// How many times the Game Boy resolution the screen is
+    #[arg(long, value_name = "N", default_value_t = rustic_yellow::DEFAULT_SCALE)]
+    scale: usize,
+
+    /// Colors of the screen: gbc, sgb, dmg, grayscale or the path to a
+    /// palette file
+    #[arg(long, value_name = "THEME", default_value = "gbc")]
+    palette: PaletteTheme,
+
+    /// Draw the Super Game Boy border around the screen
+    #[arg(long)]
+    sgb_border: bool,
+
+    /// Show more of the map on each side of the screen in the overworld
+    #[arg(long, conflicts_with = "sgb_border")]
+    widescreen: bool,
+
+    /// Directory to save screenshots (F6) and recordings (F7) to, defaults to
+    /// the `captures` directory in the data directory
+    #[arg(long, value_name = "DIR")]
+    capture_dir: Option<PathBuf>,
+
+    /// Record to animated GIFs (gif) or PNGs (apng)
+    #[arg(long, value_name = "FORMAT", default_value = "gif")]
+    recording_format: RecordingFormat,
+
+    /// Save screenshots and recordings at the Game Boy resolution instead
+    /// of the scaled size
+    #[arg(long)]
+    native_captures: bool,
+
+    /// Check that the ROMs and every file in the soundtrack manifest can be
+    /// found, and exit
+    #[arg(long)]
+    check_resources: bool,
+
+    /// Measure the loop points of every file in the soundtrack manifest that
+    /// doesn't have them, print them as manifest entries, and exit
+    #[arg(long)]
+    measure_loops: bool,
+
+    /// Keep the last N interpreted instructions, and print them when a hook
+    /// panics or F5 is pressed
+    #[arg(long, value_name = "N")]
+    trace: Option<usize>,
+
+    /// Key bindings file, defaults to `bindings.toml` in the data directory
+    #[arg(long, value_name = "FILE")]
+    bindings: Option<PathBuf>,
+
+    /// Record the keypad to this input movie, starting from the save given
+    /// with --movie-save
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "movie_save",
+        conflicts_with_all = ["play_movie", "link_listen", "link_connect"]
+    )]
+    record_movie: Option<PathBuf>,
+
+    /// Name of the save that a recorded movie starts from
+    #[arg(long, value_name = "NAME", requires = "record_movie")]
+    movie_save: Option<String>,
+
+    /// Play back the keypad from this input movie, starting from the save it
+    /// was recorded with
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["link_listen", "link_connect"])]
+    play_movie: Option<PathBuf>,
 }
 
 #[cfg(target_os = "windows")]
-fn create_window_builder() -> glium::glutin::window::WindowBuilder {
+fn create_window_builder(width: u32, height: u32) -> glium::glutin::window::WindowBuilder {
     use glium::glutin::platform::windows::WindowBuilderExtWindows;
     glium::glutin::window::WindowBuilder::new()
         .with_drag_and_drop(false)
         .with_inner_size(glium::glutin::dpi::LogicalSize::<u32>::from((
-            rustic_yellow::SCREEN_W as u32,
-            rustic_yellow::SCREEN_H as u32,
+            width, height,
         )))
         .with_title("Rustic Yellow")
 }
 
 #[cfg(not(target_os = "windows"))]
-fn create_window_builder() -> glium::glutin::window::WindowBuilder {
+fn create_window_builder(width: u32, height: u32) -> glium::glutin::window::WindowBuilder {
     glium::glutin::window::WindowBuilder::new()
         .with_inner_size(glium::glutin::dpi::LogicalSize::<u32>::from((
-            rustic_yellow::SCREEN_W as u32,
-            rustic_yellow::SCREEN_H as u32,
+            width, height,
         )))
         .with_title("Rustic Yellow")
 }
@@ -39,37 +176,149 @@ fn main() {
     env_logger::init();
 
     let args = Args::parse();
+
+    if args.list_hooks {
+        for (bank, addr, hook) in HookTable::builtin().iter() {
+            println!("{:02x}:{:04x} {:<11} {}", bank, addr, hook.kind, hook.name);
+        }
+        return;
+    }
+
+    if args.measure_loops {
+        for line in rustic_yellow::measure_loops() {
+            println!("{}", line);
+        }
+        return;
+    }
+
+    if let Err(e) = rustic_yellow::load_roms(args.rom, args.crystal_rom) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    if args.check_resources {
+        let problems = rustic_yellow::check_music();
+
+        for problem in &problems {
+            println!("{}", problem);
+        }
+
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+
+        println!("All resources found");
+        return;
+    }
+
     let starter: PokemonSpecies = args.starter.parse().unwrap();
 
-    let render_delay = Arc::new(AtomicU64::new(16_743));
+    let bindings_path = args.bindings.unwrap_or_else(Bindings::default_path);
+    let bindings = Bindings::load(&bindings_path).unwrap_or_else(|e| {
+        log::warn!("Failed to read {}: {}", bindings_path.display(), e);
+        Bindings::default()
+    });
+    let bindings = Arc::new(RwLock::new(bindings));
+
+    let render_delay = Arc::new(AtomicU64::new(SPEED_DELAYS[0]));
 
     let (sender1, receiver1) = mpsc::channel();
-    let (sender2, receiver2) = mpsc::sync_channel(1);
+    let (sender2, receiver2) = rustic_yellow::frame_channel();
+
+    let mut builder = Game::builder()
+        .starter(starter)
+        .bindings(bindings.clone())
+        .filter(args.filter)
+        .scale(args.scale)
+        .palette_theme(args.palette)
+        .sgb_border(args.sgb_border)
+        .widescreen(args.widescreen)
+        .recording_format(args.recording_format)
+        .native_captures(args.native_captures);
+
+    let (screen_w, screen_h) = builder.screen_size();
+    let (screen_w, screen_h) = (screen_w as u32, screen_h as u32);
 
     let mut eventloop = glium::glutin::event_loop::EventLoop::new();
-    let window_builder = create_window_builder();
+    let window_builder = create_window_builder(screen_w, screen_h);
     let context_builder = glium::glutin::ContextBuilder::new();
     let display =
         glium::backend::glutin::Display::new(window_builder, context_builder, &eventloop).unwrap();
-    set_window_size(display.gl_window().window());
+    set_window_size(display.gl_window().window(), screen_w, screen_h);
 
     let mut texture = glium::texture::texture2d::Texture2d::empty_with_format(
         &display,
         glium::texture::UncompressedFloatFormat::U8U8U8,
         glium::texture::MipmapsOption::NoMipmap,
-        rustic_yellow::SCREEN_W as u32,
-        rustic_yellow::SCREEN_H as u32,
+        screen_w,
+        screen_h,
     )
     .unwrap();
 
-    let gamethread = thread::spawn(move || run_game(sender2, receiver1, starter));
+    if let Some(path) = args.sym {
+        match Symbols::from_file(&path) {
+            Ok(symbols) => builder = builder.symbols(symbols),
+            Err(e) => log::warn!("Failed to read symbols from {}: {}", path.display(), e),
+        }
+    }
+
+    if let Some(path) = args.coverage {
+        builder = builder.coverage_report(path);
+    }
+
+    if let Some(port) = args.gdb {
+        builder = builder.gdb_port(port);
+    }
+
+    if let Some(addr) = args.link_listen {
+        match Link::listen(&addr) {
+            Ok(link) => builder = builder.link(link),
+            Err(e) => log::error!("Failed to listen for link cable on {}: {}", addr, e),
+        }
+    }
+
+    if let Some(addr) = args.link_connect {
+        match Link::connect(&addr) {
+            Ok(link) => builder = builder.link(link),
+            Err(e) => log::error!("Failed to connect link cable to {}: {}", addr, e),
+        }
+    }
+
+    if let Some(dir) = args.printouts {
+        builder = builder.printout_dir(dir);
+    }
+
+    if let Some(dir) = args.capture_dir {
+        builder = builder.capture_dir(dir);
+    }
+
+    if let Some(path) = args.record_audio {
+        builder = builder.record_audio(path);
+    }
+
+    if let Some(count) = args.sfx_voices {
+        builder = builder.sfx_voices(count);
+    }
+
+    if let Some(capacity) = args.trace {
+        builder = builder.trace(capacity);
+    }
+
+    if let (Some(path), Some(save)) = (args.record_movie, args.movie_save) {
+        builder = builder.record_movie(path, save);
+    }
+
+    if let Some(path) = args.play_movie {
+        builder = builder.play_movie(path);
+    }
+
+    let gamethread = thread::spawn(move || run_game(builder, sender2, receiver1));
 
     let periodic = timer_periodic(render_delay.clone());
 
     #[rustfmt::skip]
     eventloop.run_return(move |ev, _evtarget, controlflow| {
         use glium::glutin::event::ElementState::{Pressed, Released};
-        use glium::glutin::event::VirtualKeyCode;
         use glium::glutin::event::{Event, KeyboardInput, WindowEvent};
 
         let mut stop = false;
@@ -77,20 +326,15 @@ fn main() {
             Event::WindowEvent { event, .. } => match event {
                 WindowEvent::CloseRequested => stop = true,
                 WindowEvent::KeyboardInput { input, .. } => match input {
-                    KeyboardInput { state: Pressed, virtual_keycode: Some(VirtualKeyCode::Key1), .. }
-                        => render_delay.store(16_743, std::sync::atomic::Ordering::Relaxed), // 59.7 fps
-                    KeyboardInput { state: Pressed, virtual_keycode: Some(VirtualKeyCode::Key2), .. }
-                        => render_delay.store(10_000, std::sync::atomic::Ordering::Relaxed), // 100 fps
-                    KeyboardInput { state: Pressed, virtual_keycode: Some(VirtualKeyCode::Key3), .. }
-                        => render_delay.store(8_333, std::sync::atomic::Ordering::Relaxed), // 120 fps
-                    KeyboardInput { state: Pressed, virtual_keycode: Some(VirtualKeyCode::Key4), .. }
-                        => render_delay.store(5_000, std::sync::atomic::Ordering::Relaxed), // 200 fps
-                    KeyboardInput { state: Pressed, virtual_keycode: Some(VirtualKeyCode::Key5), .. }
-                        => render_delay.store(4_166, std::sync::atomic::Ordering::Relaxed), // 240 fps
-                    KeyboardInput { state: Pressed, virtual_keycode: Some(VirtualKeyCode::Key6), .. }
-                        => render_delay.store(2_500, std::sync::atomic::Ordering::Relaxed), // 400 fps
                     KeyboardInput { state: Pressed, virtual_keycode: Some(glutinkey), modifiers, .. } => {
                         if let Some(key) = glutin_to_keyboard(glutinkey) {
+                            // The game gets these too, so that they can be rebound in the Controls menu
+                            match bindings.read().unwrap().action(key) {
+                                Some(Action::Speed(preset)) => render_delay.store(SPEED_DELAYS[preset], std::sync::atomic::Ordering::Relaxed),
+                                Some(Action::Fullscreen) => toggle_fullscreen(display.gl_window().window()),
+                                _ => (),
+                            }
+
                             let _ = sender1.send(KeyboardEvent::Down { key, shift: modifiers.shift() });
                         }
                     },
@@ -107,7 +351,10 @@ fn main() {
                 periodic.recv().unwrap();
 
                 match receiver2.try_recv() {
-                    Ok(data) => { recalculate_screen(&display, &mut texture, &data); },
+                    Ok(data) => {
+                        recalculate_screen(&display, &mut texture, &data);
+                        receiver2.recycle(data);
+                    },
                     Err(mpsc::TryRecvError::Empty) => (),
                     Err(..) => stop = true, // Remote end has hung-up
                 }
@@ -135,6 +382,7 @@ fn glutin_to_keyboard(
         VirtualKeyCode::Back => Some(rustic_yellow::KeyboardKey::Backspace),
         VirtualKeyCode::Return => Some(rustic_yellow::KeyboardKey::Return),
         VirtualKeyCode::Space => Some(rustic_yellow::KeyboardKey::Space),
+        VirtualKeyCode::Tab => Some(rustic_yellow::KeyboardKey::Tab),
         VirtualKeyCode::A => Some(rustic_yellow::KeyboardKey::A),
         VirtualKeyCode::B => Some(rustic_yellow::KeyboardKey::B),
         VirtualKeyCode::C => Some(rustic_yellow::KeyboardKey::C),
@@ -161,6 +409,28 @@ fn glutin_to_keyboard(
         VirtualKeyCode::X => Some(rustic_yellow::KeyboardKey::X),
         VirtualKeyCode::Y => Some(rustic_yellow::KeyboardKey::Y),
         VirtualKeyCode::Z => Some(rustic_yellow::KeyboardKey::Z),
+        VirtualKeyCode::Key0 => Some(rustic_yellow::KeyboardKey::Key0),
+        VirtualKeyCode::Key1 => Some(rustic_yellow::KeyboardKey::Key1),
+        VirtualKeyCode::Key2 => Some(rustic_yellow::KeyboardKey::Key2),
+        VirtualKeyCode::Key3 => Some(rustic_yellow::KeyboardKey::Key3),
+        VirtualKeyCode::Key4 => Some(rustic_yellow::KeyboardKey::Key4),
+        VirtualKeyCode::Key5 => Some(rustic_yellow::KeyboardKey::Key5),
+        VirtualKeyCode::Key6 => Some(rustic_yellow::KeyboardKey::Key6),
+        VirtualKeyCode::Key7 => Some(rustic_yellow::KeyboardKey::Key7),
+        VirtualKeyCode::Key8 => Some(rustic_yellow::KeyboardKey::Key8),
+        VirtualKeyCode::Key9 => Some(rustic_yellow::KeyboardKey::Key9),
+        VirtualKeyCode::F1 => Some(rustic_yellow::KeyboardKey::F1),
+        VirtualKeyCode::F2 => Some(rustic_yellow::KeyboardKey::F2),
+        VirtualKeyCode::F3 => Some(rustic_yellow::KeyboardKey::F3),
+        VirtualKeyCode::F4 => Some(rustic_yellow::KeyboardKey::F4),
+        VirtualKeyCode::F5 => Some(rustic_yellow::KeyboardKey::F5),
+        VirtualKeyCode::F6 => Some(rustic_yellow::KeyboardKey::F6),
+        VirtualKeyCode::F7 => Some(rustic_yellow::KeyboardKey::F7),
+        VirtualKeyCode::F8 => Some(rustic_yellow::KeyboardKey::F8),
+        VirtualKeyCode::F9 => Some(rustic_yellow::KeyboardKey::F9),
+        VirtualKeyCode::F10 => Some(rustic_yellow::KeyboardKey::F10),
+        VirtualKeyCode::F11 => Some(rustic_yellow::KeyboardKey::F11),
+        VirtualKeyCode::F12 => Some(rustic_yellow::KeyboardKey::F12),
 
         _ => None,
     }
@@ -171,46 +441,70 @@ fn recalculate_screen(
     texture: &mut glium::texture::texture2d::Texture2d,
     datavec: &[u8],
 ) {
+    let (width, height) = texture.dimensions();
     use glium::Surface;
 
     let rawimage2d = glium::texture::RawImage2d {
         data: std::borrow::Cow::Borrowed(datavec),
-        width: rustic_yellow::SCREEN_W as u32,
-        height: rustic_yellow::SCREEN_H as u32,
+        width,
+        height,
         format: glium::texture::ClientFormat::U8U8U8,
     };
     texture.write(
         glium::Rect {
             left: 0,
             bottom: 0,
-            width: rustic_yellow::SCREEN_W as u32,
-            height: rustic_yellow::SCREEN_H as u32,
+            width,
+            height,
         },
         rawimage2d,
     );
 
-    // We use a custom BlitTarget to transform OpenGL coordinates to row-column coordinates
-    let target = display.draw();
+    // Keep the aspect ratio when the window isn't the size of the screen,
+    // such as in fullscreen
+    let mut target = display.draw();
     let (target_w, target_h) = target.get_dimensions();
+    let scale = f64::min(
+        target_w as f64 / width as f64,
+        target_h as f64 / height as f64,
+    );
+    let (blit_w, blit_h) = (
+        (width as f64 * scale) as u32,
+        (height as f64 * scale) as u32,
+    );
+
+    // We use a custom BlitTarget to transform OpenGL coordinates to row-column coordinates
+    target.clear_color(0.0, 0.0, 0.0, 1.0);
     texture.as_surface().blit_whole_color_to(
         &target,
         &glium::BlitTarget {
-            left: 0,
-            bottom: target_h,
-            width: target_w as i32,
-            height: -(target_h as i32),
+            left: (target_w - blit_w) / 2,
+            bottom: (target_h + blit_h) / 2,
+            width: blit_w as i32,
+            height: -(blit_h as i32),
         },
         glium::uniforms::MagnifySamplerFilter::Nearest,
     );
     target.finish().unwrap();
 }
 
-fn run_game(
-    sender: SyncSender<Vec<u8>>,
-    receiver: Receiver<KeyboardEvent>,
-    starter: PokemonSpecies,
-) {
-    Game::new(sender, receiver, starter).boot();
+fn run_game(builder: GameBuilder, sender: FrameSender, receiver: Receiver<KeyboardEvent>) {
+    let mut game = builder
+        .video(sender)
+        .input(receiver)
+        .build()
+        .expect("the ROMs are loaded at startup");
+
+    // The game only stops by unwinding, once the window is gone
+    let result = panic::catch_unwind(AssertUnwindSafe(|| game.boot()));
+
+    if let Err(e) = game.write_coverage_report() {
+        log::error!("Error writing coverage report: {}", e);
+    }
+
+    if let Err(payload) = result {
+        panic::resume_unwind(payload);
+    }
 }
 
 fn timer_periodic(delay: Arc<AtomicU64>) -> Receiver<()> {
@@ -225,15 +519,21 @@ fn timer_periodic(delay: Arc<AtomicU64>) -> Receiver<()> {
     rx
 }
 
-fn set_window_size(window: &glium::glutin::window::Window) {
+fn toggle_fullscreen(window: &glium::glutin::window::Window) {
+    use glium::glutin::window::Fullscreen;
+
+    match window.fullscreen() {
+        Some(_) => window.set_fullscreen(None),
+        None => window.set_fullscreen(Some(Fullscreen::Borderless(None))),
+    }
+}
+
+fn set_window_size(window: &glium::glutin::window::Window, width: u32, height: u32) {
     use glium::glutin::dpi::{LogicalSize, PhysicalSize};
 
     let dpi = window.scale_factor();
 
-    let physical_size = PhysicalSize::<u32>::from((
-        rustic_yellow::SCREEN_W as u32,
-        rustic_yellow::SCREEN_H as u32,
-    ));
+    let physical_size = PhysicalSize::<u32>::from((width, height));
     let logical_size = LogicalSize::<u32>::from_physical(physical_size, dpi);
 
     window.set_inner_size(logical_size);
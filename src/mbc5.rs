@@ -1,6 +1,10 @@
-use std::path;
+use std::{io, path};
 
-use crate::{rom::ROM, save_state::SaveState};
+use crate::{
+    rom::ROM,
+    save_state::SaveState,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
 
 pub struct MBC5 {
     ram: SaveState,
@@ -37,6 +41,10 @@ impl MBC5 {
         self.save_path = Some(save_path);
     }
 
+    pub fn save_path(&self) -> Option<&path::Path> {
+        self.save_path.as_deref()
+    }
+
     pub fn save_to_disk(&mut self) {
         if let Some(ref save_path) = self.save_path {
             self.ram.write_to_file(save_path).unwrap();
@@ -78,4 +86,28 @@ impl MBC5 {
         self.ram
             .set_byte((self.rambank * 0x2000) | ((a as usize) & 0x1FFF), v);
     }
+
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        self.ram.save_snapshot(w);
+        w.write_u16(self.rombank as u16);
+        w.write_u8(self.rambank as u8);
+        w.write_bool(self.ram_on);
+
+        match self.save_path.as_ref().and_then(|path| path.to_str()) {
+            Some(path) => w.write_str(path),
+            None => w.write_str(""),
+        }
+    }
+
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.ram.load_snapshot(r)?;
+        self.rombank = r.read_u16()? as usize;
+        self.rambank = r.read_u8()? as usize;
+        self.ram_on = r.read_bool()?;
+
+        let save_path = r.read_str()?;
+        self.save_path = (!save_path.is_empty()).then(|| path::PathBuf::from(save_path));
+
+        Ok(())
+    }
 }
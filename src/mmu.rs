@@ -1,11 +1,16 @@
-use std::sync::mpsc::{Receiver, SyncSender};
+use std::io;
 
 use crate::{
+    frontend::{AudioSink, InputSource, VideoSink},
     game_state::GameState,
+    gdb::Watchpoint,
     gpu::Gpu,
-    keypad::{KeyboardEvent, Keypad},
+    keypad::Keypad,
     mbc5::MBC5,
+    recorder::AudioRecorder,
     serial::Serial,
+    sgb::PacketReceiver,
+    snapshot::{SnapshotReader, SnapshotWriter},
     sound::Sound,
     sound2::Sound2,
     timer::Timer,
@@ -32,6 +37,7 @@ pub struct Mmu {
     pub serial: Serial,
     pub timer: Timer,
     pub keypad: Keypad,
+    sgb: PacketReceiver,
     pub gpu: Gpu,
     pub sound: Sound,
     pub sound2: Sound2,
@@ -43,10 +49,16 @@ pub struct Mmu {
     pub mbc: MBC5,
     gbspeed: GbSpeed,
     speed_switch_req: bool,
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    pub(crate) watch_hit: Option<(Watchpoint, u16)>,
 }
 
 impl Mmu {
-    pub fn new(update_screen: SyncSender<Vec<u8>>, keypad_events: Receiver<KeyboardEvent>) -> Mmu {
+    pub fn new(
+        video: Box<dyn VideoSink>,
+        input: Box<dyn InputSource>,
+        audio: Box<dyn AudioSink>,
+    ) -> Mmu {
         let mut mmu = Mmu {
             wram: GameState::new(),
             hdma: [0; 4],
@@ -54,10 +66,11 @@ impl Mmu {
             intf: 0,
             serial: Serial::new(),
             timer: Timer::new(),
-            keypad: Keypad::new(keypad_events),
-            gpu: Gpu::new(update_screen),
+            keypad: Keypad::new(input),
+            sgb: PacketReceiver::default(),
+            gpu: Gpu::new(video),
             sound: Sound::new(),
-            sound2: Sound2::new(),
+            sound2: Sound2::new(audio),
             hdma_status: DMAType::NoDMA,
             hdma_src: 0,
             hdma_dst: 0,
@@ -66,6 +79,8 @@ impl Mmu {
             mbc: MBC5::new(),
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
+            watchpoints: Vec::new(),
+            watch_hit: None,
         };
 
         mmu.wb(0xFF05, 0);
@@ -103,6 +118,65 @@ impl Mmu {
         mmu
     }
 
+    /// Record the APU, music and sound effects to a single file
+    pub fn record_audio(&mut self, recorder: AudioRecorder) {
+        self.sound.captured = Some(Vec::new());
+        self.sound2.record(recorder);
+    }
+
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        self.wram.save_snapshot(w);
+        w.write_bytes(&self.hdma);
+        w.write_u8(self.inte);
+        w.write_u8(self.intf);
+        self.serial.save_snapshot(w);
+        self.timer.save_snapshot(w);
+        self.keypad.save_snapshot(w);
+        self.gpu.save_snapshot(w);
+        self.sound.save_snapshot(w);
+        w.write_u8(match self.hdma_status {
+            DMAType::NoDMA => 0,
+            DMAType::Gdma => 1,
+            DMAType::Hdma => 2,
+        });
+        w.write_u16(self.hdma_src);
+        w.write_u16(self.hdma_dst);
+        w.write_u8(self.hdma_len);
+        w.write_u8(self.wrambank as u8);
+        self.mbc.save_snapshot(w);
+        w.write_bool(self.gbspeed == GbSpeed::Double);
+        w.write_bool(self.speed_switch_req);
+    }
+
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.wram.load_snapshot(r)?;
+        r.read_bytes(&mut self.hdma)?;
+        self.inte = r.read_u8()?;
+        self.intf = r.read_u8()?;
+        self.serial.load_snapshot(r)?;
+        self.timer.load_snapshot(r)?;
+        self.keypad.load_snapshot(r)?;
+        self.gpu.load_snapshot(r)?;
+        self.sound.load_snapshot(r)?;
+        self.hdma_status = match r.read_u8()? {
+            1 => DMAType::Gdma,
+            2 => DMAType::Hdma,
+            _ => DMAType::NoDMA,
+        };
+        self.hdma_src = r.read_u16()?;
+        self.hdma_dst = r.read_u16()?;
+        self.hdma_len = r.read_u8()?;
+        self.wrambank = r.read_u8()? as usize;
+        self.mbc.load_snapshot(r)?;
+        self.gbspeed = if r.read_bool()? {
+            GbSpeed::Double
+        } else {
+            GbSpeed::Single
+        };
+        self.speed_switch_req = r.read_bool()?;
+        Ok(())
+    }
+
     pub fn do_cycle(&mut self, ticks: u32) -> u32 {
         let cpudivider = match self.gbspeed {
             GbSpeed::Single => 1,
@@ -117,19 +191,44 @@ impl Mmu {
         self.timer.interrupt = 0;
 
         self.gpu.do_cycle(gputicks);
+        if self.gpu.interrupt & 0x01 != 0 {
+            self.update_sound2();
+        }
         self.intf |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
 
         self.sound.do_cycle(gputicks);
+        self.sound2.do_cycle(gputicks, self.sound.captured.as_mut());
 
+        self.serial.do_cycle(cputicks);
         self.intf |= self.serial.interrupt;
         self.serial.interrupt = 0;
 
         gputicks
     }
 
+    /// Lets the FLAC music engine follow what the ROM's audio engine is
+    /// doing, once per frame
+    fn update_sound2(&mut self) {
+        let fade = if self.wram.audio_fade_out_control() != 0 {
+            self.sound.master_volume()
+        } else {
+            1.0
+        };
+        self.sound2.set_fade_volume(fade);
+
+        // $ff is how the game turns the alarm off
+        let alarm = self.wram.low_health_alarm();
+        self.sound2
+            .set_low_health_alarm(alarm != 0xff && alarm & 0x80 != 0);
+    }
+
     #[rustfmt::skip]
     pub fn rb(&mut self, address: u16) -> u8 {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, false);
+        }
+
         match address {
             0x0000..=0x7FFF => self.mbc.readrom(address),
             0x8000..=0x9FFF => self.gpu.rb(address),
@@ -137,7 +236,7 @@ impl Mmu {
             0xC000..=0xCFFF | 0xE000..=0xEFFF => self.wram.byte(address as usize & 0x0FFF),
             0xD000..=0xDFFF | 0xF000..=0xFDFF => self.wram.byte((self.wrambank * 0x1000) | address as usize & 0x0FFF),
             0xFE00..=0xFE9F => self.gpu.rb(address),
-            0xFF00 => self.keypad.rb(),
+            0xFF00 => self.keypad.rb(self.gpu.frames()),
             0xFF01..=0xFF02 => self.serial.rb(address),
             0xFF04..=0xFF07 => self.timer.rb(address),
             0xFF0F => self.intf,
@@ -154,12 +253,43 @@ impl Mmu {
         }
     }
 
+    /// Reads a byte like `rb`, but without triggering watchpoints, taking
+    /// input or running the APU, for debuggers and diagnostics
+    #[rustfmt::skip]
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x7FFF => self.mbc.readrom(address),
+            0x8000..=0x9FFF => self.gpu.rb(address),
+            0xA000..=0xBFFF => self.mbc.readram(address),
+            0xC000..=0xCFFF | 0xE000..=0xEFFF => self.wram.byte(address as usize & 0x0FFF),
+            0xD000..=0xDFFF | 0xF000..=0xFDFF => self.wram.byte((self.wrambank * 0x1000) | address as usize & 0x0FFF),
+            0xFE00..=0xFE9F => self.gpu.rb(address),
+            0xFF00 => self.keypad.peek(),
+            0xFF01..=0xFF02 => self.serial.rb(address),
+            0xFF04..=0xFF07 => self.timer.rb(address),
+            0xFF0F => self.intf,
+            0xFF10..=0xFF3F => self.sound.peek(address),
+            0xFF4D => (if self.gbspeed == GbSpeed::Double { 0x80 } else { 0 }) | (if self.speed_switch_req { 1 } else { 0 }),
+            0xFF40..=0xFF4F => self.gpu.rb(address),
+            0xFF51..=0xFF55 => self.hdma_read(address),
+            0xFF68..=0xFF6B => self.gpu.rb(address),
+            0xFF70 => self.wrambank as u8,
+            0xFF80..=0xFFFE => self.wram.high_ram_byte(address as usize & 0x007F),
+            0xFFFF => self.inte,
+            _ => 0xFF,
+        }
+    }
+
     pub fn rw(&mut self, address: u16) -> u16 {
         (self.rb(address) as u16) | ((self.rb(address + 1) as u16) << 8)
     }
 
     #[rustfmt::skip]
     pub fn wb(&mut self, address: u16, value: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, true);
+        }
+
         match address {
             0x0000..=0x7FFF => self.mbc.writerom(address, value),
             0x8000..=0x9FFF => self.gpu.wb(address, value),
@@ -167,7 +297,7 @@ impl Mmu {
             0xC000..=0xCFFF | 0xE000..=0xEFFF => self.wram.set_byte(address as usize & 0x0FFF, value),
             0xD000..=0xDFFF | 0xF000..=0xFDFF => self.wram.set_byte((self.wrambank * 0x1000) | (address as usize & 0x0FFF), value),
             0xFE00..=0xFE9F => self.gpu.wb(address, value),
-            0xFF00 => self.keypad.wb(value),
+            0xFF00 => self.write_joypad(value),
             0xFF01..=0xFF02 => self.serial.wb(address, value),
             0xFF04..=0xFF07 => self.timer.wb(address, value),
             0xFF10..=0xFF3F => self.sound.wb(address, value),
@@ -184,6 +314,27 @@ impl Mmu {
         };
     }
 
+    /// Super Game Boy packets are sent through the joypad register
+    fn write_joypad(&mut self, value: u8) {
+        self.keypad.wb(value);
+
+        if let Some(command) = self.sgb.write(value) {
+            self.gpu.sgb_transfer(command);
+        }
+    }
+
+    fn check_watchpoints(&mut self, address: u16, write: bool) {
+        // Echo RAM is the same memory as WRAM
+        let address = match address {
+            0xE000..=0xFDFF => address - 0x2000,
+            _ => address,
+        };
+
+        if let Some(watchpoint) = self.watchpoints.iter().find(|w| w.matches(address, write)) {
+            self.watch_hit = Some((*watchpoint, address));
+        }
+    }
+
     pub fn ww(&mut self, address: u16, value: u16) {
         self.wb(address, (value & 0xFF) as u8);
         self.wb(address + 1, (value >> 8) as u8);
@@ -305,3 +456,33 @@ impl Mmu {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend::{NullAudioSink, NullInputSource, NullVideoSink};
+
+    #[test]
+    fn music_fades_with_the_master_volume() {
+        let mut mmu = Mmu::new(
+            Box::new(NullVideoSink),
+            Box::new(NullInputSource),
+            Box::new(NullAudioSink),
+        );
+        mmu.wb(0xFF26, 0x80);
+        mmu.wb(0xFF24, 0x33);
+
+        // NR50 only matters while the game is fading out
+        mmu.wram.set_audio_fade_out_control(0);
+        mmu.update_sound2();
+        assert_eq!(mmu.sound2.fade_volume(), 1.0);
+
+        mmu.wram.set_audio_fade_out_control(10);
+        mmu.update_sound2();
+        assert_eq!(mmu.sound2.fade_volume(), 3.0 / 7.0);
+
+        mmu.wb(0xFF24, 0x00);
+        mmu.update_sound2();
+        assert_eq!(mmu.sound2.fade_volume(), 0.0);
+    }
+}
@@ -0,0 +1,248 @@
+//! Palette themes, which decide the colors that the Game Boy screen is drawn
+//! with.
+//!
+//! Yellow runs in Game Boy Color mode, where its palette commands translate
+//! the Super Game Boy packets (`PAL_SET` and `ATTR_BLK`) into CGB palettes and
+//! BG map attributes. That gives every map and Pokemon its own colors, which
+//! the `gbc` and `sgb` themes show. The other themes ignore those colors and
+//! only look at which of the four shades a pixel is, like a Game Boy would.
+//!
+//! A palette file lists the shades from lightest to darkest:
+//!
+//! ```toml
+//! background = ["#e0f8d0", "#88c070", "#346856", "#081820"]
+//!
+//! # Both default to the background shades
+//! sprites0 = ["#ffffff", "#ff8484", "#943a3a", "#000000"]
+//! sprites1 = ["#ffffff", "#7bff31", "#0063c5", "#000000"]
+//! ```
+
+use std::{fs, io, path::Path, str::FromStr};
+
+use serde::Deserialize;
+
+pub type Color = [u8; 3];
+
+/// Which palette register a pixel was drawn with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shades {
+    Background,
+    Sprites0,
+    Sprites1,
+}
+
+/// Four colors for each of the palette registers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadePalette {
+    pub background: [Color; 4],
+    pub sprites0: [Color; 4],
+    pub sprites1: [Color; 4],
+}
+
+impl ShadePalette {
+    const fn uniform(shades: [Color; 4]) -> ShadePalette {
+        ShadePalette {
+            background: shades,
+            sprites0: shades,
+            sprites1: shades,
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<ShadePalette> {
+        ShadePalette::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> io::Result<ShadePalette> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct RawPalette {
+            background: [String; 4],
+            sprites0: Option<[String; 4]>,
+            sprites1: Option<[String; 4]>,
+        }
+
+        let raw: RawPalette =
+            toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let background = parse_shades(&raw.background)?;
+
+        Ok(ShadePalette {
+            background,
+            sprites0: match raw.sprites0 {
+                Some(shades) => parse_shades(&shades)?,
+                None => background,
+            },
+            sprites1: match raw.sprites1 {
+                Some(shades) => parse_shades(&shades)?,
+                None => background,
+            },
+        })
+    }
+
+    fn shades(&self, shades: Shades) -> &[Color; 4] {
+        match shades {
+            Shades::Background => &self.background,
+            Shades::Sprites0 => &self.sprites0,
+            Shades::Sprites1 => &self.sprites1,
+        }
+    }
+}
+
+fn parse_shades(shades: &[String; 4]) -> io::Result<[Color; 4]> {
+    let mut colors = [[0; 3]; 4];
+
+    for (color, text) in colors.iter_mut().zip(shades) {
+        let hex = text.strip_prefix('#').unwrap_or(text);
+
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid color {}, expected #rrggbb", text),
+            ));
+        }
+
+        let value = u32::from_str_radix(hex, 16).unwrap();
+        *color = [(value >> 16) as u8, (value >> 8) as u8, value as u8];
+    }
+
+    Ok(colors)
+}
+
+#[rustfmt::skip]
+const DMG: ShadePalette = ShadePalette::uniform([
+    [0x9b, 0xbc, 0x0f],
+    [0x8b, 0xac, 0x0f],
+    [0x30, 0x62, 0x30],
+    [0x0f, 0x38, 0x0f],
+]);
+
+#[rustfmt::skip]
+const GRAYSCALE: ShadePalette = ShadePalette::uniform([
+    [0xff, 0xff, 0xff],
+    [0xb0, 0xb0, 0xb0],
+    [0x50, 0x50, 0x50],
+    [0x00, 0x00, 0x00],
+]);
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PaletteTheme {
+    /// The colors of each map and Pokemon, as a Game Boy Color shows them
+    #[default]
+    Gbc,
+    /// The same colors as a Super Game Boy shows them on a TV, which is
+    /// brighter and more saturated than the Game Boy Color screen
+    Sgb,
+    /// Four shades of green, like the original Game Boy
+    Dmg,
+    /// White, black and two grays far apart
+    Grayscale,
+    /// Shades read from a palette file
+    Custom(ShadePalette),
+}
+
+impl PaletteTheme {
+    /// The color of a pixel with color number `colnr`, drawn with the CGB
+    /// palette `cgb` (in 5 bit components) and the DMG palette register
+    /// `dmg`
+    pub fn color(&self, shades: Shades, dmg: u8, cgb: &[[u8; 3]; 4], colnr: usize) -> Color {
+        let palette = match self {
+            PaletteTheme::Gbc => return gbc_color(cgb[colnr]),
+            PaletteTheme::Sgb => return sgb_color(cgb[colnr]),
+            PaletteTheme::Dmg => &DMG,
+            PaletteTheme::Grayscale => &GRAYSCALE,
+            PaletteTheme::Custom(palette) => palette,
+        };
+
+        let shade = (dmg >> (2 * colnr)) & 0x03;
+        palette.shades(shades)[shade as usize]
+    }
+}
+
+impl FromStr for PaletteTheme {
+    type Err = String;
+
+    /// One of the built in themes, or the path to a palette file
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gbc" => Ok(PaletteTheme::Gbc),
+            "sgb" => Ok(PaletteTheme::Sgb),
+            "dmg" => Ok(PaletteTheme::Dmg),
+            "grayscale" => Ok(PaletteTheme::Grayscale),
+            path => match ShadePalette::load(Path::new(path)) {
+                Ok(palette) => Ok(PaletteTheme::Custom(palette)),
+                Err(e) => Err(format!(
+                    "Expected gbc, sgb, dmg, grayscale or a palette file, failed to read {}: {}",
+                    path, e
+                )),
+            },
+        }
+    }
+}
+
+/// Gameboy Color RGB correction
+/// Taken from the Gambatte emulator
+fn gbc_color([r, g, b]: [u8; 3]) -> Color {
+    let r = r as u32;
+    let g = g as u32;
+    let b = b as u32;
+
+    [
+        ((r * 13 + g * 2 + b) >> 1) as u8,
+        ((g * 3 + b) << 1) as u8,
+        ((r * 3 + g * 2 + b * 11) >> 1) as u8,
+    ]
+}
+
+/// 5 bit components scaled up to 8 bits, as a Super Game Boy shows them
+pub fn sgb_color(color: [u8; 3]) -> Color {
+    color.map(|c| (c << 3) | (c >> 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_shades_through_the_palette_registers() {
+        let palette = ShadePalette::parse(
+            r##"
+            background = ["#ffffff", "#aaaaaa", "#555555", "#000000"]
+            sprites1 = ["#ff0000", "#00ff00", "#0000ff", "#123456"]
+            "##,
+        )
+        .unwrap();
+
+        assert_eq!(palette.sprites0, palette.background);
+
+        let theme = PaletteTheme::Custom(palette);
+        let cgb = [[31, 31, 31], [21, 21, 21], [10, 10, 10], [0, 0, 0]];
+
+        // BGP 0b11100100 leaves the color numbers as they are, 0b00011011
+        // reverses them
+        assert_eq!(
+            theme.color(Shades::Background, 0b11100100, &cgb, 1),
+            [0xaa; 3]
+        );
+        assert_eq!(
+            theme.color(Shades::Background, 0b00011011, &cgb, 1),
+            [0x55; 3]
+        );
+        assert_eq!(
+            theme.color(Shades::Sprites1, 0b11100100, &cgb, 3),
+            [0x12, 0x34, 0x56]
+        );
+
+        assert_eq!(
+            PaletteTheme::Sgb.color(Shades::Background, 0, &cgb, 0),
+            [0xff; 3]
+        );
+        assert_eq!(
+            PaletteTheme::Gbc.color(Shades::Background, 0, &cgb, 3),
+            [0; 3]
+        );
+
+        assert!(
+            ShadePalette::parse("background = [\"#fff\", \"#aaa\", \"#555\", \"#000\"]").is_err()
+        );
+    }
+}
@@ -0,0 +1,402 @@
+//! The Game Boy Printer, which Yellow uses to print Pokédex entries, party and
+//! PC box lists, and the diploma.
+//!
+//! The game sends packets of the form `88 33 <command> <compression>
+//! <length: u16> <data> <checksum: u16>` followed by two zero bytes, during
+//! which the printer answers with `0x81` and its status. Image data arrives
+//! as rows of 20 tiles in the usual 2bpp format, and every PRINT command
+//! prints what has been received since the last one.
+//!
+//! Printed strips are collected into one image until a PRINT command asks for
+//! a margin after the image, which is where the paper would be torn off. The
+//! image is then written as a PNG.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const BREAK: u8 = 0x08;
+const STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED: u8 = 0x08;
+
+/// Width of the paper, in pixels
+const WIDTH: usize = 160;
+
+/// The printer only has room for this much image data between prints
+const BUFFER_SIZE: usize = 0x2000;
+
+/// How many STATUS packets report that the printer is busy after a PRINT
+const PRINT_POLLS: u8 = 4;
+
+/// Paper fed per unit of margin, in pixel rows
+const MARGIN_ROWS: usize = 8;
+
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct Printer {
+    dir: PathBuf,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    status: u8,
+    busy_polls: u8,
+    /// Decompressed tile data received since the last print
+    buffer: Vec<u8>,
+    /// Shades of the current print job, one byte per pixel
+    job: Vec<u8>,
+}
+
+impl Printer {
+    /// Printouts are written as PNG files to `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Printer {
+        Printer {
+            dir: dir.into(),
+            state: State::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            status: 0,
+            busy_polls: 0,
+            buffer: Vec::new(),
+            job: Vec::new(),
+        }
+    }
+
+    /// Takes a byte shifted out by the Game Boy, returning the byte that is
+    /// shifted back in
+    pub fn exchange(&mut self, byte: u8) -> u8 {
+        let (next, reply) = match self.state {
+            State::Magic1 => (
+                if byte == 0x88 {
+                    State::Magic2
+                } else {
+                    State::Magic1
+                },
+                0x00,
+            ),
+            State::Magic2 => (
+                if byte == 0x33 {
+                    State::Command
+                } else {
+                    State::Magic1
+                },
+                0x00,
+            ),
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                (State::Compression, 0x00)
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                (State::LengthLow, 0x00)
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                (State::LengthHigh, 0x00)
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                let next = if self.length == 0 {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                };
+                (next, 0x00)
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                let next = if self.data.len() == self.length as usize {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                };
+                (next, 0x00)
+            }
+            State::ChecksumLow => {
+                self.checksum = self.checksum.wrapping_sub(byte as u16);
+                (State::ChecksumHigh, 0x00)
+            }
+            State::ChecksumHigh => {
+                self.checksum = self.checksum.wrapping_sub((byte as u16) << 8);
+
+                if self.checksum == 0 {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.handle_packet();
+                } else {
+                    log::warn!("Printer packet {:02x} has a bad checksum", self.command);
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+
+                (State::Alive, 0x00)
+            }
+            State::Alive => (State::Status, 0x81),
+            State::Status => (State::Magic1, self.status),
+        };
+
+        self.state = next;
+        reply
+    }
+
+    fn handle_packet(&mut self) {
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            DATA => {
+                let data = std::mem::take(&mut self.data);
+
+                if self.compressed {
+                    decompress(&data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+
+                self.buffer.truncate(BUFFER_SIZE);
+                self.data = data;
+
+                if self.length == 0 {
+                    // An empty packet marks the end of the image data
+                    self.status |= STATUS_IMAGE_FULL;
+                } else {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+            }
+            PRINT => {
+                let [sheets, margins, palette, _exposure] = match self.data[..] {
+                    [a, b, c, d] => [a, b, c, d],
+                    _ => [1, 0, 0xE4, 0],
+                };
+
+                self.print(sheets, margins >> 4, margins & 0x0F, palette);
+                self.status = STATUS_PRINTING | STATUS_IMAGE_FULL;
+                self.busy_polls = PRINT_POLLS;
+            }
+            BREAK => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            }
+            STATUS => {
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+
+                    if self.busy_polls == 0 {
+                        self.status &= !(STATUS_PRINTING | STATUS_IMAGE_FULL);
+                    }
+                }
+            }
+            command => log::warn!("Unknown printer command {:02x}", command),
+        }
+    }
+
+    fn print(&mut self, sheets: u8, margin_before: u8, margin_after: u8, palette: u8) {
+        // A palette of zero is treated like the usual one by the printer
+        let palette = if palette == 0 { 0xE4 } else { palette };
+
+        self.feed(margin_before);
+
+        // Zero sheets only feeds the paper
+        if sheets > 0 {
+            let rows = self.buffer.len() / (WIDTH / 8 * 16) * 8;
+
+            for y in 0..rows {
+                for x in 0..WIDTH {
+                    let tile = (y / 8) * (WIDTH / 8) + x / 8;
+                    let offset = tile * 16 + (y % 8) * 2;
+                    let bit = 7 - (x % 8);
+                    let lo = (self.buffer[offset] >> bit) & 1;
+                    let hi = (self.buffer[offset + 1] >> bit) & 1;
+                    let color = (hi << 1) | lo;
+                    let shade = (palette >> (color * 2)) & 0x03;
+                    self.job.push(SHADES[shade as usize]);
+                }
+            }
+        }
+
+        self.buffer.clear();
+        self.feed(margin_after);
+
+        if margin_after > 0 {
+            self.finish_job();
+        }
+    }
+
+    fn feed(&mut self, margin: u8) {
+        // Leading margins only matter between strips of the same printout
+        if !self.job.is_empty() {
+            let len = self.job.len() + margin as usize * MARGIN_ROWS * WIDTH;
+            self.job.resize(len, SHADES[0]);
+        }
+    }
+
+    /// Writes the current print job to a new file in the printout directory
+    fn finish_job(&mut self) {
+        if self.job.is_empty() {
+            return;
+        }
+
+        let job = std::mem::take(&mut self.job);
+
+        match write_png(&self.dir, job) {
+            Ok(path) => log::info!("Printed to {}", path.display()),
+            Err(e) => log::error!("Failed to write printout to {}: {}", self.dir.display(), e),
+        }
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.finish_job();
+    }
+}
+
+/// Runs of `0x80 | (n - 2)` followed by one byte repeated `n` times, and
+/// literals of `n - 1` followed by `n` bytes
+fn decompress(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+
+        if control & 0x80 != 0 {
+            let len = (control & 0x7F) as usize + 2;
+            let Some(&byte) = data.get(i) else {
+                break;
+            };
+            out.extend(std::iter::repeat_n(byte, len));
+            i += 1;
+        } else {
+            let len = control as usize + 1;
+            let end = (i + len).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+}
+
+fn write_png(dir: &Path, pixels: Vec<u8>) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let path = (1..)
+        .map(|n| dir.join(format!("printout-{:04}.png", n)))
+        .find(|path| !path.exists())
+        .unwrap();
+
+    let height = (pixels.len() / WIDTH) as u32;
+    let image = image::GrayImage::from_raw(WIDTH as u32, height, pixels).unwrap();
+
+    image
+        .save_with_format(&path, image::ImageFormat::Png)
+        .map_err(io::Error::other)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> u8 {
+        let mut packet = vec![command, compressed as u8];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(data);
+        let checksum = packet
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+
+        let mut bytes = vec![0x88, 0x33];
+        bytes.extend_from_slice(&packet);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+
+        for byte in bytes {
+            assert_eq!(printer.exchange(byte), 0x00);
+        }
+
+        assert_eq!(printer.exchange(0x00), 0x81);
+        printer.exchange(0x00)
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        let mut out = Vec::new();
+        decompress(&[0x82, 0xAA, 0x01, 0x12, 0x34, 0x80, 0xFF], &mut out);
+        assert_eq!(out, [0xAA, 0xAA, 0xAA, 0xAA, 0x12, 0x34, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn prints_a_strip() {
+        let mut printer = Printer::new(std::env::temp_dir().join("rustic-yellow-test"));
+
+        assert_eq!(send_packet(&mut printer, INIT, false, &[]), 0x00);
+
+        // Two rows of tiles, all black
+        let data = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFA, 0xFF];
+        let status = send_packet(&mut printer, DATA, true, &data);
+        assert_eq!(status, STATUS_UNPROCESSED);
+        assert_eq!(printer.buffer.len(), 640);
+
+        send_packet(&mut printer, DATA, false, &[]);
+
+        let status = send_packet(&mut printer, PRINT, false, &[1, 0x00, 0xE4, 0x40]);
+        assert_eq!(status & STATUS_PRINTING, STATUS_PRINTING);
+        assert_eq!(printer.job.len(), WIDTH * 16);
+        assert!(printer.job.iter().all(|&shade| shade == 0x00));
+
+        for _ in 0..PRINT_POLLS {
+            send_packet(&mut printer, STATUS, false, &[]);
+        }
+        assert_eq!(send_packet(&mut printer, STATUS, false, &[]), 0x00);
+
+        // Don't write the printout when dropped
+        printer.job.clear();
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut printer = Printer::new(std::env::temp_dir());
+
+        for byte in [0x88, 0x33, INIT, 0x00, 0x00, 0x00, 0x02, 0x00] {
+            printer.exchange(byte);
+        }
+
+        assert_eq!(printer.exchange(0x00), 0x81);
+        assert_eq!(printer.exchange(0x00), STATUS_CHECKSUM_ERROR);
+    }
+}
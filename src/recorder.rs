@@ -0,0 +1,271 @@
+//! Records everything the game plays to a WAV file.
+//!
+//! Both audio paths end up in the same recording: the emulated APU, whose
+//! output is handed over by [`Sound`](crate::sound::Sound) as it is mixed,
+//! and the music and sound effects that [`Sound2`](crate::sound2::Sound2)
+//! plays, which are pulled sample by sample. Time is measured in emulated
+//! ticks rather than on the wall clock, so a recording sounds the same no
+//! matter how fast the game ran, and headless runs record just like windowed
+//! ones.
+
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufWriter},
+    path::PathBuf,
+    rc::Rc,
+};
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+use rodio::source::UniformSourceIterator;
+
+use crate::{
+    frontend::{AudioSink, AudioSource, AudioVoice},
+    sound::CLOCKS_PER_SECOND,
+};
+
+const SAMPLE_RATE: u32 = 44100;
+
+/// Frames that are held back before being written, so that the APU output,
+/// which arrives in chunks of 2000 frames once they have been played, can
+/// still be mixed in at the right time
+const LATENCY: usize = 4096;
+
+/// Shared between the recorder and the handle returned from
+/// [`AudioRecorder::play`]
+struct VoiceState {
+    done: Cell<bool>,
+    volume: Cell<f32>,
+}
+
+struct Voice {
+    source: UniformSourceIterator<AudioSource, f32>,
+    state: Rc<VoiceState>,
+}
+
+impl Voice {
+    fn next_frame(&mut self) -> Option<[f32; 2]> {
+        if self.state.done.get() {
+            return None;
+        }
+
+        let volume = self.state.volume.get();
+
+        match (self.source.next(), self.source.next()) {
+            (Some(left), Some(right)) => Some([left * volume, right * volume]),
+            _ => {
+                self.state.done.set(true);
+                None
+            }
+        }
+    }
+}
+
+/// A voice that is both recorded and played on the output. It is done once
+/// it has been recorded to the end, since that follows the emulated time.
+struct RecordedVoice {
+    state: Rc<VoiceState>,
+    output: Box<dyn AudioVoice>,
+}
+
+impl AudioVoice for RecordedVoice {
+    fn stop(&self) {
+        self.state.done.set(true);
+        self.output.stop();
+    }
+
+    fn set_volume(&self, volume: f32) {
+        self.state.volume.set(volume);
+        self.output.set_volume(volume);
+    }
+
+    fn is_done(&self) -> bool {
+        self.state.done.get()
+    }
+}
+
+pub struct AudioRecorder {
+    path: PathBuf,
+    /// Gone after a write error, so that the error is only logged once
+    writer: Option<WavWriter<BufWriter<File>>>,
+    voices: Vec<Voice>,
+    pending: VecDeque<[f32; 2]>,
+    /// Ticks, multiplied by the sample rate, that don't make up a whole frame
+    remainder: u64,
+}
+
+impl AudioRecorder {
+    /// Starts a 16 bit stereo recording at `path`, which is finished when the
+    /// recorder is dropped
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<AudioRecorder> {
+        let path = path.into();
+
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+
+        let writer = WavWriter::create(&path, spec).map_err(into_io_error)?;
+
+        log::info!("Recording audio to {}", path.display());
+
+        Ok(AudioRecorder {
+            path,
+            writer: Some(writer),
+            voices: Vec::new(),
+            pending: VecDeque::with_capacity(LATENCY * 2),
+            remainder: 0,
+        })
+    }
+
+    /// Records `recorded` while playing `played` on `output`, which should
+    /// be two separately decoded copies of the same sound. Sharing one
+    /// between both would have to keep everything that one of them has
+    /// pulled and the other hasn't yet, which keeps growing since the
+    /// recording follows the emulated time and the output the wall clock.
+    pub fn play(
+        &mut self,
+        recorded: AudioSource,
+        played: AudioSource,
+        output: &mut dyn AudioSink,
+    ) -> Box<dyn AudioVoice> {
+        let state = Rc::new(VoiceState {
+            done: Cell::new(false),
+            volume: Cell::new(1.0),
+        });
+
+        self.voices.push(Voice {
+            source: UniformSourceIterator::new(recorded, 2, SAMPLE_RATE),
+            state: state.clone(),
+        });
+
+        Box::new(RecordedVoice {
+            state,
+            output: output.play(played),
+        })
+    }
+
+    /// Advances the recording by `ticks` of emulated time, pulling the frames
+    /// that are due from every voice
+    pub fn do_cycle(&mut self, ticks: u32) {
+        self.remainder += ticks as u64 * SAMPLE_RATE as u64;
+
+        while self.remainder >= CLOCKS_PER_SECOND as u64 {
+            self.remainder -= CLOCKS_PER_SECOND as u64;
+
+            let mut frame = [0.0; 2];
+            self.voices.retain_mut(|voice| match voice.next_frame() {
+                Some([left, right]) => {
+                    frame[0] += left;
+                    frame[1] += right;
+                    true
+                }
+                None => false,
+            });
+
+            self.pending.push_back(frame);
+        }
+
+        while self.pending.len() > LATENCY {
+            let frame = self.pending.pop_front().unwrap();
+            self.write(frame);
+        }
+    }
+
+    /// Mixes in APU output that was produced up until now
+    pub fn mix_apu(&mut self, samples: &[[f32; 2]]) {
+        let count = samples.len().min(self.pending.len());
+        let start = self.pending.len() - count;
+
+        for (frame, sample) in self
+            .pending
+            .range_mut(start..)
+            .zip(&samples[samples.len() - count..])
+        {
+            frame[0] += sample[0];
+            frame[1] += sample[1];
+        }
+    }
+
+    fn write(&mut self, frame: [f32; 2]) {
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+
+        let result = frame
+            .iter()
+            .try_for_each(|sample| writer.write_sample((sample.clamp(-1.0, 1.0) * 32767.0) as i16));
+
+        if let Err(e) = result {
+            log::error!("Error writing audio to {}: {}", self.path.display(), e);
+            self.writer = None;
+        }
+    }
+}
+
+impl Drop for AudioRecorder {
+    fn drop(&mut self) {
+        while let Some(frame) = self.pending.pop_front() {
+            self.write(frame);
+        }
+
+        let Some(writer) = self.writer.take() else {
+            return;
+        };
+
+        match writer.finalize() {
+            Ok(()) => log::info!("Wrote audio recording to {}", self.path.display()),
+            Err(e) => log::error!(
+                "Error finishing audio recording {}: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+fn into_io_error(error: hound::Error) -> io::Error {
+    match error {
+        hound::Error::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+    use crate::frontend::NullAudioSink;
+
+    #[test]
+    fn mixes_voices_and_apu_in_emulated_time() {
+        let path = std::env::temp_dir().join("rustic-yellow-recorder-test.wav");
+        let mut recorder = AudioRecorder::create(&path).unwrap();
+
+        // A mono voice at half the sample rate, lasting a tenth of a second
+        let voice = || Box::new(SamplesBuffer::new(1, SAMPLE_RATE / 2, vec![0.25f32; 2205]));
+        recorder.play(voice(), voice(), &mut NullAudioSink);
+
+        // One second, in uneven steps
+        for _ in 0..(CLOCKS_PER_SECOND / 1000) {
+            recorder.do_cycle(1000);
+        }
+        recorder.do_cycle(CLOCKS_PER_SECOND % 1000);
+
+        recorder.mix_apu(&[[0.5, -0.5]; 100]);
+        drop(recorder);
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(samples.len(), 2 * SAMPLE_RATE as usize);
+        assert_eq!(&samples[..2], &[8191, 8191]);
+        assert_eq!(&samples[10000..10002], &[0, 0]);
+        assert_eq!(&samples[samples.len() - 2..], &[16383, -16383]);
+    }
+}
@@ -1,2 +1,246 @@
-pub const ROM: &'static [u8] = include_bytes!("../pokeyellow.gbc");
-pub const CRYSTAL_ROM: &'static [u8] = include_bytes!("../pokecrystal.gbc");
+//! The original ROMs, which can't be distributed with the game and are instead
+//! read from disk at startup.
+//!
+//! Each ROM is looked for in this order:
+//!
+//! 1. the path given on the command line (`--rom` and `--crystal-rom`),
+//! 2. the path in the config file (`rom` and `crystal_rom`),
+//! 3. the data directory, next to the `saves` directory,
+//! 4. the resources directory, or the current directory.
+//!
+//! The first file that is found has to match the expected SHA1 hash.
+
+use std::{error, fmt, fs, io, ops::Deref, path::PathBuf, sync::OnceLock};
+
+use crate::{config::Config, game::resources_root, saves};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomKind {
+    Yellow,
+    Crystal,
+}
+
+impl RomKind {
+    pub fn title(self) -> &'static str {
+        match self {
+            RomKind::Yellow => "Pokemon Yellow",
+            RomKind::Crystal => "Pokemon Crystal",
+        }
+    }
+
+    pub fn file_name(self) -> &'static str {
+        match self {
+            RomKind::Yellow => "pokeyellow.gbc",
+            RomKind::Crystal => "pokecrystal.gbc",
+        }
+    }
+
+    pub fn sha1(self) -> &'static str {
+        match self {
+            RomKind::Yellow => "cc7d03262ebfaf2f06772c1a480c7d9d5f4a38e1",
+            RomKind::Crystal => "f4cd194bdee0d04ca4eac29e09b8e4e9d818c133",
+        }
+    }
+
+    fn flag(self) -> &'static str {
+        match self {
+            RomKind::Yellow => "--rom",
+            RomKind::Crystal => "--crystal-rom",
+        }
+    }
+}
+
+/// A ROM that is available as a byte slice once it has been loaded
+pub struct Rom {
+    kind: RomKind,
+    data: OnceLock<Box<[u8]>>,
+}
+
+pub static ROM: Rom = Rom::new(RomKind::Yellow);
+pub static CRYSTAL_ROM: Rom = Rom::new(RomKind::Crystal);
+
+impl Rom {
+    const fn new(kind: RomKind) -> Rom {
+        Rom {
+            kind,
+            data: OnceLock::new(),
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.data.get().is_some()
+    }
+
+    /// Loads the ROM from `path`, or from the first default location where it
+    /// exists. Does nothing if the ROM has already been loaded.
+    fn load(&self, path: Option<PathBuf>) -> Result<(), RomError> {
+        if self.is_loaded() {
+            return Ok(());
+        }
+
+        let candidates = match path {
+            Some(path) => vec![path],
+            None => vec![
+                saves::get_rom_path(self.kind.file_name()),
+                resources_root()
+                    .or_else(|| std::env::current_dir().ok())
+                    .unwrap_or_default()
+                    .join(self.kind.file_name()),
+            ],
+        };
+
+        let Some(path) = candidates.iter().find(|path| path.is_file()) else {
+            return Err(RomError::NotFound {
+                kind: self.kind,
+                searched: candidates,
+            });
+        };
+
+        let data = fs::read(path).map_err(|error| RomError::Read {
+            kind: self.kind,
+            path: path.clone(),
+            error,
+        })?;
+
+        let sha1 = sha1_smol::Sha1::from(&data).digest().to_string();
+
+        if sha1 != self.kind.sha1() {
+            return Err(RomError::Mismatch {
+                kind: self.kind,
+                path: path.clone(),
+                sha1,
+            });
+        }
+
+        log::info!("Loaded {} ROM from {}", self.kind.title(), path.display());
+
+        // Someone else might have loaded the same ROM in the meantime
+        let _ = self.data.set(data.into_boxed_slice());
+        Ok(())
+    }
+}
+
+impl Deref for Rom {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.data.get() {
+            Some(data) => data,
+            None => panic!(
+                "The {} ROM has not been loaded, call rustic_yellow::load_roms or build a Game first",
+                self.kind.title()
+            ),
+        }
+    }
+}
+
+/// Loads both ROMs, using the given paths if any, or the configured or default
+/// locations otherwise
+pub fn load_roms(yellow: Option<PathBuf>, crystal: Option<PathBuf>) -> Result<(), RomError> {
+    let config = Config::load().map_err(|error| RomError::Config {
+        path: Config::path(),
+        error,
+    })?;
+
+    ROM.load(yellow.or(config.rom))?;
+    CRYSTAL_ROM.load(crystal.or(config.crystal_rom))?;
+
+    Ok(())
+}
+
+/// Loads both ROMs from the configured or default locations, unless
+/// `load_roms` already did
+pub(crate) fn ensure_loaded() -> Result<(), RomError> {
+    if ROM.is_loaded() && CRYSTAL_ROM.is_loaded() {
+        return Ok(());
+    }
+
+    load_roms(None, None)
+}
+
+/// For tests that read from the ROMs, which have to be in one of the default
+/// locations. Returns false if they aren't, so that the test can skip itself
+/// in a checkout without them.
+#[cfg(test)]
+pub fn load_for_tests() -> bool {
+    match ensure_loaded() {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Skipping, the test needs the ROMs: {}", e);
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    Config {
+        path: PathBuf,
+        error: io::Error,
+    },
+    NotFound {
+        kind: RomKind,
+        searched: Vec<PathBuf>,
+    },
+    Read {
+        kind: RomKind,
+        path: PathBuf,
+        error: io::Error,
+    },
+    Mismatch {
+        kind: RomKind,
+        path: PathBuf,
+        sha1: String,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RomError::Config { path, error } => {
+                write!(
+                    f,
+                    "Failed to read config file {}: {}",
+                    path.display(),
+                    error
+                )
+            }
+            RomError::NotFound { kind, searched } => {
+                write!(f, "Could not find the {} ROM, looked for:", kind.title())?;
+                for path in searched {
+                    write!(f, "\n  {}", path.display())?;
+                }
+                write!(
+                    f,
+                    "\nPass its location with {} or set it in {}",
+                    kind.flag(),
+                    Config::path().display()
+                )
+            }
+            RomError::Read { kind, path, error } => write!(
+                f,
+                "Failed to read the {} ROM from {}: {}",
+                kind.title(),
+                path.display(),
+                error
+            ),
+            RomError::Mismatch { kind, path, sha1 } => write!(
+                f,
+                "{} is not the expected {} ROM\n  SHA1 is       {}\n  expected SHA1 {}",
+                path.display(),
+                kind.title(),
+                sha1,
+                kind.sha1()
+            ),
+        }
+    }
+}
+
+impl error::Error for RomError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RomError::Config { error, .. } | RomError::Read { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -3,6 +3,8 @@ use std::{
     path::PathBuf,
 };
 
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
 mod r#box;
 mod party;
 mod species;
@@ -114,6 +116,14 @@ impl SaveState {
         std::fs::write(path, &self.data)
     }
 
+    pub fn from_bytes(data: [u8; 0x8000]) -> SaveState {
+        SaveState { data }
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 0x8000] {
+        &self.data
+    }
+
     pub fn byte(&self, addr: usize) -> u8 {
         self.data[addr]
     }
@@ -122,6 +132,14 @@ impl SaveState {
         self.data[addr] = value;
     }
 
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_bytes(&self.data);
+    }
+
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        r.read_bytes(&mut self.data)
+    }
+
     pub fn player_name(&self) -> PokeString {
         PokeString::from_bytes(&self.data[0x2598..], 11)
     }
@@ -396,6 +396,10 @@ mod test {
 
     #[test]
     fn test_from_boxed_pokemon() {
+        if !crate::rom::load_for_tests() {
+            return;
+        }
+
         let ot_name = PokeString::from_bytes(&[0x80, 0x50], 2);
 
         assert_eq!(
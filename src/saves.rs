@@ -5,18 +5,43 @@
 use std::{env, ffi::OsString, fs, io::Result, path::PathBuf};
 
 #[cfg(target_os = "macos")]
-fn get_save_dir() -> PathBuf {
-    PathBuf::from(env::var("HOME").unwrap()).join("Library/Application Support/Rustic Yellow/saves")
+fn get_data_dir() -> PathBuf {
+    PathBuf::from(env::var("HOME").unwrap()).join("Library/Application Support/Rustic Yellow")
 }
 
 #[cfg(target_os = "linux")]
-fn get_save_dir() -> PathBuf {
-    PathBuf::from(env::var("HOME").unwrap()).join(".Rustic Yellow/saves")
+fn get_data_dir() -> PathBuf {
+    PathBuf::from(env::var("HOME").unwrap()).join(".Rustic Yellow")
 }
 
 #[cfg(target_os = "windows")]
+fn get_data_dir() -> PathBuf {
+    PathBuf::from(env::var("appdata").unwrap()).join("Rustic Yellow")
+}
+
 fn get_save_dir() -> PathBuf {
-    PathBuf::from(env::var("appdata").unwrap()).join("Rustic Yellow\\saves")
+    get_data_dir().join("saves")
+}
+
+/// Where the ROMs are looked for when no other location is configured
+pub fn get_rom_path(file_name: &str) -> PathBuf {
+    get_data_dir().join(file_name)
+}
+
+pub fn get_printout_dir() -> PathBuf {
+    get_data_dir().join("printouts")
+}
+
+pub fn get_capture_dir() -> PathBuf {
+    get_data_dir().join("captures")
+}
+
+pub fn get_config_path() -> PathBuf {
+    get_data_dir().join("config.toml")
+}
+
+pub fn get_bindings_path() -> PathBuf {
+    get_data_dir().join("bindings.toml")
 }
 
 pub struct SaveFile {
@@ -33,6 +58,10 @@ pub fn get_save_path(name: &str) -> PathBuf {
     get_save_dir().join(name).with_extension(ext)
 }
 
+pub fn get_snapshot_dir() -> PathBuf {
+    get_data_dir().join("snapshots")
+}
+
 pub fn save_is_free(name: &str) -> bool {
     !get_save_path(name).exists()
 }
@@ -1,7 +1,34 @@
+use std::{io, task::Poll};
+
+use crate::{
+    link::Link,
+    printer::Printer,
+    snapshot::{SnapshotReader, SnapshotWriter},
+};
+
+/// Check the link cable for transfers started by the other side this often,
+/// in ticks, which is a few times per byte transferred at the normal speed
+const LINK_POLL_INTERVAL: u32 = 1024;
+
+/// What is plugged into the serial port
+pub enum SerialDevice {
+    /// Another instance of the game
+    Link(Link),
+    Printer(Printer),
+}
+
 pub struct Serial {
     data: u8,
     control: u8,
     pub interrupt: u8,
+    /// Ticks until the current transfer is done
+    remaining: u32,
+    /// Byte that the other side shifted out, when it drives the clock
+    incoming: Option<u8>,
+    /// Transfer that we started over the link cable, waiting for the reply
+    outgoing: Option<u8>,
+    device: Option<SerialDevice>,
+    poll_countdown: u32,
 }
 
 impl Serial {
@@ -10,6 +37,24 @@ impl Serial {
             data: 0,
             control: 0,
             interrupt: 0,
+            remaining: 0,
+            incoming: None,
+            outgoing: None,
+            device: None,
+            poll_countdown: 0,
+        }
+    }
+
+    pub fn plug(&mut self, device: SerialDevice) {
+        self.device = Some(device);
+    }
+
+    /// 8 bits at 8192 Hz, or at 262144 Hz with the fast clock on CGB
+    fn transfer_ticks(&self) -> u32 {
+        if self.control & 0x02 != 0 {
+            8 * 16
+        } else {
+            8 * 512
         }
     }
 
@@ -19,8 +64,14 @@ impl Serial {
             0xFF02 => {
                 self.control = v;
 
-                if v == 0x81 {
-                    // TODO: Send data somewhere?
+                // Transfer with the internal clock, the one with the external
+                // clock waits for the other side to start it
+                if v & 0x81 == 0x81 {
+                    self.remaining = self.transfer_ticks();
+                    self.outgoing = match self.device.as_mut() {
+                        Some(SerialDevice::Link(link)) => link.start_transfer(self.data),
+                        _ => None,
+                    };
                 }
             }
             _ => panic!("Serial does not handle address {:4X} (write)", a),
@@ -34,4 +85,101 @@ impl Serial {
             _ => panic!("Serial does not handle address {:4X} (read)", a),
         }
     }
+
+    pub fn do_cycle(&mut self, ticks: u32) {
+        if let Some(SerialDevice::Link(_)) = self.device {
+            self.poll_countdown = self.poll_countdown.saturating_sub(ticks);
+
+            if self.poll_countdown == 0 {
+                self.poll_countdown = LINK_POLL_INTERVAL;
+                self.poll_link();
+
+                // Our transfer takes as long as the other side needs to reply
+                if self.remaining == 0 && self.outgoing.is_some() {
+                    self.finish_transfer();
+                }
+            }
+        }
+
+        if self.remaining == 0 {
+            return;
+        }
+
+        self.remaining = self.remaining.saturating_sub(ticks);
+
+        if self.remaining == 0 {
+            self.finish_transfer();
+        }
+    }
+
+    fn poll_link(&mut self) {
+        let transfer_ticks = self.transfer_ticks();
+        let Some(SerialDevice::Link(link)) = self.device.as_mut() else {
+            return;
+        };
+
+        while let Some((seq, value)) = link.poll() {
+            let waiting = self.control & 0x81 == 0x80 && self.incoming.is_none();
+
+            if waiting {
+                // Our side finishes when the other side's clock does
+                link.reply(seq, self.data);
+                self.incoming = Some(value);
+                self.remaining = transfer_ticks;
+            } else {
+                link.reply(seq, 0xFF);
+            }
+        }
+    }
+
+    fn finish_transfer(&mut self) {
+        self.data = if self.control & 0x01 != 0 {
+            // Nothing is shifted in when no one is on the other end
+            match (self.outgoing.take(), self.device.as_mut()) {
+                (Some(seq), Some(SerialDevice::Link(link))) => match link.finish_transfer(seq) {
+                    Poll::Ready(value) => value.unwrap_or(0xFF),
+                    Poll::Pending => {
+                        self.outgoing = Some(seq);
+                        return;
+                    }
+                },
+                (_, Some(SerialDevice::Printer(printer))) => printer.exchange(self.data),
+                _ => 0xFF,
+            }
+        } else {
+            self.incoming.take().unwrap_or(0xFF)
+        };
+
+        self.control &= 0x7F;
+        self.interrupt |= 0x08;
+    }
+
+    /// The device itself is not part of the snapshot, so a link cable
+    /// transfer in progress finishes as if the cable had been disconnected
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.data);
+        w.write_u8(self.control);
+        w.write_u8(self.interrupt);
+        w.write_u32(self.remaining);
+        w.write_bool(self.incoming.is_some());
+        w.write_u8(self.incoming.unwrap_or(0xFF));
+    }
+
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.data = r.read_u8()?;
+        self.control = r.read_u8()?;
+        self.interrupt = r.read_u8()?;
+        self.remaining = r.read_u32()?;
+        let has_incoming = r.read_bool()?;
+        let incoming = r.read_u8()?;
+        self.incoming = has_incoming.then_some(incoming);
+        self.outgoing = None;
+
+        // Finish a transfer that was waiting for its reply right away
+        if self.control & 0x81 == 0x81 && self.remaining == 0 {
+            self.remaining = 1;
+        }
+
+        Ok(())
+    }
 }
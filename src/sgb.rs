@@ -0,0 +1,275 @@
+//! The parts of the Super Game Boy that Yellow uses for its border: command
+//! packets, which are sent by pulsing the joypad register, and the VRAM
+//! transfers that carry the border tiles, map and palettes.
+
+use crate::{
+    gpu::{GB_SCREEN_H, GB_SCREEN_W},
+    palette::{sgb_color, Color},
+};
+
+/// Size of the Super Game Boy screen, with the Game Boy screen in the middle
+pub const SGB_SCREEN_W: usize = 256;
+pub const SGB_SCREEN_H: usize = 224;
+
+/// Where the Game Boy screen is within the border
+const GB_SCREEN_X: usize = 48;
+const GB_SCREEN_Y: usize = 40;
+
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+
+/// How many bytes a VRAM transfer copies
+pub const TRANSFER_SIZE: usize = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Border tiles 0x00 to 0x7f, or 0x80 to 0xff when `high` is set
+    ChrTrn { high: bool },
+    /// Border map and palettes
+    PctTrn,
+    /// Anything the border doesn't need
+    Other(u8),
+}
+
+impl Command {
+    fn parse(data: &[u8]) -> Command {
+        match data[0] >> 3 {
+            CHR_TRN => Command::ChrTrn {
+                high: data[1] & 0x01 != 0,
+            },
+            PCT_TRN => Command::PctTrn,
+            other => Command::Other(other),
+        }
+    }
+}
+
+/// Reassembles packets from writes to P1. A pulse with both lines low starts
+/// a packet, and each following pulse on P15 is a 1 and on P14 a 0, least
+/// significant bit first. A packet is 16 bytes and a 0 stop bit, and the low
+/// bits of the first byte say how many packets the command has.
+#[derive(Default)]
+pub struct PacketReceiver {
+    /// The previous value of the select lines
+    lines: u8,
+    /// Bits of the current packet so far, `None` between packets
+    bits: Option<usize>,
+    packet: [u8; 16],
+    /// Packets of the current command so far
+    data: Vec<u8>,
+}
+
+impl PacketReceiver {
+    pub fn write(&mut self, value: u8) -> Option<Command> {
+        let lines = value & 0x30;
+        let previous = std::mem::replace(&mut self.lines, lines);
+
+        if lines == 0x00 {
+            self.bits = Some(0);
+            self.packet = [0; 16];
+            return None;
+        }
+
+        // Bits are sent as a pulse from both lines high
+        if previous != 0x30 || lines == 0x30 {
+            return None;
+        }
+
+        let bits = self.bits?;
+
+        if bits < 128 {
+            if lines == 0x10 {
+                self.packet[bits / 8] |= 1 << (bits % 8);
+            }
+
+            self.bits = Some(bits + 1);
+            return None;
+        }
+
+        self.bits = None;
+
+        if lines != 0x20 {
+            log::debug!("Dropping SGB packet without a stop bit");
+            self.data.clear();
+            return None;
+        }
+
+        self.data.extend_from_slice(&self.packet);
+
+        let count = (self.data[0] & 0x07).max(1) as usize;
+
+        if self.data.len() < count * 16 {
+            return None;
+        }
+
+        let command = Command::parse(&self.data);
+        self.data.clear();
+        Some(command)
+    }
+}
+
+/// The border as sent by the game, which is black until it is transferred
+pub struct Border {
+    /// 256 tiles in the SNES 4 bits per pixel format
+    tiles: Vec<u8>,
+    /// 32x32 map entries, followed by four palettes of 16 colors
+    map: Vec<u8>,
+    /// The color that shows through where the border is transparent
+    backdrop: Color,
+    /// The border at `scale` with the Game Boy screen in the middle
+    frame: Vec<u8>,
+    scale: usize,
+    stale: bool,
+}
+
+impl Border {
+    pub fn new() -> Border {
+        Border {
+            tiles: vec![0; 2 * TRANSFER_SIZE],
+            map: vec![0; TRANSFER_SIZE],
+            backdrop: [0; 3],
+            frame: Vec::new(),
+            scale: 0,
+            stale: true,
+        }
+    }
+
+    pub fn transfer(&mut self, command: Command, data: &[u8]) {
+        match command {
+            Command::ChrTrn { high: false } => self.tiles[..TRANSFER_SIZE].copy_from_slice(data),
+            Command::ChrTrn { high: true } => self.tiles[TRANSFER_SIZE..].copy_from_slice(data),
+            Command::PctTrn => self.map.copy_from_slice(data),
+            Command::Other(_) => return,
+        }
+
+        self.stale = true;
+    }
+
+    pub fn set_backdrop(&mut self, backdrop: Color) {
+        if self.backdrop != backdrop {
+            self.backdrop = backdrop;
+            self.stale = true;
+        }
+    }
+
+    /// Puts the Game Boy screen, at `scale` times the native resolution,
+    /// inside the border
+    pub fn frame(&mut self, screen: &[u8], scale: usize) -> &[u8] {
+        if self.stale || self.scale != scale {
+            self.render(scale);
+        }
+
+        let width = SGB_SCREEN_W * scale * 3;
+        let row_len = GB_SCREEN_W * scale * 3;
+
+        debug_assert_eq!(screen.len(), row_len * GB_SCREEN_H * scale);
+
+        for (y, row) in screen.chunks_exact(row_len).enumerate() {
+            let start = (GB_SCREEN_Y * scale + y) * width + GB_SCREEN_X * scale * 3;
+            self.frame[start..start + row_len].copy_from_slice(row);
+        }
+
+        &self.frame
+    }
+
+    fn render(&mut self, scale: usize) {
+        self.scale = scale;
+        self.stale = false;
+
+        let width = SGB_SCREEN_W * scale;
+        self.frame.clear();
+        self.frame.resize(width * SGB_SCREEN_H * scale * 3, 0);
+
+        for y in 0..SGB_SCREEN_H {
+            for x in 0..SGB_SCREEN_W {
+                let color = self.pixel(x, y);
+
+                for dy in 0..scale {
+                    let start = ((y * scale + dy) * width + x * scale) * 3;
+
+                    for pixel in self.frame[start..start + scale * 3].chunks_exact_mut(3) {
+                        pixel.copy_from_slice(&color);
+                    }
+                }
+            }
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Color {
+        let entry = (y / 8) * 32 + x / 8;
+        let entry = u16::from_le_bytes([self.map[entry * 2], self.map[entry * 2 + 1]]);
+
+        let tile = (entry & 0xff) as usize;
+        let palette = ((entry >> 10) & 0x03) as usize;
+        let tx = if entry & 0x4000 != 0 {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        let ty = if entry & 0x8000 != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+
+        let planes = &self.tiles[tile * 32..tile * 32 + 32];
+        let bit = 7 - tx;
+        let index = ((planes[ty * 2] >> bit) & 1)
+            | (((planes[ty * 2 + 1] >> bit) & 1) << 1)
+            | (((planes[16 + ty * 2] >> bit) & 1) << 2)
+            | (((planes[16 + ty * 2 + 1] >> bit) & 1) << 3);
+
+        if index == 0 {
+            return self.backdrop;
+        }
+
+        let offset = 0x800 + (palette * 16 + index as usize) * 2;
+        let color = u16::from_le_bytes([self.map[offset], self.map[offset + 1]]);
+
+        sgb_color([color, color >> 5, color >> 10].map(|c| (c & 0x1f) as u8))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(receiver: &mut PacketReceiver, packet: &[u8; 16]) -> Option<Command> {
+        let mut result = receiver.write(0x00);
+        result = result.or(receiver.write(0x30));
+
+        for byte in packet {
+            for bit in 0..8 {
+                let lines = if byte & (1 << bit) != 0 { 0x10 } else { 0x20 };
+                result = result.or(receiver.write(lines));
+                result = result.or(receiver.write(0x30));
+            }
+        }
+
+        result = result.or(receiver.write(0x20));
+        result.or(receiver.write(0x30))
+    }
+
+    #[test]
+    fn receives_packets_from_the_joypad_register() {
+        let mut receiver = PacketReceiver::default();
+
+        // Reading the joypad in between packets doesn't send anything
+        assert_eq!(receiver.write(0x20), None);
+        assert_eq!(receiver.write(0x10), None);
+        assert_eq!(receiver.write(0x30), None);
+
+        let mut chr_trn = [0; 16];
+        chr_trn[0] = (CHR_TRN << 3) | 1;
+        chr_trn[1] = 1;
+        assert_eq!(
+            send(&mut receiver, &chr_trn),
+            Some(Command::ChrTrn { high: true })
+        );
+
+        // ATTR_BLK with two packets
+        let mut attr_blk = [0; 16];
+        attr_blk[0] = (0x04 << 3) | 2;
+        assert_eq!(send(&mut receiver, &attr_blk), None);
+        assert_eq!(send(&mut receiver, &[0xff; 16]), Some(Command::Other(0x04)));
+    }
+}
@@ -0,0 +1,393 @@
+//! Quick save states.
+//!
+//! A snapshot holds the complete emulated machine: CPU registers, WRAM/HRAM,
+//! VRAM/OAM and the LCD registers, the timer, joypad, serial port, the APU
+//! channels, and the cartridge RAM and bank registers. It is written as a
+//! small versioned binary file, one file per save and slot.
+//!
+//! Since a lot of the game is implemented in Rust, part of the machine state
+//! lives on the Rust call stack whenever a hook is running. Snapshots are thus
+//! only taken and restored at a *safe point*, where nothing but the outermost
+//! `Cpu::call` from `Game::boot` is active:
+//!
+//! - between two interpreted instructions, when no hook is running, or
+//! - at the top of the overworld loop, which is re-entered from scratch.
+//!
+//! Requests made elsewhere (e.g. during a battle or in a menu implemented in
+//! Rust) are held until the next safe point is reached.
+
+use std::{fs, io, path::Path};
+
+const MAGIC: &[u8; 4] = b"RYSS";
+
+/// Bump whenever the layout of any component changes
+const VERSION: u16 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SnapshotRequest {
+    Save(u8),
+    Load(u8),
+}
+
+/// Panic payload used to unwind back to `Game::boot` after a snapshot has
+/// been loaded, discarding whatever hooks were running on the Rust stack.
+pub struct SnapshotRestored;
+
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> SnapshotWriter {
+        let mut data = Vec::with_capacity(0x20000);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        SnapshotWriter { data }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Writes a length prefixed string
+    pub fn write_str(&mut self, value: &str) {
+        self.write_u32(value.len() as u32);
+        self.write_bytes(value.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_to_file(self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        fs::write(path, self.data)
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> io::Result<SnapshotReader<'a>> {
+        let mut reader = SnapshotReader { data };
+
+        let mut magic = [0; 4];
+        reader.read_bytes(&mut magic)?;
+
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a snapshot file",
+            ));
+        }
+
+        let version = reader.read_u16()?;
+
+        if version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported snapshot version {version} (expected {VERSION})"),
+            ));
+        }
+
+        Ok(reader)
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Snapshot is truncated",
+            ));
+        }
+
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> io::Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> io::Result<()> {
+        out.copy_from_slice(self.take(out.len())?);
+        Ok(())
+    }
+
+    pub fn read_str(&mut self) -> io::Result<String> {
+        let len = self.read_u32()? as usize;
+
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Fails if there is data left over, which means that the snapshot was
+    /// written with a different layout than the one we are reading
+    pub fn finish(self) -> io::Result<()> {
+        if !self.data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected trailing data in snapshot",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        cpu::Cpu,
+        hooks::Hook,
+        keypad::{KeyboardEvent, KeyboardKey},
+        Game, Headless,
+    };
+
+    /// Where a new game starts
+    const REDS_HOUSE_2F: u8 = 0x26;
+
+    /// How many times the overworld loop has called LoadGBPal in the
+    /// player's room
+    static LOOPS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_loop(cpu: &mut Cpu) {
+        if cpu.borrow_wram().cur_map() == REDS_HOUSE_2F {
+            LOOPS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn tap(key: KeyboardKey, shift: bool) -> Vec<KeyboardEvent> {
+        vec![
+            KeyboardEvent::Down { key, shift },
+            KeyboardEvent::Up { key },
+        ]
+    }
+
+    /// Lets the player stand still until a step runs LoadGBPal, which leaves
+    /// the overworld loop at the same place every time
+    fn stand_still(headless: &mut Headless) {
+        for _ in 0..30 {
+            headless.step_frame(&[]);
+        }
+
+        let loops = LOOPS.load(Ordering::Relaxed);
+        for _ in 0..10 {
+            headless.step_frame(&[]);
+            if LOOPS.load(Ordering::Relaxed) != loops {
+                return;
+            }
+        }
+
+        panic!("Not in the overworld loop");
+    }
+
+    /// The snapshot in a slot, whatever the name of the save
+    fn slot_path(dir: &Path, slot: u8) -> Option<PathBuf> {
+        let suffix = format!("-{}.snap", slot);
+
+        fs::read_dir(dir)
+            .ok()?
+            .flatten()
+            .find(|entry| entry.file_name().to_string_lossy().ends_with(&suffix))
+            .map(|entry| entry.path())
+    }
+
+    fn read_slot(dir: &Path, slot: u8) -> Option<Vec<u8>> {
+        slot_path(dir, slot).map(|path| fs::read(path).unwrap())
+    }
+
+    /// Reads the snapshot in a slot and removes it, so that it can be saved
+    /// again
+    fn take_slot(dir: &Path, slot: u8) -> Option<Vec<u8>> {
+        let path = slot_path(dir, slot)?;
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        Some(data)
+    }
+
+    /// Steps through `script`, returning every frame
+    fn play(headless: &mut Headless, script: &[Vec<KeyboardEvent>]) -> Vec<Vec<u8>> {
+        script
+            .iter()
+            .map(|input| headless.step_frame(input).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn restores_the_running_game_exactly() {
+        if !crate::rom::load_for_tests() {
+            return;
+        }
+
+        let dir = std::env::temp_dir().join("rustic-yellow-snapshot-test");
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut headless = Game::builder()
+            .without_audio()
+            .snapshot_dir(&dir)
+            .hook(0x00, 0x1e6f, Hook::observer("count_loop", count_loop))
+            .build_headless()
+            .unwrap();
+
+        // Through the intro and Oak's speech into the player's room, and out
+        // of the start menu in case the last Start opened it
+        let mut frames = 0;
+        while LOOPS.load(Ordering::Relaxed) == 0 {
+            assert!(frames < 20000, "Never reached the overworld");
+
+            let key = match frames / 10 % 2 {
+                0 => KeyboardKey::Z,
+                _ => KeyboardKey::Return,
+            };
+
+            let input = match frames % 10 {
+                0 => tap(key, false),
+                _ => vec![],
+            };
+
+            headless.step_frame(&input);
+            frames += 1;
+        }
+
+        for frame in 0..60 {
+            let input = match frame % 20 {
+                0 => tap(KeyboardKey::X, false),
+                _ => vec![],
+            };
+
+            headless.step_frame(&input);
+        }
+
+        // Saving happens at the next safe point, in the overworld loop
+        stand_still(&mut headless);
+        headless.step_frame(&tap(KeyboardKey::F1, true));
+        let mut delay = 0;
+        while read_slot(&dir, 1).is_none() {
+            assert!(delay < 10, "Never saved the snapshot");
+            headless.step_frame(&[]);
+            delay += 1;
+        }
+        let saved = headless.frame().to_vec();
+
+        // Around the room, and then saving again
+        use KeyboardKey::{Down, Left, Right, Up};
+        let mut script = Vec::new();
+        for key in [Left, Up, Right, Right, Down] {
+            script.push(vec![KeyboardEvent::Down { key, shift: false }]);
+            script.extend(vec![vec![]; 16]);
+            script.push(vec![KeyboardEvent::Up { key }]);
+            script.extend(vec![vec![]; 16]);
+        }
+        script.push(tap(KeyboardKey::F2, true));
+        script.extend(vec![vec![]; 20]);
+
+        let expected = play(&mut headless, &script);
+        let snapshot = take_slot(&dir, 2).expect("Never saved the second snapshot");
+
+        // Loading is held until the same place in the overworld loop as
+        // saving was
+        stand_still(&mut headless);
+        headless.step_frame(&tap(KeyboardKey::F1, false));
+        for _ in 0..delay {
+            headless.step_frame(&[]);
+        }
+        assert!(headless.frame() == saved, "Different frame after loading");
+
+        assert!(play(&mut headless, &script) == expected, "Different frames");
+        assert!(take_slot(&dir, 2) == Some(snapshot), "Different snapshot");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = SnapshotWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789a_bcde);
+        writer.write_i32(-1);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_str("RED.sav");
+        let data = writer.into_bytes();
+
+        let mut reader = SnapshotReader::new(&data).unwrap();
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert!(reader.read_bool().unwrap());
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_u32().unwrap(), 0x789a_bcde);
+        assert_eq!(reader.read_i32().unwrap(), -1);
+        let mut bytes = [0; 3];
+        reader.read_bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [1, 2, 3]);
+        assert_eq!(reader.read_str().unwrap(), "RED.sav");
+        reader.finish().unwrap();
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = SnapshotWriter::new().into_bytes();
+        data[4] = data[4].wrapping_add(1);
+
+        assert!(SnapshotReader::new(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let mut writer = SnapshotWriter::new();
+        writer.write_u32(42);
+        let data = writer.into_bytes();
+
+        let mut reader = SnapshotReader::new(&data[..data.len() - 1]).unwrap();
+        assert!(reader.read_u32().is_err());
+    }
+}
@@ -1,12 +1,16 @@
+use std::io;
+
 use blip_buf::BlipBuf;
 
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
 const WAVE_PATTERN: [[i32; 8]; 4] = [
     [-1, -1, -1, -1, 1, -1, -1, -1],
     [-1, -1, -1, -1, 1, 1, -1, -1],
     [-1, -1, 1, 1, 1, 1, -1, -1],
     [1, 1, 1, 1, -1, -1, 1, 1],
 ];
-const CLOCKS_PER_SECOND: u32 = 1 << 22;
+pub(crate) const CLOCKS_PER_SECOND: u32 = 1 << 22;
 const CLOCKS_PER_FRAME: u32 = CLOCKS_PER_SECOND / 512;
 const OUTPUT_SAMPLE_COUNT: usize = 2000; // this should be less than blip_buf::MAX_FRAME
 const SWEEP_DELAY_ZERO_PERIOD: u8 = 8;
@@ -34,6 +38,23 @@ impl VolumeEnvelope {
         }
     }
 
+    fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.period);
+        w.write_bool(self.goes_up);
+        w.write_u8(self.delay);
+        w.write_u8(self.initial_volume);
+        w.write_u8(self.volume);
+    }
+
+    fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.period = r.read_u8()?;
+        self.goes_up = r.read_bool()?;
+        self.delay = r.read_u8()?;
+        self.initial_volume = r.read_u8()?;
+        self.volume = r.read_u8()?;
+        Ok(())
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF12 | 0xFF17 | 0xFF21 => {
@@ -93,6 +114,17 @@ impl LengthCounter {
         }
     }
 
+    fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_bool(self.enabled);
+        w.write_u16(self.value);
+    }
+
+    fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.enabled = r.read_bool()?;
+        self.value = r.read_u16()?;
+        Ok(())
+    }
+
     fn is_active(&self) -> bool {
         self.value > 0
     }
@@ -183,6 +215,48 @@ impl SquareChannel {
         self.active
     }
 
+    fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_bool(self.active);
+        w.write_bool(self.dac_enabled);
+        w.write_u8(self.duty);
+        w.write_u8(self.phase);
+        self.length.save_snapshot(w);
+        w.write_u16(self.frequency);
+        w.write_u32(self.period);
+        w.write_i32(self.last_amp);
+        w.write_u32(self.delay);
+        w.write_bool(self.sweep_enabled);
+        w.write_u16(self.sweep_frequency);
+        w.write_u8(self.sweep_delay);
+        w.write_u8(self.sweep_period);
+        w.write_u8(self.sweep_shift);
+        w.write_bool(self.sweep_negate);
+        w.write_bool(self.sweep_did_negate);
+        self.volume_envelope.save_snapshot(w);
+    }
+
+    fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.active = r.read_bool()?;
+        self.dac_enabled = r.read_bool()?;
+        self.duty = r.read_u8()?;
+        self.phase = r.read_u8()?;
+        self.length.load_snapshot(r)?;
+        self.frequency = r.read_u16()?;
+        self.period = r.read_u32()?;
+        self.last_amp = r.read_i32()?;
+        self.delay = r.read_u32()?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_frequency = r.read_u16()?;
+        self.sweep_delay = r.read_u8()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_shift = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_did_negate = r.read_bool()?;
+        self.volume_envelope.load_snapshot(r)?;
+        self.blip.clear();
+        Ok(())
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF10 => {
@@ -373,6 +447,36 @@ impl WaveChannel {
         }
     }
 
+    fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_bool(self.active);
+        w.write_bool(self.dac_enabled);
+        self.length.save_snapshot(w);
+        w.write_u16(self.frequency);
+        w.write_u32(self.period);
+        w.write_i32(self.last_amp);
+        w.write_u32(self.delay);
+        w.write_u8(self.volume_shift);
+        w.write_bytes(&self.waveram);
+        w.write_u8(self.current_wave);
+        w.write_bool(self.sample_recently_accessed);
+    }
+
+    fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.active = r.read_bool()?;
+        self.dac_enabled = r.read_bool()?;
+        self.length.load_snapshot(r)?;
+        self.frequency = r.read_u16()?;
+        self.period = r.read_u32()?;
+        self.last_amp = r.read_i32()?;
+        self.delay = r.read_u32()?;
+        self.volume_shift = r.read_u8()?;
+        r.read_bytes(&mut self.waveram)?;
+        self.current_wave = r.read_u8()?;
+        self.sample_recently_accessed = r.read_bool()?;
+        self.blip.clear();
+        Ok(())
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF1A => (if self.dac_enabled { 0x80 } else { 0 }) | 0x7F,
@@ -561,6 +665,34 @@ impl NoiseChannel {
         }
     }
 
+    fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_bool(self.active);
+        w.write_bool(self.dac_enabled);
+        w.write_u8(self.reg_ff22);
+        self.length.save_snapshot(w);
+        self.volume_envelope.save_snapshot(w);
+        w.write_u32(self.period);
+        w.write_u8(self.shift_width);
+        w.write_u16(self.state);
+        w.write_u32(self.delay);
+        w.write_i32(self.last_amp);
+    }
+
+    fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.active = r.read_bool()?;
+        self.dac_enabled = r.read_bool()?;
+        self.reg_ff22 = r.read_u8()?;
+        self.length.load_snapshot(r)?;
+        self.volume_envelope.load_snapshot(r)?;
+        self.period = r.read_u32()?;
+        self.shift_width = r.read_u8()?;
+        self.state = r.read_u16()?;
+        self.delay = r.read_u32()?;
+        self.last_amp = r.read_i32()?;
+        self.blip.clear();
+        Ok(())
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF20 => 0xFF,
@@ -665,6 +797,8 @@ pub struct Sound {
     reg_ff25: u8,
     need_sync: bool,
     dmg_mode: bool,
+    /// Mixed output that hasn't been picked up yet, only kept while recording
+    pub captured: Option<Vec<[f32; 2]>>,
 }
 
 impl Sound {
@@ -693,12 +827,54 @@ impl Sound {
             reg_ff25: 0x00,
             need_sync: false,
             dmg_mode: false,
+            captured: None,
         }
     }
 
+    /// The blip buffers only hold samples that are waiting to be mixed, so
+    /// they are cleared instead of being part of the snapshot
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_bool(self.on);
+        w.write_u32(self.time);
+        w.write_u32(self.prev_time);
+        w.write_u32(self.next_time);
+        w.write_u8(self.frame_step);
+        self.channel1.save_snapshot(w);
+        self.channel2.save_snapshot(w);
+        self.channel3.save_snapshot(w);
+        self.channel4.save_snapshot(w);
+        w.write_u8(self.volume_left);
+        w.write_u8(self.volume_right);
+        w.write_u8(self.reg_vin_to_so);
+        w.write_u8(self.reg_ff25);
+        w.write_bool(self.need_sync);
+    }
+
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.on = r.read_bool()?;
+        self.time = r.read_u32()?;
+        self.prev_time = r.read_u32()?;
+        self.next_time = r.read_u32()?;
+        self.frame_step = r.read_u8()?;
+        self.channel1.load_snapshot(r)?;
+        self.channel2.load_snapshot(r)?;
+        self.channel3.load_snapshot(r)?;
+        self.channel4.load_snapshot(r)?;
+        self.volume_left = r.read_u8()?;
+        self.volume_right = r.read_u8()?;
+        self.reg_vin_to_so = r.read_u8()?;
+        self.reg_ff25 = r.read_u8()?;
+        self.need_sync = r.read_bool()?;
+        Ok(())
+    }
+
     pub fn rb(&mut self, a: u16) -> u8 {
         self.run();
+        self.peek(a)
+    }
 
+    /// Reads a register without catching up with the emulated time first
+    pub fn peek(&self, a: u16) -> u8 {
         match a {
             0xFF10..=0xFF14 => self.channel1.rb(a),
             0xFF16..=0xFF19 => self.channel2.rb(a),
@@ -779,6 +955,12 @@ impl Sound {
         }
     }
 
+    /// The master volume in NR50, from 0.0 to 1.0, which the game lowers to
+    /// fade out the music
+    pub fn master_volume(&self) -> f32 {
+        self.volume_left.max(self.volume_right) as f32 / 7.0
+    }
+
     pub fn sync(&mut self) {
         self.need_sync = true;
     }
@@ -902,6 +1084,10 @@ impl Sound {
             debug_assert!(count1 == count3);
             debug_assert!(count1 == count4);
 
+            if let Some(captured) = self.captured.as_mut() {
+                captured.extend((0..count1).map(|i| [buf_left[i], buf_right[i]]));
+            }
+
             outputted += count1;
         }
     }
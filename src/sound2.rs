@@ -1,38 +1,169 @@
-use rodio::{OutputStream, OutputStreamHandle, Sink};
+use crate::{
+    frontend::{AudioSink, AudioSource, AudioVoice},
+    game::audio::low_health_alarm::LowHealthAlarm,
+    recorder::AudioRecorder,
+};
+
+/// How many sound effects can play at the same time by default
+pub const DEFAULT_SFX_VOICES: usize = 4;
+
+/// How often finished sound effects are cleaned up, in ticks, so that the
+/// music comes back up soon after a cry ends
+const SFX_POLL_INTERVAL: u32 = 4096;
+
+/// Which sound effects give way when all voices are busy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SfxPriority {
+    /// Menu beeps and other short blips
+    Low,
+    Normal,
+    /// Cries and jingles
+    High,
+}
 
 pub trait Sfx<TSource> {
     fn open(self) -> TSource;
+
+    fn priority(&self) -> SfxPriority {
+        SfxPriority::Normal
+    }
+
+    /// Identifies the sound, so that playing it again while it still plays
+    /// replaces it like in the original game, instead of piling up voices.
+    /// Sounds without one always get a voice of their own.
+    fn sfx_id(&self) -> Option<u32> {
+        None
+    }
+
+    /// Volume of the music while this plays, where the original game pauses
+    /// some or all of the music channels
+    fn music_volume(&self) -> f32 {
+        1.0
+    }
 }
 
 pub trait Music<TSource>: Sfx<TSource> {
     fn id(&self) -> u32;
 }
 
+struct SfxVoice {
+    voice: Box<dyn AudioVoice>,
+    id: Option<u32>,
+    priority: SfxPriority,
+    music_volume: f32,
+}
+
 pub struct Sound2 {
-    handle: OutputStreamHandle,
-    music: Option<(u32, Sink)>,
-    sfx: Option<Sink>,
-    _stream: OutputStream,
+    output: Box<dyn AudioSink>,
+    music: Option<(u32, Box<dyn AudioVoice>)>,
+    /// Oldest first
+    sfx: Vec<SfxVoice>,
+    max_sfx: usize,
+    sfx_poll_countdown: u32,
+    /// Volume of the music while the game is fading it out
+    fade_volume: f32,
+    low_health_alarm: Option<Box<dyn AudioVoice>>,
+    recorder: Option<AudioRecorder>,
 }
 
 impl Sound2 {
-    pub fn new() -> Self {
-        let (stream, handle) = OutputStream::try_default().unwrap();
-
+    pub fn new(output: Box<dyn AudioSink>) -> Self {
         Sound2 {
-            _stream: stream,
+            output,
             music: None,
-            handle,
-            sfx: None,
+            sfx: Vec::new(),
+            max_sfx: DEFAULT_SFX_VOICES,
+            sfx_poll_countdown: 0,
+            fade_volume: 1.0,
+            low_health_alarm: None,
+            recorder: None,
+        }
+    }
+
+    /// How many sound effects can play at the same time, at least one
+    pub fn set_sfx_voices(&mut self, count: usize) {
+        self.max_sfx = count.max(1);
+    }
+
+    /// Record everything that is played from now on, mixed with the APU
+    /// output that is passed to [`Sound2::do_cycle`]
+    pub fn record(&mut self, recorder: AudioRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn do_cycle(&mut self, ticks: u32, apu: Option<&mut Vec<[f32; 2]>>) {
+        if !self.sfx.is_empty() {
+            self.sfx_poll_countdown = self.sfx_poll_countdown.saturating_sub(ticks);
+
+            if self.sfx_poll_countdown == 0 {
+                self.sfx_poll_countdown = SFX_POLL_INTERVAL;
+                self.remove_finished_sfx();
+            }
+        }
+
+        let Some(recorder) = self.recorder.as_mut() else {
+            return;
+        };
+
+        recorder.do_cycle(ticks);
+
+        if let Some(apu) = apu {
+            recorder.mix_apu(apu);
+            apu.clear();
+        }
+    }
+
+    /// The recorder decodes its own copy of the sound, since it is pulled in
+    /// emulated time while the output plays on the wall clock
+    fn play(&mut self, open: impl Fn() -> AudioSource) -> Box<dyn AudioVoice> {
+        match self.recorder.as_mut() {
+            Some(recorder) => recorder.play(open(), open(), &mut *self.output),
+            None => self.output.play(open()),
+        }
+    }
+
+    /// Follows the master volume while the game fades out the music, which
+    /// takes the same number of frames as in the original game since the
+    /// next track isn't started until the fade is done
+    pub fn set_fade_volume(&mut self, volume: f32) {
+        if self.fade_volume != volume {
+            self.fade_volume = volume;
+            self.update_music_volume();
+        }
+    }
+
+    #[cfg(test)]
+    pub fn fade_volume(&self) -> f32 {
+        self.fade_volume
+    }
+
+    pub fn set_low_health_alarm(&mut self, enabled: bool) {
+        match (enabled, self.low_health_alarm.is_some()) {
+            (true, false) => {
+                // Only played live, since the APU already plays the ROM's own
+                // alarm into recordings
+                let voice = self.output.play(Box::new(LowHealthAlarm::new()));
+                self.low_health_alarm = Some(voice);
+            }
+            (false, true) => {
+                if let Some(voice) = self.low_health_alarm.take() {
+                    voice.stop();
+                }
+            }
+            _ => {}
         }
     }
 
     pub fn stop_music(&mut self) {
-        if let Some((_, sink)) = self.music.take() {
-            sink.stop();
+        if let Some((_, voice)) = self.music.take() {
+            voice.stop();
         }
     }
 
+    pub fn music_id(&self) -> Option<u32> {
+        self.music.as_ref().map(|(id, _)| *id)
+    }
+
     fn is_playing_music(&self, id: u32) -> bool {
         if let Some((playing, _)) = self.music.as_ref() {
             *playing == id
@@ -43,7 +174,7 @@ impl Sound2 {
 
     pub fn start_music<T, TSource>(&mut self, music: T)
     where
-        T: Music<TSource>,
+        T: Music<TSource> + Clone,
         TSource: rodio::Source + Send + 'static,
         f32: cpal::FromSample<TSource::Item>,
         TSource::Item: rodio::Sample + Send,
@@ -56,24 +187,188 @@ impl Sound2 {
 
         self.stop_music();
 
-        let sink = Sink::try_new(&self.handle).unwrap();
-        sink.append(music.open());
-        self.music = Some((id, sink));
+        let voice = self.play(|| Box::new(music.clone().open().convert_samples::<f32>()));
+        self.music = Some((id, voice));
+        self.update_music_volume();
     }
 
     pub fn play_sfx<T, TSource>(&mut self, sound: T)
     where
-        T: Sfx<TSource>,
+        T: Sfx<TSource> + Clone,
         TSource: rodio::Source + Send + 'static,
         f32: cpal::FromSample<TSource::Item>,
         TSource::Item: rodio::Sample + Send,
     {
-        if let Some(sink) = self.sfx.take() {
-            sink.stop();
+        let id = sound.sfx_id();
+        let priority = sound.priority();
+        let music_volume = sound.music_volume();
+
+        self.sfx.retain(|sfx| !sfx.voice.is_done());
+
+        if let Some(index) = id.and_then(|id| self.sfx.iter().position(|sfx| sfx.id == Some(id))) {
+            self.sfx.remove(index).voice.stop();
+        }
+
+        if self.sfx.len() >= self.max_sfx {
+            // The oldest of the lowest priority sounds makes room, unless the
+            // new sound has an even lower priority
+            let (index, lowest) = self
+                .sfx
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, sfx)| sfx.priority)
+                .unwrap();
+
+            if lowest.priority > priority {
+                return;
+            }
+
+            self.sfx.remove(index).voice.stop();
+        }
+
+        let voice = self.play(|| Box::new(sound.clone().open().convert_samples::<f32>()));
+        self.sfx.push(SfxVoice {
+            voice,
+            id,
+            priority,
+            music_volume,
+        });
+        self.update_music_volume();
+    }
+
+    fn remove_finished_sfx(&mut self) {
+        let count = self.sfx.len();
+        self.sfx.retain(|sfx| !sfx.voice.is_done());
+
+        if self.sfx.len() != count {
+            self.update_music_volume();
+        }
+    }
+
+    /// Ducks the music to the lowest volume that any playing sound asks for,
+    /// on top of any fade out
+    fn update_music_volume(&self) {
+        let Some((_, music)) = self.music.as_ref() else {
+            return;
+        };
+
+        let ducked = self
+            .sfx
+            .iter()
+            .map(|sfx| sfx.music_volume)
+            .fold(1.0, f32::min);
+
+        music.set_volume(ducked * self.fade_volume);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        rc::Rc,
+    };
+
+    use rodio::buffer::SamplesBuffer;
+
+    use super::*;
+
+    /// Keeps every voice playing until it is stopped
+    struct TestSink(Rc<RefCell<Vec<Rc<TestVoice>>>>);
+
+    struct TestVoice {
+        stopped: Cell<bool>,
+        volume: Cell<f32>,
+    }
+
+    impl AudioSink for TestSink {
+        fn play(&mut self, _source: AudioSource) -> Box<dyn AudioVoice> {
+            let voice = Rc::new(TestVoice {
+                stopped: Cell::new(false),
+                volume: Cell::new(1.0),
+            });
+            self.0.borrow_mut().push(voice.clone());
+            Box::new(voice)
+        }
+    }
+
+    impl AudioVoice for Rc<TestVoice> {
+        fn stop(&self) {
+            self.stopped.set(true);
+        }
+
+        fn set_volume(&self, volume: f32) {
+            self.volume.set(volume);
+        }
+
+        fn is_done(&self) -> bool {
+            self.stopped.get()
+        }
+    }
+
+    /// An id, a priority and a music volume
+    #[derive(Clone)]
+    struct TestSfx(u32, SfxPriority, f32);
+
+    impl Sfx<SamplesBuffer<f32>> for TestSfx {
+        fn open(self) -> SamplesBuffer<f32> {
+            SamplesBuffer::new(1, 44100, vec![0.0; 10])
+        }
+
+        fn priority(&self) -> SfxPriority {
+            self.1
+        }
+
+        fn sfx_id(&self) -> Option<u32> {
+            Some(self.0)
         }
 
-        let sink = Sink::try_new(&self.handle).unwrap();
-        sink.append(sound.open());
-        self.sfx = Some(sink);
+        fn music_volume(&self) -> f32 {
+            self.2
+        }
+    }
+
+    impl Music<SamplesBuffer<f32>> for TestSfx {
+        fn id(&self) -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn lower_priority_sfx_make_room() {
+        let voices = Rc::new(RefCell::new(Vec::new()));
+        let mut sound = Sound2::new(Box::new(TestSink(voices.clone())));
+        sound.set_sfx_voices(2);
+
+        sound.start_music(TestSfx(0, SfxPriority::Normal, 1.0));
+        sound.play_sfx(TestSfx(1, SfxPriority::Low, 1.0));
+        sound.play_sfx(TestSfx(2, SfxPriority::High, 0.5));
+        sound.play_sfx(TestSfx(3, SfxPriority::Normal, 1.0));
+        sound.play_sfx(TestSfx(4, SfxPriority::Low, 1.0));
+
+        let voices = voices.borrow().clone();
+        let stopped: Vec<bool> = voices.iter().map(|v| v.stopped.get()).collect();
+
+        // The low priority beep gave way, and the last one was dropped
+        assert_eq!(stopped, [false, true, false, false]);
+        assert_eq!(voices[0].volume.get(), 0.5);
+
+        voices[2].stop();
+        sound.remove_finished_sfx();
+        assert_eq!(voices[0].volume.get(), 1.0);
+    }
+
+    #[test]
+    fn repeated_sfx_replace_each_other() {
+        let voices = Rc::new(RefCell::new(Vec::new()));
+        let mut sound = Sound2::new(Box::new(TestSink(voices.clone())));
+
+        sound.play_sfx(TestSfx(1, SfxPriority::Low, 1.0));
+        sound.play_sfx(TestSfx(2, SfxPriority::Low, 1.0));
+        sound.play_sfx(TestSfx(1, SfxPriority::Low, 1.0));
+        sound.play_sfx(TestSfx(1, SfxPriority::Low, 1.0));
+
+        let stopped: Vec<bool> = voices.borrow().iter().map(|v| v.stopped.get()).collect();
+        assert_eq!(stopped, [true, false, true, false]);
     }
 }
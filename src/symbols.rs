@@ -0,0 +1,118 @@
+//! Names for ROM addresses, read from a `.sym` file as produced when building
+//! pret/pokeyellow (`pokeyellow.sym`).
+
+use std::{collections::HashMap, fs, io, path::Path};
+
+/// Addresses outside of the switchable ROM bank are the same in every bank
+pub fn normalize(bank: usize, addr: u16) -> (usize, u16) {
+    if (0x4000..0x8000).contains(&addr) {
+        (bank, addr)
+    } else {
+        (0x00, addr)
+    }
+}
+
+pub struct Symbols {
+    /// Sorted by address within each bank
+    banks: HashMap<usize, Vec<(u16, String)>>,
+}
+
+impl Symbols {
+    pub fn from_file(path: &Path) -> io::Result<Symbols> {
+        Ok(Symbols::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Parses lines of the form `01:5ba6 MainMenu`, ignoring comments and
+    /// anything else that doesn't look like a symbol
+    pub fn parse(text: &str) -> Symbols {
+        let mut banks: HashMap<usize, Vec<(u16, String)>> = HashMap::new();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+
+            let Some((location, name)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let Some((bank, addr)) = location.split_once(':') else {
+                continue;
+            };
+            let (Ok(bank), Ok(addr)) = (
+                usize::from_str_radix(bank, 16),
+                u16::from_str_radix(addr, 16),
+            ) else {
+                continue;
+            };
+
+            let (bank, addr) = normalize(bank, addr);
+            banks
+                .entry(bank)
+                .or_default()
+                .push((addr, name.trim().to_owned()));
+        }
+
+        for symbols in banks.values_mut() {
+            symbols.sort_by_key(|&(addr, _)| addr);
+        }
+
+        Symbols { banks }
+    }
+
+    /// The symbol at exactly this address
+    pub fn exact(&self, bank: usize, addr: u16) -> Option<&str> {
+        let (bank, addr) = normalize(bank, addr);
+        let symbols = self.banks.get(&bank)?;
+        let idx = symbols.partition_point(|&(a, _)| a < addr);
+
+        match symbols.get(idx) {
+            Some((a, name)) if *a == addr => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The closest symbol at or before this address, and the offset from it
+    pub fn lookup(&self, bank: usize, addr: u16) -> Option<(&str, u16)> {
+        let (bank, addr) = normalize(bank, addr);
+        let symbols = self.banks.get(&bank)?;
+        let idx = symbols.partition_point(|&(a, _)| a <= addr);
+        let (a, name) = symbols.get(idx.checked_sub(1)?)?;
+
+        Some((name, addr - a))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "; File generated by rgblink\n\
+        00:0150 Init\n\
+        00:0242 OverworldLoop\n\
+        00:0245 OverworldLoopLessDelay\n\
+        01:5ba6 MainMenu\n\
+        01:5c83 MainMenu.pressedA\n\
+        03:5ba6 SomethingElse\n";
+
+    #[test]
+    fn exact() {
+        let symbols = Symbols::parse(SYM);
+
+        assert_eq!(symbols.exact(0x00, 0x0242), Some("OverworldLoop"));
+        assert_eq!(symbols.exact(0x1c, 0x0242), Some("OverworldLoop"));
+        assert_eq!(symbols.exact(0x01, 0x5ba6), Some("MainMenu"));
+        assert_eq!(symbols.exact(0x03, 0x5ba6), Some("SomethingElse"));
+        assert_eq!(symbols.exact(0x02, 0x5ba6), None);
+        assert_eq!(symbols.exact(0x00, 0x0243), None);
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = Symbols::parse(SYM);
+
+        assert_eq!(symbols.lookup(0x00, 0x0243), Some(("OverworldLoop", 1)));
+        assert_eq!(
+            symbols.lookup(0x01, 0x5c90),
+            Some(("MainMenu.pressedA", 13))
+        );
+        assert_eq!(symbols.lookup(0x00, 0x0100), None);
+    }
+}
@@ -1,3 +1,7 @@
+use std::io;
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
 pub struct Timer {
     divider: u8,
     counter: u8,
@@ -85,4 +89,27 @@ impl Timer {
             }
         }
     }
+
+    pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.write_u8(self.divider);
+        w.write_u8(self.counter);
+        w.write_u8(self.modulo);
+        w.write_bool(self.enabled);
+        w.write_u32(self.step);
+        w.write_u32(self.internalcnt);
+        w.write_u32(self.internaldiv);
+        w.write_u8(self.interrupt);
+    }
+
+    pub fn load_snapshot(&mut self, r: &mut SnapshotReader) -> io::Result<()> {
+        self.divider = r.read_u8()?;
+        self.counter = r.read_u8()?;
+        self.modulo = r.read_u8()?;
+        self.enabled = r.read_bool()?;
+        self.step = r.read_u32()?;
+        self.internalcnt = r.read_u32()?;
+        self.internaldiv = r.read_u32()?;
+        self.interrupt = r.read_u8()?;
+        Ok(())
+    }
 }
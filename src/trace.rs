@@ -0,0 +1,152 @@
+//! Keeps the last instructions run by the interpreter, along with the hooks
+//! that were entered, so that they can be printed when something goes wrong.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    sync::Arc,
+};
+
+use crate::{disasm, symbols::Symbols};
+
+/// State of the CPU right before an instruction was executed
+pub struct Step {
+    pub bank: usize,
+    pub pc: u16,
+    pub bytes: [u8; 3],
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+    pub sp: u16,
+}
+
+pub enum Event {
+    Step(Step),
+    Hook {
+        bank: usize,
+        pc: u16,
+        name: &'static str,
+    },
+}
+
+pub struct Trace {
+    events: VecDeque<Event>,
+    capacity: usize,
+    symbols: Option<Arc<Symbols>>,
+}
+
+impl Trace {
+    pub fn new(capacity: usize, symbols: Option<Arc<Symbols>>) -> Trace {
+        Trace {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            symbols,
+        }
+    }
+
+    pub fn record(&mut self, event: Event) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+
+        self.events.push_back(event);
+    }
+
+    /// Writes one line per event, oldest first
+    pub fn dump(&self, out: &mut impl Write) -> io::Result<()> {
+        let symbols = self.symbols.as_deref();
+
+        for event in &self.events {
+            match event {
+                Event::Step(step) => {
+                    let len = disasm::length(step.bytes[0]) as usize;
+                    let bytes = &step.bytes[..len];
+                    let hex = bytes
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+
+                    writeln!(
+                        out,
+                        "{:02x}:{:04x}  {:<8}  {:<28}  AF={:04x} BC={:04x} DE={:04x} HL={:04x} SP={:04x} {}{}",
+                        step.bank,
+                        step.pc,
+                        hex,
+                        disasm::disassemble(bytes, step.bank, step.pc, symbols),
+                        step.af,
+                        step.bc,
+                        step.de,
+                        step.hl,
+                        step.sp,
+                        location(symbols, step.bank, step.pc),
+                        flags(step.af as u8),
+                    )?;
+                }
+                Event::Hook { bank, pc, name } => {
+                    writeln!(out, "{:02x}:{:04x}  -> {}", bank, pc, name)?;
+                }
+            }
+        }
+
+        out.flush()
+    }
+}
+
+/// `ZNHC`, with `-` for flags that are clear
+fn flags(f: u8) -> String {
+    [(0x80, 'Z'), (0x40, 'N'), (0x20, 'H'), (0x10, 'C')]
+        .iter()
+        .map(|&(mask, c)| if f & mask != 0 { c } else { '-' })
+        .collect()
+}
+
+/// The routine an instruction belongs to, e.g. `MainMenu+12 `
+fn location(symbols: Option<&Symbols>, bank: usize, pc: u16) -> String {
+    match symbols.and_then(|s| s.lookup(bank, pc)) {
+        Some((name, 0)) => format!("{} ", name),
+        Some((name, offset)) => format!("{}+{} ", name, offset),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(pc: u16) -> Event {
+        Event::Step(Step {
+            bank: 0x00,
+            pc,
+            bytes: [0x00, 0x00, 0x00],
+            af: 0x01b0,
+            bc: 0x0013,
+            de: 0x00d8,
+            hl: 0x014d,
+            sp: 0xfffe,
+        })
+    }
+
+    #[test]
+    fn keeps_the_last_events() {
+        let mut trace = Trace::new(2, None);
+        trace.record(step(0x0150));
+        trace.record(step(0x0151));
+        trace.record(step(0x0152));
+
+        let mut out = Vec::new();
+        trace.dump(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("00:0151  00        nop"));
+        assert!(lines[1].starts_with("00:0152  00        nop"));
+        assert!(lines[1].ends_with("SP=fffe Z-HC"));
+    }
+}
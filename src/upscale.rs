@@ -0,0 +1,412 @@
+//! Filters that scale the 160x144 Game Boy frame up to the window size on the
+//! CPU, before the Rust layers are drawn on top.
+
+use std::{fmt, str::FromStr};
+
+use crate::gpu::{GB_SCREEN_H, GB_SCREEN_W};
+
+/// How many times the native resolution the screen is by default
+pub const DEFAULT_SCALE: usize = 4;
+
+type Pixel = [u8; 3];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Filter {
+    /// Every pixel becomes a square block
+    #[default]
+    Nearest,
+    /// Rounds off diagonal edges, applied once per factor of 2 in the scale
+    Scale2x,
+    /// Like `Scale2x`, applied once per factor of 3 in the scale
+    Scale3x,
+    /// Like `Scale2x`, but blends the rounded corners and treats colors that
+    /// are close as equal, like the hqx filters
+    Hqx,
+    /// Thin dark lines between the pixels, like the Game Boy LCD
+    Lcd,
+    /// A dark line below every row of pixels, like a CRT
+    Scanlines,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Filter::Nearest),
+            "scale2x" => Ok(Filter::Scale2x),
+            "scale3x" => Ok(Filter::Scale3x),
+            "hqx" => Ok(Filter::Hqx),
+            "lcd" => Ok(Filter::Lcd),
+            "scanlines" => Ok(Filter::Scanlines),
+            _ => Err(format!(
+                "Unknown filter {}, expected nearest, scale2x, scale3x, hqx, lcd or scanlines",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Filter::Nearest => "nearest",
+            Filter::Scale2x => "scale2x",
+            Filter::Scale3x => "scale3x",
+            Filter::Hqx => "hqx",
+            Filter::Lcd => "lcd",
+            Filter::Scanlines => "scanlines",
+        };
+
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Upscaler {
+    filter: Filter,
+    scale: usize,
+}
+
+impl Default for Upscaler {
+    fn default() -> Self {
+        Upscaler::new(Filter::Nearest, DEFAULT_SCALE)
+    }
+}
+
+impl Upscaler {
+    /// The scale is at least 1
+    pub fn new(filter: Filter, scale: usize) -> Self {
+        Upscaler {
+            filter,
+            scale: scale.max(1),
+        }
+    }
+
+    pub fn scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Width and height of the upscaled frame
+    pub fn screen_size(&self) -> (usize, usize) {
+        (GB_SCREEN_W * self.scale, GB_SCREEN_H * self.scale)
+    }
+
+    /// Scales a 160x144 RGB frame up to [`Upscaler::screen_size`], replacing
+    /// the contents of `out`
+    pub fn upscale_into(&self, frame: &[u8], out: &mut Vec<u8>) {
+        debug_assert_eq!(frame.len(), GB_SCREEN_W * GB_SCREEN_H * 3);
+
+        self.upscale_image_into(frame, GB_SCREEN_W, out);
+    }
+
+    /// Scales an RGB image that is `width` pixels wide up by the scale,
+    /// replacing the contents of `out`
+    pub fn upscale_image_into(&self, image: &[u8], width: usize, out: &mut Vec<u8>) {
+        out.clear();
+
+        let (image, scale) = match self.filter {
+            Filter::Nearest | Filter::Lcd | Filter::Scanlines => {
+                nearest(image, width, self.scale, out);
+
+                match self.filter {
+                    Filter::Lcd => lcd(out, width, self.scale),
+                    Filter::Scanlines => scanlines(out, width, self.scale),
+                    _ => {}
+                }
+
+                return;
+            }
+            Filter::Scale2x => repeat(image, width, self.scale, (2, scale2x)),
+            Filter::Scale3x => repeat(image, width, self.scale, (3, scale3x)),
+            Filter::Hqx => repeat(image, width, self.scale, (2, hq2x)),
+        };
+
+        nearest(image.pixels.as_flattened(), image.width, scale, out);
+    }
+}
+
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Pixel>,
+}
+
+impl Image {
+    fn from_rgb(rgb: &[u8], width: usize) -> Self {
+        Image {
+            width,
+            height: rgb.len() / 3 / width,
+            pixels: rgb.chunks_exact(3).map(|p| [p[0], p[1], p[2]]).collect(),
+        }
+    }
+
+    /// Pixels outside the image repeat the closest edge pixel
+    fn get(&self, x: isize, y: isize) -> Pixel {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// The 3x3 neighbourhood around a pixel, row by row
+    fn neighbours(&self, x: usize, y: usize) -> [Pixel; 9] {
+        let (x, y) = (x as isize, y as isize);
+
+        [
+            self.get(x - 1, y - 1),
+            self.get(x, y - 1),
+            self.get(x + 1, y - 1),
+            self.get(x - 1, y),
+            self.get(x, y),
+            self.get(x + 1, y),
+            self.get(x - 1, y + 1),
+            self.get(x, y + 1),
+            self.get(x + 1, y + 1),
+        ]
+    }
+
+    /// Builds an image `FACTOR` times the size, from the `FACTOR`x`FACTOR`
+    /// block that `block` returns for every pixel, row by row
+    fn map_blocks<const FACTOR: usize, const N: usize, F>(&self, block: F) -> Image
+    where
+        F: Fn([Pixel; 9]) -> [Pixel; N],
+    {
+        let width = self.width * FACTOR;
+        let mut pixels = vec![[0; 3]; width * self.height * FACTOR];
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let block = block(self.neighbours(x, y));
+
+                for (row, chunk) in block.chunks_exact(FACTOR).enumerate() {
+                    let start = (y * FACTOR + row) * width + x * FACTOR;
+                    pixels[start..start + FACTOR].copy_from_slice(chunk);
+                }
+            }
+        }
+
+        Image {
+            width,
+            height: self.height * FACTOR,
+            pixels,
+        }
+    }
+}
+
+/// Applies a filter pass that doubles or triples the size for as long as
+/// that divides the scale, and returns the scale that is left for nearest
+/// neighbour
+fn repeat(
+    rgb: &[u8],
+    width: usize,
+    scale: usize,
+    (factor, pass): (usize, fn(&Image) -> Image),
+) -> (Image, usize) {
+    let mut image = Image::from_rgb(rgb, width);
+    let mut remaining = scale;
+
+    while remaining.is_multiple_of(factor) {
+        image = pass(&image);
+        remaining /= factor;
+    }
+
+    (image, remaining)
+}
+
+/// Appends the RGB image `src`, `scale` times the size, to `out`
+fn nearest(src: &[u8], width: usize, scale: usize, out: &mut Vec<u8>) {
+    let row_len = width * 3;
+    out.reserve(src.len() * scale * scale);
+
+    for row in src.chunks_exact(row_len) {
+        let start = out.len();
+
+        if scale == 1 {
+            out.extend_from_slice(row);
+            continue;
+        }
+
+        for pixel in row.chunks_exact(3) {
+            for _ in 0..scale {
+                out.extend_from_slice(pixel);
+            }
+        }
+
+        for _ in 1..scale {
+            out.extend_from_within(start..start + row_len * scale);
+        }
+    }
+}
+
+/// The EPX/Scale2x algorithm
+fn scale2x(image: &Image) -> Image {
+    image.map_blocks::<2, 4, _>(|[_, b, _, d, e, f, _, h, _]| {
+        if b != h && d != f {
+            [
+                if d == b { d } else { e },
+                if b == f { f } else { e },
+                if d == h { d } else { e },
+                if h == f { f } else { e },
+            ]
+        } else {
+            [e; 4]
+        }
+    })
+}
+
+/// The AdvMAME3x/Scale3x algorithm
+#[rustfmt::skip]
+fn scale3x(image: &Image) -> Image {
+    image.map_blocks::<3, 9, _>(|[a, b, c, d, e, f, g, h, i]| {
+        if b != h && d != f {
+            [
+                if d == b { d } else { e },
+                if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                if b == f { f } else { e },
+                if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                e,
+                if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                if d == h { d } else { e },
+                if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                if h == f { f } else { e },
+            ]
+        } else {
+            [e; 9]
+        }
+    })
+}
+
+/// Scale2x with the corner rules of hqx: colors count as equal when they are
+/// close in YUV, and the corners are blended instead of copied
+fn hq2x(image: &Image) -> Image {
+    image.map_blocks::<2, 4, _>(|[a, b, c, d, e, f, g, h, i]| {
+        // Each corner looks at its two edge neighbours and the diagonal one
+        [(b, d, a), (b, f, c), (h, d, g), (h, f, i)].map(|(vertical, horizontal, diagonal)| {
+            if similar(vertical, horizontal) && !similar(e, vertical) {
+                if similar(e, diagonal) {
+                    // A thin line crossing the corner
+                    blend(&[(e, 2), (vertical, 1), (horizontal, 1)])
+                } else {
+                    blend(&[(e, 1), (vertical, 1), (horizontal, 1)])
+                }
+            } else if !similar(e, diagonal) && similar(e, vertical) && similar(e, horizontal) {
+                blend(&[(e, 3), (diagonal, 1)])
+            } else {
+                e
+            }
+        })
+    })
+}
+
+/// Same thresholds as the hqx filters
+fn similar(a: Pixel, b: Pixel) -> bool {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+
+    (ya - yb).abs() <= 48.0 && (ua - ub).abs() <= 7.0 && (va - vb).abs() <= 6.0
+}
+
+fn yuv([r, g, b]: Pixel) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        -0.169 * r - 0.331 * g + 0.5 * b + 128.0,
+        0.5 * r - 0.419 * g - 0.081 * b + 128.0,
+    )
+}
+
+fn blend(weighted: &[(Pixel, u32)]) -> Pixel {
+    let total: u32 = weighted.iter().map(|(_, weight)| weight).sum();
+
+    std::array::from_fn(|channel| {
+        let sum: u32 = weighted
+            .iter()
+            .map(|(pixel, weight)| pixel[channel] as u32 * weight)
+            .sum();
+
+        (sum / total) as u8
+    })
+}
+
+fn darken(pixel: &mut [u8], percent: u32) {
+    for c in pixel {
+        *c = (*c as u32 * percent / 100) as u8;
+    }
+}
+
+/// Darkens the last row and column of every block
+fn lcd(rgb: &mut [u8], width: usize, scale: usize) {
+    if scale == 1 {
+        return;
+    }
+
+    let width = width * scale;
+
+    for (i, pixel) in rgb.chunks_exact_mut(3).enumerate() {
+        let (x, y) = (i % width, i / width);
+
+        if x % scale == scale - 1 || y % scale == scale - 1 {
+            darken(pixel, 70);
+        }
+    }
+}
+
+/// Darkens the last row of every block
+fn scanlines(rgb: &mut [u8], width: usize, scale: usize) {
+    if scale == 1 {
+        return;
+    }
+
+    let row_len = width * scale * 3;
+
+    for row in rgb.chunks_exact_mut(row_len).skip(scale - 1).step_by(scale) {
+        darken(row, 50);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale2x_follows_the_diagonals() {
+        const W: Pixel = [255; 3];
+        const K: Pixel = [0; 3];
+
+        let image = Image {
+            width: 2,
+            height: 2,
+            pixels: vec![K, W, W, K],
+        };
+
+        let result = scale2x(&image);
+        assert_eq!((result.width, result.height), (4, 4));
+
+        #[rustfmt::skip]
+        assert_eq!(result.pixels, [
+            K, K, W, W,
+            K, W, K, W,
+            W, K, W, K,
+            W, W, K, K,
+        ]);
+    }
+
+    #[test]
+    fn every_filter_fills_the_screen() {
+        let frame = vec![0x80; GB_SCREEN_W * GB_SCREEN_H * 3];
+
+        for filter in ["nearest", "scale2x", "scale3x", "hqx", "lcd", "scanlines"] {
+            let filter: Filter = filter.parse().unwrap();
+
+            for scale in [1, 2, 3, 4, 5] {
+                let upscaler = Upscaler::new(filter, scale);
+                let (width, height) = upscaler.screen_size();
+
+                let mut screen = vec![1, 2, 3];
+                upscaler.upscale_into(&frame, &mut screen);
+                assert_eq!(screen.len(), width * height * 3);
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Widescreen mode, which shows more of the overworld on each side of the
+//! Game Boy screen.
+//!
+//! The BG map is 32 tiles wide and the map view only uses 20 of them, so the
+//! game writes 5 more columns of the map on each side of the view into it.
+//! That leaves the 2 columns that scroll out of view during a step alone.
+//! NPCs there are hidden by the game, since they're off its screen, so they
+//! are handed over as sprites in BG coordinates instead.
+//!
+//! Battles and screens that replace the map keep the sides black, and while
+//! the window covers a whole line, like it does for text boxes and menus,
+//! the sides keep showing the map from before.
+
+use crate::{
+    gpu::{GB_SCREEN_H, GB_SCREEN_W},
+    upscale::Upscaler,
+};
+
+/// Width of each side, in pixels
+pub const SIDE_W: usize = 40;
+pub const WIDE_SCREEN_W: usize = GB_SCREEN_W + 2 * SIDE_W;
+
+/// An 8x8 sprite outside the Game Boy screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WideSprite {
+    /// Position of the top left corner in the BG map, in pixels
+    pub x: u8,
+    pub y: u8,
+    pub tile: u8,
+    /// Same as the OAM attributes
+    pub flags: u8,
+}
+
+impl WideSprite {
+    /// Where the sprite is on the screen, given the BG scroll position
+    pub fn screen_position(&self, scx: u8, scy: u8) -> (i32, i32) {
+        // Sprites further left or up than the sides reach wrap around to the
+        // right or bottom of the BG map, where nothing is drawn
+        let x = self.x.wrapping_sub(scx).wrapping_add(SIDE_W as u8 + 8) as i32;
+        let y = self.y.wrapping_sub(scy).wrapping_add(8) as i32;
+
+        (x - SIDE_W as i32 - 8, y - 8)
+    }
+}
+
+/// Which x, relative to the Game Boy screen, each pixel of the sides is at
+pub fn side_x(index: usize) -> i32 {
+    match index < SIDE_W {
+        true => index as i32 - SIDE_W as i32,
+        false => (GB_SCREEN_W + index - SIDE_W) as i32,
+    }
+}
+
+/// Where the pixel at `x` relative to the Game Boy screen is in the sides,
+/// if it's in one of them
+pub fn side_index(x: i32) -> Option<usize> {
+    match x {
+        -40..=-1 => Some((x + SIDE_W as i32) as usize),
+        160..=199 => Some(x as usize - GB_SCREEN_W + SIDE_W),
+        _ => None,
+    }
+}
+
+pub struct Widescreen {
+    /// Whether the BG map holds the map view with the extra columns
+    map_view: bool,
+    sprites: Vec<WideSprite>,
+    /// Both sides at the native resolution, one image each
+    left: Vec<u8>,
+    right: Vec<u8>,
+    scaled_left: Vec<u8>,
+    scaled_right: Vec<u8>,
+    /// The upscaled screen with the sides around it
+    frame: Vec<u8>,
+}
+
+impl Widescreen {
+    pub fn new() -> Widescreen {
+        Widescreen {
+            map_view: false,
+            sprites: Vec::new(),
+            left: vec![0; SIDE_W * GB_SCREEN_H * 3],
+            right: vec![0; SIDE_W * GB_SCREEN_H * 3],
+            scaled_left: Vec::new(),
+            scaled_right: Vec::new(),
+            frame: Vec::new(),
+        }
+    }
+
+    pub fn map_view(&self) -> bool {
+        self.map_view
+    }
+
+    /// Turning the map view off blacks out the sides
+    pub fn set_map_view(&mut self, map_view: bool) {
+        self.map_view = map_view;
+
+        if !map_view {
+            self.sprites.clear();
+            self.left.fill(0);
+            self.right.fill(0);
+        }
+    }
+
+    pub fn sprites(&self) -> &[WideSprite] {
+        &self.sprites
+    }
+
+    pub fn set_sprites(&mut self, sprites: Vec<WideSprite>) {
+        self.sprites = sprites;
+    }
+
+    /// Stores one line of both sides, left to right
+    pub fn set_line(&mut self, line: usize, colors: &[[u8; 3]; 2 * SIDE_W]) {
+        let start = line * SIDE_W * 3;
+        let (left, right) = colors.split_at(SIDE_W);
+
+        self.left[start..start + SIDE_W * 3].copy_from_slice(left.as_flattened());
+        self.right[start..start + SIDE_W * 3].copy_from_slice(right.as_flattened());
+    }
+
+    /// Puts the sides, scaled up with `upscaler`, around the upscaled
+    /// screen
+    pub fn frame(&mut self, screen: &[u8], upscaler: Upscaler) -> &[u8] {
+        let scale = upscaler.scale();
+        let row_len = GB_SCREEN_W * scale * 3;
+        let side_len = SIDE_W * scale * 3;
+
+        debug_assert_eq!(screen.len(), row_len * GB_SCREEN_H * scale);
+
+        upscaler.upscale_image_into(&self.left, SIDE_W, &mut self.scaled_left);
+        upscaler.upscale_image_into(&self.right, SIDE_W, &mut self.scaled_right);
+
+        self.frame.clear();
+
+        for ((row, left), right) in screen
+            .chunks_exact(row_len)
+            .zip(self.scaled_left.chunks_exact(side_len))
+            .zip(self.scaled_right.chunks_exact(side_len))
+        {
+            self.frame.extend_from_slice(left);
+            self.frame.extend_from_slice(row);
+            self.frame.extend_from_slice(right);
+        }
+
+        &self.frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::upscale::Filter;
+
+    #[test]
+    fn puts_the_sides_around_the_screen() {
+        for x in [-40, -1, 160, 199] {
+            assert_eq!(side_x(side_index(x).unwrap()), x);
+        }
+        assert_eq!(side_index(0), None);
+        assert_eq!(side_index(200), None);
+
+        // A sprite 8 pixels left of the screen, and one that has scrolled
+        // off the right side and wrapped around
+        let sprite = WideSprite {
+            x: 0xf8,
+            y: 0x10,
+            tile: 0,
+            flags: 0,
+        };
+        assert_eq!(sprite.screen_position(0, 0), (-8, 16));
+        assert_eq!(sprite.screen_position(0x30, 0x20), (200, 240));
+
+        let mut wide = Widescreen::new();
+        wide.set_map_view(true);
+
+        let mut colors = [[0; 3]; 2 * SIDE_W];
+        colors[..SIDE_W].fill([1; 3]);
+        colors[SIDE_W..].fill([2; 3]);
+        wide.set_line(0, &colors);
+
+        let screen = vec![9; GB_SCREEN_W * GB_SCREEN_H * 2 * 2 * 3];
+        let frame = wide.frame(&screen, Upscaler::new(Filter::Nearest, 2));
+        let width = WIDE_SCREEN_W * 2 * 3;
+
+        assert_eq!(frame.len(), width * GB_SCREEN_H * 2);
+        assert_eq!(frame[width + SIDE_W * 2 * 3 - 1], 1);
+        assert_eq!(frame[width + SIDE_W * 2 * 3], 9);
+        assert_eq!(frame[2 * width - 1], 2);
+        assert_eq!(frame[2 * width], 0);
+    }
+}